@@ -0,0 +1,231 @@
+//! Access logging of HTTP requests
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+use http::{header, Method, Uri};
+use tracing::info;
+
+use crate::{config::ArxConfig, request_id::RequestId};
+
+/// Entries subject to `access_log_sampling` so far, across all requests
+static SAMPLED_ENTRIES: AtomicU64 = AtomicU64::new(0);
+
+/// An access log entry for a request in progress
+pub struct AccessLog {
+    method: Method,
+    uri: Uri,
+    request_id: Option<String>,
+    start: Instant,
+    sampling: Option<u32>,
+}
+
+impl AccessLog {
+    /// Begin an access log entry for a request.
+    ///
+    /// Returns `None` if access logging is disabled, or the request is exempt from it
+    /// (e.g. health checks from Kubernetes probes).
+    pub fn begin<B>(req: &http::Request<B>, cfg: &ArxConfig) -> Option<Self> {
+        if !cfg.access_log || is_exempt(req, cfg) {
+            return None;
+        }
+
+        Some(Self {
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            request_id: req
+                .extensions()
+                .get::<RequestId>()
+                .and_then(|RequestId(request_id)| request_id.to_str().ok())
+                .map(str::to_string),
+            start: Instant::now(),
+            sampling: cfg.access_log_sampling,
+        })
+    }
+
+    /// Finish the entry by logging it together with its response.
+    ///
+    /// With `access_log_sampling`, only one in every so many entries without a server error are logged.
+    pub fn finish<B>(self, response: &http::Response<B>) {
+        if let Some(sampling) = self.sampling.filter(|sampling| *sampling > 1) {
+            if !response.status().is_server_error()
+                && SAMPLED_ENTRIES.fetch_add(1, Ordering::Relaxed) % u64::from(sampling) != 0
+            {
+                return;
+            }
+        }
+
+        info!(
+            method = %self.method,
+            uri = %self.uri,
+            status = response.status().as_u16(),
+            request_id = self.request_id.as_deref(),
+            latency = ?self.start.elapsed(),
+            "access"
+        );
+    }
+}
+
+fn is_exempt<B>(req: &http::Request<B>, cfg: &ArxConfig) -> bool {
+    if cfg
+        .access_log_exempt_paths
+        .iter()
+        .any(|path| path == req.uri().path())
+    {
+        return true;
+    }
+
+    let Some(user_agent) = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|h| h.to_str().ok())
+    else {
+        return false;
+    };
+
+    cfg.access_log_exempt_user_agents
+        .iter()
+        .any(|prefix| user_agent.starts_with(prefix.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use tracing_subscriber::fmt::MakeWriter;
+
+    use crate::{config::ArxConfig, request_id::RequestId};
+
+    use super::AccessLog;
+
+    #[derive(Clone, Default)]
+    struct CapturedLog(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for CapturedLog {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for CapturedLog {
+        type Writer = Self;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    /// Run a request with the given user agent through access logging
+    fn log_request(cfg: &ArxConfig, path: &str, user_agent: &str) -> String {
+        let req = http::Request::get(path)
+            .header(http::header::USER_AGENT, user_agent)
+            .body(())
+            .unwrap();
+        capture_log(cfg, req)
+    }
+
+    /// Run a request through access logging, returning the log output
+    fn capture_log(cfg: &ArxConfig, req: http::Request<()>) -> String {
+        let captured = CapturedLog::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(captured.clone())
+            .with_ansi(false)
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            if let Some(access_log) = AccessLog::begin(&req, cfg) {
+                access_log.finish(&http::Response::new(()));
+            }
+        });
+
+        let output = captured.0.lock().unwrap().clone();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn logs_requests() {
+        let cfg = ArxConfig {
+            access_log: true,
+            ..Default::default()
+        };
+        let log = log_request(&cfg, "/api", "curl/8.0");
+        assert!(log.contains("access"), "{log}");
+        assert!(log.contains("/api"), "{log}");
+    }
+
+    #[test]
+    fn logs_request_id() {
+        let cfg = ArxConfig {
+            access_log: true,
+            ..Default::default()
+        };
+        let mut req = http::Request::get("/api").body(()).unwrap();
+        req.extensions_mut()
+            .insert(RequestId(http::HeaderValue::from_static("abc123")));
+        let log = capture_log(&cfg, req);
+        assert!(log.contains("request_id=\"abc123\""), "{log}");
+    }
+
+    #[test]
+    fn sampled() {
+        let cfg = ArxConfig {
+            access_log: true,
+            access_log_sampling: Some(10),
+            ..Default::default()
+        };
+        let captured = CapturedLog::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(captured.clone())
+            .with_ansi(false)
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            for status in [200; 100].into_iter().chain([503; 5]) {
+                let req = http::Request::get("/api").body(()).unwrap();
+                let mut response = http::Response::new(());
+                *response.status_mut() = http::StatusCode::from_u16(status).unwrap();
+                AccessLog::begin(&req, &cfg).unwrap().finish(&response);
+            }
+        });
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(10, output.matches("status=200").count(), "{output}");
+        assert_eq!(5, output.matches("status=503").count(), "{output}");
+    }
+
+    #[test]
+    fn disabled() {
+        let cfg = ArxConfig::default();
+        assert_eq!("", log_request(&cfg, "/api", "curl/8.0"));
+    }
+
+    #[test]
+    fn exempt_probe_user_agent() {
+        let cfg = ArxConfig {
+            access_log: true,
+            ..Default::default()
+        };
+        assert_eq!("", log_request(&cfg, "/health", "kube-probe/1.31"));
+    }
+
+    #[test]
+    fn exempt_path() {
+        let cfg = ArxConfig {
+            access_log: true,
+            access_log_exempt_paths: vec!["/health".into()],
+            ..Default::default()
+        };
+        assert_eq!("", log_request(&cfg, "/health", "curl/8.0"));
+        assert_ne!("", log_request(&cfg, "/api", "curl/8.0"));
+    }
+}
@@ -0,0 +1,516 @@
+//! Admission control, spreading out or shedding bursts of expensive requests
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::config::ArxConfig;
+
+/// Admits requests at a fixed rate, delaying those arriving in a burst.
+///
+/// Each admitted request reserves the next free slot, so a burst is spread out evenly over time.
+/// Requests whose slot is further away than `max_wait` are not admitted.
+pub struct AdmissionControl {
+    interval: Duration,
+    max_wait: Duration,
+    next_slot: Mutex<Instant>,
+}
+
+impl AdmissionControl {
+    pub fn new(per_second: u32, max_wait: Duration) -> Self {
+        Self {
+            interval: Duration::from_secs(1) / per_second.max(1),
+            max_wait,
+            next_slot: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait for a request to be admitted.
+    ///
+    /// When it can't be admitted within `max_wait`, returns how long to wait before trying again.
+    pub async fn admit(&self) -> Result<(), Duration> {
+        let slot = {
+            let now = Instant::now();
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let slot = (*next_slot).max(now);
+            let wait = slot - now;
+            if wait > self.max_wait {
+                return Err(wait);
+            }
+            *next_slot = slot + self.interval;
+            slot
+        };
+
+        tokio::time::sleep_until(slot).await;
+        Ok(())
+    }
+}
+
+/// Requests in flight to backends, counted per route key.
+///
+/// Only keys with requests in flight or waiting are kept, so the keys of removed routes are forgotten.
+#[derive(Default)]
+pub struct InFlightLimits {
+    limits: Mutex<HashMap<String, Limit>>,
+}
+
+struct Limit {
+    max: u32,
+    queue: Option<u32>,
+    /// Alive as long as a request holds or waits for a permit.
+    /// An idle limit starts counting afresh, which makes no difference.
+    in_flight: Weak<Semaphore>,
+    /// Places for requests waiting to be let through, when bounded
+    queued: Option<Arc<Semaphore>>,
+}
+
+impl InFlightLimits {
+    /// Wait up to `wait` for a request to be let through, staying within `max` requests in flight under `key`.
+    /// With a `queue`, at most that many requests wait at once, others are not let through right away.
+    ///
+    /// The request counts as in flight until the permit is dropped.
+    pub async fn acquire(
+        &self,
+        key: &str,
+        max: u32,
+        wait: Duration,
+        queue: Option<u32>,
+    ) -> Option<OwnedSemaphorePermit> {
+        let (in_flight, queued) = {
+            let mut limits = self.limits.lock().unwrap();
+            // a changed limit starts counting afresh
+            let current = limits
+                .get(key)
+                .filter(|limit| limit.max == max && limit.queue == queue)
+                .and_then(|limit| Some((limit.in_flight.upgrade()?, limit.queued.clone())));
+
+            match current {
+                Some(current) => current,
+                None => {
+                    // forget idle keys
+                    limits.retain(|_, limit| limit.in_flight.strong_count() > 0);
+
+                    let in_flight = Arc::new(Semaphore::new(max as usize));
+                    let queued = queue.map(|queue| Arc::new(Semaphore::new(queue as usize)));
+                    limits.insert(
+                        key.to_string(),
+                        Limit {
+                            max,
+                            queue,
+                            in_flight: Arc::downgrade(&in_flight),
+                            queued: queued.clone(),
+                        },
+                    );
+                    (in_flight, queued)
+                }
+            }
+        };
+
+        if let Ok(permit) = in_flight.clone().try_acquire_owned() {
+            return Some(permit);
+        }
+        if wait.is_zero() {
+            return None;
+        }
+
+        // the place in the queue is held while waiting
+        let _queued = match queued {
+            Some(queued) => Some(queued.try_acquire_owned().ok()?),
+            None => None,
+        };
+
+        tokio::time::timeout(wait, in_flight.acquire_owned())
+            .await
+            .ok()?
+            .ok()
+    }
+
+    #[cfg(test)]
+    fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<_> = self.limits.lock().unwrap().keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+}
+
+/// Caps retries at a percentage of the requests within a window, see `ArxConfig::retry_budget_percent`.
+pub struct RetryBudget {
+    percent: u64,
+    min_retries: u64,
+    window: Duration,
+    counts: Mutex<RetryCounts>,
+}
+
+struct RetryCounts {
+    window_start: Instant,
+    requests: u64,
+    retries: u64,
+}
+
+impl RetryBudget {
+    pub fn new(percent: u32, min_retries: u32, window: Duration) -> Self {
+        Self {
+            percent: percent.into(),
+            min_retries: min_retries.into(),
+            window,
+            counts: Mutex::new(RetryCounts {
+                window_start: Instant::now(),
+                requests: 0,
+                retries: 0,
+            }),
+        }
+    }
+
+    /// The retry budget configured, if any
+    pub fn from_config(cfg: &ArxConfig) -> Option<Self> {
+        cfg.retry_budget_percent.map(|percent| {
+            Self::new(
+                percent,
+                cfg.retry_budget_min_retries,
+                cfg.retry_budget_window,
+            )
+        })
+    }
+
+    /// Count a request towards the budget
+    pub fn record_request(&self) {
+        self.current_counts().requests += 1;
+    }
+
+    /// Spend a retry from the budget, returning whether there was one left
+    pub fn try_retry(&self) -> bool {
+        let mut counts = self.current_counts();
+        let within_budget = counts.retries < self.min_retries
+            || counts.retries * 100 < counts.requests * self.percent;
+        if within_budget {
+            counts.retries += 1;
+        }
+        within_budget
+    }
+
+    fn current_counts(&self) -> std::sync::MutexGuard<'_, RetryCounts> {
+        let mut counts = self.counts.lock().unwrap();
+        if counts.window_start.elapsed() >= self.window {
+            *counts = RetryCounts {
+                window_start: Instant::now(),
+                requests: 0,
+                retries: 0,
+            };
+        }
+        counts
+    }
+}
+
+/// Consecutive one-second windows with messages beyond the rate before a client is considered abusive
+const SUSTAINED_EXCESS_WINDOWS: u32 = 3;
+
+/// Messages from a websocket client, admitted by a token bucket for `websocket_max_messages_per_second`.
+///
+/// The bucket holds up to one second worth of messages and refills continuously, so short bursts are absorbed.
+/// Windows of one second in which messages were refused are tracked, so only a sustained excess closes a tunnel.
+pub struct MessageRate {
+    max: u32,
+    tokens: f64,
+    refilled_at: Instant,
+    window_start: Instant,
+    window_exceeded: bool,
+    exceeded_windows: u32,
+}
+
+/// The outcome of [MessageRate::admit]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MessageAdmission {
+    Admitted,
+    /// Beyond the rate
+    Excess,
+    /// Beyond the rate, for `SUSTAINED_EXCESS_WINDOWS` consecutive windows
+    SustainedExcess,
+}
+
+impl MessageRate {
+    pub fn new(max: u32) -> Self {
+        let now = Instant::now();
+        Self {
+            max,
+            tokens: max as f64,
+            refilled_at: now,
+            window_start: now,
+            window_exceeded: false,
+            exceeded_windows: 0,
+        }
+    }
+
+    /// Count a message against the rate
+    pub fn admit(&mut self) -> MessageAdmission {
+        let now = Instant::now();
+
+        self.tokens = (self.tokens
+            + now.duration_since(self.refilled_at).as_secs_f64() * self.max as f64)
+            .min(self.max as f64);
+        self.refilled_at = now;
+
+        let elapsed_windows = now.duration_since(self.window_start).as_secs();
+        if elapsed_windows > 0 {
+            // a window without excess, even one without messages, breaks the streak
+            if !self.window_exceeded || elapsed_windows > 1 {
+                self.exceeded_windows = 0;
+            }
+            self.window_start += Duration::from_secs(elapsed_windows);
+            self.window_exceeded = false;
+        }
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return MessageAdmission::Admitted;
+        }
+
+        if !self.window_exceeded {
+            self.window_exceeded = true;
+            self.exceeded_windows += 1;
+        }
+
+        if self.exceeded_windows >= SUSTAINED_EXCESS_WINDOWS {
+            MessageAdmission::SustainedExcess
+        } else {
+            MessageAdmission::Excess
+        }
+    }
+}
+
+/// The slots held by a websocket tunnel, released when it is dropped along with the tunnel.
+#[derive(Clone)]
+pub struct TunnelSlots {
+    _permits: Arc<Vec<OwnedSemaphorePermit>>,
+}
+
+impl TunnelSlots {
+    pub fn new(permits: Vec<OwnedSemaphorePermit>) -> Self {
+        Self {
+            _permits: Arc::new(permits),
+        }
+    }
+}
+
+/// Requests cancelled by the client on one connection, e.g. by resetting HTTP/2 streams,
+/// counted over fixed windows for `http2_max_reset_streams`.
+#[derive(Clone)]
+pub struct StreamResets(Arc<StreamResetsInner>);
+
+struct StreamResetsInner {
+    max: Option<u32>,
+    window: Duration,
+    counts: Mutex<(Instant, u32)>,
+    exceeded: CancellationToken,
+}
+
+/// A request being served, counted as cancelled if dropped before [TrackedRequest::complete].
+pub struct TrackedRequest {
+    resets: StreamResets,
+    complete: bool,
+}
+
+impl StreamResets {
+    pub fn new(max: Option<u32>, window: Duration) -> Self {
+        Self(Arc::new(StreamResetsInner {
+            max,
+            window,
+            counts: Mutex::new((Instant::now(), 0)),
+            exceeded: CancellationToken::new(),
+        }))
+    }
+
+    pub fn track(&self) -> TrackedRequest {
+        TrackedRequest {
+            resets: self.clone(),
+            complete: false,
+        }
+    }
+
+    /// Completes once more than `max` requests were cancelled within a window.
+    pub async fn exceeded(&self) {
+        self.0.exceeded.cancelled().await
+    }
+
+    fn record(&self) {
+        let Some(max) = self.0.max else {
+            return;
+        };
+        let mut counts = self.0.counts.lock().unwrap();
+        if counts.0.elapsed() >= self.0.window {
+            *counts = (Instant::now(), 0);
+        }
+        counts.1 += 1;
+        if counts.1 > max {
+            self.0.exceeded.cancel();
+        }
+    }
+}
+
+impl TrackedRequest {
+    pub fn complete(mut self) {
+        self.complete = true;
+    }
+}
+
+impl Drop for TrackedRequest {
+    fn drop(&mut self) {
+        if !self.complete {
+            self.resets.record();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn excess_rejected() {
+        let admission = AdmissionControl::new(10, Duration::ZERO);
+
+        admission.admit().await.unwrap();
+        let retry_after = admission.admit().await.unwrap_err();
+        assert!(retry_after <= Duration::from_millis(100), "{retry_after:?}");
+    }
+
+    #[tokio::test]
+    async fn excess_delayed() {
+        let admission = AdmissionControl::new(10, Duration::from_millis(150));
+        let start = Instant::now();
+
+        // a burst gets slots 0ms, 100ms and 200ms after the start
+        let (first, second, third) =
+            tokio::join!(admission.admit(), admission.admit(), admission.admit());
+        assert_eq!(Ok(()), first);
+        assert_eq!(Ok(()), second);
+        assert!(third.is_err());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn message_burst_absorbed() {
+        let mut rate = MessageRate::new(10);
+
+        for _ in 0..10 {
+            assert_eq!(MessageAdmission::Admitted, rate.admit());
+        }
+        assert_eq!(MessageAdmission::Excess, rate.admit());
+
+        // refilled continuously
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(MessageAdmission::Admitted, rate.admit());
+    }
+
+    #[tokio::test]
+    async fn in_flight_limited() {
+        let limits = InFlightLimits::default();
+
+        let first = limits
+            .acquire("route/0", 1, Duration::ZERO, None)
+            .await
+            .unwrap();
+        assert!(limits
+            .acquire("route/0", 1, Duration::ZERO, None)
+            .await
+            .is_none());
+        assert!(limits
+            .acquire("route/1", 1, Duration::ZERO, None)
+            .await
+            .is_some());
+
+        drop(first);
+        assert!(limits
+            .acquire("route/0", 1, Duration::ZERO, None)
+            .await
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn idle_in_flight_limits_forgotten() {
+        let limits = InFlightLimits::default();
+
+        let held = limits
+            .acquire("route/0", 1, Duration::ZERO, None)
+            .await
+            .unwrap();
+        drop(
+            limits
+                .acquire("route/1", 1, Duration::ZERO, None)
+                .await
+                .unwrap(),
+        );
+        assert_eq!(vec!["route/0", "route/1"], limits.keys());
+
+        // e.g. route/1 was removed, it's forgotten when another key is counted
+        let _other = limits
+            .acquire("route/2", 1, Duration::ZERO, None)
+            .await
+            .unwrap();
+        assert_eq!(vec!["route/0", "route/2"], limits.keys());
+
+        // the limit is kept while in use
+        assert!(limits
+            .acquire("route/0", 1, Duration::ZERO, None)
+            .await
+            .is_none());
+        drop(held);
+    }
+
+    #[tokio::test]
+    async fn in_flight_queue_bounded() {
+        let limits = InFlightLimits::default();
+        let wait = Duration::from_millis(200);
+
+        let first = limits.acquire("route/0", 1, wait, Some(1)).await.unwrap();
+        // the second waits for the first, the third finds the queue full
+        let (second, third) = tokio::join!(limits.acquire("route/0", 1, wait, Some(1)), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let third = limits.acquire("route/0", 1, wait, Some(1)).await;
+            drop(first);
+            third
+        });
+        assert!(second.is_some());
+        assert!(third.is_none());
+    }
+
+    #[tokio::test]
+    async fn retries_within_budget() {
+        let budget = RetryBudget::new(20, 1, Duration::from_millis(100));
+
+        for _ in 0..10 {
+            budget.record_request();
+        }
+        assert!(budget.try_retry());
+        assert!(budget.try_retry());
+        assert!(!budget.try_retry());
+
+        // a new window only has the minimum retries
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(budget.try_retry());
+        assert!(!budget.try_retry());
+    }
+
+    #[tokio::test]
+    async fn stream_resets_exceeded() {
+        let resets = StreamResets::new(Some(2), Duration::from_secs(10));
+
+        resets.track().complete();
+        drop(resets.track());
+        drop(resets.track());
+        let exceeded = tokio::time::timeout(Duration::from_millis(10), resets.exceeded()).await;
+        assert!(exceeded.is_err());
+
+        drop(resets.track());
+        tokio::time::timeout(Duration::from_millis(10), resets.exceeded())
+            .await
+            .unwrap();
+    }
+}
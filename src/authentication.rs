@@ -1,55 +1,246 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
 use cookie::Cookie;
+use futures_util::{
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
 use http::{
     header::{self, AUTHORIZATION},
-    HeaderMap,
+    HeaderMap, HeaderValue,
 };
-use tracing::warn;
+use tracing::{debug, warn};
 
 use crate::{route::AuthDirective, ArxError};
 
+/// The cookie holding the Authly session
+const SESSION_COOKIE: &str = "session-cookie";
+
+/// An access token obtained in exchange for a session.
+#[derive(Clone)]
+pub struct AccessToken {
+    pub token: String,
+    pub expires_at: SystemTime,
+}
+
+/// Something that exchanges a session for an access token, i.e. Authly.
+#[async_trait]
+pub trait AccessTokenSource: Send + Sync {
+    async fn access_token(&self, session_token: &str) -> anyhow::Result<AccessToken>;
+}
+
+#[async_trait]
+impl AccessTokenSource for authly_client::Client {
+    async fn access_token(&self, session_token: &str) -> anyhow::Result<AccessToken> {
+        let access_token = self.get_access_token(session_token).await?;
+        Ok(AccessToken {
+            token: access_token.token.clone(),
+            expires_at: SystemTime::UNIX_EPOCH
+                + Duration::from_secs(access_token.claims.exp.max(0) as u64),
+        })
+    }
+}
+
+/// An exchange of a session in progress, with the error as a message so it can be shared
+type SharedExchange = Shared<BoxFuture<'static, Result<AccessToken, String>>>;
+
+/// Shares one exchange between concurrent requests with the same session, see `ArxConfig::auth_single_flight`.
+///
+/// An exchange is only shared while it is awaited, the access token is not cached.
+/// It is forgotten once its last waiter is done or gives up, so an exchange nobody awaits is never joined.
+pub struct SingleFlight {
+    source: Arc<dyn AccessTokenSource>,
+    in_flight: Mutex<HashMap<String, InFlightExchange>>,
+}
+
+struct InFlightExchange {
+    exchange: SharedExchange,
+    waiters: usize,
+}
+
+/// Held by a waiter of an exchange, forgetting the exchange when the last waiter is dropped
+struct ExchangeWaiter<'a> {
+    in_flight: &'a Mutex<HashMap<String, InFlightExchange>>,
+    session_token: &'a str,
+}
+
+impl Drop for ExchangeWaiter<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(exchange) = in_flight.get_mut(self.session_token) {
+            exchange.waiters -= 1;
+            if exchange.waiters == 0 {
+                in_flight.remove(self.session_token);
+            }
+        }
+    }
+}
+
+impl SingleFlight {
+    pub fn new(source: Arc<dyn AccessTokenSource>) -> Self {
+        Self {
+            source,
+            in_flight: Default::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl AccessTokenSource for SingleFlight {
+    async fn access_token(&self, session_token: &str) -> anyhow::Result<AccessToken> {
+        let exchange = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            let in_flight_exchange =
+                in_flight
+                    .entry(session_token.to_string())
+                    .or_insert_with(|| {
+                        let source = self.source.clone();
+                        let session_token = session_token.to_string();
+                        InFlightExchange {
+                            exchange: async move {
+                                source
+                                    .access_token(&session_token)
+                                    .await
+                                    .map_err(|err| format!("{err:#}"))
+                            }
+                            .boxed()
+                            .shared(),
+                            waiters: 0,
+                        }
+                    });
+            in_flight_exchange.waiters += 1;
+            in_flight_exchange.exchange.clone()
+        };
+        let _waiter = ExchangeWaiter {
+            in_flight: &self.in_flight,
+            session_token,
+        };
+
+        exchange.await.map_err(|err| anyhow!(err))
+    }
+}
+
 /// Process the auth directive, by interacting with Authly in various ways.
 ///
 /// The auth directive represents a rule on when to exchange a session for an access token.
-pub async fn process_auth_directive(
+///
+/// With an `expiry_skew`, access tokens expiring within the skew are refreshed before being forwarded.
+///
+/// An exchange taking longer than `timeout` fails with [ArxError::AuthTimeout],
+/// unless authentication is opportunistic, in which case the request goes on without an access token.
+pub async fn process_auth_directive<S: AccessTokenSource + ?Sized>(
     auth_directive: AuthDirective,
     target_headers: &mut http::HeaderMap,
-    authly_client: Option<&authly_client::Client>,
+    authly_client: Option<&S>,
+    expiry_skew: Option<Duration>,
+    timeout: Duration,
 ) -> Result<(), ArxError> {
     match (auth_directive, authly_client) {
         (AuthDirective::Mandatory, Some(client)) => {
             let cookie_jar = cookie_jar(target_headers);
-            let Some(session_cookie) = cookie_jar.get("session-cookie") else {
+            let Some(session_cookie) = cookie_jar.get(SESSION_COOKIE) else {
                 return Err(ArxError::NotAuthenticated);
             };
 
-            inject_access_token(target_headers, session_cookie, client).await
+            tokio::time::timeout(
+                timeout,
+                inject_access_token(target_headers, session_cookie, client, expiry_skew),
+            )
+            .await
+            .map_err(|_| {
+                warn!("authly access token exchange timed out");
+                ArxError::AuthTimeout
+            })?
         }
         (AuthDirective::Mandatory, None) => Err(ArxError::NotAuthenticated),
         (AuthDirective::Opportunistic, Some(client)) => {
             let cookie_jar = cookie_jar(target_headers);
-            let Some(session_cookie) = cookie_jar.get("session-cookie") else {
+            let Some(session_cookie) = cookie_jar.get(SESSION_COOKIE) else {
                 return Ok(());
             };
 
-            inject_access_token(target_headers, session_cookie, client).await
+            match tokio::time::timeout(
+                timeout,
+                inject_access_token(target_headers, session_cookie, client, expiry_skew),
+            )
+            .await
+            {
+                Ok(result) => result,
+                Err(_) => {
+                    warn!(
+                        "authly access token exchange timed out, continuing without access token"
+                    );
+                    Ok(())
+                }
+            }
         }
         (AuthDirective::Opportunistic, None) => Ok(()),
         (AuthDirective::Disabled, _) => Ok(()),
     }
 }
 
-async fn inject_access_token(
+/// Remove the credentials of the client, the `Authorization` header and the session cookie,
+/// so they don't reach backends of routes without authentication.
+pub fn strip_credentials(headers: &mut HeaderMap) {
+    headers.remove(AUTHORIZATION);
+
+    let other_cookies: Vec<&str> = headers
+        .get_all(header::COOKIE)
+        .into_iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .map(str::trim)
+        .filter(|cookie| {
+            !cookie.is_empty() && cookie.split('=').next().map(str::trim) != Some(SESSION_COOKIE)
+        })
+        .collect();
+    let other_cookies = other_cookies.join("; ");
+
+    headers.remove(header::COOKIE);
+    if !other_cookies.is_empty() {
+        // made of parts of valid header values
+        headers.insert(
+            header::COOKIE,
+            HeaderValue::from_str(&other_cookies).unwrap(),
+        );
+    }
+}
+
+async fn inject_access_token<S: AccessTokenSource + ?Sized>(
     target_headers: &mut HeaderMap,
     session_cookie: &Cookie<'static>,
-    authly_client: &authly_client::Client,
+    authly_client: &S,
+    expiry_skew: Option<Duration>,
 ) -> Result<(), ArxError> {
-    let access_token = authly_client
-        .get_access_token(session_cookie.value_trimmed())
-        .await
-        .map_err(|err| {
-            warn!(?err, "authly access token error");
-            ArxError::NotAuthenticated
-        })?;
+    let get_access_token = || async move {
+        authly_client
+            .access_token(session_cookie.value_trimmed())
+            .await
+            .map_err(|err| {
+                warn!(?err, "authly access token error");
+                ArxError::NotAuthenticated
+            })
+    };
+
+    let mut access_token = get_access_token().await?;
+
+    if let Some(expiry_skew) = expiry_skew {
+        if expires_within(&access_token, expiry_skew) {
+            debug!("access token about to expire, refreshing");
+            access_token = get_access_token().await?;
+
+            if expires_within(&access_token, Duration::ZERO) {
+                warn!("authly returned an expired access token");
+                return Err(ArxError::NotAuthenticated);
+            }
+        }
+    }
 
     target_headers.insert(
         AUTHORIZATION,
@@ -59,6 +250,14 @@ async fn inject_access_token(
     Ok(())
 }
 
+/// Whether the access token expires within the given duration from now
+fn expires_within(access_token: &AccessToken, duration: Duration) -> bool {
+    !access_token
+        .expires_at
+        .duration_since(SystemTime::now())
+        .is_ok_and(|remaining| remaining > duration)
+}
+
 fn cookie_jar(headers: &http::HeaderMap) -> cookie::CookieJar {
     let cookies = headers
         .get_all(header::COOKIE)
@@ -74,3 +273,166 @@ fn cookie_jar(headers: &http::HeaderMap) -> cookie::CookieJar {
 
     jar
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Hands out the given tokens in order
+    struct StubTokenSource {
+        tokens: Mutex<Vec<AccessToken>>,
+    }
+
+    impl StubTokenSource {
+        fn new(expiries: &[(&str, Duration)]) -> Self {
+            Self {
+                tokens: Mutex::new(
+                    expiries
+                        .iter()
+                        .rev()
+                        .map(|(token, expires_in)| AccessToken {
+                            token: token.to_string(),
+                            expires_at: SystemTime::now() + *expires_in,
+                        })
+                        .collect(),
+                ),
+            }
+        }
+
+        fn remaining(&self) -> usize {
+            self.tokens.lock().unwrap().len()
+        }
+    }
+
+    #[async_trait]
+    impl AccessTokenSource for StubTokenSource {
+        async fn access_token(&self, _session_token: &str) -> anyhow::Result<AccessToken> {
+            self.tokens
+                .lock()
+                .unwrap()
+                .pop()
+                .ok_or_else(|| anyhow::anyhow!("no more tokens"))
+        }
+    }
+
+    fn session_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, "session-cookie=s3ss10n".parse().unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn nearly_expired_token_is_refreshed() {
+        let source = StubTokenSource::new(&[
+            ("old", Duration::from_secs(5)),
+            ("new", Duration::from_secs(3600)),
+        ]);
+        let mut headers = session_headers();
+
+        process_auth_directive(
+            AuthDirective::Mandatory,
+            &mut headers,
+            Some(&source),
+            Some(Duration::from_secs(30)),
+            Duration::from_secs(10),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(0, source.remaining());
+        assert_eq!(headers[AUTHORIZATION], "Bearer new");
+    }
+
+    #[tokio::test]
+    async fn token_forwarded_without_expiry_skew() {
+        let source = StubTokenSource::new(&[
+            ("old", Duration::from_secs(5)),
+            ("new", Duration::from_secs(3600)),
+        ]);
+        let mut headers = session_headers();
+
+        process_auth_directive(
+            AuthDirective::Mandatory,
+            &mut headers,
+            Some(&source),
+            None,
+            Duration::from_secs(10),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(1, source.remaining());
+        assert_eq!(headers[AUTHORIZATION], "Bearer old");
+    }
+
+    /// Never answers the first exchange, answers the following ones
+    struct HangingTokenSource {
+        calls: Mutex<usize>,
+    }
+
+    #[async_trait]
+    impl AccessTokenSource for HangingTokenSource {
+        async fn access_token(&self, _session_token: &str) -> anyhow::Result<AccessToken> {
+            let first = {
+                let mut calls = self.calls.lock().unwrap();
+                *calls += 1;
+                *calls == 1
+            };
+            if first {
+                std::future::pending::<()>().await;
+            }
+            Ok(AccessToken {
+                token: "token".to_string(),
+                expires_at: SystemTime::now() + Duration::from_secs(3600),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn abandoned_exchange_forgotten() {
+        let single_flight = SingleFlight::new(Arc::new(HangingTokenSource {
+            calls: Mutex::new(0),
+        }));
+
+        let timed_out = tokio::time::timeout(
+            Duration::from_millis(50),
+            single_flight.access_token("s3ss10n"),
+        )
+        .await;
+        assert!(timed_out.is_err());
+        assert!(single_flight.in_flight.lock().unwrap().is_empty());
+
+        // the next request does not join the stuck exchange
+        let access_token = tokio::time::timeout(
+            Duration::from_secs(1),
+            single_flight.access_token("s3ss10n"),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!("token", access_token.token);
+        assert!(single_flight.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn credentials_stripped() {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, "Bearer impersonated".parse().unwrap());
+        headers.append(
+            header::COOKIE,
+            "theme=dark; session-cookie=s3ss10n".parse().unwrap(),
+        );
+        headers.append(header::COOKIE, "lang=nb".parse().unwrap());
+
+        strip_credentials(&mut headers);
+
+        assert!(!headers.contains_key(AUTHORIZATION));
+        assert_eq!(headers[header::COOKIE], "theme=dark; lang=nb");
+
+        let mut headers = session_headers();
+        strip_credentials(&mut headers);
+        assert!(!headers.contains_key(header::COOKIE));
+    }
+}
@@ -0,0 +1,289 @@
+//! Wrappers around HTTP bodies
+
+use std::{
+    fmt::Display,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use bytes::Buf;
+use http_body::{Body, Frame, SizeHint};
+use tokio::time::Sleep;
+
+use crate::{
+    hyper::DynHttpError,
+    metrics::{BackendMetrics, RouteMetrics},
+};
+
+/// A body that fails when the inner body does not produce any data within the idle timeout.
+///
+/// The timer only runs while the body is being polled, so time spent before the consumer
+/// starts reading (e.g. connecting to an upstream server) does not count.
+pub struct IdleTimeoutBody<B> {
+    inner: Pin<Box<B>>,
+    timeout: Duration,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<B> IdleTimeoutBody<B> {
+    pub fn new(inner: B, timeout: Duration) -> Self {
+        Self {
+            inner: Box::pin(inner),
+            timeout,
+            sleep: None,
+        }
+    }
+}
+
+/// The error produced by [IdleTimeoutBody].
+#[derive(Debug)]
+pub struct BodyIdleTimeout;
+
+impl Display for BodyIdleTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "body idle timeout")
+    }
+}
+
+impl std::error::Error for BodyIdleTimeout {}
+
+impl<B> Body for IdleTimeoutBody<B>
+where
+    B: Body,
+    B::Error: Into<DynHttpError>,
+{
+    type Data = B::Data;
+    type Error = DynHttpError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+
+        match this.inner.as_mut().poll_frame(cx) {
+            Poll::Ready(frame) => {
+                this.sleep = None;
+                Poll::Ready(frame.map(|result| result.map_err(Into::into)))
+            }
+            Poll::Pending => {
+                let timeout = this.timeout;
+                let sleep = this
+                    .sleep
+                    .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+
+                match sleep.as_mut().poll(cx) {
+                    Poll::Ready(()) => Poll::Ready(Some(Err(Box::new(BodyIdleTimeout)))),
+                    Poll::Pending => Poll::Pending,
+                }
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// A body that adds the number of data bytes passing through it to a route metrics counter.
+pub struct CountingBody<B> {
+    inner: Pin<Box<B>>,
+    metrics: Arc<RouteMetrics>,
+    counter: fn(&RouteMetrics) -> &AtomicU64,
+}
+
+impl<B> CountingBody<B> {
+    pub fn new(
+        inner: B,
+        metrics: Arc<RouteMetrics>,
+        counter: fn(&RouteMetrics) -> &AtomicU64,
+    ) -> Self {
+        Self {
+            inner: Box::pin(inner),
+            metrics,
+            counter,
+        }
+    }
+}
+
+impl<B: Body> Body for CountingBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = self.inner.as_mut().poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                (self.counter)(&self.metrics).fetch_add(data.remaining() as u64, Ordering::Relaxed);
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// A body that records the total latency of a backend response when it is dropped,
+/// i.e. when it is finished or abandoned.
+pub struct TotalLatencyBody<B> {
+    inner: Pin<Box<B>>,
+    metrics: Arc<BackendMetrics>,
+    start: Instant,
+}
+
+impl<B> TotalLatencyBody<B> {
+    pub fn new(inner: B, metrics: Arc<BackendMetrics>, start: Instant) -> Self {
+        Self {
+            inner: Box::pin(inner),
+            metrics,
+            start,
+        }
+    }
+}
+
+impl<B: Body> Body for TotalLatencyBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        self.inner.as_mut().poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl<B> Drop for TotalLatencyBody<B> {
+    fn drop(&mut self) {
+        self.metrics.total.observe(self.start.elapsed());
+    }
+}
+
+/// A body that holds on to a value, e.g. a permit, until it is dropped.
+pub struct HoldingBody<B, T> {
+    inner: Pin<Box<B>>,
+    _held: T,
+}
+
+impl<B, T> HoldingBody<B, T> {
+    pub fn new(inner: B, held: T) -> Self {
+        Self {
+            inner: Box::pin(inner),
+            _held: held,
+        }
+    }
+}
+
+impl<B: Body, T: Unpin> Body for HoldingBody<B, T> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        self.inner.as_mut().poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// A body without the trailers of the inner body, for clients that don't accept them.
+pub struct WithoutTrailersBody<B> {
+    inner: Pin<Box<B>>,
+}
+
+impl<B> WithoutTrailersBody<B> {
+    pub fn new(inner: B) -> Self {
+        Self {
+            inner: Box::pin(inner),
+        }
+    }
+}
+
+impl<B: Body> Body for WithoutTrailersBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        loop {
+            match self.inner.as_mut().poll_frame(cx) {
+                Poll::Ready(Some(Ok(frame))) if frame.is_trailers() => continue,
+                poll => return poll,
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Whether the error, or any of its sources, is of type `E`.
+pub fn caused_by<E: std::error::Error + 'static>(
+    error: &(dyn std::error::Error + 'static),
+) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if error.is::<E>() {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+/// Whether the error is caused by a backend closing its connection before completing the message.
+pub fn closed_early(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if error
+            .downcast_ref::<hyper::Error>()
+            .is_some_and(hyper::Error::is_incomplete_message)
+        {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
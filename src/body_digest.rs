@@ -0,0 +1,103 @@
+//! Validation of request bodies against their `Digest` header (RFC 3230)
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use bytesize::ByteSize;
+use http::{HeaderMap, HeaderName};
+use http_body::Body;
+use http_body_util::Full;
+use hyper::body::Incoming;
+use sha2::{Digest, Sha256, Sha512};
+use tracing::debug;
+
+use crate::{
+    body::IdleTimeoutBody, config::ArxConfig, hyper::HttpError, reverse_proxy::collect_request_body,
+};
+
+const DIGEST: HeaderName = HeaderName::from_static("digest");
+
+/// Collect the body of a request and check it against its `Digest` header, before it is forwarded.
+///
+/// A request with a body must come with a `sha-256` or `sha-512` digest of it, other algorithms are ignored.
+/// Requests without a supported digest, or with a mismatching one, are rejected with `400 Bad Request`.
+/// Bodies are limited to `max_size`.
+pub async fn verify_body_digest(
+    req: http::Request<Incoming>,
+    max_size: ByteSize,
+    cfg: &ArxConfig,
+) -> Result<http::Request<Full<Bytes>>, HttpError> {
+    let (parts, body) = req.into_parts();
+    let has_body = !body.is_end_stream();
+    let bytes = collect_request_body(
+        IdleTimeoutBody::new(body, cfg.request_body_idle_timeout),
+        max_size,
+    )
+    .await?;
+
+    if has_body {
+        check_digest(&parts.headers, &bytes)?;
+    }
+
+    Ok(http::Request::from_parts(parts, Full::new(bytes)))
+}
+
+/// Check a body against every supported digest in the `Digest` headers, requiring at least one.
+fn check_digest(headers: &HeaderMap, body: &[u8]) -> Result<(), HttpError> {
+    let digests = headers
+        .get_all(DIGEST)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|digest| digest.trim().split_once('='));
+
+    let mut checked = false;
+    for (algorithm, value) in digests {
+        let expected = match algorithm.to_ascii_lowercase().as_str() {
+            "sha-256" => Sha256::digest(body).to_vec(),
+            "sha-512" => Sha512::digest(body).to_vec(),
+            _ => continue,
+        };
+        if STANDARD.decode(value).ok() != Some(expected) {
+            debug!(algorithm, "request body digest mismatch");
+            return Err(HttpError::bad_request("request body digest mismatch"));
+        }
+        checked = true;
+    }
+
+    if !checked {
+        debug!("no supported request body digest");
+        return Err(HttpError::bad_request("missing request body digest"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn digests_checked() {
+        // `echo -n hello | openssl dgst -sha256 -binary | base64`
+        let sha256 = "sha-256=LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=";
+        let headers = |values: &[&str]| {
+            let mut headers = HeaderMap::new();
+            for value in values {
+                headers.append(DIGEST, HeaderValue::from_str(value).unwrap());
+            }
+            headers
+        };
+
+        assert!(check_digest(&headers(&[sha256]), b"hello").is_ok());
+        assert!(check_digest(
+            &headers(&["MD5=XUFAKrxLKna5cZ2REBfFkg==", sha256]),
+            b"hello"
+        )
+        .is_ok());
+        assert!(check_digest(&headers(&[sha256.replace("sha", "SHA").as_str()]), b"hello").is_ok());
+        assert!(check_digest(&headers(&[sha256]), b"hellO").is_err());
+        assert!(check_digest(&headers(&["MD5=XUFAKrxLKna5cZ2REBfFkg=="]), b"hello").is_err());
+        assert!(check_digest(&headers(&[]), b"hello").is_err());
+    }
+}
@@ -0,0 +1,79 @@
+//! The address of the client the gateway is talking to
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+/// The remote address of the connection a request arrived on, stored as a request extension.
+#[derive(Clone, Copy, Debug)]
+pub struct PeerAddr(pub SocketAddr);
+
+/// The IP address of the client, if known
+pub fn client_ip<B>(req: &http::Request<B>) -> Option<IpAddr> {
+    req.extensions()
+        .get::<PeerAddr>()
+        .map(|peer_addr| peer_addr.0.ip())
+}
+
+/// Open connections by client IP, for `max_connections_per_ip`
+#[derive(Clone, Default)]
+pub struct ConnectionCounts(Arc<Mutex<HashMap<IpAddr, usize>>>);
+
+/// An open connection counted in [ConnectionCounts], until dropped
+pub struct CountedConnection {
+    counts: ConnectionCounts,
+    ip: IpAddr,
+}
+
+impl ConnectionCounts {
+    /// Count a new connection from `ip`, unless it already has `max` open connections.
+    pub fn try_open(&self, ip: IpAddr, max: usize) -> Option<CountedConnection> {
+        let mut counts = self.0.lock().unwrap();
+        let count = counts.entry(ip).or_default();
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+
+        Some(CountedConnection {
+            counts: self.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for CountedConnection {
+    fn drop(&mut self) {
+        let mut counts = self.counts.0.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn connection_counts() {
+        let counts = ConnectionCounts::default();
+        let a = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let b = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        let first = counts.try_open(a, 2).unwrap();
+        let _second = counts.try_open(a, 2).unwrap();
+        assert!(counts.try_open(a, 2).is_none());
+        assert!(counts.try_open(b, 2).is_some());
+
+        drop(first);
+        assert!(counts.try_open(a, 2).is_some());
+    }
+}
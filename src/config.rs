@@ -1,40 +1,225 @@
-use std::{fmt::Display, str::FromStr, time::Duration};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use ipnet::IpNet;
 
 use bytesize::ByteSize;
 use figment::{
     providers::{Env, Serialized},
     Figment,
 };
-use http::HeaderName;
+use http::{HeaderName, HeaderValue, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 use url::Url;
 
+use crate::{json_transform::JsonFieldRemoval, path_rewrite::PathRewrite};
+
 #[serde_as]
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct ArxConfig {
     /// Overrides the default log level.
+    /// Can be changed at runtime in the admin API at `/admin/log-level`.
     pub log_level: String,
     /// Enables logging of HTTP requests.
+    /// Otherwise, responses are logged at `INFO` level without the access log's fields and exemptions.
     pub access_log: bool,
+    /// User agent prefixes of requests that are never access logged, e.g. Kubernetes probes.
+    pub access_log_exempt_user_agents: Vec<String>,
+    /// Request paths that are never access logged.
+    pub access_log_exempt_paths: Vec<String>,
+    /// When set, only one in this many requests are access logged, to reduce logging on high traffic.
+    /// Requests answered with a server error (`5xx`) are always logged.
+    pub access_log_sampling: Option<u32>,
+    /// How requests are identified in the access log, error pages and requests to backends.
+    /// Valid options are "none" (the `request_id_header` sent by the client, if any),
+    /// "header" (the `request_id_header`, generated when missing),
+    /// "traceparent" (the trace ID of the W3C `traceparent` header, starting a new trace when missing)
+    /// or "both" (the trace ID, also forwarded in the `request_id_header`).
+    pub request_id: RequestIdFormat,
+    /// The header carrying request IDs, see `request_id`.
+    pub request_id_header: String,
 
     /// Url for connecting to the Authly service.
     pub authly_url: Url,
+    /// Additional named Authly clusters, selected per route with [RoutePolicy].
+    /// Routes without a selected cluster use the one at `authly_url`.
+    pub authly_clusters: HashMap<String, Url>,
+    /// When set, access tokens from Authly expiring within this duration are refreshed before being forwarded.
+    #[serde(default, with = "humantime_serde")]
+    pub access_token_expiry_skew: Option<Duration>,
+    /// Timeout exchanging a session for an access token with Authly.
+    /// When exceeded, requests requiring authentication are answered with `504 Gateway Timeout`,
+    /// and opportunistically authenticated requests are forwarded without an access token.
+    #[serde(with = "humantime_serde")]
+    pub auth_timeout: Duration,
+    /// Share one exchange with Authly between concurrent requests with the same session,
+    /// rather than exchanging the session once per request.
+    pub auth_single_flight: bool,
+    /// Forward the `Authorization` header and session cookie of clients to backends of routes without authentication.
+    /// By default they're removed, so clients can't impersonate others towards backends trusting them.
+    pub forward_credentials_without_auth: bool,
+    /// Authentication of routes without an `authly.id` extensionRef,
+    /// "mandatory", "opportunistic" or "disabled" (the default).
+    /// With "mandatory", routes are secure by default and opt out with `authn-disabled`.
+    pub default_authn: Authn,
+
+    /// Accept HTTP/2 over cleartext with prior knowledge (h2c), e.g. behind a TLS-terminating load balancer.
+    /// HTTP/1 is served either way.
+    pub http2_cleartext: bool,
+    /// Maximum number of HTTP/2 streams a client may reset before the gateway has accepted them, per connection.
+    /// Beyond it, the connection is closed with `ENHANCE_YOUR_CALM`, against rapid reset attacks (CVE-2023-44487).
+    pub http2_max_pending_reset_streams: usize,
+    /// Maximum number of requests a client may cancel within `http2_reset_streams_window` on one connection,
+    /// e.g. by resetting HTTP/2 streams. Beyond it, the connection is closed. By default, cancellations are unlimited.
+    pub http2_max_reset_streams: Option<u32>,
+    /// The window over which `http2_max_reset_streams` is counted.
+    #[serde(with = "humantime_serde")]
+    pub http2_reset_streams_window: Duration,
+
+    /// Expect a PROXY protocol (v1 or v2) header on every connection, e.g. behind an L4 load balancer.
+    /// The client address it carries is used for `X-Forwarded-For` and IP filters.
+    pub proxy_protocol: bool,
+    /// Clients trusted to be proxies themselves, as CIDR ranges, e.g. `["10.0.0.0/8"]`.
+    /// The `X-Forwarded-For` chain sent by a trusted proxy is extended with its address,
+    /// the one sent by any other client is replaced by the client's address.
+    pub trusted_proxies: Vec<IpNet>,
+
+    /// Maximum number of concurrent connections from a single client IP.
+    /// Connections beyond the limit are answered with `503 Service Unavailable` and closed,
+    /// or closed right away when many rejected connections are already waiting for their response.
+    pub max_connections_per_ip: Option<usize>,
+
+    /// Redirect requests not forwarded over HTTPS (`X-Forwarded-Proto: https`) to their HTTPS URL.
+    pub https_redirect: bool,
+    /// Path prefixes that are served over plain HTTP even with `https_redirect`.
+    pub https_redirect_exempt_paths: Vec<String>,
+
+    /// Forward the path and query the client requested, before rewriting, in `X-Original-URI`,
+    /// and the request method in `X-Original-Method`, e.g. for authentication or logging backends.
+    pub forward_original_uri: bool,
+    /// What to do when the `Host` header differs from the request authority,
+    /// i.e. `:authority` in HTTP/2 or an absolute URI in HTTP/1.
+    /// Valid options are "reject" (respond with `400 Bad Request`) or "authority" (use the authority, ignoring `Host`).
+    /// Requests with several `Host` headers are always rejected.
+    pub host_mismatch: HostMismatchHandling,
+    /// What to do with a request path not starting with `/`, e.g. the empty path of an authority-form request.
+    /// Valid options are "reject" (respond with `400 Bad Request`) or "normalize" (prepend `/`).
+    /// `OPTIONS *` is answered by the gateway itself, other asterisk-form requests are rejected.
+    pub malformed_paths: MalformedPathHandling,
+    /// The maximum number of segments in a request path. Requests with deeper paths are rejected with `400 Bad Request`
+    /// before route matching.
+    pub max_path_segments: u32,
+
+    /// Directory of ACME HTTP-01 challenge responses written by an external ACME client, one file per token.
+    /// When set, `/.well-known/acme-challenge/{token}` is served from it, and never redirected to HTTPS.
+    pub acme_challenge_dir: Option<PathBuf>,
 
     /// Maximum size of a request.
+    /// Requests declaring a larger `Content-Length` are rejected with `413 Payload Too Large`,
+    /// other request bodies are cut off once they exceed it.
     pub request_max_size: ByteSize,
+    /// Maximum size of responses transformed according to route policies,
+    /// i.e. JSON field removal and link rewriting.
+    /// Larger JSON or HTML responses on routes removing fields or rewriting links fail with `502 Bad Gateway`.
+    pub json_transform_max_size: ByteSize,
+    /// Maximum size of responses collected in full on `buffered` routes.
+    /// Larger responses fail with `502 Bad Gateway` instead of being held in memory.
+    pub buffered_response_max_size: ByteSize,
+    /// Maximum total size of the response headers from a backend, e.g. with many or giant `Set-Cookie` headers.
+    /// Larger responses are replaced with `502 Bad Gateway`, rather than forwarded to clients that would reject them.
+    pub response_max_header_size: ByteSize,
+    /// Bodies of error responses from the gateway itself by status code,
+    /// e.g. `502` when a backend can't be reached, `503` when shedding load or `504` when a backend times out.
+    /// Error responses from backends are forwarded as they are. By default, the body is a short plain text message.
+    pub error_pages: HashMap<u16, ErrorPage>,
     /// Timeout waiting for a request to complete.
     #[serde(with = "humantime_serde")]
     pub connect_timeout: Duration,
     /// Timeout waiting for a request to complete.
     #[serde(with = "humantime_serde")]
     pub request_timeout: Duration,
+    /// Timeout waiting for the next chunk of a request body from the client.
+    #[serde(with = "humantime_serde")]
+    pub request_body_idle_timeout: Duration,
     /// Timeout for processing and returning a response.
     #[serde(with = "humantime_serde")]
     pub response_timeout: Duration,
     /// Timeout for keeping a TCP connection open when using the `keep-alive` header.
+    /// Does not apply to upgraded (websocket) connections, see `websocket_idle_timeout`.
     #[serde(with = "humantime_serde")]
     pub keep_alive_timeout: Duration,
+    /// Timeout for closing a websocket tunnel when no messages are passed in either direction.
+    #[serde(with = "humantime_serde")]
+    pub websocket_idle_timeout: Duration,
+    /// Send websocket clients a ping after this long without messages from the gateway,
+    /// so load balancers and other intermediaries don't close quiet tunnels.
+    /// The pongs of clients don't count as messages for `websocket_idle_timeout`. By default, no pings are sent.
+    #[serde(default, with = "humantime_serde")]
+    pub websocket_ping_interval: Option<Duration>,
+    /// Request headers forwarded to the backend when proxying a websocket upgrade.
+    pub websocket_header_filter: HeaderFilter,
+    /// Maximum total size of the request headers forwarded when proxying a websocket upgrade.
+    /// Larger upgrade requests are rejected with `431 Request Header Fields Too Large`.
+    pub websocket_max_header_size: ByteSize,
+    /// Maximum rate of websocket upgrades admitted per second, spreading out reconnect storms (e.g. after a deploy).
+    /// Upgrades beyond the rate are delayed, up to `websocket_upgrade_max_wait`.
+    pub websocket_max_upgrades_per_second: Option<u32>,
+    /// How long a websocket upgrade may be delayed by `websocket_max_upgrades_per_second`.
+    /// Upgrades that would wait longer are rejected with `503 Service Unavailable` and a `Retry-After` header.
+    #[serde(with = "humantime_serde")]
+    pub websocket_upgrade_max_wait: Duration,
+    /// Maximum number of websocket tunnels open at once, across all routes.
+    /// Upgrades beyond it are rejected with `503 Service Unavailable`.
+    pub websocket_max_tunnels: Option<u32>,
+    /// Maximum rate of messages a client may send over a websocket tunnel, per second.
+    /// Bursts of up to one second worth of messages are allowed. Messages beyond the rate are handled according to `websocket_message_rate_exceeded`.
+    pub websocket_max_messages_per_second: Option<u32>,
+    /// What to do with websocket messages beyond `websocket_max_messages_per_second`.
+    /// Valid options are "drop" (discard the message) or "close" (close the tunnel with code 1008, policy violation,
+    /// once the rate has been exceeded for three seconds in a row; until then, excess messages are dropped).
+    pub websocket_message_rate_exceeded: ExcessMessageHandling,
+    /// Close websocket tunnels when their path is no longer routed to their backend after a routing change,
+    /// sending the client a close frame with code 1001 (going away). By default, tunnels outlive their routes.
+    pub websocket_drain_on_route_change: bool,
+    /// Maximum number of redirects issued by backends to follow.
+    /// By default (0), redirects are not followed but forwarded to the client.
+    /// Redirects to another origin than the backend's are always forwarded to the client.
+    pub backend_max_redirects: usize,
+    /// Connect to the backends of HTTPRoutes whenever the routing table is rebuilt, by sending each a `HEAD` request,
+    /// so the first requests proxied to them don't wait for a connection to be established.
+    /// Authly mesh backends and backends with a BackendTLSPolicy are not warmed up.
+    pub backend_warm_up: bool,
+    /// Maximum retries by route policies (see `RoutePolicy::retries`), as a percentage of the requests proxied
+    /// within `retry_budget_window`. Once the budget is spent, failed requests are not retried,
+    /// so retries can't multiply the load on backends during a partial outage. By default, retries are not capped.
+    pub retry_budget_percent: Option<u32>,
+    /// Retries allowed within each `retry_budget_window` regardless of `retry_budget_percent`,
+    /// so requests are still retried when there is little traffic.
+    pub retry_budget_min_retries: u32,
+    /// The window over which `retry_budget_percent` is counted.
+    #[serde(with = "humantime_serde")]
+    pub retry_budget_window: Duration,
+    /// What to do with backend response headers that can't be served to the client, like an unparsable `Content-Length`.
+    /// Valid options are "drop" (remove the header and log a warning) or "reject" (respond with `502 Bad Gateway`).
+    pub invalid_response_headers: InvalidHeaderHandling,
+    /// Statuses of backend responses replaced before they are forwarded, e.g. `403: 404` to hide the existence of resources.
+    /// The response is replaced by the gateway's own response for the new status, without the backend's headers and body,
+    /// which could reveal the original status. Statuses without a body (`1xx`, `204`, `205`, `304`) can't be mapped to.
+    pub backend_status_map: HashMap<u16, u16>,
+    /// Static host name to IP address mappings for backends, bypassing DNS resolution.
+    /// The port is taken from the backend URL.
+    pub backend_static_hosts: HashMap<String, IpAddr>,
+    /// Casing of the header names of requests forwarded to HTTP/1 backends.
+    /// Valid options are "lowercase" or "titlecase" (e.g. `Content-Type`, for backends that expect it).
+    /// HTTP/2 requires lowercase header names, so they are always lowercase for HTTP/2 backends.
+    pub backend_header_case: HeaderCase,
     /// Whether the HTTP client accepts invalid certificates. Should remain false unless you're debugging.
     pub http_accept_invalid_certs: bool,
     /// Use system root CA certs.
@@ -57,15 +242,38 @@ pub struct ArxConfig {
 
     /// HTTP compression level. Valid options are "fastest", "best", "default",
     /// or a number (as a string) that sets a precise level for a specific compression algorithm.
-    /// Arx supports brotli, gzip and deflate compression.
+    /// Arx supports brotli, gzip, deflate and zstd compression.
     #[serde_as(as = "DisplayFromStr")]
     pub http_compression_level: CompressionLevel,
+    /// Brotli compression level, overriding `http_compression_level`.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub http_compression_level_br: Option<CompressionLevel>,
+    /// Gzip compression level, overriding `http_compression_level`.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub http_compression_level_gzip: Option<CompressionLevel>,
+    /// Deflate compression level, overriding `http_compression_level`.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub http_compression_level_deflate: Option<CompressionLevel>,
+    /// Zstd compression level, overriding `http_compression_level`.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub http_compression_level_zstd: Option<CompressionLevel>,
     /// Minimum size of an HTTP response for compression. Responses below this size are not compressed.
     pub http_compression_min_size: ByteSize,
     /// Whether HTTP responses with an image content type should be compressed.
     pub http_compression_compress_images: bool,
+    /// Whether Server-Sent Events (`text/event-stream`) should be compressed.
+    /// Compression buffers events until enough data is available, delaying them,
+    /// whatever the client accepts.
+    pub http_compression_compress_event_streams: bool,
     /// Comma-separated list of content types for which compression should be disabled.
     pub http_compression_exempt_content_types: Vec<String>,
+    /// Content types (e.g. `application/json`) compressed whatever their size, ignoring `http_compression_min_size`,
+    /// when the client accepts a compressed encoding.
+    pub http_compression_always_content_types: Vec<String>,
+    /// Whether a response is sent uncompressed when compressing it fails before producing any data.
+    /// This delays the response headers until the first compressed data is available,
+    /// i.e. until the first body chunk of streaming responses (long polling, NDJSON) from slow backends.
+    pub http_compression_fallback: bool,
 
     /// Value of the CORS header `access-control-allow-origin`.
     pub cors_allow_origin: String,
@@ -82,6 +290,60 @@ pub struct ArxConfig {
     /// Value of the CORS header `access-control-max-age`.
     #[serde(with = "humantime_serde")]
     pub cors_max_age: Duration,
+
+    /// Headers added to all responses, e.g. security headers like `X-Content-Type-Options: nosniff`
+    /// or `Strict-Transport-Security`. Headers already present in a response are kept.
+    pub response_headers: HashMap<String, String>,
+    /// Whether `response_headers` replace the values of headers already present in a response.
+    pub response_headers_override: bool,
+
+    /// Whether a path prefix route redirects between its slashed and unslashed form (e.g. `/foo` to `/foo/`).
+    /// When disabled, both forms are proxied directly.
+    pub trailing_slash_redirect: bool,
+
+    /// Reject HTTPRoutes without rules, instead of accepting them with a warning.
+    /// Either way, such a route matches no requests.
+    pub reject_ruleless_routes: bool,
+    /// Reject HTTPRoutes with extensionRef filters of a group or kind the gateway doesn't recognize,
+    /// instead of accepting them with a warning. Either way, such filters have no effect.
+    pub reject_unknown_extension_refs: bool,
+
+    /// Respond to `OPTIONS` requests to proxied routes with the allowed methods, instead of forwarding them.
+    /// CORS preflight requests are handled separately, see `cors_allow_methods`.
+    pub auto_options: bool,
+    /// Let `HEAD` requests match routes for `GET` only, as a `HEAD` is a `GET` without a response body.
+    /// Routes for `HEAD` specifically take precedence.
+    pub head_matches_get: bool,
+
+    /// Named per-route policies, see [RoutePolicy].
+    pub route_policies: HashMap<String, RoutePolicy>,
+
+    /// Named per-backend policies, see [BackendPolicy].
+    pub backend_policies: HashMap<String, BackendPolicy>,
+    /// Watch Gateway API BackendTLSPolicies, and connect to the Services they target over TLS,
+    /// with their server name and CA certificates.
+    /// Replaces inferring TLS (and the Authly mesh) from backend port 443.
+    pub backend_tls_policies: bool,
+
+    /// Client IP ranges allowed to access the gateway. Can be overridden per route with [RoutePolicy].
+    pub ip_filter: IpFilter,
+
+    /// Count the request and response body bytes proxied by each HTTPRoute.
+    /// Metrics are exposed in the admin API at `/admin/metrics`.
+    pub route_metrics: bool,
+    /// Record connect, time-to-first-byte and total latency histograms for each backend.
+    /// Metrics are exposed in the admin API at `/admin/metrics`.
+    pub backend_metrics: bool,
+
+    /// Enable the admin API under `/admin/`, served on `admin_bind_address`.
+    pub admin_api: bool,
+    /// Address of the admin API listener, apart from the public listener.
+    /// The admin API is not authenticated, so the default only accepts connections from the pod itself.
+    pub admin_bind_address: SocketAddr,
+    /// Serve a listing of the proxied routes (paths, methods, header and query parameter matches
+    /// and backends) at `/docs/routes.json`, for the docs to show the routes currently in the routing table.
+    /// The listing is public like the rest of `/docs/`, and exposes the internal URIs of the backends.
+    pub docs_route_listing: bool,
 }
 
 impl Default for ArxConfig {
@@ -89,14 +351,67 @@ impl Default for ArxConfig {
         ArxConfig {
             log_level: "INFO".into(),
             access_log: false,
+            access_log_exempt_user_agents: vec!["kube-probe".into()],
+            access_log_exempt_paths: vec![],
+            access_log_sampling: None,
+            request_id: RequestIdFormat::None,
+            request_id_header: "x-request-id".to_string(),
 
             authly_url: "https://authly".parse().unwrap(),
+            authly_clusters: HashMap::new(),
+            access_token_expiry_skew: None,
+            auth_timeout: Duration::from_secs(10),
+            auth_single_flight: true,
+            forward_credentials_without_auth: false,
+            default_authn: Authn::Disabled,
+
+            http2_cleartext: false,
+            http2_max_pending_reset_streams: 20,
+            http2_max_reset_streams: None,
+            http2_reset_streams_window: Duration::from_secs(10),
+            proxy_protocol: false,
+            trusted_proxies: vec![],
+            max_connections_per_ip: None,
+            https_redirect: false,
+            https_redirect_exempt_paths: vec![
+                "/health".into(),
+                "/.well-known/acme-challenge/".into(),
+            ],
+            forward_original_uri: false,
+            host_mismatch: HostMismatchHandling::Reject,
+            malformed_paths: MalformedPathHandling::Reject,
+            max_path_segments: 1000,
+            acme_challenge_dir: None,
 
             request_max_size: ByteSize::gb(20),
+            json_transform_max_size: ByteSize::mib(1),
+            buffered_response_max_size: ByteSize::mib(10),
+            response_max_header_size: ByteSize::kib(64),
+            error_pages: HashMap::new(),
             connect_timeout: Duration::from_secs(60),
             request_timeout: Duration::from_secs(60),
+            request_body_idle_timeout: Duration::from_secs(60),
             response_timeout: Duration::from_secs(60),
             keep_alive_timeout: Duration::from_secs(15),
+            websocket_idle_timeout: Duration::from_secs(60 * 60),
+            websocket_ping_interval: None,
+            websocket_header_filter: HeaderFilter::default(),
+            websocket_max_header_size: ByteSize::kib(64),
+            websocket_max_upgrades_per_second: None,
+            websocket_upgrade_max_wait: Duration::from_secs(1),
+            websocket_max_tunnels: None,
+            websocket_max_messages_per_second: None,
+            websocket_message_rate_exceeded: ExcessMessageHandling::Drop,
+            websocket_drain_on_route_change: false,
+            backend_max_redirects: 0,
+            backend_warm_up: false,
+            retry_budget_percent: None,
+            retry_budget_min_retries: 10,
+            retry_budget_window: Duration::from_secs(10),
+            invalid_response_headers: InvalidHeaderHandling::Drop,
+            backend_status_map: HashMap::new(),
+            backend_static_hosts: HashMap::new(),
+            backend_header_case: HeaderCase::Lowercase,
             http_accept_invalid_certs: false,
             use_root_certs: true,
             use_webpki_certs: true,
@@ -107,9 +422,16 @@ impl Default for ArxConfig {
             backoff_jitter: Jitter::Full,
 
             http_compression_level: CompressionLevel::from_str("default").unwrap(),
+            http_compression_level_br: None,
+            http_compression_level_gzip: None,
+            http_compression_level_deflate: None,
+            http_compression_level_zstd: None,
             http_compression_min_size: ByteSize::b(32),
             http_compression_compress_images: false,
+            http_compression_compress_event_streams: false,
             http_compression_exempt_content_types: vec![],
+            http_compression_always_content_types: vec![],
+            http_compression_fallback: false,
 
             cors_allow_origin: "*".into(),
             cors_allow_methods: vec![Method::Any],
@@ -126,17 +448,456 @@ impl Default for ArxConfig {
             cors_allow_credentials: false,
             cors_allow_private_network: true,
             cors_max_age: Duration::from_secs(60),
+
+            response_headers: HashMap::new(),
+            response_headers_override: false,
+
+            trailing_slash_redirect: true,
+            reject_ruleless_routes: false,
+            reject_unknown_extension_refs: false,
+            auto_options: false,
+            head_matches_get: true,
+
+            route_policies: HashMap::new(),
+            backend_policies: HashMap::new(),
+            backend_tls_policies: false,
+
+            ip_filter: IpFilter::default(),
+
+            route_metrics: true,
+            backend_metrics: true,
+
+            admin_api: false,
+            admin_bind_address: SocketAddr::from(([127, 0, 0, 1], 9090)),
+            docs_route_listing: false,
         }
     }
 }
 
 impl ArxConfig {
-    pub fn from_env() -> Self {
+    pub fn from_env() -> Result<Self, figment::Error> {
         Figment::from(Serialized::defaults(Self::default()))
             .merge(Env::prefixed("ARX_"))
             .extract()
-            .unwrap()
     }
+
+    /// Problems with the config that would only surface once routes or requests use it
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+
+        if self.log_level.parse::<tracing::Level>().is_err() {
+            problems.push(format!("invalid log level `{}`", self.log_level));
+        }
+
+        if HeaderName::from_str(&self.request_id_header).is_err() {
+            problems.push(format!(
+                "invalid request ID header `{}`",
+                self.request_id_header
+            ));
+        }
+
+        for (backend_status, status) in &self.backend_status_map {
+            if StatusCode::from_u16(*backend_status).is_err()
+                || !(200..=599).contains(status)
+                || matches!(status, 204 | 205 | 304)
+            {
+                problems.push(format!(
+                    "invalid backend status mapping `{backend_status}: {status}`"
+                ));
+            }
+        }
+
+        for (name, value) in &self.response_headers {
+            if HeaderName::from_str(name).is_err() || HeaderValue::from_str(value).is_err() {
+                problems.push(format!("invalid response header `{name}: {value}`"));
+            }
+        }
+
+        for (name, policy) in &self.route_policies {
+            if let Err(err) = JsonFieldRemoval::parse(&policy.json_remove_fields) {
+                problems.push(format!("route policy `{name}`: {err}"));
+            }
+            if let Some(rewrite) = &policy.rewrite_path {
+                if let Err(err) = PathRewrite::new(&rewrite.pattern, &rewrite.replacement) {
+                    problems.push(format!(
+                        "route policy `{name}`: invalid path rewrite: {err}"
+                    ));
+                }
+            }
+        }
+
+        problems.sort();
+        problems
+    }
+}
+
+/// Settings for individual routes, overriding the global config.
+///
+/// A policy is applied to an HTTPRoute rule with an `ExtensionRef` filter
+/// with group `arx.protojour.com`, kind `RoutePolicy` and the name of the policy.
+/// When a rule references several policies, later ones take precedence.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RoutePolicy {
+    /// Overrides `trailing_slash_redirect`.
+    pub trailing_slash_redirect: Option<bool>,
+    /// Overrides `ip_filter`.
+    pub ip_filter: Option<IpFilter>,
+    /// Name of the Authly cluster in `authly_clusters` used for authentication.
+    pub authly: Option<String>,
+    /// Overrides `auth_timeout`.
+    #[serde(default, with = "humantime_serde")]
+    pub auth_timeout: Option<Duration>,
+    /// Overrides `connect_timeout`, e.g. for backends that are slow to accept connections while scaling up.
+    #[serde(default, with = "humantime_serde")]
+    pub connect_timeout: Option<Duration>,
+    /// Overrides `request_timeout`, e.g. for slow reports that need a longer deadline than other routes.
+    #[serde(default, with = "humantime_serde")]
+    pub request_timeout: Option<Duration>,
+    /// Overrides `request_max_size`, e.g. for file uploads that need more than API routes.
+    pub request_max_size: Option<ByteSize>,
+    /// Split traffic between the backendRefs of a rule by hashing the value of this request header,
+    /// weighted by the backendRef weights. Requests without the header go to the first backendRef.
+    pub split_by_header: Option<String>,
+    /// Select the backendRef of a rule by the value of a request header, before any `split_by_header`.
+    /// Requests without the header, or with an unmapped value, get the default selection.
+    pub select_by_header: Option<HeaderBackendSelection>,
+    /// With `split_by_header`, ramp up the share of traffic sent to a backend over this duration
+    /// from when the gateway first routes to it, so new backends aren't overloaded right away.
+    #[serde(default, with = "humantime_serde")]
+    pub slow_start: Option<Duration>,
+    /// Collect request and response bodies in full before forwarding them, instead of streaming.
+    /// For backends that misbehave with chunked transfer encoding.
+    /// Request bodies are limited by `request_max_size`, responses by `buffered_response_max_size`.
+    pub buffered: Option<bool>,
+    /// Require requests with a body to carry a `Digest` header (RFC 3230) with its `sha-256` or `sha-512` digest,
+    /// e.g. for integrity-sensitive uploads. Requests without one, or with a mismatching one,
+    /// are rejected with `400 Bad Request` before being forwarded. Bodies are limited by `request_max_size`.
+    pub require_body_digest: Option<bool>,
+    /// Fields removed from JSON responses, as JSONPath expressions (e.g. `$.debug` or `$.items[*].internal`).
+    /// JSON responses larger than `json_transform_max_size` fail with `502 Bad Gateway` instead of leaking the fields.
+    pub json_remove_fields: Vec<String>,
+    /// Absolute URLs to rewrite in HTML and JSON responses, from backend base URLs to gateway-facing base URLs,
+    /// e.g. `http://app.internal:8080/` to `https://example.com/app/`, for apps mounted under a prefix.
+    /// HTML and JSON responses larger than `json_transform_max_size` fail with `502 Bad Gateway`.
+    pub rewrite_links: HashMap<String, String>,
+    /// Translate the status of failed gRPC calls by non-gRPC clients into an HTTP status and a JSON error body,
+    /// e.g. `grpc-status: 5` into `404 Not Found` with `{"code":5,"status":"NOT_FOUND","message":"..."}`.
+    /// A status in the trailers is translated when the call sends no message before it, as failed unary calls do.
+    /// Responses with messages are streamed with a `200 OK`, their status can only be relayed in the trailers.
+    pub grpc_status_to_http: Option<bool>,
+    /// Remove `Set-Cookie` from responses, e.g. for public routes cached by a CDN,
+    /// where a cached cookie would be handed out to every client.
+    pub strip_set_cookie: Option<bool>,
+    /// Forward requests without `X-Forwarded-*` headers, as if they came directly from the client,
+    /// for backends that misbehave behind a proxy. Any sent by the client are removed, along with `Forwarded`.
+    pub omit_forwarded_headers: Option<bool>,
+    /// Media types accepted in request bodies, e.g. `application/json`.
+    /// Requests with a body of another `Content-Type` are rejected with `415 Unsupported Media Type`.
+    pub request_content_types: Vec<String>,
+    /// Media types returned by the backends.
+    /// Requests whose `Accept` allows none of them are rejected with `406 Not Acceptable`.
+    pub response_content_types: Vec<String>,
+    /// Retry requests that fail to reach the backend, or get `502`, `503` or `504` from it, this many times.
+    /// With `split_by_header`, each retry goes to the next backendRef instead of the one that failed.
+    /// Only requests without a body are retried, within the global `retry_budget_percent`.
+    /// Those with a method that isn't idempotent, e.g. `POST`, only when the backend could not be connected to.
+    pub retries: Option<u32>,
+    /// Maximum number of requests in flight to the backends of the rule, until their responses are complete.
+    /// Excess requests are rejected with `503 Service Unavailable`, after waiting up to `max_in_flight_wait`.
+    pub max_in_flight: Option<u32>,
+    /// How long requests beyond `max_in_flight` wait for others to complete.
+    #[serde(default, with = "humantime_serde")]
+    pub max_in_flight_wait: Option<Duration>,
+    /// Maximum number of requests waiting for `max_in_flight` at once.
+    /// Requests beyond it are rejected right away, instead of waiting. By default, any number of requests may wait.
+    pub max_in_flight_queue: Option<u32>,
+    /// Maximum number of websocket tunnels open to the backends of the rule.
+    /// Upgrades beyond it are rejected with `503 Service Unavailable`, see also `websocket_max_tunnels`.
+    pub max_websocket_tunnels: Option<u32>,
+    /// Rewrite the path of proxied requests with a regular expression, after any prefix replacement,
+    /// e.g. `^/v1/(.*)` to `/api/$1`.
+    pub rewrite_path: Option<PathRegexRewrite>,
+}
+
+impl RoutePolicy {
+    /// Apply another policy on top of this one.
+    pub fn merge(&mut self, other: &RoutePolicy) {
+        self.trailing_slash_redirect = other
+            .trailing_slash_redirect
+            .or(self.trailing_slash_redirect);
+        if let Some(ip_filter) = &other.ip_filter {
+            self.ip_filter = Some(ip_filter.clone());
+        }
+        if let Some(authly) = &other.authly {
+            self.authly = Some(authly.clone());
+        }
+        self.auth_timeout = other.auth_timeout.or(self.auth_timeout);
+        self.connect_timeout = other.connect_timeout.or(self.connect_timeout);
+        self.request_timeout = other.request_timeout.or(self.request_timeout);
+        self.request_max_size = other.request_max_size.or(self.request_max_size);
+        if let Some(split_by_header) = &other.split_by_header {
+            self.split_by_header = Some(split_by_header.clone());
+        }
+        if let Some(select_by_header) = &other.select_by_header {
+            self.select_by_header = Some(select_by_header.clone());
+        }
+        self.slow_start = other.slow_start.or(self.slow_start);
+        self.buffered = other.buffered.or(self.buffered);
+        self.require_body_digest = other.require_body_digest.or(self.require_body_digest);
+        self.json_remove_fields
+            .extend(other.json_remove_fields.iter().cloned());
+        self.rewrite_links.extend(other.rewrite_links.clone());
+        self.grpc_status_to_http = other.grpc_status_to_http.or(self.grpc_status_to_http);
+        self.strip_set_cookie = other.strip_set_cookie.or(self.strip_set_cookie);
+        self.omit_forwarded_headers = other.omit_forwarded_headers.or(self.omit_forwarded_headers);
+        self.request_content_types
+            .extend(other.request_content_types.iter().cloned());
+        self.response_content_types
+            .extend(other.response_content_types.iter().cloned());
+        self.retries = other.retries.or(self.retries);
+        self.max_in_flight = other.max_in_flight.or(self.max_in_flight);
+        self.max_in_flight_wait = other.max_in_flight_wait.or(self.max_in_flight_wait);
+        self.max_in_flight_queue = other.max_in_flight_queue.or(self.max_in_flight_queue);
+        self.max_websocket_tunnels = other.max_websocket_tunnels.or(self.max_websocket_tunnels);
+        if let Some(rewrite_path) = &other.rewrite_path {
+            self.rewrite_path = Some(rewrite_path.clone());
+        }
+    }
+}
+
+/// Selection of backendRefs by the value of a request header, see `RoutePolicy::select_by_header`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HeaderBackendSelection {
+    /// The request header, e.g. `x-region`.
+    pub header: String,
+    /// The name of the backendRef selected by each header value, e.g. `eu: app-eu`.
+    pub backends: HashMap<String, String>,
+}
+
+/// Rewriting of request paths by regular expression, see `RoutePolicy::rewrite_path`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PathRegexRewrite {
+    /// The regular expression matched against the path.
+    pub pattern: String,
+    /// The replacement of the first match, with `$1` or `${name}` for the captured groups.
+    pub replacement: String,
+}
+
+/// Settings for individual backends.
+///
+/// A policy is applied to an HTTPRoute backendRef with an `ExtensionRef` filter
+/// with group `arx.protojour.com`, kind `BackendPolicy` and the name of the policy.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackendPolicy {
+    /// Connect to the backend over TLS, whatever its port.
+    pub tls: bool,
+    /// TLS server name (SNI) to use instead of the backend name. Implies `tls`.
+    /// The backend gets a connection pool of its own. Not supported for Authly mesh backends.
+    pub server_name: Option<String>,
+    /// The backend only speaks HTTP/1.0, which has no chunked transfer encoding.
+    /// Request bodies are buffered (up to `request_max_size`) and sent with a `Content-Length`.
+    pub http10: bool,
+}
+
+/// CIDR allow and deny lists for client IPs.
+///
+/// A client is denied if its IP is in any `deny` range,
+/// or if `allow` is non-empty and its IP is in none of the `allow` ranges.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IpFilter {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    /// Whether a client is permitted. Clients with unknown IP only pass a filter without `allow` ranges.
+    pub fn permits(&self, client_ip: Option<IpAddr>) -> bool {
+        match client_ip {
+            Some(ip) => {
+                !self.deny.iter().any(|net| net.contains(&ip))
+                    && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip)))
+            }
+            None => self.allow.is_empty(),
+        }
+    }
+}
+
+/// Allow and deny lists for header names, compared case-insensitively.
+///
+/// A header is denied if its name is in `deny`,
+/// or if `allow` is non-empty and its name is not in `allow`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HeaderFilter {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl HeaderFilter {
+    /// Whether a header is permitted.
+    pub fn permits(&self, name: &HeaderName) -> bool {
+        let listed = |names: &[String]| {
+            names
+                .iter()
+                .any(|listed| listed.eq_ignore_ascii_case(name.as_str()))
+        };
+        !listed(&self.deny) && (self.allow.is_empty() || listed(&self.allow))
+    }
+}
+
+/// The body of an error response, see `ArxConfig::error_pages`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ErrorPage {
+    pub content_type: String,
+    /// A template where `{status}`, `{message}` and `{request_id}` are replaced by the status (e.g. `502 Bad Gateway`),
+    /// the error message and the ID of the request (empty without one, see `ArxConfig::request_id`).
+    /// The values are HTML-escaped when the content type is HTML.
+    pub template: String,
+}
+
+impl Default for ErrorPage {
+    fn default() -> Self {
+        Self {
+            content_type: "text/html; charset=utf-8".to_string(),
+            template: String::new(),
+        }
+    }
+}
+
+impl ErrorPage {
+    pub fn render(&self, status: StatusCode, message: &str, request_id: &str) -> String {
+        let is_html = self.content_type.to_ascii_lowercase().contains("html");
+        let status = status.to_string();
+        let placeholders = [
+            ("{status}", status.as_str()),
+            ("{message}", message),
+            ("{request_id}", request_id),
+        ];
+
+        // a single pass, so placeholders in the values are left alone
+        let mut rendered = String::with_capacity(self.template.len());
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find('{') {
+            rendered.push_str(&rest[..start]);
+            rest = &rest[start..];
+            match placeholders
+                .iter()
+                .find(|(placeholder, _)| rest.starts_with(placeholder))
+            {
+                Some((placeholder, value)) => {
+                    if is_html {
+                        rendered.push_str(&html_escape(value));
+                    } else {
+                        rendered.push_str(value);
+                    }
+                    rest = &rest[placeholder.len()..];
+                }
+                None => {
+                    rendered.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        rendered.push_str(rest);
+        rendered
+    }
+}
+
+fn html_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Authentication of a route, like the `authn-*` extensionRefs of the `authly.id` group
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Authn {
+    /// A session is required
+    Mandatory,
+    /// A session is used when present
+    Opportunistic,
+    /// No session is used
+    Disabled,
+}
+
+/// What to do with a backend response header that can't be served to the client
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InvalidHeaderHandling {
+    /// Remove the header, logging a warning
+    Drop,
+    /// Respond with `502 Bad Gateway`
+    Reject,
+}
+
+/// How requests are identified, see `ArxConfig::request_id`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestIdFormat {
+    /// Only a request ID sent by the client
+    None,
+    /// `request_id_header`, generated when missing
+    Header,
+    /// The trace ID of `traceparent`
+    Traceparent,
+    /// The trace ID of `traceparent`, also in `request_id_header`
+    Both,
+}
+
+/// What to do with websocket messages beyond `websocket_max_messages_per_second`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExcessMessageHandling {
+    /// Discard the message
+    Drop,
+    /// Close the tunnel
+    Close,
+}
+
+/// Casing of forwarded HTTP/1 header names
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HeaderCase {
+    /// e.g. `content-type`
+    Lowercase,
+    /// e.g. `Content-Type`
+    Titlecase,
+}
+
+/// What to do with a request whose `Host` header differs from its authority
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HostMismatchHandling {
+    /// Respond with `400 Bad Request`
+    Reject,
+    /// Use the request authority
+    Authority,
+}
+
+/// What to do with a request path not starting with `/`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MalformedPathHandling {
+    /// Respond with `400 Bad Request`
+    Reject,
+    /// Prepend `/`, so an empty path becomes `/`
+    Normalize,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
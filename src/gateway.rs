@@ -1,32 +1,74 @@
-use std::sync::Arc;
+use std::{collections::HashMap, convert::Infallible, future::Future, sync::Arc, time::Duration};
 
 use arc_swap::ArcSwap;
-use http::{header, HeaderValue, Request, StatusCode, Uri};
-use tower::ServiceBuilder;
+use bytes::Bytes;
+use bytesize::ByteSize;
+use http::{header, HeaderName, HeaderValue, Method, Request, StatusCode, Uri};
+use http_body_util::{BodyExt, Either, Empty, Full};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto,
+};
+use tokio::{
+    net::TcpListener,
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+use tokio_util::sync::CancellationToken;
+use tower::{ServiceBuilder, ServiceExt};
 use tower_http::trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer};
-use tracing::{debug, error, trace, Level};
+use tracing::{debug, error, info, trace, Instrument, Level};
 
 use crate::{
-    authentication::process_auth_directive,
-    config::ArxConfig,
-    headers::set_proxy_headers,
-    http_client::{HttpClient, HttpClientInstance},
+    access_log::AccessLog,
+    admission::{AdmissionControl, InFlightLimits, RetryBudget, StreamResets, TunnelSlots},
+    authentication::{process_auth_directive, strip_credentials, AccessTokenSource, SingleFlight},
+    body::HoldingBody,
+    body_digest::verify_body_digest,
+    client_ip::{client_ip, ConnectionCounts, PeerAddr},
+    config::{ArxConfig, IpFilter, MalformedPathHandling},
+    headers::{
+        add_response_headers, has_ambiguous_framing, has_unsupported_transfer_coding,
+        set_proxy_headers,
+    },
+    http_client::{HttpClient, HttpClientInstance, CONNECT_TIMEOUT, REQUEST_TIMEOUT},
     hyper::{empty_body, HttpError, HyperResponse},
+    json_transform::JsonFieldRemoval,
     layers::{compression_layer, cors_layer},
+    link_rewrite::LinkRewrite,
     local::LocalService,
-    reverse_proxy::reverse_proxy,
-    route::{AuthDirective, BackendClass, Route},
+    metrics::RouteMetrics,
+    path_rewrite::PathRewrite,
+    proxy_protocol::read_proxy_header,
+    request_id::{assign_request_id, request_span},
+    reverse_proxy::{reverse_proxy, RequestBodyHandling, ResponseTransforms},
+    route::{
+        redirect_loops, AuthDirective, Backend, BackendClass, InFlightLimit, Proxy, Route,
+        RouteWatch,
+    },
+    ArxError,
 };
 
 #[derive(Clone)]
 pub struct Gateway {
     state: Arc<GatewayState>,
+    /// Admission of websocket upgrades, see `websocket_max_upgrades_per_second`
+    websocket_admission: Option<Arc<AdmissionControl>>,
 }
 
 pub struct GatewayState {
     pub routes: Arc<ArcSwap<matchit::Router<Route>>>,
     pub backends: Backends,
-    pub authly_client: Option<authly_client::Client>,
+    pub authly_client: Option<Arc<dyn AccessTokenSource>>,
+    /// Named Authly clusters, see `ArxConfig::authly_clusters`
+    pub authly_clusters: HashMap<String, Arc<dyn AccessTokenSource>>,
+    /// Requests in flight to the backends of routes with an in-flight limit
+    pub in_flight: InFlightLimits,
+    /// Open websocket tunnels, see `websocket_max_tunnels`
+    pub websocket_tunnels: InFlightLimits,
+    /// Retries left across all routes, see `retry_budget_percent`
+    pub retry_budget: Option<RetryBudget>,
+    /// The configured `response_headers`, parsed
+    pub response_headers: Vec<(HeaderName, HeaderValue)>,
     pub cfg: &'static ArxConfig,
 }
 
@@ -36,11 +78,45 @@ pub struct Backends {
     pub authly: HttpClient,
 }
 
-/// serve the gateway on a bound HttpServer
-pub async fn serve_gateway(
-    gateway: Gateway,
-    http_server: tower_server::TowerServer,
-) -> anyhow::Result<()> {
+/// The bound listener the gateway is served on
+pub enum GatewayListener {
+    /// HTTP/1 served by tower-server
+    Server(tower_server::TowerServer),
+    /// Connections accepted by the gateway itself, for protocol detection (h2c),
+    /// PROXY protocol headers and/or per-IP connection limits
+    Tcp(TcpListener, CancellationToken),
+}
+
+/// The key counting all websocket tunnels in `GatewayState::websocket_tunnels`
+const ALL_TUNNELS: &str = "*";
+
+/// How long a client may take to send its PROXY protocol header
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The delays before accepting again after failing to accept a connection, doubling up to the maximum
+const ACCEPT_ERROR_MIN_BACKOFF: Duration = Duration::from_millis(10);
+const ACCEPT_ERROR_MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How long a connection rejected by `max_connections_per_ip` is kept open to receive its `503` response
+const REJECTED_CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many rejected connections may be kept open at once, further ones are closed right away
+const MAX_REJECTED_CONNECTIONS: usize = 256;
+
+/// How long a client may take to send the headers of an HTTP/1 request, also when idle between requests
+#[cfg(not(test))]
+const HEADER_READ_TIMEOUT: Duration = Duration::from_secs(30);
+#[cfg(test)]
+const HEADER_READ_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// serve the gateway on a bound listener
+pub async fn serve_gateway(gateway: Gateway, listener: GatewayListener) -> anyhow::Result<()> {
+    // with the access log, responses are logged there instead
+    let on_response_level = if gateway.state.cfg.access_log {
+        Level::DEBUG
+    } else {
+        Level::INFO
+    };
     let tower_layer = ServiceBuilder::new()
         .layer(
             TraceLayer::new_for_http()
@@ -49,71 +125,620 @@ pub async fn serve_gateway(
                         .level(Level::INFO)
                         .include_headers(false),
                 )
-                .on_response(DefaultOnResponse::new().level(Level::INFO)),
+                .on_response(DefaultOnResponse::new().level(on_response_level)),
         )
         .layer(compression_layer(gateway.state.cfg))
         .layer(cors_layer(gateway.state.cfg));
 
+    let gateway_cfg = gateway.state.cfg;
+    let service = tower_layer.service_fn(move |req| {
+        let gateway = gateway.clone();
+        async move { gateway.serve_request(req).await }
+    });
+
+    match listener {
+        GatewayListener::Server(http_server) => http_server.serve(service).await,
+        GatewayListener::Tcp(listener, cancel) => {
+            serve_tcp(listener, service, gateway_cfg, cancel).await
+        }
+    }
+
+    Ok(())
+}
+
+/// Serve the admin API on a listener of its own, so it's never reachable through the public routing table.
+pub async fn serve_admin(
+    routes: matchit::Router<Route>,
+    http_server: tower_server::TowerServer,
+) -> anyhow::Result<()> {
+    let routes = Arc::new(routes);
     http_server
-        .serve(tower_layer.service_fn(move |req| {
-            let gateway = gateway.clone();
-            async move { gateway.serve_request(req).await }
+        .serve(tower::service_fn(move |req| {
+            let routes = routes.clone();
+            async move { Ok::<_, hyper::Error>(serve_admin_request(&routes, req).await) }
         }))
         .await;
 
     Ok(())
 }
 
+/// Serve an admin request, only local services are routed.
+pub(crate) async fn serve_admin_request(
+    routes: &matchit::Router<Route>,
+    req: Request<hyper::body::Incoming>,
+) -> HyperResponse {
+    let result = match routes.at(req.uri().path()) {
+        Ok(matchit::Match {
+            value: Route::Local(service),
+            ..
+        }) => service.handle(req).await,
+        _ => Err(HttpError::Static(StatusCode::NOT_FOUND, "Not found")),
+    };
+
+    result.unwrap_or_else(HttpError::into_hyper_response)
+}
+
+/// Serve accepted connections directly.
+///
+/// With `http2_cleartext`, protocols are detected so clients may speak HTTP/2 without an `Upgrade`.
+/// With `proxy_protocol`, the client address is read from the PROXY protocol header of each connection.
+/// With `max_connections_per_ip`, connections beyond the limit are answered with `503 Service Unavailable`,
+/// as long as fewer than [MAX_REJECTED_CONNECTIONS] are waiting for theirs, and closed otherwise.
+/// With `http2_max_reset_streams`, connections on which the client cancels too many requests are closed.
+async fn serve_tcp<S, B>(
+    listener: TcpListener,
+    service: S,
+    cfg: &'static ArxConfig,
+    cancel: CancellationToken,
+) where
+    S: tower::Service<Request<hyper::body::Incoming>, Response = http::Response<B>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    B: http_body::Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let connection_counts = ConnectionCounts::default();
+    let rejected_connections = Arc::new(Semaphore::new(MAX_REJECTED_CONNECTIONS));
+    let mut accept_backoff = ACCEPT_ERROR_MIN_BACKOFF;
+
+    loop {
+        let (mut stream, mut remote_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => {
+                    accept_backoff = ACCEPT_ERROR_MIN_BACKOFF;
+                    accepted
+                }
+                Err(err) => {
+                    // e.g. out of file descriptors, which retrying right away won't fix
+                    info!(?err, ?accept_backoff, "failed to accept connection");
+                    tokio::select! {
+                        _ = tokio::time::sleep(accept_backoff) => {}
+                        _ = cancel.cancelled() => return,
+                    }
+                    accept_backoff = (accept_backoff * 2).min(ACCEPT_ERROR_MAX_BACKOFF);
+                    continue;
+                }
+            },
+            _ = cancel.cancelled() => return,
+        };
+
+        let service = service.clone();
+        let cancel = cancel.clone();
+        let connection_counts = connection_counts.clone();
+        let rejected_connections = rejected_connections.clone();
+
+        tokio::spawn(async move {
+            if cfg.proxy_protocol {
+                match tokio::time::timeout(PROXY_HEADER_TIMEOUT, read_proxy_header(&mut stream))
+                    .await
+                {
+                    Ok(Ok(Some(client_addr))) => remote_addr = client_addr,
+                    Ok(Ok(None)) => {}
+                    Ok(Err(err)) => {
+                        info!(?err, %remote_addr, "invalid PROXY protocol header");
+                        return;
+                    }
+                    Err(_) => {
+                        info!(%remote_addr, "PROXY protocol header timed out");
+                        return;
+                    }
+                }
+            }
+
+            let mut builder = auto::Builder::new(TokioExecutor::new());
+            // without a timer, hyper disables its timeouts
+            builder
+                .http1()
+                .timer(TokioTimer::new())
+                .header_read_timeout(HEADER_READ_TIMEOUT);
+            builder.http2().timer(TokioTimer::new());
+            if cfg.http2_cleartext {
+                builder
+                    .http2()
+                    .max_pending_accept_reset_streams(cfg.http2_max_pending_reset_streams);
+            } else {
+                builder = builder.http1_only();
+            }
+
+            let _counted = match cfg.max_connections_per_ip {
+                Some(max) => match connection_counts.try_open(remote_addr.ip(), max) {
+                    Some(counted) => Some(counted),
+                    None => {
+                        debug!(%remote_addr, "too many connections from client IP");
+                        // a client opening connections without sending requests can't hold on to more tasks
+                        let Ok(_rejected) = rejected_connections.try_acquire_owned() else {
+                            return;
+                        };
+                        builder.http1().keep_alive(false);
+                        let connection = builder.serve_connection(
+                            TokioIo::new(stream),
+                            hyper::service::service_fn(|_req| async {
+                                Ok::<_, Infallible>(
+                                    HttpError::Static(
+                                        StatusCode::SERVICE_UNAVAILABLE,
+                                        "too many connections",
+                                    )
+                                    .into_hyper_response(),
+                                )
+                            }),
+                        );
+                        let _ = tokio::time::timeout(REJECTED_CONNECTION_TIMEOUT, connection).await;
+                        return;
+                    }
+                },
+                None => None,
+            };
+
+            let stream_resets =
+                StreamResets::new(cfg.http2_max_reset_streams, cfg.http2_reset_streams_window);
+            let connection = builder.serve_connection_with_upgrades(
+                TokioIo::new(stream),
+                hyper::service::service_fn({
+                    let stream_resets = stream_resets.clone();
+                    move |mut req: Request<hyper::body::Incoming>| {
+                        req.extensions_mut().insert(PeerAddr(remote_addr));
+                        let tracked = stream_resets.track();
+                        let response = service.clone().oneshot(req);
+                        async move {
+                            let response = response.await;
+                            tracked.complete();
+                            response
+                        }
+                    }
+                }),
+            );
+            tokio::pin!(connection);
+
+            tokio::select! {
+                _ = connection.as_mut() => {}
+                _ = stream_resets.exceeded() => {
+                    info!(%remote_addr, "too many cancelled requests, closing connection");
+                }
+                _ = cancel.cancelled() => {
+                    connection.as_mut().graceful_shutdown();
+                    let _ = connection.await;
+                }
+            }
+        });
+    }
+}
+
+/// Make sure the path of a request URI starts with `/`, which route matching relies on.
+///
+/// A path without it is rejected, or prepended with `/` according to `malformed_paths`.
+fn normalize_path(uri: &mut Uri, handling: MalformedPathHandling) -> Result<(), HttpError> {
+    if uri.path().starts_with('/') {
+        return Ok(());
+    }
+    if handling == MalformedPathHandling::Reject {
+        debug!(%uri, "path does not start with `/`");
+        return Err(HttpError::bad_request("invalid request path"));
+    }
+
+    let path_and_query = match uri.query() {
+        Some(query) => format!("/{}?{query}", uri.path()),
+        None => format!("/{}", uri.path()),
+    };
+    let mut parts = std::mem::take(uri).into_parts();
+    parts.path_and_query = Some(
+        path_and_query
+            .parse()
+            .map_err(|_| HttpError::bad_request("invalid request path"))?,
+    );
+    if parts.scheme.is_none() {
+        // an authority-form target, the host is in the `Host` header
+        parts.authority = None;
+    }
+    *uri = Uri::from_parts(parts).map_err(|_| HttpError::bad_request("invalid request path"))?;
+
+    Ok(())
+}
+
+/// The HTTPS URL a request should be redirected to, according to `https_redirect`
+fn https_redirect<B>(req: &Request<B>, cfg: &ArxConfig) -> Result<Option<Uri>, HttpError> {
+    if !cfg.https_redirect
+        || req.uri().scheme() == Some(&http::uri::Scheme::HTTPS)
+        || req
+            .headers()
+            .get("x-forwarded-proto")
+            .is_some_and(|proto| proto.as_bytes().eq_ignore_ascii_case(b"https"))
+        || cfg
+            .https_redirect_exempt_paths
+            .iter()
+            .any(|prefix| req.uri().path().starts_with(prefix.as_str()))
+        || (cfg.acme_challenge_dir.is_some()
+            && req.uri().path().starts_with("/.well-known/acme-challenge/"))
+    {
+        return Ok(None);
+    }
+
+    let host = req
+        .uri()
+        .host()
+        .or_else(|| {
+            req.headers()
+                .get(header::HOST)
+                .and_then(|host| host.to_str().ok())
+                .and_then(|host| host.parse::<http::uri::Authority>().ok())
+                .map(|authority| &host[..authority.host().len()])
+        })
+        .ok_or(HttpError::Static(StatusCode::BAD_REQUEST, "missing host"))?;
+
+    // the port is dropped, HTTPS is served on its default port
+    Uri::builder()
+        .scheme(http::uri::Scheme::HTTPS)
+        .authority(host)
+        .path_and_query(
+            req.uri()
+                .path_and_query()
+                .map(|path_and_query| path_and_query.as_str())
+                .unwrap_or("/"),
+        )
+        .build()
+        .map(Some)
+        .map_err(|_| HttpError::Static(StatusCode::BAD_REQUEST, "invalid host"))
+}
+
 enum RouteMatch {
     Proxy {
         // The HTTP client to use when proxying
         http_client_instance: Arc<HttpClientInstance>,
         req: Request<hyper::body::Incoming>,
         auth_directive: AuthDirective,
+        // Route-specific client IP filter
+        ip_filter: Option<Arc<IpFilter>>,
+        metrics: Option<Arc<RouteMetrics>>,
+        authly_client: Option<Arc<dyn AccessTokenSource>>,
+        auth_timeout: Duration,
+        connect_timeout: Option<Duration>,
+        request_timeout: Option<Duration>,
+        request_max_size: Option<ByteSize>,
+        buffered: bool,
+        body_digest: bool,
+        // The backend only speaks HTTP/1.0, so the request body is buffered
+        http10: bool,
+        json_field_removal: Option<Arc<JsonFieldRemoval>>,
+        link_rewrite: Option<Arc<LinkRewrite>>,
+        grpc_status_to_http: bool,
+        strip_set_cookie: bool,
+        // The backends to retry against, one per retry
+        retry_backends: Vec<Backend>,
+        in_flight_limit: Option<InFlightLimit>,
+        websocket_tunnel_limit: Option<InFlightLimit>,
     },
     LocalService {
         req: Request<hyper::body::Incoming>,
         service: Arc<dyn LocalService + Send + Sync>,
     },
     TemporaryRedirect(Uri),
+    /// Automatic response to `OPTIONS`, with the allowed methods
+    Options(Vec<Method>),
 }
 
 impl Gateway {
-    pub fn new(state: GatewayState) -> Self {
+    pub fn new(mut state: GatewayState) -> Self {
+        if state.cfg.auth_single_flight {
+            let single_flight =
+                |source| Arc::new(SingleFlight::new(source)) as Arc<dyn AccessTokenSource>;
+            state.authly_client = state.authly_client.map(single_flight);
+            state.authly_clusters = state
+                .authly_clusters
+                .into_iter()
+                .map(|(name, source)| (name, single_flight(source)))
+                .collect();
+        }
+
+        let websocket_admission = state
+            .cfg
+            .websocket_max_upgrades_per_second
+            .map(|per_second| {
+                Arc::new(AdmissionControl::new(
+                    per_second,
+                    state.cfg.websocket_upgrade_max_wait,
+                ))
+            });
+
         Self {
             state: Arc::new(state),
+            websocket_admission,
         }
     }
 
-    async fn serve_request(
+    pub(crate) async fn serve_request(
         &self,
-        req: Request<hyper::body::Incoming>,
+        mut req: Request<hyper::body::Incoming>,
     ) -> Result<HyperResponse, hyper::Error> {
-        match self.serve_request_inner(req).await {
-            Ok(response) => Ok(response),
-            Err(error) => Ok(error.into_hyper_response()),
+        let request_id = assign_request_id(&mut req, self.state.cfg);
+        let access_log = AccessLog::begin(&req, self.state.cfg);
+        let span = request_span(&req, self.state.cfg);
+
+        let mut response = match self.serve_request_inner(req).instrument(span).await {
+            Ok(response) => response,
+            Err(error) => error_response(error, request_id.as_ref(), self.state.cfg),
+        };
+        add_response_headers(
+            response.headers_mut(),
+            &self.state.response_headers,
+            self.state.cfg,
+        );
+
+        if let Some(access_log) = access_log {
+            access_log.finish(&response);
         }
+
+        Ok(response)
     }
 
     async fn serve_request_inner(
         &self,
-        req: Request<hyper::body::Incoming>,
+        mut req: Request<hyper::body::Incoming>,
     ) -> Result<HyperResponse, HttpError> {
-        match self.match_route(req)? {
+        if has_ambiguous_framing(req.headers()) {
+            debug!("ambiguous request body framing");
+            return Err(HttpError::bad_request("ambiguous request body framing"));
+        }
+        // the body would be forwarded still encoded, without its transfer codings
+        if has_unsupported_transfer_coding(req.headers()) {
+            debug!("unsupported transfer coding");
+            return Err(HttpError::Static(
+                StatusCode::NOT_IMPLEMENTED,
+                "unsupported transfer coding",
+            ));
+        }
+
+        if req.uri().path() == "*" {
+            // asterisk-form is about the server as a whole, which has nothing to announce
+            if req.method() != Method::OPTIONS {
+                return Err(HttpError::bad_request(
+                    "`*` is only a valid target for OPTIONS",
+                ));
+            }
+            return Ok(http::Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(empty_body())
+                .unwrap());
+        }
+        normalize_path(req.uri_mut(), self.state.cfg.malformed_paths)?;
+
+        if let Some(https_uri) = https_redirect(&req, self.state.cfg)? {
+            return Ok(http::Response::builder()
+                .status(StatusCode::PERMANENT_REDIRECT)
+                .header(
+                    header::LOCATION,
+                    HeaderValue::from_str(&https_uri.to_string()).unwrap(),
+                )
+                .body(empty_body())
+                .unwrap());
+        }
+
+        let client_ip = client_ip(&req);
+        let route_match = self.match_route(req)?;
+
+        let ip_filter = match &route_match {
+            RouteMatch::Proxy {
+                ip_filter: Some(ip_filter),
+                ..
+            } => ip_filter.as_ref(),
+            _ => &self.state.cfg.ip_filter,
+        };
+        if !ip_filter.permits(client_ip) {
+            debug!(?client_ip, "client IP denied");
+            return Err(HttpError::Static(StatusCode::FORBIDDEN, "forbidden"));
+        }
+
+        match route_match {
             RouteMatch::Proxy {
                 http_client_instance,
                 mut req,
                 auth_directive,
+                ip_filter: _,
+                metrics,
+                authly_client,
+                auth_timeout,
+                connect_timeout,
+                request_timeout,
+                request_max_size,
+                buffered,
+                body_digest,
+                http10,
+                json_field_removal,
+                link_rewrite,
+                grpc_status_to_http,
+                strip_set_cookie,
+                retry_backends,
+                in_flight_limit,
+                websocket_tunnel_limit,
             } => {
                 process_auth_directive(
                     auth_directive,
                     req.headers_mut(),
-                    self.state.authly_client.as_ref(),
+                    authly_client.as_deref(),
+                    self.state.cfg.access_token_expiry_skew,
+                    auth_timeout,
                 )
                 .await
-                .map_err(|_| HttpError::Static(StatusCode::UNAUTHORIZED, "unauthorized"))?;
+                .map_err(|err| match err {
+                    ArxError::AuthTimeout => {
+                        HttpError::Static(StatusCode::GATEWAY_TIMEOUT, "authentication timed out")
+                    }
+                    _ => HttpError::Static(StatusCode::UNAUTHORIZED, "unauthorized"),
+                })?;
 
-                reverse_proxy(req, &http_client_instance).await
+                if matches!(auth_directive, AuthDirective::Disabled)
+                    && !self.state.cfg.forward_credentials_without_auth
+                {
+                    strip_credentials(req.headers_mut());
+                }
+
+                let max_size = request_max_size.unwrap_or(self.state.cfg.request_max_size);
+                if exceeds_max_size(&req, max_size) {
+                    debug!(%max_size, "request body too large");
+                    return Err(HttpError::Static(
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        "request body too large",
+                    ));
+                }
+
+                let mut req = if body_digest {
+                    verify_body_digest(req, max_size, self.state.cfg)
+                        .await?
+                        .map(Either::Right)
+                } else {
+                    req.map(Either::Left)
+                };
+
+                let is_websocket = req
+                    .headers()
+                    .get(header::UPGRADE)
+                    .is_some_and(|upgrade| upgrade.as_bytes() == b"websocket");
+                if is_websocket {
+                    if let Some(admission) = &self.websocket_admission {
+                        if let Err(retry_after) = admission.admit().await {
+                            debug!("websocket upgrade not admitted");
+                            return Ok(http::Response::builder()
+                                .status(StatusCode::SERVICE_UNAVAILABLE)
+                                .header(
+                                    header::RETRY_AFTER,
+                                    HeaderValue::from(retry_after.as_secs_f64().ceil() as u64),
+                                )
+                                .body(empty_body())
+                                .unwrap());
+                        }
+                    }
+
+                    let tunnel_slots = self
+                        .acquire_tunnel_slots(websocket_tunnel_limit.as_ref())
+                        .await?;
+                    req.extensions_mut().insert(tunnel_slots);
+                }
+
+                let in_flight_permit = match &in_flight_limit {
+                    Some(limit) => Some(
+                        self.state
+                            .in_flight
+                            .acquire(&limit.key, limit.max, limit.wait, limit.queue)
+                            .await
+                            .ok_or_else(|| {
+                                debug!(key = %limit.key, "too many requests in flight");
+                                HttpError::Static(
+                                    StatusCode::SERVICE_UNAVAILABLE,
+                                    "too many requests in flight",
+                                )
+                            })?,
+                    ),
+                    None => None,
+                };
+
+                let transforms = ResponseTransforms {
+                    json_field_removal: json_field_removal.as_deref(),
+                    link_rewrite: link_rewrite.as_deref(),
+                    grpc_status_to_http,
+                    strip_set_cookie,
+                };
+
+                if let Some(retry_budget) = &self.state.retry_budget {
+                    retry_budget.record_request();
+                }
+
+                if retry_backends.is_empty() || !is_replayable(&req) {
+                    let proxied = reverse_proxy(
+                        req,
+                        &http_client_instance,
+                        metrics,
+                        RequestBodyHandling {
+                            buffered,
+                            buffer_request: http10,
+                            max_size,
+                        },
+                        transforms,
+                        self.state.cfg,
+                    );
+                    return hold_permit(
+                        scope_backend_connection(proxied, connect_timeout, request_timeout).await,
+                        in_flight_permit,
+                    );
+                }
+
+                // the request has no body, so it can be sent again
+                let idempotent = is_idempotent(req.method());
+                let (parts, _body) = req.into_parts();
+                let attempt = |uri: Uri| {
+                    let mut req = Request::new(Empty::<Bytes>::new());
+                    *req.method_mut() = parts.method.clone();
+                    *req.uri_mut() = uri;
+                    *req.version_mut() = parts.version;
+                    *req.headers_mut() = parts.headers.clone();
+                    req
+                };
+
+                let proxied = reverse_proxy(
+                    attempt(parts.uri.clone()),
+                    &http_client_instance,
+                    metrics.clone(),
+                    RequestBodyHandling {
+                        buffered,
+                        buffer_request: http10,
+                        max_size,
+                    },
+                    transforms,
+                    self.state.cfg,
+                );
+                let mut result =
+                    scope_backend_connection(proxied, connect_timeout, request_timeout).await;
+
+                for backend in &retry_backends {
+                    if !should_retry(&result, idempotent) {
+                        break;
+                    }
+                    if let Some(retry_budget) = &self.state.retry_budget {
+                        if !retry_budget.try_retry() {
+                            debug!("retry budget exhausted, not retrying");
+                            break;
+                        }
+                    }
+                    debug!(backend = %backend.uri, "retrying request");
+
+                    let proxied = reverse_proxy(
+                        attempt(retarget_uri(&parts.uri, &backend.uri)?),
+                        &self.backend_client(backend),
+                        metrics.clone(),
+                        RequestBodyHandling {
+                            buffered,
+                            buffer_request: backend.http10,
+                            max_size,
+                        },
+                        transforms,
+                        self.state.cfg,
+                    );
+                    result =
+                        scope_backend_connection(proxied, connect_timeout, request_timeout).await;
+                }
+
+                hold_permit(result, in_flight_permit)
             }
             RouteMatch::TemporaryRedirect(uri) => Ok(http::Response::builder()
                 .status(StatusCode::TEMPORARY_REDIRECT)
@@ -123,6 +748,18 @@ impl Gateway {
                 )
                 .body(empty_body())
                 .unwrap()),
+            RouteMatch::Options(methods) => {
+                let allow = methods
+                    .iter()
+                    .map(Method::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                Ok(http::Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .header(header::ALLOW, HeaderValue::from_str(&allow).unwrap())
+                    .body(empty_body())
+                    .unwrap())
+            }
             RouteMatch::LocalService {
                 req,
                 service: endpoint,
@@ -130,12 +767,63 @@ impl Gateway {
         }
     }
 
+    /// Take the slots for a new websocket tunnel, within `websocket_max_tunnels` and the route's tunnel limit
+    async fn acquire_tunnel_slots(
+        &self,
+        route_limit: Option<&InFlightLimit>,
+    ) -> Result<TunnelSlots, HttpError> {
+        let limits = self
+            .state
+            .cfg
+            .websocket_max_tunnels
+            .map(|max| (ALL_TUNNELS, max, Duration::ZERO, None))
+            .into_iter()
+            .chain(
+                route_limit.map(|limit| (limit.key.as_str(), limit.max, limit.wait, limit.queue)),
+            );
+
+        let mut permits = vec![];
+        for (key, max, wait, queue) in limits {
+            let permit = self
+                .state
+                .websocket_tunnels
+                .acquire(key, max, wait, queue)
+                .await
+                .ok_or_else(|| {
+                    debug!(key, "too many websocket tunnels");
+                    HttpError::Static(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "too many websocket tunnels",
+                    )
+                })?;
+            permits.push(permit);
+        }
+
+        Ok(TunnelSlots::new(permits))
+    }
+
+    /// The HTTP client for a backend, by its class and CA certificates
+    fn backend_client(&self, backend: &Backend) -> Arc<HttpClientInstance> {
+        let backends = &self.state.backends;
+        match (backend.class, &backend.client) {
+            (BackendClass::Plain, None) => backends.default.current_instance(),
+            (BackendClass::Plain, Some(client)) => client.current_instance(),
+            (BackendClass::AuthlyMesh, _) => backends.authly.current_instance(),
+        }
+    }
+
     /// match_route is synchronous, to avoid contention on the ArcSwap Guard (if accidentally held across `await` points).
     /// i.e. this function can't do any networking stuff.
     fn match_route(
         &self,
         mut req: Request<hyper::body::Incoming>,
     ) -> Result<RouteMatch, HttpError> {
+        let segments = req.uri().path().split('/').skip(1).count();
+        if segments > self.state.cfg.max_path_segments as usize {
+            debug!(segments, "too many path segments");
+            return Err(HttpError::bad_request("too many path segments"));
+        }
+
         let routes = self.state.routes.load();
 
         let matchit = routes.at(req.uri().path()).map_err(|_| {
@@ -144,46 +832,120 @@ impl Gateway {
         })?;
 
         match matchit.value {
-            Route::Proxy(proxy) => {
-                trace!(
-                    "original URI: `{}` match: `{}`",
-                    req.uri(),
-                    proxy.backend_uri()
-                );
+            Route::Proxy(_) | Route::ProxyAlternatives(_) => {
+                let proxies = match matchit.value {
+                    Route::ProxyAlternatives(proxies) => proxies.as_slice(),
+                    proxy => std::slice::from_ref(proxy),
+                };
+                if req.method() == Method::OPTIONS
+                    && self.state.cfg.auto_options
+                    && !proxies
+                        .iter()
+                        .any(|proxy| proxy.method() == Some(&Method::OPTIONS))
+                {
+                    return Ok(RouteMatch::Options(options_allowed_methods(
+                        proxies,
+                        self.state.cfg,
+                    )));
+                }
+
+                let Some(proxy) = select_proxy(proxies, &req, self.state.cfg) else {
+                    // without a proxy for its headers and query, the request matches no route at all
+                    if !proxies.iter().any(|proxy| proxy.matches_conditions(&req)) {
+                        return Err(HttpError::Static(StatusCode::NOT_FOUND, "Not found"));
+                    }
+                    return Err(HttpError::MethodNotAllowed(allowed_methods(
+                        proxies,
+                        self.state.cfg,
+                    )));
+                };
+
+                if let Some(media_types) = proxy.media_types() {
+                    media_types.check(&req)?;
+                }
+
+                let backend = proxy.select_backend(&req);
 
-                let original_uri = req.uri().clone();
+                trace!("original URI: `{}` match: `{}`", req.uri(), backend.uri);
+
+                if self.state.cfg.websocket_drain_on_route_change
+                    && req.headers().contains_key(header::UPGRADE)
+                {
+                    let route_watch = RouteWatch::new(
+                        self.state.routes.clone(),
+                        req.uri().path().to_string(),
+                        backend.uri.clone(),
+                    );
+                    req.extensions_mut().insert(route_watch);
+                }
+
+                let stripped_prefix = proxy
+                    .replace_prefix()
+                    .map(|_| matched_prefix(req.uri().path(), &matchit).to_string());
                 let rewritten_uri = rewrite_proxied_uri(
                     req.uri().clone(),
-                    Some(proxy.backend_uri()),
+                    Some(&backend.uri),
                     &matchit,
                     proxy.replace_prefix(),
+                    proxy.path_rewrite().map(AsRef::as_ref),
                 )?;
 
-                (*req.uri_mut()) = rewritten_uri;
+                let original_uri = std::mem::replace(req.uri_mut(), rewritten_uri);
                 debug!("rewritten URI: `{}`", req.uri());
 
-                set_proxy_headers(&mut req, &original_uri)?;
+                set_proxy_headers(
+                    &mut req,
+                    stripped_prefix.as_deref(),
+                    &original_uri,
+                    proxy.forwarded_headers(),
+                    self.state.cfg,
+                )?;
 
                 let auth_directive = proxy.get_auth_directive(&req);
 
-                let http_client = match proxy.backend_class() {
-                    BackendClass::Plain => &self.state.backends.default,
-                    BackendClass::AuthlyMesh => &self.state.backends.authly,
-                };
-
                 Ok(RouteMatch::Proxy {
-                    http_client_instance: http_client.current_instance(),
+                    http_client_instance: self.backend_client(backend),
                     req,
                     auth_directive,
+                    ip_filter: proxy.ip_filter().cloned(),
+                    metrics: proxy.metrics().cloned(),
+                    authly_client: match proxy.authly_cluster() {
+                        Some(name) => self.state.authly_clusters.get(name).cloned(),
+                        None => self.state.authly_client.clone(),
+                    },
+                    auth_timeout: proxy.auth_timeout().unwrap_or(self.state.cfg.auth_timeout),
+                    connect_timeout: proxy.connect_timeout(),
+                    request_timeout: proxy.request_timeout(),
+                    request_max_size: proxy.request_max_size(),
+                    buffered: proxy.buffered(),
+                    body_digest: proxy.body_digest(),
+                    http10: backend.http10,
+                    json_field_removal: proxy.json_field_removal().cloned(),
+                    link_rewrite: proxy.link_rewrite().cloned(),
+                    grpc_status_to_http: proxy.grpc_status_to_http(),
+                    strip_set_cookie: proxy.strip_set_cookie(),
+                    retry_backends: proxy.retry_backends(&req).into_iter().cloned().collect(),
+                    in_flight_limit: proxy.in_flight_limit().cloned(),
+                    websocket_tunnel_limit: proxy.websocket_tunnel_limit().cloned(),
                 })
             }
-            Route::TemporaryRedirect(uri) => Ok(RouteMatch::TemporaryRedirect(uri.clone())),
+            Route::TemporaryRedirect(uri) => {
+                if redirect_loops(&routes, req.uri().path(), uri) {
+                    error!(path = req.uri().path(), "redirect loop");
+                    return Err(HttpError::Static(
+                        StatusCode::LOOP_DETECTED,
+                        "redirect loop",
+                    ));
+                }
+                Ok(RouteMatch::TemporaryRedirect(uri.clone()))
+            }
             Route::Local(local_service) => {
                 let rewritten_uri = rewrite_proxied_uri(
                     req.uri().clone(),
                     None,
                     &matchit,
                     local_service.replace_prefix(),
+                    None,
                 )?;
                 (*req.uri_mut()) = rewritten_uri;
 
@@ -196,6 +958,174 @@ impl Gateway {
     }
 }
 
+/// Run a proxied request within the timeouts of its route
+async fn scope_backend_connection(
+    proxied: impl Future<Output = Result<HyperResponse, HttpError>>,
+    connect_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+) -> Result<HyperResponse, HttpError> {
+    let proxied = async {
+        match request_timeout {
+            Some(request_timeout) => REQUEST_TIMEOUT.scope(request_timeout, proxied).await,
+            None => proxied.await,
+        }
+    };
+    match connect_timeout {
+        Some(connect_timeout) => CONNECT_TIMEOUT.scope(connect_timeout, proxied).await,
+        None => proxied.await,
+    }
+}
+
+/// The response to an error, with the configured error page for its status, if any
+fn error_response(
+    error: HttpError,
+    request_id: Option<&HeaderValue>,
+    cfg: &ArxConfig,
+) -> HyperResponse {
+    let Some(error_page) = cfg.error_pages.get(&error.status().as_u16()) else {
+        return error.into_hyper_response();
+    };
+    let body = error_page.render(
+        error.status(),
+        error.message(),
+        request_id
+            .and_then(|request_id| request_id.to_str().ok())
+            .unwrap_or_default(),
+    );
+
+    let mut response = error.into_hyper_response();
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(&error_page.content_type)
+            .unwrap_or(HeaderValue::from_static("text/plain")),
+    );
+    *response.body_mut() = Full::new(Bytes::from(body))
+        .map_err(|never| match never {})
+        .boxed_unsync();
+    response
+}
+
+/// Hold the in-flight permit, if any, until the response body is finished
+fn hold_permit(
+    result: Result<HyperResponse, HttpError>,
+    permit: Option<OwnedSemaphorePermit>,
+) -> Result<HyperResponse, HttpError> {
+    match (result, permit) {
+        (Ok(response), Some(permit)) => {
+            Ok(response.map(|body| HoldingBody::new(body, permit).boxed_unsync()))
+        }
+        (result, _) => result,
+    }
+}
+
+/// Whether a request can be sent again to retry it: it has no body to replay, and is no upgrade
+fn is_replayable<B: http_body::Body>(req: &Request<B>) -> bool {
+    req.body().is_end_stream() && !req.headers().contains_key(header::UPGRADE)
+}
+
+/// Whether sending a request more than once has the same effect as sending it once
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE | Method::TRACE
+    )
+}
+
+/// Whether a request declares a body larger than `max_size`, by its `Content-Length`
+fn exceeds_max_size<B: http_body::Body>(req: &Request<B>, max_size: ByteSize) -> bool {
+    req.body().size_hint().lower() > max_size.as_u64()
+}
+
+/// Whether a proxied request failed in a way worth retrying against another backend.
+///
+/// A request the backend never received can always be retried.
+/// One the backend may have acted upon only when `idempotent`, and the failure looks temporary.
+fn should_retry(result: &Result<HyperResponse, HttpError>, idempotent: bool) -> bool {
+    let status = match result {
+        Err(HttpError::Unreachable(_)) => return true,
+        Err(err) => err.status(),
+        Ok(response) => response.status(),
+    };
+    idempotent
+        && matches!(
+            status,
+            StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+        )
+}
+
+/// Point a proxied URI at another backend
+fn retarget_uri(uri: &Uri, backend_uri: &Uri) -> Result<Uri, HttpError> {
+    let mut parts = uri.clone().into_parts();
+    parts.scheme = backend_uri.scheme().cloned();
+    parts.authority = backend_uri.authority().cloned();
+    Uri::from_parts(parts).map_err(|_| HttpError::bad_gateway("invalid backend URI"))
+}
+
+/// The first of a set of proxies sharing the same path that matches the request.
+///
+/// With `head_matches_get`, a `HEAD` request matches a proxy for `GET`, unless a proxy for `HEAD` matches it.
+fn select_proxy<'p, B>(
+    proxies: &'p [Proxy],
+    req: &Request<B>,
+    cfg: &ArxConfig,
+) -> Option<&'p Proxy> {
+    if req.method() == Method::HEAD && cfg.head_matches_get {
+        let for_method = |method: &Method| {
+            proxies
+                .iter()
+                .find(|proxy| proxy.method() == Some(method) && proxy.matches_conditions(req))
+        };
+        if let Some(proxy) = for_method(&Method::HEAD).or_else(|| for_method(&Method::GET)) {
+            return Some(proxy);
+        }
+    }
+
+    proxies.iter().find(|proxy| proxy.matches_request(req))
+}
+
+/// The methods allowed by a set of proxies sharing the same path
+fn allowed_methods(proxies: &[Proxy], cfg: &ArxConfig) -> Vec<Method> {
+    let mut methods: Vec<Method> = vec![];
+    for method in proxies.iter().filter_map(Proxy::method) {
+        if !methods.contains(method) {
+            methods.push(method.clone());
+        }
+        if method == Method::GET && cfg.head_matches_get && !methods.contains(&Method::HEAD) {
+            methods.push(Method::HEAD);
+        }
+    }
+    methods
+}
+
+/// The methods in the `Allow` header of an automatic `OPTIONS` response
+fn options_allowed_methods(proxies: &[Proxy], cfg: &ArxConfig) -> Vec<Method> {
+    let mut methods = if proxies.iter().any(|proxy| proxy.method().is_none()) {
+        // some proxy accepts any method
+        vec![
+            Method::GET,
+            Method::HEAD,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+        ]
+    } else {
+        allowed_methods(proxies, cfg)
+    };
+    methods.push(Method::OPTIONS);
+    methods
+}
+
+/// The prefix of the original path matched by a route, excluding the trailing slash.
+///
+/// This is the part of the path that gets replaced when the route has a `replace_prefix`.
+fn matched_prefix<'p>(path: &'p str, matchit: &matchit::Match<&Route>) -> &'p str {
+    let rest = matchit.params.get("path").unwrap_or("");
+    path.strip_suffix(rest)
+        .unwrap_or(path)
+        .trim_end_matches('/')
+}
+
 /// Rewrite the original Uri for proxying.
 ///
 /// scheme and authority are rewritten based on `target_uri`.
@@ -207,6 +1137,7 @@ pub(crate) fn rewrite_proxied_uri(
     target_uri: Option<&Uri>,
     matchit: &matchit::Match<&Route>,
     replace_prefix: Option<&str>,
+    path_rewrite: Option<&PathRewrite>,
 ) -> Result<Uri, HttpError> {
     let mut parts = original.into_parts();
 
@@ -248,8 +1179,1916 @@ pub(crate) fn rewrite_proxied_uri(
             })?);
     }
 
+    if let (Some(path_rewrite), Some(path_and_query)) = (path_rewrite, &parts.path_and_query) {
+        if let Some(path) = path_rewrite.apply(path_and_query.path()) {
+            let new_path_query = match path_and_query.query() {
+                Some(query) => format!("{path}?{query}"),
+                None => path,
+            };
+            parts.path_and_query = Some(new_path_query.parse().map_err(|_| {
+                HttpError::Static(StatusCode::INTERNAL_SERVER_ERROR, "uri problem")
+            })?);
+        }
+    }
+
     Uri::from_parts(parts).map_err(|err| {
         error!(?err, "URI rewrite failed");
         HttpError::Static(StatusCode::INTERNAL_SERVER_ERROR, "invalid uri")
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, SystemTime},
+    };
+
+    use arc_swap::ArcSwap;
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use http::Method;
+    use http_body_util::BodyExt;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_util::sync::CancellationToken;
+    use wiremock::{
+        matchers::{header, method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{
+        authentication::{AccessToken, AccessTokenSource},
+        config::{ArxConfig, ErrorPage, MalformedPathHandling, RequestIdFormat},
+        media_type::MediaTypes,
+        path_rewrite::PathRewrite,
+        route::{
+            AuthDirective, Backend, BackendClass, InFlightLimit, Proxy, Route, SplitBackend,
+            TrafficSplit,
+        },
+        static_routes::static_routes,
+        testing::{leak_config, spawn_gateway, spawn_gateway_with_authly_clusters, test_gateway},
+    };
+
+    use super::{serve_gateway, GatewayListener};
+
+    /// An Authly stub handing out the same token for every session
+    struct FixedToken(&'static str);
+
+    #[async_trait]
+    impl AccessTokenSource for FixedToken {
+        async fn access_token(&self, _session_token: &str) -> anyhow::Result<AccessToken> {
+            Ok(AccessToken {
+                token: self.0.to_string(),
+                expires_at: SystemTime::now() + Duration::from_secs(3600),
+            })
+        }
+    }
+
+    /// An Authly stub taking its time to answer
+    struct SlowAuthly;
+
+    #[async_trait]
+    impl AccessTokenSource for SlowAuthly {
+        async fn access_token(&self, _session_token: &str) -> anyhow::Result<AccessToken> {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok(AccessToken {
+                token: "token".to_string(),
+                expires_at: SystemTime::now() + Duration::from_secs(3600),
+            })
+        }
+    }
+
+    /// An Authly stub counting its exchanges, each taking a while
+    #[derive(Default)]
+    struct CountingAuthly(AtomicUsize);
+
+    #[async_trait]
+    impl AccessTokenSource for CountingAuthly {
+        async fn access_token(&self, _session_token: &str) -> anyhow::Result<AccessToken> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok(AccessToken {
+                token: "token".to_string(),
+                expires_at: SystemTime::now() + Duration::from_secs(3600),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn forwarded_prefix_with_non_suffix_rewrite() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/users"))
+            .and(header("x-forwarded-prefix", "/authly/api"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/authly/api/{*path}",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .with_replace_prefix("/api/")
+                    .into(),
+            )
+            .unwrap();
+
+        let cfg = leak_config(ArxConfig::default());
+        let addr = spawn_gateway(cfg, routes).await;
+
+        let response = reqwest::Client::new()
+            .get(format!("http://{addr}/authly/api/users"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(200, response.status().as_u16());
+    }
+
+    #[tokio::test]
+    async fn original_uri_forwarded() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/users"))
+            .and(header("x-original-uri", "/api/users?page=2"))
+            .and(header("x-original-method", "GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/api/{*path}",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .with_replace_prefix("/")
+                    .into(),
+            )
+            .unwrap();
+
+        let cfg = leak_config(ArxConfig {
+            forward_original_uri: true,
+            ..Default::default()
+        });
+        let addr = spawn_gateway(cfg, routes).await;
+
+        let response = reqwest::Client::new()
+            .get(format!("http://{addr}/api/users?page=2"))
+            .header("x-original-uri", "/spoofed")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(200, response.status().as_u16());
+    }
+
+    #[tokio::test]
+    async fn path_rewritten_by_regex() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/users"))
+            .and(query_param("page", "2"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/v1/{*path}",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .with_path_rewrite(PathRewrite::new("^/v1/(.*)", "/api/$1").unwrap())
+                    .into(),
+            )
+            .unwrap();
+        let addr = spawn_gateway(leak_config(ArxConfig::default()), routes).await;
+
+        let response = reqwest::Client::new()
+            .get(format!("http://{addr}/v1/users?page=2"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(200, response.status().as_u16());
+    }
+
+    #[tokio::test]
+    async fn client_cert_headers_stripped() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/api",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+        let addr = spawn_gateway(leak_config(ArxConfig::default()), routes).await;
+
+        let response = reqwest::Client::new()
+            .get(format!("http://{addr}/api"))
+            .header("x-client-cert-subject", "CN=admin")
+            .header("x-client-cert-fingerprint", "00:11:22")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(200, response.status().as_u16());
+
+        let received = mock_server.received_requests().await.unwrap();
+        assert!(!received[0].headers.contains_key("x-client-cert-subject"));
+        assert!(!received[0]
+            .headers
+            .contains_key("x-client-cert-fingerprint"));
+    }
+
+    #[tokio::test]
+    async fn forwarded_headers_omitted() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        for (prefix, forwarded_headers) in [("/direct", false), ("/proxied", true)] {
+            routes
+                .insert(
+                    prefix,
+                    Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                        .unwrap()
+                        .with_forwarded_headers(forwarded_headers)
+                        .into(),
+                )
+                .unwrap();
+        }
+        let addr = spawn_gateway(leak_config(ArxConfig::default()), routes).await;
+
+        for prefix in ["/direct", "/proxied"] {
+            let response = reqwest::Client::new()
+                .get(format!("http://{addr}{prefix}"))
+                .header("x-forwarded-for", "10.0.0.1")
+                .header("forwarded", "for=10.0.0.1")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(200, response.status().as_u16());
+        }
+
+        let received = mock_server.received_requests().await.unwrap();
+        let forwarded_names = |index: usize| -> Vec<String> {
+            received[index]
+                .headers
+                .keys()
+                .map(|name| name.as_str().to_string())
+                .filter(|name| name.starts_with("x-forwarded-") || name == "forwarded")
+                .collect()
+        };
+        assert_eq!(forwarded_names(0), Vec::<String>::new());
+        assert!(forwarded_names(1).contains(&"x-forwarded-for".to_string()));
+        assert!(forwarded_names(1).contains(&"x-forwarded-proto".to_string()));
+    }
+
+    #[tokio::test]
+    async fn request_id_forwarded() {
+        for format in [
+            RequestIdFormat::Header,
+            RequestIdFormat::Traceparent,
+            RequestIdFormat::Both,
+        ] {
+            let mock_server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .respond_with(ResponseTemplate::new(200))
+                .expect(1)
+                .mount(&mock_server)
+                .await;
+
+            let mut routes = matchit::Router::new();
+            routes
+                .insert(
+                    "/api",
+                    Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                        .unwrap()
+                        .into(),
+                )
+                .unwrap();
+            let cfg = leak_config(ArxConfig {
+                request_id: format,
+                request_id_header: "x-correlation-id".to_string(),
+                ..Default::default()
+            });
+            let addr = spawn_gateway(cfg, routes).await;
+
+            let response = reqwest::get(format!("http://{addr}/api")).await.unwrap();
+            assert_eq!(200, response.status().as_u16());
+
+            let received = mock_server.received_requests().await.unwrap();
+            let received_header = |name: &str| {
+                received[0]
+                    .headers
+                    .get(name)
+                    .map(|value| value.to_str().unwrap().to_string())
+            };
+            // the trace ID of `00-{trace-id}-{parent-id}-{flags}`
+            let trace_id = received_header("traceparent").map(|value| value[3..35].to_string());
+
+            match format {
+                RequestIdFormat::Header => {
+                    assert_eq!(32, received_header("x-correlation-id").unwrap().len());
+                    assert_eq!(None, trace_id);
+                }
+                RequestIdFormat::Traceparent => {
+                    assert!(trace_id.is_some());
+                    assert_eq!(None, received_header("x-correlation-id"));
+                }
+                RequestIdFormat::Both => {
+                    assert!(trace_id.is_some());
+                    assert_eq!(trace_id, received_header("x-correlation-id"));
+                }
+                RequestIdFormat::None => unreachable!(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_session_exchanges_shared() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(header("authorization", "Bearer token"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(20)
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/api",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .with_auth_directive_fn(|_| AuthDirective::Mandatory)
+                    .with_authly_cluster("authly")
+                    .into(),
+            )
+            .unwrap();
+
+        let authly = Arc::new(CountingAuthly::default());
+        let addr = spawn_gateway_with_authly_clusters(
+            leak_config(ArxConfig::default()),
+            Arc::new(ArcSwap::new(Arc::new(routes))),
+            HashMap::from([(
+                "authly".to_string(),
+                authly.clone() as Arc<dyn AccessTokenSource>,
+            )]),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+        let responses = futures_util::future::join_all((0..20).map(|_| {
+            client
+                .get(format!("http://{addr}/api"))
+                .header("cookie", "session-cookie=c0ld")
+                .send()
+        }))
+        .await;
+        for response in responses {
+            assert_eq!(200, response.unwrap().status().as_u16());
+        }
+        assert_eq!(1, authly.0.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn routes_use_their_authly_cluster() {
+        let mock_server = MockServer::start().await;
+        for (tenant, token) in [("a", "Bearer token-a"), ("b", "Bearer token-b")] {
+            Mock::given(method("GET"))
+                .and(path(format!("/{tenant}")))
+                .and(header("authorization", token))
+                .respond_with(ResponseTemplate::new(200))
+                .expect(1)
+                .mount(&mock_server)
+                .await;
+        }
+
+        let mut routes = matchit::Router::new();
+        for tenant in ["a", "b"] {
+            routes
+                .insert(
+                    format!("/{tenant}"),
+                    Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                        .unwrap()
+                        .with_auth_directive_fn(|_| AuthDirective::Mandatory)
+                        .with_authly_cluster(tenant)
+                        .into(),
+                )
+                .unwrap();
+        }
+
+        let authly_clusters: HashMap<String, Arc<dyn AccessTokenSource>> = HashMap::from([
+            (
+                "a".to_string(),
+                Arc::new(FixedToken("token-a")) as Arc<dyn AccessTokenSource>,
+            ),
+            (
+                "b".to_string(),
+                Arc::new(FixedToken("token-b")) as Arc<dyn AccessTokenSource>,
+            ),
+        ]);
+
+        let cfg = leak_config(ArxConfig::default());
+        let addr = spawn_gateway_with_authly_clusters(
+            cfg,
+            Arc::new(ArcSwap::new(Arc::new(routes))),
+            authly_clusters,
+        )
+        .await;
+
+        for tenant in ["a", "b"] {
+            let response = reqwest::Client::new()
+                .get(format!("http://{addr}/{tenant}"))
+                .header("cookie", "session-cookie=s3ss10n")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(200, response.status().as_u16());
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_authly_times_out() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/mandatory",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .with_auth_directive_fn(|_| AuthDirective::Mandatory)
+                    .with_authly_cluster("slow")
+                    .with_auth_timeout(Duration::from_millis(100))
+                    .into(),
+            )
+            .unwrap();
+        routes
+            .insert(
+                "/opportunistic",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .with_auth_directive_fn(|_| AuthDirective::Opportunistic)
+                    .with_authly_cluster("slow")
+                    .into(),
+            )
+            .unwrap();
+
+        let cfg = leak_config(ArxConfig {
+            auth_timeout: Duration::from_millis(100),
+            ..Default::default()
+        });
+        let addr = spawn_gateway_with_authly_clusters(
+            cfg,
+            Arc::new(ArcSwap::new(Arc::new(routes))),
+            HashMap::from([(
+                "slow".to_string(),
+                Arc::new(SlowAuthly) as Arc<dyn AccessTokenSource>,
+            )]),
+        )
+        .await;
+
+        let response = reqwest::Client::new()
+            .get(format!("http://{addr}/mandatory"))
+            .header("cookie", "session-cookie=s3ss10n")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(504, response.status().as_u16());
+
+        // forwarded without an access token
+        let response = reqwest::Client::new()
+            .get(format!("http://{addr}/opportunistic"))
+            .header("cookie", "session-cookie=s3ss10n")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(200, response.status().as_u16());
+        let received = mock_server.received_requests().await.unwrap();
+        assert!(!received[0].headers.contains_key("authorization"));
+    }
+
+    #[tokio::test]
+    async fn credentials_stripped_without_auth() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/public",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+
+        for (forward_credentials_without_auth, expected) in
+            [(false, None), (true, Some("Bearer x"))]
+        {
+            let cfg = leak_config(ArxConfig {
+                forward_credentials_without_auth,
+                ..Default::default()
+            });
+            let addr = spawn_gateway(cfg, routes.clone()).await;
+
+            let response = reqwest::Client::new()
+                .get(format!("http://{addr}/public"))
+                .header("authorization", "Bearer x")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(200, response.status().as_u16());
+
+            let received = mock_server.received_requests().await.unwrap();
+            let authorization = received.last().unwrap().headers.get("authorization");
+            assert_eq!(expected, authorization.map(|value| value.to_str().unwrap()));
+        }
+    }
+
+    #[tokio::test]
+    async fn h2c_prior_knowledge() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/h2c"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("over h2c"))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/h2c",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+
+        let cfg = leak_config(ArxConfig {
+            http2_cleartext: true,
+            ..Default::default()
+        });
+        let gateway = test_gateway(
+            cfg,
+            Arc::new(ArcSwap::new(Arc::new(routes))),
+            HashMap::new(),
+        )
+        .await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+        let _drop = cancel.clone().drop_guard();
+        tokio::spawn(serve_gateway(
+            gateway,
+            GatewayListener::Tcp(listener, cancel),
+        ));
+
+        // HTTP/2 from the first byte, no `Upgrade`
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, connection) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+
+        let response = sender
+            .send_request(
+                http::Request::get(format!("http://{addr}/h2c"))
+                    .body(http_body_util::Empty::<Bytes>::new())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(http::Version::HTTP_2, response.version());
+        assert_eq!(200, response.status().as_u16());
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&b"over h2c"[..], &body[..]);
+    }
+
+    #[tokio::test]
+    async fn original_uri_not_a_header_value_refused() {
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/api/{*path}",
+                Proxy::from_backend_uri("http://127.0.0.1:1".parse().unwrap())
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+
+        let cfg = leak_config(ArxConfig {
+            http2_cleartext: true,
+            forward_original_uri: true,
+            ..Default::default()
+        });
+        let gateway = test_gateway(
+            cfg,
+            Arc::new(ArcSwap::new(Arc::new(routes))),
+            HashMap::new(),
+        )
+        .await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+        let _drop = cancel.clone().drop_guard();
+        tokio::spawn(serve_gateway(
+            gateway,
+            GatewayListener::Tcp(listener, cancel),
+        ));
+
+        // HTTP/2 passes on a DEL in the path, which is not allowed in header values
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, connection) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+
+        let response = sender
+            .send_request(
+                http::Request::get(format!("http://{addr}/api/a\x7f"))
+                    .body(http_body_util::Empty::<Bytes>::new())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(400, response.status().as_u16());
+    }
+
+    #[tokio::test]
+    async fn rapid_resets_close_connection() {
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/slow"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(10)))
+            .mount(&mock_server)
+            .await;
+        Mock::given(path("/fast"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        for prefix in ["/slow", "/fast"] {
+            routes
+                .insert(
+                    prefix,
+                    Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                        .unwrap()
+                        .into(),
+                )
+                .unwrap();
+        }
+
+        let cfg = leak_config(ArxConfig {
+            http2_cleartext: true,
+            http2_max_reset_streams: Some(3),
+            ..Default::default()
+        });
+        let gateway = test_gateway(
+            cfg,
+            Arc::new(ArcSwap::new(Arc::new(routes))),
+            HashMap::new(),
+        )
+        .await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+        let _drop = cancel.clone().drop_guard();
+        tokio::spawn(serve_gateway(
+            gateway,
+            GatewayListener::Tcp(listener, cancel),
+        ));
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, connection) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await
+                .unwrap();
+        let connection = tokio::spawn(connection);
+
+        let request = |path: &str| {
+            http::Request::get(format!("http://{addr}{path}"))
+                .body(http_body_util::Empty::<Bytes>::new())
+                .unwrap()
+        };
+        // dropping the response future resets the stream
+        for _ in 0..3 {
+            let response = sender.send_request(request("/slow"));
+            let _ = tokio::time::timeout(Duration::from_millis(100), response).await;
+        }
+        let response = sender.send_request(request("/fast")).await.unwrap();
+        assert_eq!(200, response.status().as_u16());
+
+        let response = sender.send_request(request("/slow"));
+        let _ = tokio::time::timeout(Duration::from_millis(100), response).await;
+        let closed = tokio::time::timeout(Duration::from_secs(5), connection).await;
+        assert!(closed.is_ok(), "connection not closed");
+    }
+
+    #[tokio::test]
+    async fn ambiguous_host_rejected() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/host"))
+            .and(header("x-forwarded-host", "127.0.0.1"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/host",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+
+        let cfg = leak_config(ArxConfig {
+            http2_cleartext: true,
+            ..Default::default()
+        });
+        let gateway = test_gateway(
+            cfg,
+            Arc::new(ArcSwap::new(Arc::new(routes))),
+            HashMap::new(),
+        )
+        .await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+        let _drop = cancel.clone().drop_guard();
+        tokio::spawn(serve_gateway(
+            gateway,
+            GatewayListener::Tcp(listener, cancel),
+        ));
+
+        // HTTP/2, where the `:authority` differs from `Host`
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, connection) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+
+        let request = |host: Option<&str>| {
+            let mut request = http::Request::get(format!("http://{addr}/host"));
+            if let Some(host) = host {
+                request = request.header(http::header::HOST, host);
+            }
+            request.body(http_body_util::Empty::<Bytes>::new()).unwrap()
+        };
+        let response = sender
+            .send_request(request(Some("other.example.com")))
+            .await
+            .unwrap();
+        assert_eq!(400, response.status().as_u16());
+
+        let response = sender.send_request(request(None)).await.unwrap();
+        assert_eq!(200, response.status().as_u16());
+
+        // HTTP/1 with several `Host` headers
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"GET /host HTTP/1.1\r\nhost: 127.0.0.1\r\nhost: other.example.com\r\nconnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn forwarded_for_extended_for_trusted_proxies() {
+        for (trusted_proxies, forwarded_for) in [
+            (vec![], "127.0.0.1"),
+            (vec!["127.0.0.0/8".parse().unwrap()], "10.0.0.1, 127.0.0.1"),
+        ] {
+            let mock_server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .respond_with(ResponseTemplate::new(200))
+                .expect(1)
+                .mount(&mock_server)
+                .await;
+
+            let mut routes = matchit::Router::new();
+            routes
+                .insert(
+                    "/client",
+                    Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                        .unwrap()
+                        .into(),
+                )
+                .unwrap();
+            let cfg = leak_config(ArxConfig {
+                trusted_proxies,
+                ..Default::default()
+            });
+            let addr = spawn_gateway(cfg, routes).await;
+
+            let response = reqwest::Client::new()
+                .get(format!("http://{addr}/client"))
+                .header("x-forwarded-for", "10.0.0.1")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(200, response.status().as_u16());
+
+            let received = mock_server.received_requests().await.unwrap();
+            assert_eq!(
+                received[0].headers.get("x-forwarded-for").unwrap(),
+                forwarded_for
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn proxy_protocol_client_address() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/client"))
+            .and(header("x-forwarded-for", "203.0.113.7"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/client",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+
+        let cfg = leak_config(ArxConfig {
+            proxy_protocol: true,
+            ..Default::default()
+        });
+        let gateway = test_gateway(
+            cfg,
+            Arc::new(ArcSwap::new(Arc::new(routes))),
+            HashMap::new(),
+        )
+        .await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+        let _drop = cancel.clone().drop_guard();
+        tokio::spawn(serve_gateway(
+            gateway,
+            GatewayListener::Tcp(listener, cancel),
+        ));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 80\r\n\
+                GET /client HTTP/1.1\r\nhost: arx\r\nconnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn connection_limit_per_ip() {
+        let cfg = leak_config(ArxConfig {
+            max_connections_per_ip: Some(2),
+            ..Default::default()
+        });
+        let gateway = test_gateway(
+            cfg,
+            Arc::new(ArcSwap::new(Arc::new(matchit::Router::new()))),
+            HashMap::new(),
+        )
+        .await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+        let _drop = cancel.clone().drop_guard();
+        tokio::spawn(serve_gateway(
+            gateway,
+            GatewayListener::Tcp(listener, cancel),
+        ));
+
+        async fn request(stream: &mut TcpStream) -> String {
+            stream
+                .write_all(b"GET /missing HTTP/1.1\r\nhost: arx\r\n\r\n")
+                .await
+                .unwrap();
+            let mut buf = vec![0; 1024];
+            let len = stream.read(&mut buf).await.unwrap();
+            String::from_utf8_lossy(&buf[..len]).into_owned()
+        }
+
+        // kept alive
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let response = request(&mut first).await;
+        assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+        let response = request(&mut second).await;
+        assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+
+        let mut third = TcpStream::connect(addr).await.unwrap();
+        let response = request(&mut third).await;
+        assert!(response.starts_with("HTTP/1.1 503"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn idle_connection_closed() {
+        let cfg = leak_config(ArxConfig {
+            max_connections_per_ip: Some(2),
+            ..Default::default()
+        });
+        let gateway = test_gateway(
+            cfg,
+            Arc::new(ArcSwap::new(Arc::new(matchit::Router::new()))),
+            HashMap::new(),
+        )
+        .await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+        let _drop = cancel.clone().drop_guard();
+        tokio::spawn(serve_gateway(
+            gateway,
+            GatewayListener::Tcp(listener, cancel),
+        ));
+
+        // never sends a request
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut response = vec![];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+            .await
+            .expect("idle connection not closed")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn retry_against_other_backend() {
+        let mock_server = MockServer::start().await;
+        for method_name in ["GET", "POST"] {
+            Mock::given(method(method_name))
+                .and(path("/api"))
+                .respond_with(ResponseTemplate::new(200))
+                .expect(1)
+                .mount(&mock_server)
+                .await;
+        }
+        // a POST the unavailable backend may have acted upon is not retried
+        Mock::given(method("GET"))
+            .and(path("/unavailable"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let unavailable_server = MockServer::start().await;
+        Mock::given(path("/unavailable"))
+            .respond_with(ResponseTemplate::new(503))
+            .expect(2)
+            .mount(&unavailable_server)
+            .await;
+
+        // nothing listens on the failing backend
+        let failing_addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let backend = |uri: String| Backend {
+            uri: uri.parse().unwrap(),
+            class: BackendClass::Plain,
+            connect_host: None,
+            ca_certificates: None,
+            client: None,
+            http10: false,
+        };
+        let mut routes = matchit::Router::new();
+        for (prefix, first_backend) in [
+            ("/api", format!("http://{failing_addr}")),
+            ("/unavailable", unavailable_server.uri()),
+        ] {
+            let split = TrafficSplit::new(
+                http::HeaderName::from_static("x-user-id"),
+                vec![
+                    SplitBackend {
+                        weight: 1,
+                        backend: backend(first_backend.clone()),
+                    },
+                    SplitBackend {
+                        weight: 1,
+                        backend: backend(mock_server.uri()),
+                    },
+                ],
+            );
+            routes
+                .insert(
+                    prefix,
+                    Proxy::from_backend(backend(first_backend))
+                        .with_traffic_split(split)
+                        .with_retries(1)
+                        .into(),
+                )
+                .unwrap();
+        }
+        let addr = spawn_gateway(leak_config(ArxConfig::default()), routes).await;
+        let client = reqwest::Client::new();
+
+        // requests without the header go to the first, failing, backend
+        for request_method in [http::Method::GET, http::Method::POST] {
+            let response = client
+                .request(request_method.clone(), format!("http://{addr}/api"))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(200, response.status().as_u16(), "{request_method}");
+        }
+
+        let response = client
+            .get(format!("http://{addr}/unavailable"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(200, response.status().as_u16());
+        let response = client
+            .post(format!("http://{addr}/unavailable"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(503, response.status().as_u16());
+    }
+
+    #[tokio::test]
+    async fn error_page_on_connection_failure() {
+        // nothing listens on the backend
+        let backend = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/api",
+                Proxy::from_backend_uri(format!("http://{backend}").parse().unwrap())
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+        let cfg = leak_config(ArxConfig {
+            error_pages: [(
+                502,
+                ErrorPage {
+                    content_type: "text/html; charset=utf-8".to_string(),
+                    template: "<h1>{status}</h1><p>Request ID: {request_id}</p>".to_string(),
+                },
+            )]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        });
+        let addr = spawn_gateway(cfg, routes).await;
+        let client = reqwest::Client::new();
+
+        let response = client
+            .get(format!("http://{addr}/api"))
+            .header("x-request-id", "abc<1>")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(502, response.status().as_u16());
+        assert_eq!(
+            "text/html; charset=utf-8",
+            response.headers()["content-type"]
+        );
+        assert_eq!(
+            "<h1>502 Bad Gateway</h1><p>Request ID: abc&lt;1&gt;</p>",
+            response.text().await.unwrap()
+        );
+
+        // other errors keep their plain bodies
+        let response = client
+            .get(format!("http://{addr}/other"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(404, response.status().as_u16());
+        assert_eq!("Not found", response.text().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn retries_suppressed_beyond_budget() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/api",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .with_retries(1)
+                    .into(),
+            )
+            .unwrap();
+        let cfg = leak_config(ArxConfig {
+            retry_budget_percent: Some(50),
+            retry_budget_min_retries: 0,
+            retry_budget_window: Duration::from_secs(60),
+            ..Default::default()
+        });
+        let addr = spawn_gateway(cfg, routes).await;
+        let backend_requests = || async { mock_server.received_requests().await.unwrap().len() };
+
+        // one request, one retry
+        let response = reqwest::get(format!("http://{addr}/api")).await.unwrap();
+        assert_eq!(503, response.status().as_u16());
+        assert_eq!(2, backend_requests().await);
+
+        // a second retry would be more than half the requests
+        let response = reqwest::get(format!("http://{addr}/api")).await.unwrap();
+        assert_eq!(503, response.status().as_u16());
+        assert_eq!(3, backend_requests().await);
+
+        let response = reqwest::get(format!("http://{addr}/api")).await.unwrap();
+        assert_eq!(503, response.status().as_u16());
+        assert_eq!(5, backend_requests().await);
+    }
+
+    #[tokio::test]
+    async fn in_flight_limit_sheds_excess() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(500)))
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/api",
+                Proxy::from_backend(Backend {
+                    uri: mock_server.uri().parse().unwrap(),
+                    class: BackendClass::Plain,
+                    connect_host: None,
+                    ca_certificates: None,
+                    client: None,
+                    http10: false,
+                })
+                .with_in_flight_limit(InFlightLimit {
+                    key: "default/api/0".to_string(),
+                    max: 2,
+                    wait: Duration::ZERO,
+                    queue: None,
+                })
+                .into(),
+            )
+            .unwrap();
+        let addr = spawn_gateway(leak_config(ArxConfig::default()), routes).await;
+
+        let get = || async {
+            reqwest::get(format!("http://{addr}/api"))
+                .await
+                .unwrap()
+                .status()
+                .as_u16()
+        };
+        // the third request arrives while the first two are in flight
+        let (first, second, third) = tokio::join!(get(), get(), async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            get().await
+        });
+        assert_eq!(200, first);
+        assert_eq!(200, second);
+        assert_eq!(503, third);
+
+        // the permits are released when the responses are complete
+        assert_eq!(200, get().await);
+    }
+
+    #[tokio::test]
+    async fn in_flight_limit_queues_excess() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(300)))
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        for (path, wait) in [("/patient", 1000), ("/impatient", 100)] {
+            routes
+                .insert(
+                    path,
+                    Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                        .unwrap()
+                        .with_in_flight_limit(InFlightLimit {
+                            key: format!("default{path}/0"),
+                            max: 1,
+                            wait: Duration::from_millis(wait),
+                            queue: Some(1),
+                        })
+                        .into(),
+                )
+                .unwrap();
+        }
+        let addr = spawn_gateway(leak_config(ArxConfig::default()), routes).await;
+
+        let get = |path: &'static str, delay: u64| async move {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            reqwest::get(format!("http://{addr}{path}"))
+                .await
+                .unwrap()
+                .status()
+                .as_u16()
+        };
+
+        // the second request waits for the first, the third finds the queue full
+        let (first, second, third) = tokio::join!(
+            get("/patient", 0),
+            get("/patient", 50),
+            get("/patient", 100)
+        );
+        assert_eq!(200, first);
+        assert_eq!(200, second);
+        assert_eq!(503, third);
+
+        // the second request gives up waiting before the first is complete
+        let (first, second) = tokio::join!(get("/impatient", 0), get("/impatient", 50));
+        assert_eq!(200, first);
+        assert_eq!(503, second);
+    }
+
+    #[tokio::test]
+    async fn json_only_route_rejects_form() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api"))
+            .and(header("content-type", "application/json"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/api",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .with_media_types(MediaTypes {
+                        request: vec!["application/json".to_string()],
+                        response: vec!["application/json".to_string()],
+                    })
+                    .into(),
+            )
+            .unwrap();
+        let addr = spawn_gateway(leak_config(ArxConfig::default()), routes).await;
+        let client = reqwest::Client::new();
+
+        let response = client
+            .post(format!("http://{addr}/api"))
+            .form(&[("name", "value")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(415, response.status().as_u16());
+
+        let response = client
+            .post(format!("http://{addr}/api"))
+            .header("accept", "text/html")
+            .json(&serde_json::json!({ "name": "value" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(406, response.status().as_u16());
+
+        let response = client
+            .post(format!("http://{addr}/api"))
+            .json(&serde_json::json!({ "name": "value" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(200, response.status().as_u16());
+    }
+
+    #[tokio::test]
+    async fn body_digest_validated() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/upload",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .with_body_digest(true)
+                    .into(),
+            )
+            .unwrap();
+        let addr = spawn_gateway(
+            leak_config(ArxConfig {
+                request_max_size: bytesize::ByteSize::b(16),
+                ..Default::default()
+            }),
+            routes,
+        )
+        .await;
+
+        // the SHA-256 digest of `hello`
+        let digest = "sha-256=LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=";
+        let upload = |body: &'static str, digest: Option<&str>| {
+            let request = reqwest::Client::new()
+                .post(format!("http://{addr}/upload"))
+                .body(body);
+            match digest {
+                Some(digest) => request.header("digest", digest),
+                None => request,
+            }
+        };
+
+        let response = upload("hello", Some(digest)).send().await.unwrap();
+        assert_eq!(200, response.status().as_u16());
+        let received = mock_server.received_requests().await.unwrap();
+        assert_eq!(&b"hello"[..], &received[0].body[..]);
+
+        let response = upload("hellO", Some(digest)).send().await.unwrap();
+        assert_eq!(400, response.status().as_u16());
+        let response = upload("hello", None).send().await.unwrap();
+        assert_eq!(400, response.status().as_u16());
+        let response = upload("hello, world of uploads", Some(digest))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(413, response.status().as_u16());
+    }
+
+    #[tokio::test]
+    async fn route_request_max_size() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/api",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+        routes
+            .insert(
+                "/upload",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .with_request_max_size(bytesize::ByteSize::kib(1))
+                    .with_buffered(true)
+                    .into(),
+            )
+            .unwrap();
+        let addr = spawn_gateway(
+            leak_config(ArxConfig {
+                request_max_size: bytesize::ByteSize::b(16),
+                ..Default::default()
+            }),
+            routes,
+        )
+        .await;
+        let client = reqwest::Client::new();
+        let upload = |path: &str, body: reqwest::Body| {
+            client
+                .post(format!("http://{addr}{path}"))
+                .body(body)
+                .send()
+        };
+        // without a `Content-Length`, the body is limited as it's read
+        let chunked = |size: usize| {
+            reqwest::Body::wrap_stream(futures_util::stream::iter([Ok::<_, std::io::Error>(
+                vec![b'x'; size],
+            )]))
+        };
+
+        let response = upload("/api", vec![b'x'; 100].into()).await.unwrap();
+        assert_eq!(413, response.status().as_u16());
+        // the streamed body is limited as it's forwarded
+        let response = upload("/api", chunked(10)).await.unwrap();
+        assert_eq!(200, response.status().as_u16());
+        let response = upload("/api", chunked(100)).await.unwrap();
+        assert_eq!(413, response.status().as_u16());
+
+        let response = upload("/upload", vec![b'x'; 100].into()).await.unwrap();
+        assert_eq!(200, response.status().as_u16());
+        let response = upload("/upload", chunked(100)).await.unwrap();
+        assert_eq!(200, response.status().as_u16());
+        let response = upload("/upload", vec![b'x'; 2000].into()).await.unwrap();
+        assert_eq!(413, response.status().as_u16());
+    }
+
+    #[tokio::test]
+    async fn route_request_timeout() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(500)))
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/report",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .with_request_timeout(Duration::from_secs(5))
+                    .into(),
+            )
+            .unwrap();
+        routes
+            .insert(
+                "/api",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+        let cfg = leak_config(ArxConfig {
+            request_timeout: Duration::from_millis(200),
+            backoff_max_num_retries: 0,
+            ..Default::default()
+        });
+        let addr = spawn_gateway(cfg, routes).await;
+
+        let response = reqwest::get(format!("http://{addr}/report")).await.unwrap();
+        assert_eq!(200, response.status().as_u16());
+
+        let response = reqwest::get(format!("http://{addr}/api")).await.unwrap();
+        assert_eq!(504, response.status().as_u16());
+    }
+
+    #[tokio::test]
+    async fn deep_path_rejected() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/{*path}",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+        let addr = spawn_gateway(
+            leak_config(ArxConfig {
+                max_path_segments: 100,
+                ..Default::default()
+            }),
+            routes,
+        )
+        .await;
+        let client = reqwest::Client::new();
+
+        let response = client
+            .get(format!("http://{addr}{}", "/a".repeat(100)))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(200, response.status().as_u16());
+
+        let response = client
+            .get(format!("http://{addr}{}", "/a".repeat(5000)))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(400, response.status().as_u16());
+    }
+
+    #[tokio::test]
+    async fn asterisk_form_and_empty_path() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+
+        let routes = || {
+            let mut routes = matchit::Router::new();
+            routes
+                .insert(
+                    "/",
+                    Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                        .unwrap()
+                        .into(),
+                )
+                .unwrap();
+            routes
+        };
+        let rejecting = spawn_gateway(leak_config(ArxConfig::default()), routes()).await;
+        let normalizing = spawn_gateway(
+            leak_config(ArxConfig {
+                malformed_paths: MalformedPathHandling::Normalize,
+                ..Default::default()
+            }),
+            routes(),
+        )
+        .await;
+
+        let status_line = |addr: std::net::SocketAddr, request_line: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(
+                    format!("{request_line}\r\nhost: example.com\r\nconnection: close\r\n\r\n")
+                        .as_bytes(),
+                )
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response.lines().next().unwrap_or_default().to_string()
+        };
+
+        assert_eq!(
+            "HTTP/1.1 204 No Content",
+            status_line(rejecting, "OPTIONS * HTTP/1.1").await
+        );
+        assert_eq!(
+            "HTTP/1.1 400 Bad Request",
+            status_line(rejecting, "GET * HTTP/1.1").await
+        );
+
+        // authority-form, with an empty path
+        assert_eq!(
+            "HTTP/1.1 400 Bad Request",
+            status_line(rejecting, "GET example.com:80 HTTP/1.1").await
+        );
+        assert_eq!(
+            "HTTP/1.1 200 OK",
+            status_line(normalizing, "GET example.com:80 HTTP/1.1").await
+        );
+    }
+
+    #[tokio::test]
+    async fn https_redirect() {
+        let cfg = leak_config(ArxConfig {
+            https_redirect: true,
+            ..Default::default()
+        });
+        let addr = spawn_gateway(cfg, matchit::Router::new()).await;
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+
+        let response = client
+            .get(format!("http://{addr}/some/path?q=1"))
+            .header("host", "arx.example.com:8080")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(308, response.status().as_u16());
+        assert_eq!(
+            "https://arx.example.com/some/path?q=1",
+            response.headers()["location"]
+        );
+
+        // already HTTPS at the load balancer, or exempt: no route
+        let response = client
+            .get(format!("http://{addr}/some/path"))
+            .header("x-forwarded-proto", "https")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(404, response.status().as_u16());
+
+        let response = client
+            .get(format!("http://{addr}/.well-known/acme-challenge/token"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(404, response.status().as_u16());
+    }
+
+    #[tokio::test]
+    async fn acme_challenge_served() {
+        let dir = std::env::temp_dir().join(format!("arx-acme-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("t0k3n"), "t0k3n.thumbprint").unwrap();
+
+        let cfg = leak_config(ArxConfig {
+            https_redirect: true,
+            https_redirect_exempt_paths: vec![],
+            acme_challenge_dir: Some(dir.clone()),
+            ..Default::default()
+        });
+        let routes = static_routes(reqwest::Client::new(), Some(&dir)).unwrap();
+        let addr = spawn_gateway(cfg, routes).await;
+
+        let response = reqwest::get(format!("http://{addr}/.well-known/acme-challenge/t0k3n"))
+            .await
+            .unwrap();
+        assert_eq!(200, response.status().as_u16());
+        assert_eq!("text/plain", response.headers()["content-type"]);
+        assert_eq!("t0k3n.thumbprint", response.text().await.unwrap());
+
+        let response = reqwest::get(format!("http://{addr}/.well-known/acme-challenge/other"))
+            .await
+            .unwrap();
+        assert_eq!(404, response.status().as_u16());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn ambiguous_framing_rejected() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/upload",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+
+        let cfg = leak_config(ArxConfig::default());
+        let addr = spawn_gateway(cfg, routes).await;
+
+        for framing in [
+            "content-length: 5\r\ntransfer-encoding: chunked",
+            "content-length: 5\r\ncontent-length: 6",
+            "transfer-encoding: chunked, gzip",
+        ] {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(
+                    format!(
+                        "POST /upload HTTP/1.1\r\nhost: arx\r\nconnection: close\r\n{framing}\r\n\r\n0\r\n\r\n"
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+
+            let mut response = vec![];
+            tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+                .await
+                .unwrap()
+                .unwrap();
+            let response = String::from_utf8_lossy(&response);
+            assert!(
+                response.starts_with("HTTP/1.1 400"),
+                "{framing}: {response}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn unsupported_transfer_coding() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/upload",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+        let addr = spawn_gateway(leak_config(ArxConfig::default()), routes).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"POST /upload HTTP/1.1\r\nhost: arx\r\nconnection: close\r\ntransfer-encoding: gzip, chunked\r\n\r\n0\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let mut response = vec![];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 501"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn auto_options() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("OPTIONS"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/api",
+                Route::ProxyAlternatives(
+                    [Method::GET, Method::PUT]
+                        .into_iter()
+                        .map(|method| {
+                            Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                                .unwrap()
+                                .with_method(method)
+                        })
+                        .collect(),
+                ),
+            )
+            .unwrap();
+
+        let cfg = leak_config(ArxConfig {
+            auto_options: true,
+            ..Default::default()
+        });
+        let addr = spawn_gateway(cfg, routes).await;
+
+        let response = reqwest::Client::new()
+            .request(Method::OPTIONS, format!("http://{addr}/api"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(204, response.status().as_u16());
+        assert_eq!("GET, HEAD, PUT, OPTIONS", response.headers()["allow"]);
+    }
+
+    #[tokio::test]
+    async fn head_matches_get_route() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("HEAD"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let routes = || {
+            let mut routes = matchit::Router::new();
+            routes
+                .insert(
+                    "/api",
+                    Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                        .unwrap()
+                        .with_method(Method::GET)
+                        .into(),
+                )
+                .unwrap();
+            routes
+        };
+
+        let addr = spawn_gateway(leak_config(ArxConfig::default()), routes()).await;
+        let response = reqwest::Client::new()
+            .head(format!("http://{addr}/api"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(200, response.status().as_u16());
+
+        let cfg = leak_config(ArxConfig {
+            head_matches_get: false,
+            ..Default::default()
+        });
+        let addr = spawn_gateway(cfg, routes()).await;
+        let response = reqwest::Client::new()
+            .head(format!("http://{addr}/api"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(405, response.status().as_u16());
+        assert_eq!("GET", response.headers()["allow"]);
+    }
+
+    #[tokio::test]
+    async fn security_response_headers() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/page"))
+            .respond_with(ResponseTemplate::new(200).insert_header("x-frame-options", "SAMEORIGIN"))
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/page",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+
+        let cfg = leak_config(ArxConfig {
+            response_headers: [
+                ("x-content-type-options", "nosniff"),
+                ("x-frame-options", "DENY"),
+            ]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+            ..Default::default()
+        });
+        let addr = spawn_gateway(cfg, routes).await;
+
+        let response = reqwest::get(format!("http://{addr}/page")).await.unwrap();
+        assert_eq!(200, response.status().as_u16());
+        assert_eq!("nosniff", response.headers()["x-content-type-options"]);
+        // set by the backend
+        assert_eq!("SAMEORIGIN", response.headers()["x-frame-options"]);
+
+        // gateway responses too
+        let response = reqwest::get(format!("http://{addr}/missing"))
+            .await
+            .unwrap();
+        assert_eq!(404, response.status().as_u16());
+        assert_eq!("DENY", response.headers()["x-frame-options"]);
+    }
+}
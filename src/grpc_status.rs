@@ -0,0 +1,174 @@
+//! Translation of gRPC status to HTTP, for clients that don't speak gRPC
+
+use bytes::Bytes;
+use http::{header, HeaderMap, HeaderValue, StatusCode};
+use http_body_util::{BodyExt, Full};
+use serde::Serialize;
+
+use crate::hyper::HyperResponse;
+
+/// The status of a gRPC call, from the `grpc-status` and `grpc-message` trailers
+/// (or headers, for a trailers-only response).
+#[derive(Debug, PartialEq)]
+pub struct GrpcStatus {
+    pub code: u32,
+    pub message: String,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: u32,
+    status: &'a str,
+    message: &'a str,
+}
+
+impl GrpcStatus {
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let code = headers
+            .get("grpc-status")?
+            .to_str()
+            .ok()?
+            .trim()
+            .parse()
+            .ok()?;
+        let message = headers
+            .get("grpc-message")
+            .map(|message| percent_decode(message.as_bytes()))
+            .unwrap_or_default();
+
+        Some(Self { code, message })
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.code == 0
+    }
+
+    /// The name of the status code, e.g. `NOT_FOUND`
+    pub fn name(&self) -> &'static str {
+        match self.code {
+            0 => "OK",
+            1 => "CANCELLED",
+            2 => "UNKNOWN",
+            3 => "INVALID_ARGUMENT",
+            4 => "DEADLINE_EXCEEDED",
+            5 => "NOT_FOUND",
+            6 => "ALREADY_EXISTS",
+            7 => "PERMISSION_DENIED",
+            8 => "RESOURCE_EXHAUSTED",
+            9 => "FAILED_PRECONDITION",
+            10 => "ABORTED",
+            11 => "OUT_OF_RANGE",
+            12 => "UNIMPLEMENTED",
+            13 => "INTERNAL",
+            14 => "UNAVAILABLE",
+            15 => "DATA_LOSS",
+            16 => "UNAUTHENTICATED",
+            _ => "UNKNOWN",
+        }
+    }
+
+    /// The corresponding HTTP status, as mapped by common gRPC-to-HTTP gateways
+    pub fn http_status(&self) -> StatusCode {
+        match self.code {
+            0 => StatusCode::OK,
+            // nginx' "client closed request"
+            1 => StatusCode::from_u16(499).unwrap(),
+            3 | 9 | 11 => StatusCode::BAD_REQUEST,
+            4 => StatusCode::GATEWAY_TIMEOUT,
+            5 => StatusCode::NOT_FOUND,
+            6 | 10 => StatusCode::CONFLICT,
+            7 => StatusCode::FORBIDDEN,
+            8 => StatusCode::TOO_MANY_REQUESTS,
+            12 => StatusCode::NOT_IMPLEMENTED,
+            14 => StatusCode::SERVICE_UNAVAILABLE,
+            16 => StatusCode::UNAUTHORIZED,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// An HTTP response with the corresponding status and a JSON error body
+    pub fn into_http_response(self) -> HyperResponse {
+        let body = serde_json::to_vec(&ErrorBody {
+            code: self.code,
+            status: self.name(),
+            message: &self.message,
+        })
+        .unwrap();
+
+        http::Response::builder()
+            .status(self.http_status())
+            .header(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            )
+            .header(header::CONTENT_LENGTH, HeaderValue::from(body.len()))
+            .body(
+                Full::new(Bytes::from(body))
+                    .map_err(|never| match never {})
+                    .boxed_unsync(),
+            )
+            .unwrap()
+    }
+}
+
+/// Whether the `Content-Type` is gRPC, e.g. `application/grpc+proto`
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .is_some_and(|media_type| {
+            let media_type = media_type.trim();
+            media_type.eq_ignore_ascii_case("application/grpc")
+                || media_type
+                    .get(..17)
+                    .is_some_and(|prefix| prefix.eq_ignore_ascii_case("application/grpc+"))
+        })
+}
+
+/// `grpc-message` is percent-encoded
+fn percent_decode(encoded: &[u8]) -> String {
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut rest = encoded;
+
+    while let Some((&byte, after)) = rest.split_first() {
+        let hex = after
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (byte, hex) {
+            (b'%', Some(hex)) => {
+                decoded.push(hex);
+                rest = &after[2..];
+            }
+            _ => {
+                decoded.push(byte);
+                rest = after;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_from_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("grpc-status", HeaderValue::from_static("5"));
+        headers.insert(
+            "grpc-message",
+            HeaderValue::from_static("no%20such%20item: 50%25"),
+        );
+
+        let status = GrpcStatus::from_headers(&headers).unwrap();
+        assert_eq!("no such item: 50%", status.message);
+        assert_eq!("NOT_FOUND", status.name());
+        assert_eq!(StatusCode::NOT_FOUND, status.http_status());
+
+        assert_eq!(None, GrpcStatus::from_headers(&HeaderMap::new()));
+    }
+}
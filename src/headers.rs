@@ -1,28 +1,109 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, str::FromStr};
 
-use http::{header::HOST, HeaderName, HeaderValue, StatusCode, Uri};
+use anyhow::anyhow;
+use http::{
+    header::{CONTENT_LENGTH, FORWARDED, HOST, TE, TRANSFER_ENCODING},
+    uri::Authority,
+    HeaderMap, HeaderName, HeaderValue, StatusCode, Uri,
+};
 use hyper::body::Incoming;
-use tracing::error;
+use tracing::{debug, error};
 
-use crate::hyper::HttpError;
+use crate::{
+    client_ip::client_ip,
+    config::{ArxConfig, HostMismatchHandling},
+    hyper::HttpError,
+};
 
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
 const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
 const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
 const X_FORWARDED_PORT: HeaderName = HeaderName::from_static("x-forwarded-port");
 const X_FORWARDED_PREFIX: HeaderName = HeaderName::from_static("x-forwarded-prefix");
+const X_ORIGINAL_URI: HeaderName = HeaderName::from_static("x-original-uri");
+const X_ORIGINAL_METHOD: HeaderName = HeaderName::from_static("x-original-method");
+const X_CLIENT_CERT_SUBJECT: HeaderName = HeaderName::from_static("x-client-cert-subject");
+const X_CLIENT_CERT_FINGERPRINT: HeaderName = HeaderName::from_static("x-client-cert-fingerprint");
 
+/// Set the `X-Forwarded-*` headers of a proxied request.
+///
+/// `original_uri` is the URI from before rewriting, and `prefix` the part of its path that was replaced, if any.
+/// With `forward_original_uri`, the original URI is forwarded in `X-Original-URI`,
+/// along with the method in `X-Original-Method`, replacing any values sent by the client.
+///
+/// Client certificate headers sent by the client are removed,
+/// since the gateway doesn't terminate TLS and never has a client certificate to vouch for.
+///
+/// Without `forwarded_headers`, the request is forwarded as if it came directly from the client:
+/// no `X-Forwarded-*` headers are set, and those sent by the client are removed along with `Forwarded`.
+///
+/// Requests with an ambiguous host are rejected, see [effective_authority].
 pub fn set_proxy_headers(
     req: &mut http::Request<Incoming>,
+    prefix: Option<&str>,
     original_uri: &Uri,
+    forwarded_headers: bool,
+    cfg: &ArxConfig,
 ) -> Result<(), HttpError> {
-    let prefix = original_uri.path().strip_suffix(req.uri().path());
+    let client_ip = client_ip(req);
+    let method = req.method().clone();
     let headers = req.headers_mut();
 
-    let host_header = headers.remove(HOST);
-    let host_port = host_header
-        .as_ref()
-        .and_then(|host| host.to_str().ok())
-        .and_then(|host| host.split_once(':'));
+    let authority = effective_authority(original_uri, headers, cfg.host_mismatch)?;
+
+    if cfg.forward_original_uri {
+        let path_and_query = original_uri
+            .path_and_query()
+            .map(|path_and_query| path_and_query.as_str())
+            .unwrap_or("/");
+        // a parsed URI may still contain bytes not allowed in header values, e.g. DEL in an HTTP/2 `:path`
+        headers.insert(
+            X_ORIGINAL_URI,
+            HeaderValue::from_str(path_and_query)
+                .map_err(|_| HttpError::bad_request("invalid request target"))?,
+        );
+        headers.insert(
+            X_ORIGINAL_METHOD,
+            HeaderValue::from_str(method.as_str()).unwrap(),
+        );
+    }
+
+    headers.remove(HOST);
+
+    // backends must not take these for client certificates verified by the gateway
+    headers.remove(X_CLIENT_CERT_SUBJECT);
+    headers.remove(X_CLIENT_CERT_FINGERPRINT);
+
+    if !forwarded_headers {
+        for name in [
+            X_FORWARDED_FOR,
+            X_FORWARDED_PROTO,
+            X_FORWARDED_HOST,
+            X_FORWARDED_PORT,
+            X_FORWARDED_PREFIX,
+            FORWARDED,
+        ] {
+            headers.remove(name);
+        }
+        return Ok(());
+    }
+
+    // append the client to the chain of previous proxies, if it is one we trust
+    if let Some(client_ip) = client_ip {
+        let trusted = cfg
+            .trusted_proxies
+            .iter()
+            .any(|range| range.contains(&client_ip));
+        let forwarded_for = match headers.get(X_FORWARDED_FOR).map(|prev| prev.to_str()) {
+            Some(Ok(prev)) if trusted => format!("{prev}, {client_ip}"),
+            _ => client_ip.to_string(),
+        };
+        // an IP address is always a valid header value
+        headers.insert(
+            X_FORWARDED_FOR,
+            HeaderValue::from_str(&forwarded_for).unwrap(),
+        );
+    }
 
     if !headers.contains_key(X_FORWARDED_PROTO) {
         // for now, Arx always runs plain HTTP.
@@ -32,26 +113,18 @@ pub fn set_proxy_headers(
 
     // if headers already contain x-forwarded-host from another proxy, don't touch it
     if !headers.contains_key(X_FORWARDED_HOST) {
-        if let Some((host, _port)) = host_port.as_ref() {
+        if let Some(authority) = &authority {
+            // a parsed host is always a valid header value
             headers.insert(
                 X_FORWARDED_HOST,
-                HeaderValue::from_str(host).map_err(|_| {
-                    error!("invalid host: {}", host);
-                    HttpError::Static(StatusCode::BAD_REQUEST, "")
-                })?,
+                HeaderValue::from_str(&authority.host().to_ascii_lowercase()).unwrap(),
             );
         }
     }
 
     if !headers.contains_key(X_FORWARDED_PORT) {
-        if let Some((_host, port)) = host_port.as_ref() {
-            headers.insert(
-                X_FORWARDED_PORT,
-                HeaderValue::from_str(port).map_err(|_| {
-                    error!("invalid port: {}", port);
-                    HttpError::Static(StatusCode::BAD_REQUEST, "")
-                })?,
-            );
+        if let Some(port) = authority.as_ref().and_then(Authority::port_u16) {
+            headers.insert(X_FORWARDED_PORT, HeaderValue::from(port));
         }
     }
 
@@ -75,3 +148,322 @@ pub fn set_proxy_headers(
 
     Ok(())
 }
+
+/// The authority a request is for, from its URI (`:authority` in HTTP/2) or else its `Host` header.
+///
+/// Several `Host` headers, or an invalid one, are rejected with `400 Bad Request`.
+/// So is a `Host` header differing from the URI authority, unless `host_mismatch` says to use the authority.
+fn effective_authority(
+    uri: &Uri,
+    headers: &HeaderMap,
+    host_mismatch: HostMismatchHandling,
+) -> Result<Option<Authority>, HttpError> {
+    let mut host_headers = headers.get_all(HOST).iter();
+    let host_header = host_headers.next();
+    if host_headers.next().is_some() {
+        debug!("multiple Host headers");
+        return Err(HttpError::bad_request("multiple Host headers"));
+    }
+
+    let host = host_header
+        .map(|host| {
+            host.to_str()
+                .ok()
+                .and_then(|host| Authority::from_str(host).ok())
+                .filter(|host| !host.as_str().contains('@'))
+                .ok_or_else(|| {
+                    debug!(?host, "invalid Host header");
+                    HttpError::bad_request("invalid Host header")
+                })
+        })
+        .transpose()?;
+
+    // an explicit default port is the same authority (RFC 9112 §3.2.2, RFC 9110 §4.2.3)
+    let default_port = match uri.scheme_str() {
+        Some("http") => Some(80),
+        Some("https") => Some(443),
+        _ => None,
+    };
+
+    match (uri.authority(), host) {
+        (Some(authority), Some(host))
+            if !authority.host().eq_ignore_ascii_case(host.host())
+                || authority.port_u16().or(default_port) != host.port_u16().or(default_port) =>
+        {
+            match host_mismatch {
+                HostMismatchHandling::Reject => {
+                    debug!(%authority, %host, "Host header differs from the request authority");
+                    Err(HttpError::bad_request(
+                        "Host header differs from the request authority",
+                    ))
+                }
+                HostMismatchHandling::Authority => Ok(Some(authority.clone())),
+            }
+        }
+        (Some(authority), _) => Ok(Some(authority.clone())),
+        (None, host) => Ok(host),
+    }
+}
+
+/// Parse the configured `response_headers` once, at startup.
+///
+/// Invalid entries fail the gateway instead of being skipped on every response, `arx check` reports them too.
+pub fn parse_response_headers(cfg: &ArxConfig) -> anyhow::Result<Vec<(HeaderName, HeaderValue)>> {
+    cfg.response_headers
+        .iter()
+        .map(
+            |(name, value)| match (HeaderName::from_str(name), HeaderValue::from_str(value)) {
+                (Ok(name), Ok(value)) => Ok((name, value)),
+                _ => Err(anyhow!("invalid response header `{name}: {value}`")),
+            },
+        )
+        .collect()
+}
+
+/// Add the parsed `response_headers` to a response.
+pub fn add_response_headers(
+    headers: &mut HeaderMap,
+    response_headers: &[(HeaderName, HeaderValue)],
+    cfg: &ArxConfig,
+) {
+    for (name, value) in response_headers {
+        if cfg.response_headers_override || !headers.contains_key(name) {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+}
+
+/// Whether the framing of a request body is ambiguous, which could be used for request smuggling.
+///
+/// That's when both `Content-Length` and `Transfer-Encoding` are present,
+/// `Content-Length` has conflicting values, or `Transfer-Encoding` does not end with `chunked`.
+pub fn has_ambiguous_framing(headers: &HeaderMap) -> bool {
+    let mut content_lengths = headers.get_all(CONTENT_LENGTH).iter();
+    let content_length = content_lengths.next();
+    if content_length.is_some_and(|first| content_lengths.any(|other| other != first)) {
+        return true;
+    }
+
+    let Some(last_encoding) = headers.get_all(TRANSFER_ENCODING).iter().next_back() else {
+        return false;
+    };
+
+    content_length.is_some()
+        || !last_encoding.to_str().is_ok_and(|encodings| {
+            encodings
+                .rsplit(',')
+                .next()
+                .is_some_and(|last| last.trim().eq_ignore_ascii_case("chunked"))
+        })
+}
+
+/// Whether a request body has transfer codings other than `chunked`, e.g. `Transfer-Encoding: gzip, chunked`.
+pub fn has_unsupported_transfer_coding(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(TRANSFER_ENCODING)
+        .iter()
+        .any(|value| match value.to_str() {
+            Ok(codings) => codings
+                .split(',')
+                .map(str::trim)
+                .any(|coding| !coding.is_empty() && !coding.eq_ignore_ascii_case("chunked")),
+            Err(_) => true,
+        })
+}
+
+/// The total size of the headers, counting names and values.
+pub fn header_size(headers: &HeaderMap) -> usize {
+    headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len())
+        .sum()
+}
+
+/// Whether the client accepts trailers in the response, by sending `TE: trailers`.
+pub fn accepts_trailers(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(TE)
+        .iter()
+        .filter_map(|te| te.to_str().ok())
+        .flat_map(|te| te.split(','))
+        .any(|coding| {
+            coding
+                .split(';')
+                .next()
+                .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("trailers"))
+        })
+}
+
+/// Backend response headers that hyper refuses to serve, failing the whole response.
+///
+/// That's a `Content-Length` that isn't a single number on every line, e.g. `5, 5`,
+/// which the HTTP client accepts but the server can't encode.
+pub fn invalid_response_headers(headers: &HeaderMap) -> Vec<HeaderName> {
+    let mut content_lengths = headers
+        .get_all(CONTENT_LENGTH)
+        .iter()
+        .map(|value| value.to_str().ok()?.parse::<u64>().ok());
+    let valid_content_length = match content_lengths.next() {
+        None => true,
+        Some(first) => first.is_some() && content_lengths.all(|other| other == first),
+    };
+
+    if valid_content_length {
+        vec![]
+    } else {
+        vec![CONTENT_LENGTH]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{header::CONTENT_LENGTH, HeaderMap, HeaderValue, Uri};
+
+    use crate::config::{ArxConfig, HostMismatchHandling};
+
+    use super::{
+        effective_authority, has_ambiguous_framing, invalid_response_headers,
+        parse_response_headers,
+    };
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn ambiguous_framing() {
+        for ambiguous in [
+            headers(&[("content-length", "10"), ("transfer-encoding", "chunked")]),
+            headers(&[("transfer-encoding", "chunked"), ("content-length", "0")]),
+            headers(&[("content-length", "10"), ("content-length", "20")]),
+            headers(&[("transfer-encoding", "chunked, gzip")]),
+            headers(&[
+                ("transfer-encoding", "chunked"),
+                ("transfer-encoding", "gzip"),
+            ]),
+        ] {
+            assert!(has_ambiguous_framing(&ambiguous), "{ambiguous:?}");
+        }
+
+        for unambiguous in [
+            headers(&[]),
+            headers(&[("content-length", "10")]),
+            headers(&[("content-length", "10"), ("content-length", "10")]),
+            headers(&[("transfer-encoding", "chunked")]),
+            headers(&[("transfer-encoding", "gzip, Chunked")]),
+        ] {
+            assert!(!has_ambiguous_framing(&unambiguous), "{unambiguous:?}");
+        }
+    }
+
+    #[test]
+    fn invalid_content_length() {
+        for invalid in [
+            headers(&[("content-length", "5, 5")]),
+            headers(&[("content-length", "five")]),
+            headers(&[("content-length", "5"), ("content-length", "6")]),
+        ] {
+            assert_eq!(
+                vec![CONTENT_LENGTH],
+                invalid_response_headers(&invalid),
+                "{invalid:?}"
+            );
+        }
+
+        for valid in [
+            headers(&[]),
+            headers(&[("content-length", "5")]),
+            headers(&[("content-length", "5"), ("content-length", "5")]),
+        ] {
+            assert!(invalid_response_headers(&valid).is_empty(), "{valid:?}");
+        }
+    }
+
+    #[test]
+    fn ambiguous_host() {
+        let origin_form = Uri::from_static("/path");
+        let absolute_form = Uri::from_static("http://example.com:8080/path");
+        let authority = |uri, headers, host_mismatch| {
+            effective_authority(uri, headers, host_mismatch)
+                .ok()
+                .map(|authority| authority.map(|authority| authority.to_string()))
+        };
+
+        for (uri, headers, expected) in [
+            (&origin_form, headers(&[]), Some(None)),
+            (
+                &origin_form,
+                headers(&[("host", "example.com")]),
+                Some(Some("example.com")),
+            ),
+            (&absolute_form, headers(&[]), Some(Some("example.com:8080"))),
+            (
+                &absolute_form,
+                headers(&[("host", "EXAMPLE.com:8080")]),
+                Some(Some("example.com:8080")),
+            ),
+            // default ports
+            (
+                &Uri::from_static("http://example.com/path"),
+                headers(&[("host", "example.com:80")]),
+                Some(Some("example.com")),
+            ),
+            (
+                &Uri::from_static("https://example.com:443/path"),
+                headers(&[("host", "example.com")]),
+                Some(Some("example.com:443")),
+            ),
+            (
+                &Uri::from_static("http://example.com/path"),
+                headers(&[("host", "example.com:443")]),
+                None,
+            ),
+            // several hosts
+            (
+                &origin_form,
+                headers(&[("host", "example.com"), ("host", "example.com")]),
+                None,
+            ),
+            // differing from the authority
+            (&absolute_form, headers(&[("host", "example.com")]), None),
+            (&absolute_form, headers(&[("host", "evil.com:8080")]), None),
+            // invalid
+            (&origin_form, headers(&[("host", "user@example.com")]), None),
+            (&origin_form, headers(&[("host", "exa mple.com")]), None),
+        ] {
+            assert_eq!(
+                expected.map(|authority| authority.map(str::to_string)),
+                authority(uri, &headers, HostMismatchHandling::Reject),
+                "{uri} {headers:?}"
+            );
+        }
+
+        assert_eq!(
+            Some(Some("example.com:8080".to_string())),
+            authority(
+                &absolute_form,
+                &headers(&[("host", "evil.com")]),
+                HostMismatchHandling::Authority
+            )
+        );
+    }
+
+    #[test]
+    fn invalid_response_header_config_refused() {
+        let mut cfg = ArxConfig::default();
+        cfg.response_headers
+            .insert("x-frame-options".to_string(), "DENY".to_string());
+        let parsed = parse_response_headers(&cfg).unwrap();
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].0, "x-frame-options");
+        assert_eq!(parsed[0].1, "DENY");
+
+        cfg.response_headers
+            .insert("bad header".to_string(), "value".to_string());
+        assert!(parse_response_headers(&cfg).is_err());
+    }
+}
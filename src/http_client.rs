@@ -1,22 +1,49 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
 
 use anyhow::anyhow;
 use arc_swap::ArcSwap;
-use futures_util::{Stream, StreamExt};
+use futures_util::{future::BoxFuture, Stream, StreamExt};
 use reqwest_retry::policies::ExponentialBackoff;
 use reqwest_tracing::TracingMiddleware;
 use tokio_util::sync::CancellationToken;
 
-use crate::{arx_anyhow, config::ArxConfig, ArxError};
+use crate::{
+    arx_anyhow,
+    config::{ArxConfig, HeaderCase},
+    metrics::CURRENT_BACKEND,
+    route::Backend,
+    ArxError,
+};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+tokio::task_local! {
+    /// The connect timeout of the route being proxied, overriding `connect_timeout`
+    pub static CONNECT_TIMEOUT: Duration;
+
+    /// The request timeout of the route being proxied, overriding `request_timeout`
+    pub static REQUEST_TIMEOUT: Duration;
+}
+
 /// A wrapper around reqwest/reqwest_middleware with tracing support.
 #[derive(Clone)]
 pub struct HttpClient {
     instance: Arc<ArcSwap<HttpClientInstance>>,
 }
 
+impl std::fmt::Debug for HttpClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpClient").finish_non_exhaustive()
+    }
+}
+
 pub struct HttpClientInstance {
     pub reqwest_client: reqwest::Client,
     pub middleware_client: reqwest_middleware::ClientWithMiddleware,
@@ -44,7 +71,7 @@ impl HttpClient {
             return Err(ArxError::Internal(anyhow!("no client builders")));
         };
 
-        let instance = build_instance(cfg, initial_builder)?;
+        let instance = build_instance(cfg, initial_builder, true)?;
         let client = HttpClient {
             instance: Arc::new(ArcSwap::new(Arc::new(instance))),
         };
@@ -56,7 +83,7 @@ impl HttpClient {
                     tokio::select! {
                         next = client_builder_stream.next() => {
                             if let Some(builder) = next {
-                                match build_instance(cfg, builder) {
+                                match build_instance(cfg, builder, true) {
                                     Ok(instance) => {
                                         client.instance.store(
                                             Arc::new(instance)
@@ -82,26 +109,177 @@ impl HttpClient {
         Ok(client)
     }
 
+    /// A client of a single backend, verified with the given PEM-encoded CA certificates only,
+    /// and connected to through `server_name_host`: its TLS server name, and the host actually serving it.
+    pub fn create_for_backend(
+        cfg: &ArxConfig,
+        ca_certificates: Option<&str>,
+        server_name_host: Option<(String, String)>,
+    ) -> Result<Self, ArxError> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(ca_certificates) = ca_certificates {
+            builder = reqwest::Certificate::from_pem_bundle(ca_certificates.as_bytes())
+                .map_err(arx_anyhow)?
+                .into_iter()
+                .fold(builder, |builder, certificate| {
+                    builder.add_root_certificate(certificate)
+                });
+        }
+        if let Some((server_name, host)) = server_name_host {
+            builder = builder.dns_resolver(Arc::new(ConnectHostResolver { server_name, host }));
+        }
+
+        Ok(HttpClient {
+            instance: Arc::new(ArcSwap::new(Arc::new(build_instance(
+                cfg,
+                builder,
+                ca_certificates.is_none(),
+            )?))),
+        })
+    }
+
     pub fn current_instance(&self) -> Arc<HttpClientInstance> {
         self.instance.load_full()
     }
 }
 
+/// Clients of backends needing one of their own: those verified with CA certificates of their own,
+/// or connected to by another host than their TLS server name.
+///
+/// A backend's connection pool is keyed by its URI, i.e. by the server name alone,
+/// so backends sharing a server name but served by different hosts must never share a client.
+///
+/// Clients are created while the routing table is rebuilt, never while proxying,
+/// and dropped once the rebuilt table has no backends using them.
+#[derive(Default)]
+pub struct BackendClients(Mutex<HashMap<BackendClientKey, HttpClient>>);
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct BackendClientKey {
+    ca_certificates: Option<Arc<str>>,
+    server_name_host: Option<(String, String)>,
+}
+
+impl BackendClientKey {
+    fn of(backend: &Backend) -> Option<Self> {
+        let key = Self {
+            ca_certificates: backend.ca_certificates.clone(),
+            server_name_host: backend.server_name_host(),
+        };
+        (key.ca_certificates.is_some() || key.server_name_host.is_some()).then_some(key)
+    }
+}
+
+impl BackendClients {
+    /// The client of `backend`, created when missing,
+    /// or `None` when the backend uses the default client.
+    pub fn get_or_create(
+        &self,
+        backend: &Backend,
+        cfg: &ArxConfig,
+    ) -> Result<Option<HttpClient>, ArxError> {
+        let Some(key) = BackendClientKey::of(backend) else {
+            return Ok(None);
+        };
+
+        let mut clients = self.0.lock().unwrap();
+        if let Some(client) = clients.get(&key) {
+            return Ok(Some(client.clone()));
+        }
+
+        let client = HttpClient::create_for_backend(
+            cfg,
+            key.ca_certificates.as_deref(),
+            key.server_name_host.clone(),
+        )?;
+        clients.insert(key, client.clone());
+        Ok(Some(client))
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+
+    /// Drop the clients no longer used by any of the `backends` of the routing table
+    pub fn retain_used(&self, backends: &[Backend]) {
+        let used: Vec<BackendClientKey> =
+            backends.iter().filter_map(BackendClientKey::of).collect();
+        self.0.lock().unwrap().retain(|key, _| used.contains(key));
+    }
+}
+
+/// Follow up to `max_redirects` redirects issued by a backend, within the backend's own origin.
+///
+/// A redirect to another origin is forwarded to the client instead, so a backend can't make the gateway
+/// request arbitrary hosts, and the client's credentials (`Authorization`, cookies) never leave the backend.
+fn redirect_policy(max_redirects: usize) -> reqwest::redirect::Policy {
+    if max_redirects == 0 {
+        return reqwest::redirect::Policy::none();
+    }
+
+    reqwest::redirect::Policy::custom(move |attempt| {
+        let same_origin = attempt
+            .previous()
+            .first()
+            .is_some_and(|backend| backend.origin() == attempt.url().origin());
+
+        if !same_origin {
+            attempt.stop()
+        } else if attempt.previous().len() > max_redirects {
+            attempt.error("too many redirects")
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+/// Build a client from a builder, applying the config.
+/// Without `built_in_root_certs`, only root certificates added to the builder are trusted.
+///
+/// TLS to backends uses the rustls defaults chosen by reqwest, which has no settings for them:
+/// sessions are resumed (with tickets or session IDs) from an in-memory cache belonging to the client,
+/// so a rebuilt client, e.g. after an Authly certificate rotation, starts with full handshakes.
+/// 0-RTT early data is never sent. Early data can be replayed by an attacker on the network,
+/// so it would only be safe for idempotent requests, which the gateway can't guarantee.
 fn build_instance(
-    cfg: &'static ArxConfig,
+    cfg: &ArxConfig,
     builder: reqwest::ClientBuilder,
+    built_in_root_certs: bool,
 ) -> Result<HttpClientInstance, ArxError> {
-    let builder = builder
+    let mut builder = builder
         .user_agent(format!("Arx/{}", VERSION))
-        .connect_timeout(cfg.connect_timeout)
+        // applied by the connector, so it can be overridden per route
+        .connector_layer(ConnectTimeoutLayer {
+            default: cfg.connect_timeout,
+        })
         .timeout(cfg.request_timeout)
         .tcp_keepalive(cfg.keep_alive_timeout)
         .http2_keep_alive_timeout(cfg.keep_alive_timeout)
         .danger_accept_invalid_certs(cfg.http_accept_invalid_certs)
-        .tls_built_in_root_certs(cfg.use_root_certs)
-        .tls_built_in_webpki_certs(cfg.use_webpki_certs)
-        // redirects should be reflected
-        .redirect(reqwest::redirect::Policy::none());
+        .tls_built_in_root_certs(cfg.use_root_certs && built_in_root_certs)
+        .tls_built_in_webpki_certs(cfg.use_webpki_certs && built_in_root_certs)
+        // encoded responses are passed through as they are, compression is up to the gateway's own layer.
+        // Without decompression, routes transforming response bodies (JSON field removal, link rewriting)
+        // ask their backends for `Accept-Encoding: identity` instead, see `reverse_proxy`.
+        .no_gzip()
+        .no_brotli()
+        .no_deflate()
+        // redirects should be reflected, unless configured otherwise
+        .redirect(redirect_policy(cfg.backend_max_redirects));
+
+    if cfg.backend_header_case == HeaderCase::Titlecase {
+        builder = builder.http1_title_case_headers();
+    }
+
+    for (host, ip) in &cfg.backend_static_hosts {
+        // port 0: use the port of the backend URL, or the default port of its scheme
+        builder = builder.resolve(host, SocketAddr::new(*ip, 0));
+    }
+
+    if cfg.backend_metrics {
+        builder = builder.connector_layer(ConnectLatencyLayer);
+    }
 
     let client = builder.build().map_err(arx_anyhow)?;
 
@@ -124,11 +302,146 @@ fn build_instance(
     })
 }
 
+/// DNS resolver connecting to `host` instead of the TLS server name of a backend,
+/// so the backend can be reached with a server name of its own.
+struct ConnectHostResolver {
+    server_name: String,
+    host: String,
+}
+
+impl reqwest::dns::Resolve for ConnectHostResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let host = if name.as_str() == self.server_name {
+            self.host.clone()
+        } else {
+            name.as_str().to_string()
+        };
+
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((host.as_str(), 0)).await?;
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(Box::new(addrs) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Connector layer limiting the time to establish new connections
+/// to the [CONNECT_TIMEOUT] of the current route, or a default.
+#[derive(Clone)]
+struct ConnectTimeoutLayer {
+    default: Duration,
+}
+
+impl<S> tower::Layer<S> for ConnectTimeoutLayer {
+    type Service = ConnectTimeout<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ConnectTimeout {
+            inner,
+            default: self.default,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct ConnectTimeout<S> {
+    inner: S,
+    default: Duration,
+}
+
+impl<S, R> tower::Service<R> for ConnectTimeout<S>
+where
+    S: tower::Service<R>,
+    S::Response: Send + 'static,
+    S::Error: From<io::Error> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<S::Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: R) -> Self::Future {
+        let timeout = CONNECT_TIMEOUT
+            .try_with(|timeout| *timeout)
+            .unwrap_or(self.default);
+        let connecting = self.inner.call(req);
+
+        Box::pin(async move {
+            match tokio::time::timeout(timeout, connecting).await {
+                Ok(result) => result,
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out").into()),
+            }
+        })
+    }
+}
+
+/// Connector layer recording the time to establish new connections
+/// in the metrics of the [CURRENT_BACKEND].
+#[derive(Clone)]
+struct ConnectLatencyLayer;
+
+impl<S> tower::Layer<S> for ConnectLatencyLayer {
+    type Service = ConnectLatency<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ConnectLatency { inner }
+    }
+}
+
+#[derive(Clone)]
+struct ConnectLatency<S> {
+    inner: S,
+}
+
+impl<S, R> tower::Service<R> for ConnectLatency<S>
+where
+    S: tower::Service<R>,
+    S::Response: Send + 'static,
+    S::Error: Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<S::Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: R) -> Self::Future {
+        // The connection may be finished in the background,
+        // so the backend is looked up when it's initiated.
+        let backend = CURRENT_BACKEND.try_with(Arc::clone).ok();
+        let start = Instant::now();
+        let connecting = self.inner.call(req);
+
+        Box::pin(async move {
+            let result = connecting.await;
+            if let (Some(backend), Ok(_)) = (backend, &result) {
+                backend.connect.observe(start.elapsed());
+            }
+            result
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use crate::{
+        route::Proxy,
+        testing::{leak_config, spawn_gateway},
+    };
+
     use tokio_util::sync::DropGuard;
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
 
     async fn test_client(cfg: &'static ArxConfig) -> (HttpClient, DropGuard) {
         let cancel = CancellationToken::new();
@@ -170,6 +483,173 @@ mod tests {
     }
     */
 
+    #[tokio::test]
+    async fn route_connect_timeout() {
+        // a backend taking 100ms to accept connections
+        let slow_connector = tower::service_fn(|()| async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok::<_, io::Error>(())
+        });
+        let mut connector = tower::Layer::layer(
+            &ConnectTimeoutLayer {
+                default: Duration::from_millis(20),
+            },
+            slow_connector,
+        );
+
+        let err = tower::Service::call(&mut connector, ()).await.unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, err.kind());
+
+        // the route timeout is looked up when the connection is initiated
+        CONNECT_TIMEOUT
+            .scope(Duration::from_secs(1), async {
+                tower::Service::call(&mut connector, ()).await
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn backend_redirect_is_forwarded() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/old"))
+            .respond_with(ResponseTemplate::new(302).insert_header("location", "/new"))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/new"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+
+        let cfg = Box::leak(Box::new(ArxConfig::default()));
+        let (client, _drop) = test_client(cfg).await;
+        let response = client
+            .current_instance()
+            .reqwest_client
+            .get(format!("{}/old", mock_server.uri()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(302, response.status().as_u16());
+        assert_eq!("/new", response.headers()["location"]);
+
+        let cfg = Box::leak(Box::new(ArxConfig {
+            backend_max_redirects: 1,
+            ..Default::default()
+        }));
+        let (client, _drop) = test_client(cfg).await;
+        let response = client
+            .current_instance()
+            .reqwest_client
+            .get(format!("{}/old", mock_server.uri()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(200, response.status().as_u16());
+    }
+
+    #[tokio::test]
+    async fn backend_redirect_to_other_origin_is_forwarded() {
+        let other_origin = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("internal"))
+            .expect(0)
+            .mount(&other_origin)
+            .await;
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/old"))
+            .respond_with(ResponseTemplate::new(302).insert_header("location", "/new"))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/new"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("new"))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/elsewhere"))
+            .respond_with(
+                ResponseTemplate::new(302)
+                    .insert_header("location", format!("{}/internal", other_origin.uri())),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/{*path}",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+        let cfg = leak_config(ArxConfig {
+            backend_max_redirects: 5,
+            ..Default::default()
+        });
+        let addr = spawn_gateway(cfg, routes).await;
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+
+        // followed within the backend
+        let response = client
+            .get(format!("http://{addr}/old"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(200, response.status().as_u16());
+        assert_eq!("new", response.text().await.unwrap());
+
+        // forwarded to the client instead, the other origin never sees the credentials
+        let response = client
+            .get(format!("http://{addr}/elsewhere"))
+            .header("authorization", "Bearer s3cr3t")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(302, response.status().as_u16());
+        assert_eq!(
+            format!("{}/internal", other_origin.uri()),
+            response.headers()["location"]
+        );
+    }
+
+    #[tokio::test]
+    async fn backend_static_host() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+
+        let cfg = Box::leak(Box::new(ArxConfig {
+            backend_static_hosts: [("backend.svc".to_string(), mock_server.address().ip())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        }));
+        let (client, _drop) = test_client(cfg).await;
+        let response = client
+            .current_instance()
+            .reqwest_client
+            .get(format!(
+                "http://backend.svc:{}/",
+                mock_server.address().port()
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(200, response.status().as_u16());
+    }
+
     #[tokio::test]
     async fn verify_webpki_certs() {
         let cfg = Box::leak(Box::new(ArxConfig {
@@ -1,5 +1,5 @@
 use bytes::Bytes;
-use http::{Response, StatusCode};
+use http::{header, HeaderValue, Method, Response, StatusCode};
 use http_body_util::{combinators::UnsyncBoxBody, BodyExt, Empty, Full};
 
 pub type DynHttpError = Box<(dyn std::error::Error + Send + Sync + 'static)>;
@@ -10,6 +10,12 @@ pub type HyperResponse = Response<HyperBody>;
 pub enum HttpError {
     Static(StatusCode, &'static str),
     Dynamic(StatusCode, String),
+    /// The backend could not be connected to, so the request was never sent.
+    /// Answered with `502 Bad Gateway`.
+    Unreachable(String),
+    /// The path exists, but not for the request method.
+    /// Contains the methods that are allowed.
+    MethodNotAllowed(Vec<Method>),
 }
 
 impl HttpError {
@@ -21,6 +27,23 @@ impl HttpError {
         Self::Static(StatusCode::BAD_GATEWAY, msg)
     }
 
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Static(status, _) | Self::Dynamic(status, _) => *status,
+            Self::Unreachable(_) => StatusCode::BAD_GATEWAY,
+            Self::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            Self::Static(_, msg) => msg,
+            Self::Dynamic(_, msg) => msg,
+            Self::Unreachable(msg) => msg,
+            Self::MethodNotAllowed(_) => "method not allowed",
+        }
+    }
+
     pub fn into_hyper_response(self) -> HyperResponse {
         match self {
             Self::Static(status, msg) => Response::builder()
@@ -39,6 +62,34 @@ impl HttpError {
                         .boxed_unsync(),
                 )
                 .unwrap(),
+            Self::Unreachable(msg) => Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .body(
+                    Full::new(msg.into())
+                        .map_err(|never| match never {})
+                        .boxed_unsync(),
+                )
+                .unwrap(),
+            Self::MethodNotAllowed(methods) => {
+                let allow = methods
+                    .iter()
+                    .map(Method::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+
+                Response::builder()
+                    .status(StatusCode::METHOD_NOT_ALLOWED)
+                    .header(
+                        header::ALLOW,
+                        HeaderValue::from_str(&allow).unwrap_or(HeaderValue::from_static("")),
+                    )
+                    .body(
+                        Full::new("method not allowed".into())
+                            .map_err(|never| match never {})
+                            .boxed_unsync(),
+                    )
+                    .unwrap()
+            }
         }
     }
 }
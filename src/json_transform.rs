@@ -0,0 +1,156 @@
+//! Transformations of JSON response bodies
+
+use anyhow::anyhow;
+use serde_json::Value;
+
+/// Removal of fields from JSON documents, given as JSONPath expressions.
+///
+/// A subset of JSONPath is supported: `$` followed by `.field`, `['field']`, `.*` and `[*]` segments,
+/// e.g. `$.debug` or `$.items[*].internal`.
+#[derive(Clone, Debug)]
+pub struct JsonFieldRemoval {
+    paths: Vec<Vec<Segment>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Field(String),
+    Wildcard,
+}
+
+impl JsonFieldRemoval {
+    pub fn parse(paths: &[String]) -> anyhow::Result<Self> {
+        Ok(Self {
+            paths: paths
+                .iter()
+                .map(|path| parse_path(path))
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+
+    /// Remove the fields from a document
+    pub fn apply(&self, value: &mut Value) {
+        for path in &self.paths {
+            remove(value, path);
+        }
+    }
+}
+
+fn parse_path(path: &str) -> anyhow::Result<Vec<Segment>> {
+    let invalid = || anyhow!("invalid JSONPath `{path}`");
+    let mut rest = path.strip_prefix('$').ok_or_else(invalid)?;
+    let mut segments = vec![];
+
+    while !rest.is_empty() {
+        if let Some(bracketed) = rest.strip_prefix('[') {
+            let (selector, after) = bracketed.split_once(']').ok_or_else(invalid)?;
+            let segment = match selector {
+                "*" => Segment::Wildcard,
+                quoted => {
+                    let field = quoted
+                        .strip_prefix('\'')
+                        .and_then(|field| field.strip_suffix('\''))
+                        .ok_or_else(invalid)?;
+                    Segment::Field(field.to_string())
+                }
+            };
+            segments.push(segment);
+            rest = after;
+        } else if let Some(dotted) = rest.strip_prefix('.') {
+            let end = dotted.find(['.', '[']).unwrap_or(dotted.len());
+            let segment = match &dotted[..end] {
+                "" => return Err(invalid()),
+                "*" => Segment::Wildcard,
+                field => Segment::Field(field.to_string()),
+            };
+            segments.push(segment);
+            rest = &dotted[end..];
+        } else {
+            return Err(invalid());
+        }
+    }
+
+    if segments.is_empty() {
+        // the whole document can't be removed
+        return Err(invalid());
+    }
+
+    Ok(segments)
+}
+
+fn remove(value: &mut Value, path: &[Segment]) {
+    let Some((segment, rest)) = path.split_first() else {
+        return;
+    };
+
+    match (segment, value) {
+        (Segment::Field(field), Value::Object(object)) => {
+            if rest.is_empty() {
+                object.remove(field);
+            } else if let Some(child) = object.get_mut(field) {
+                remove(child, rest);
+            }
+        }
+        (Segment::Wildcard, Value::Object(object)) => {
+            if rest.is_empty() {
+                object.clear();
+            } else {
+                for child in object.values_mut() {
+                    remove(child, rest);
+                }
+            }
+        }
+        (Segment::Wildcard, Value::Array(array)) => {
+            if rest.is_empty() {
+                array.clear();
+            } else {
+                for child in array {
+                    remove(child, rest);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn remove_fields() {
+        let removal = JsonFieldRemoval::parse(&[
+            "$.debug".to_string(),
+            "$.items[*].internal".to_string(),
+            "$['meta'].trace".to_string(),
+        ])
+        .unwrap();
+
+        let mut value = json!({
+            "debug": { "query": "select" },
+            "items": [{ "id": 1, "internal": true }, { "id": 2 }],
+            "meta": { "trace": "abc", "count": 2 },
+        });
+        removal.apply(&mut value);
+
+        assert_eq!(
+            json!({
+                "items": [{ "id": 1 }, { "id": 2 }],
+                "meta": { "count": 2 },
+            }),
+            value
+        );
+    }
+
+    #[test]
+    fn invalid_paths() {
+        for path in ["debug", "$", "$.", "$[debug]", "$.items[*"] {
+            assert!(
+                JsonFieldRemoval::parse(&[path.to_string()]).is_err(),
+                "{path}"
+            );
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! Upstream TLS settings from Gateway API BackendTLSPolicies.
+//!
+//! Note: This requires permission to `list` and `watch` `backendtlspolicies`,
+//! and to `get`, `list` and `watch` the ConfigMaps holding their CA certificates,
+//! so rotated certificates are picked up.
+
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::anyhow;
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::{runtime::reflector::Lookup, Api, CustomResource};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// The key of the CA certificates in a referenced ConfigMap
+const CA_CERTIFICATE_KEY: &str = "ca.crt";
+
+/// The experimental-channel BackendTLSPolicy, with the fields the gateway supports.
+#[derive(CustomResource, Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[kube(
+    group = "gateway.networking.k8s.io",
+    version = "v1alpha3",
+    kind = "BackendTLSPolicy",
+    namespaced
+)]
+#[serde(rename_all = "camelCase")]
+pub struct BackendTLSPolicySpec {
+    /// The Services the policy applies to
+    pub target_refs: Vec<BackendTLSPolicyTargetRef>,
+    pub validation: BackendTLSPolicyValidation,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BackendTLSPolicyTargetRef {
+    #[serde(default)]
+    pub group: String,
+    pub kind: String,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BackendTLSPolicyValidation {
+    /// ConfigMaps with the CA certificates to verify the backend with, under the `ca.crt` key
+    #[serde(default)]
+    pub ca_certificate_refs: Vec<BackendTLSPolicyCaCertificateRef>,
+    /// `System`: verify the backend with the gateway's usual root certificates
+    #[serde(default)]
+    pub well_known_ca_certificates: Option<String>,
+    /// The TLS server name (SNI) of the backend, which its certificate is verified against
+    pub hostname: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BackendTLSPolicyCaCertificateRef {
+    #[serde(default)]
+    pub group: String,
+    pub kind: String,
+    pub name: String,
+}
+
+/// How to connect to a backend over TLS
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackendTls {
+    /// The TLS server name of the backend
+    pub hostname: String,
+    /// PEM-encoded CA certificates trusted instead of the gateway's root certificates
+    pub ca_certificates: Option<Arc<str>>,
+}
+
+/// The BackendTLSPolicies in the cluster, by `namespace/name`
+#[derive(Default)]
+pub struct BackendTlsPolicies(HashMap<String, AppliedPolicy>);
+
+struct AppliedPolicy {
+    namespace: String,
+    services: Vec<String>,
+    tls: BackendTls,
+}
+
+impl BackendTlsPolicies {
+    /// Add or replace a policy, with the contents of its referenced CA certificates
+    pub fn insert(
+        &mut self,
+        policy: &BackendTLSPolicy,
+        ca_certificates: Vec<String>,
+    ) -> anyhow::Result<()> {
+        let validation = &policy.spec.validation;
+        let ca_certificates = match (
+            validation.ca_certificate_refs.is_empty(),
+            validation.well_known_ca_certificates.as_deref(),
+        ) {
+            (true, Some("System")) => None,
+            (false, None) => Some(Arc::from(ca_certificates.join("\n"))),
+            (true, None) => return Err(anyhow!("policy has no CA certificates")),
+            (false, Some(_)) | (true, Some(_)) => {
+                return Err(anyhow!("unsupported CA certificate validation"))
+            }
+        };
+
+        if let Some(pem) = &ca_certificates {
+            let certificates = reqwest::Certificate::from_pem_bundle(pem.as_bytes())
+                .map_err(|err| anyhow!("invalid CA certificates: {err}"))?;
+            if certificates.is_empty() {
+                return Err(anyhow!("no CA certificates found"));
+            }
+        }
+
+        let namespace = policy.namespace().unwrap_or_default().to_string();
+        self.0.insert(
+            policy_key(policy),
+            AppliedPolicy {
+                services: policy
+                    .spec
+                    .target_refs
+                    .iter()
+                    .filter(|target| target.group.is_empty() && target.kind == "Service")
+                    .map(|target| target.name.clone())
+                    .collect(),
+                namespace,
+                tls: BackendTls {
+                    hostname: validation.hostname.clone(),
+                    ca_certificates,
+                },
+            },
+        );
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, policy: &BackendTLSPolicy) {
+        self.0.remove(&policy_key(policy));
+    }
+
+    /// The TLS settings of a Service, from the first policy targeting it by `namespace/name`
+    pub fn get(&self, namespace: &str, service: &str) -> Option<&BackendTls> {
+        self.0
+            .iter()
+            .filter(|(_, applied)| {
+                applied.namespace == namespace
+                    && applied.services.iter().any(|name| name == service)
+            })
+            .min_by_key(|(key, _)| key.as_str())
+            .map(|(_, applied)| &applied.tls)
+    }
+}
+
+pub fn policy_key(policy: &BackendTLSPolicy) -> String {
+    format!(
+        "{}/{}",
+        policy.namespace().unwrap_or_default(),
+        policy.name().unwrap_or_default()
+    )
+}
+
+/// Whether a policy takes CA certificates from a ConfigMap
+pub fn references_config_map(policy: &BackendTLSPolicy, config_map: &ConfigMap) -> bool {
+    policy.namespace() == config_map.namespace()
+        && policy
+            .spec
+            .validation
+            .ca_certificate_refs
+            .iter()
+            .any(|ca_ref| {
+                ca_ref.kind == "ConfigMap" && config_map.name().as_deref() == Some(&ca_ref.name)
+            })
+}
+
+/// Fetch the CA certificates referenced by a policy, from ConfigMaps in its namespace
+pub async fn fetch_ca_certificates(
+    kube_client: &kube::Client,
+    policy: &BackendTLSPolicy,
+) -> anyhow::Result<Vec<String>> {
+    let config_maps =
+        Api::<ConfigMap>::namespaced(kube_client.clone(), &policy.namespace().unwrap_or_default());
+
+    let mut ca_certificates = vec![];
+    for ca_ref in &policy.spec.validation.ca_certificate_refs {
+        if !ca_ref.group.is_empty() || ca_ref.kind != "ConfigMap" {
+            return Err(anyhow!(
+                "unsupported CA certificate reference kind `{}`",
+                ca_ref.kind
+            ));
+        }
+        let config_map = config_maps.get(&ca_ref.name).await?;
+        let Some(pem) = config_map
+            .data
+            .and_then(|mut data| data.remove(CA_CERTIFICATE_KEY))
+        else {
+            return Err(anyhow!(
+                "ConfigMap `{}` has no `{CA_CERTIFICATE_KEY}`",
+                ca_ref.name
+            ));
+        };
+        ca_certificates.push(pem);
+    }
+
+    Ok(ca_certificates)
+}
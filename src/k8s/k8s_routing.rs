@@ -1,37 +1,121 @@
 use std::{
+    cmp::Reverse,
     collections::HashMap,
+    panic::{catch_unwind, AssertUnwindSafe},
     str::FromStr,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
+use anyhow::anyhow;
 use arc_swap::ArcSwap;
-use gateway_api::apis::standard::httproutes::{HTTPRoute, HTTPRouteRulesMatchesPathType};
-use http::Uri;
+use gateway_api::apis::standard::httproutes::{
+    HTTPRoute, HTTPRouteRulesBackendRefs, HTTPRouteRulesFiltersType, HTTPRouteRulesMatches,
+    HTTPRouteRulesMatchesHeadersType, HTTPRouteRulesMatchesMethod, HTTPRouteRulesMatchesPathType,
+    HTTPRouteRulesMatchesQueryParamsType,
+};
+use http::{HeaderName, HeaderValue, Uri};
+use k8s_openapi::api::core::v1::ConfigMap;
 use kube::{runtime::reflector::Lookup, Api};
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info, info_span, warn};
+use tracing::{debug, error, info, info_span, warn};
 
 use crate::{
-    route::{AuthDirective, BackendClass, Proxy, Route},
+    config::{ArxConfig, BackendPolicy, HeaderBackendSelection, RoutePolicy},
+    http_client::BackendClients,
+    json_transform::JsonFieldRemoval,
+    link_rewrite::LinkRewrite,
+    local,
+    media_type::MediaTypes,
+    metrics::METRICS,
+    path_rewrite::PathRewrite,
+    route::{
+        redirect_loops, AuthDirective, Backend, BackendClass, HeaderSelection, InFlightLimit,
+        Proxy, RequestConditions, Route, SplitBackend, TrafficSplit, ValueMatch,
+    },
     static_routes::static_routes,
 };
 
-use super::k8s_util::{api_watcher, ApiWatcherCallbacks};
+use super::{
+    backend_tls::{
+        fetch_ca_certificates, policy_key, references_config_map, BackendTLSPolicy,
+        BackendTlsPolicies,
+    },
+    k8s_util::{api_watcher, ApiWatcherCallbacks},
+    route_status::{report_route_statuses, RouteStatus},
+};
+
+/// The extensionRef group of Arx-specific filters
+const ARX_EXTENSION_GROUP: &str = "arx.protojour.com";
+
+/// Handle for requesting a rebuild of the routing table from the current HTTPRoute snapshot.
+#[derive(Clone)]
+pub struct RouteReloadTrigger(mpsc::Sender<oneshot::Sender<()>>);
+
+/// The receiving end of [RouteReloadTrigger], handled by the HTTPRoute watcher.
+pub struct RouteReloadRequests(mpsc::Receiver<oneshot::Sender<()>>);
+
+impl RouteReloadTrigger {
+    pub fn channel() -> (Self, RouteReloadRequests) {
+        let (tx, rx) = mpsc::channel(1);
+        (Self(tx), RouteReloadRequests(rx))
+    }
+
+    /// Rebuild the routing table, returning when the new table is in place.
+    pub async fn reload(&self) -> anyhow::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(tx)
+            .await
+            .map_err(|_| anyhow!("route watcher is not running"))?;
+        rx.await
+            .map_err(|_| anyhow!("route watcher stopped before reloading"))?;
+        Ok(())
+    }
+}
 
 pub async fn spawn_k8s_watchers(
     gateway_routes: Arc<ArcSwap<matchit::Router<Route>>>,
     client: reqwest::Client,
+    reload_requests: RouteReloadRequests,
+    cfg: &'static ArxConfig,
     cancel: CancellationToken,
 ) -> anyhow::Result<()> {
     let kube_client = kube::Client::try_default().await?;
 
+    let watcher = Arc::new(HttpRouteWatcher {
+        gateway_routes,
+        k8s_routes: Mutex::new(Default::default()),
+        tls_policies: Mutex::new(Default::default()),
+        backend_tls: Mutex::new(Default::default()),
+        routing_state: Default::default(),
+        client,
+        kube_client: kube_client.clone(),
+        cfg,
+    });
+
+    tokio::spawn(handle_reload_requests(
+        watcher.clone(),
+        reload_requests,
+        cancel.clone(),
+    ));
+    if cfg.backend_tls_policies {
+        tokio::spawn(api_watcher(
+            Api::<BackendTLSPolicy>::all(kube_client.clone()),
+            watcher.clone(),
+            cancel.clone(),
+        ));
+        tokio::spawn(api_watcher(
+            Api::<ConfigMap>::all(kube_client.clone()),
+            watcher.clone(),
+            cancel.clone(),
+        ));
+    }
     tokio::spawn(api_watcher(
-        Api::<HTTPRoute>::all(kube_client.clone()),
-        HttpRouteWatcher {
-            gateway_routes,
-            k8s_routes: Mutex::new(Default::default()),
-            client,
-        },
+        Api::<HTTPRoute>::all(kube_client),
+        watcher,
         cancel,
     ));
 
@@ -41,37 +125,186 @@ pub async fn spawn_k8s_watchers(
 struct HttpRouteWatcher {
     gateway_routes: Arc<ArcSwap<matchit::Router<Route>>>,
     k8s_routes: Mutex<HashMap<String, HTTPRoute>>,
+    /// Every BackendTLSPolicy seen, including those that could not be applied, by `namespace/name`
+    tls_policies: Mutex<HashMap<String, BackendTLSPolicy>>,
+    backend_tls: Mutex<BackendTlsPolicies>,
+    routing_state: RoutingState,
     client: reqwest::Client,
+    kube_client: kube::Client,
+    cfg: &'static ArxConfig,
+}
+
+impl HttpRouteWatcher {
+    /// Rebuild the routing table from the current HTTPRoute snapshot
+    async fn reload(&self) {
+        let statuses = {
+            let k8s_lock = self.k8s_routes.lock().unwrap();
+
+            update_routing_table(
+                &k8s_lock,
+                &self.backend_tls.lock().unwrap(),
+                &self.routing_state,
+                self.gateway_routes.clone(),
+                self.client.clone(),
+                self.cfg,
+            )
+        };
+
+        report_route_statuses(&self.kube_client, statuses).await;
+    }
+
+    /// Apply policies with the current contents of their CA certificate ConfigMaps, leaving out those that are invalid
+    async fn apply_tls_policies(&self, policies: Vec<BackendTLSPolicy>) {
+        for policy in policies {
+            let ca_certificates = fetch_ca_certificates(&self.kube_client, &policy).await;
+
+            let mut backend_tls = self.backend_tls.lock().unwrap();
+            if let Err(err) = ca_certificates.and_then(|ca| backend_tls.insert(&policy, ca)) {
+                warn!(?err, name = ?policy.name(), "invalid BackendTLSPolicy, ignoring");
+                backend_tls.remove(&policy);
+            }
+        }
+    }
+
+    /// Apply the policies referencing changed ConfigMaps again, and rebuild the routing table
+    async fn config_maps_changed(&self, config_maps: Vec<ConfigMap>) {
+        let policies: Vec<BackendTLSPolicy> = self
+            .tls_policies
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|policy| {
+                config_maps
+                    .iter()
+                    .any(|config_map| references_config_map(policy, config_map))
+            })
+            .cloned()
+            .collect();
+        if policies.is_empty() {
+            return;
+        }
+
+        self.apply_tls_policies(policies).await;
+        self.reload().await;
+    }
+}
+
+async fn handle_reload_requests(
+    watcher: Arc<HttpRouteWatcher>,
+    mut requests: RouteReloadRequests,
+    cancel: CancellationToken,
+) {
+    loop {
+        tokio::select! {
+            request = requests.0.recv() => {
+                let Some(done) = request else {
+                    break;
+                };
+                info!("reloading routes on request");
+                watcher.reload().await;
+                let _ = done.send(());
+            }
+            _ = cancel.cancelled() => {
+                break
+            }
+        }
+    }
 }
 
 impl ApiWatcherCallbacks<HTTPRoute> for HttpRouteWatcher {
     async fn apply(&self, objs: Vec<HTTPRoute>) -> anyhow::Result<()> {
-        let mut k8s_lock = self.k8s_routes.lock().unwrap();
+        let statuses = {
+            let mut k8s_lock = self.k8s_routes.lock().unwrap();
 
-        for obj in objs {
-            let Some((name, route)) = filter_k8s_http_route(obj) else {
-                continue;
-            };
-            k8s_lock.insert(name, route);
-        }
+            for obj in objs {
+                let Some((name, route)) = filter_k8s_http_route(obj) else {
+                    continue;
+                };
+                k8s_lock.insert(name, route);
+            }
 
-        update_routing_table(&k8s_lock, self.gateway_routes.clone(), self.client.clone());
+            update_routing_table(
+                &k8s_lock,
+                &self.backend_tls.lock().unwrap(),
+                &self.routing_state,
+                self.gateway_routes.clone(),
+                self.client.clone(),
+                self.cfg,
+            )
+        };
+
+        report_route_statuses(&self.kube_client, statuses).await;
 
         Ok(())
     }
 
     async fn delete(&self, objs: Vec<HTTPRoute>) -> anyhow::Result<()> {
-        let mut k8s_lock = self.k8s_routes.lock().unwrap();
+        let statuses = {
+            let mut k8s_lock = self.k8s_routes.lock().unwrap();
 
-        for obj in objs {
-            let Some((name, _route)) = filter_k8s_http_route(obj) else {
-                continue;
-            };
-            k8s_lock.remove(&name);
+            for obj in objs {
+                let Some((name, _route)) = filter_k8s_http_route(obj) else {
+                    continue;
+                };
+                k8s_lock.remove(&name);
+            }
+
+            update_routing_table(
+                &k8s_lock,
+                &self.backend_tls.lock().unwrap(),
+                &self.routing_state,
+                self.gateway_routes.clone(),
+                self.client.clone(),
+                self.cfg,
+            )
+        };
+
+        report_route_statuses(&self.kube_client, statuses).await;
+
+        Ok(())
+    }
+}
+
+impl ApiWatcherCallbacks<BackendTLSPolicy> for HttpRouteWatcher {
+    async fn apply(&self, objs: Vec<BackendTLSPolicy>) -> anyhow::Result<()> {
+        {
+            let mut tls_policies = self.tls_policies.lock().unwrap();
+            for obj in &objs {
+                tls_policies.insert(policy_key(obj), obj.clone());
+            }
+        }
+
+        self.apply_tls_policies(objs).await;
+        self.reload().await;
+
+        Ok(())
+    }
+
+    async fn delete(&self, objs: Vec<BackendTLSPolicy>) -> anyhow::Result<()> {
+        {
+            let mut tls_policies = self.tls_policies.lock().unwrap();
+            let mut backend_tls = self.backend_tls.lock().unwrap();
+            for obj in objs {
+                tls_policies.remove(&policy_key(&obj));
+                backend_tls.remove(&obj);
+            }
         }
 
-        update_routing_table(&k8s_lock, self.gateway_routes.clone(), self.client.clone());
+        self.reload().await;
+
+        Ok(())
+    }
+}
+
+/// CA certificates are read from ConfigMaps, so the policies referencing a changed ConfigMap are applied again.
+impl ApiWatcherCallbacks<ConfigMap> for HttpRouteWatcher {
+    async fn apply(&self, objs: Vec<ConfigMap>) -> anyhow::Result<()> {
+        self.config_maps_changed(objs).await;
+        Ok(())
+    }
 
+    async fn delete(&self, objs: Vec<ConfigMap>) -> anyhow::Result<()> {
+        self.config_maps_changed(objs).await;
         Ok(())
     }
 }
@@ -90,153 +323,601 @@ fn filter_k8s_http_route(http_route: HTTPRoute) -> Option<(String, HTTPRoute)> {
     Some((name.to_string(), http_route))
 }
 
+/// Rebuild and store the routing table, returning the statuses to report for each HTTPRoute.
 fn update_routing_table(
     k8s_routes: &HashMap<String, HTTPRoute>,
+    backend_tls: &BackendTlsPolicies,
+    state: &RoutingState,
     gateway_routes: Arc<ArcSwap<matchit::Router<Route>>>,
     client: reqwest::Client,
-) {
-    match rebuild_routing_table(k8s_routes, client) {
-        Ok(new_routes) => {
-            gateway_routes.store(Arc::new(new_routes));
+    cfg: &ArxConfig,
+) -> Vec<(HTTPRoute, RouteStatus)> {
+    match rebuild_routing_table(k8s_routes, backend_tls, state, client.clone(), cfg) {
+        Ok(RoutingTable {
+            routes,
+            backends,
+            statuses,
+        }) => {
+            gateway_routes.store(Arc::new(routes));
+            // the previous table is gone, along with the metrics of its removed routes
+            METRICS.prune_routes();
+
+            if cfg.backend_warm_up {
+                tokio::spawn(warm_up_backends(client, backends));
+            }
+
+            statuses
+                .into_iter()
+                .filter_map(|(name, status)| Some((k8s_routes.get(&name)?.clone(), status)))
+                .collect()
         }
         Err(err) => {
             error!(?err, "could not build new routing table");
+            vec![]
+        }
+    }
+}
+
+/// Connect to backends ahead of the first requests proxied to them, leaving the connections in the client's pool.
+///
+/// Each backend gets a `HEAD` request, whose response is ignored.
+/// Only backends proxied to with the default client are warmed up,
+/// not Authly mesh backends or those with a BackendTLSPolicy.
+async fn warm_up_backends(client: reqwest::Client, backends: Vec<Backend>) {
+    let requests = backends
+        .into_iter()
+        .filter(|backend| {
+            matches!(backend.class, BackendClass::Plain)
+                && backend.ca_certificates.is_none()
+                && backend.connect_host.is_none()
+        })
+        .map(|backend| {
+            let request = client.head(backend.uri.to_string()).send();
+            async move {
+                if let Err(err) = request.await {
+                    debug!(?err, backend = %backend.uri, "backend warm-up failed");
+                }
+            }
+        });
+
+    futures_util::future::join_all(requests).await;
+}
+
+/// Build the routing table from the HTTPRoutes currently in the cluster, without serving it.
+///
+/// Returns a description of each rejected HTTPRoute.
+pub async fn check_k8s_routes(cfg: &ArxConfig) -> anyhow::Result<Vec<String>> {
+    let kube_client = kube::Client::try_default().await?;
+    let k8s_routes = Api::<HTTPRoute>::all(kube_client.clone())
+        .list(&Default::default())
+        .await?
+        .items
+        .into_iter()
+        .filter_map(filter_k8s_http_route)
+        .collect();
+
+    let mut backend_tls = BackendTlsPolicies::default();
+    if cfg.backend_tls_policies {
+        for policy in Api::<BackendTLSPolicy>::all(kube_client.clone())
+            .list(&Default::default())
+            .await?
+            .items
+        {
+            let ca_certificates = fetch_ca_certificates(&kube_client, &policy).await?;
+            backend_tls.insert(&policy, ca_certificates)?;
         }
     }
+
+    check_routing_table(&k8s_routes, &backend_tls, cfg)
+}
+
+fn check_routing_table(
+    k8s_routes: &HashMap<String, HTTPRoute>,
+    backend_tls: &BackendTlsPolicies,
+    cfg: &ArxConfig,
+) -> anyhow::Result<Vec<String>> {
+    let RoutingTable { statuses, .. } = rebuild_routing_table(
+        k8s_routes,
+        backend_tls,
+        &Default::default(),
+        reqwest::Client::new(),
+        cfg,
+    )?;
+
+    let mut problems: Vec<String> = statuses
+        .into_iter()
+        .filter_map(|(name, status)| match status {
+            RouteStatus::Accepted { .. } => None,
+            RouteStatus::Rejected { message, .. } => {
+                Some(format!("HTTPRoute `{name}` rejected: {message}"))
+            }
+        })
+        .collect();
+    problems.sort();
+
+    Ok(problems)
+}
+
+/// State kept from one rebuild of the routing table to the next,
+/// dropped for backends the rebuilt table no longer proxies to.
+#[derive(Default)]
+pub struct RoutingState {
+    /// Clients of backends needing one of their own
+    pub backend_clients: BackendClients,
+    /// When the gateway first routed to each backend, for slow start
+    first_seen: Mutex<HashMap<Uri, Instant>>,
+}
+
+impl RoutingState {
+    fn backend_first_seen(&self, backend: &Backend) -> Instant {
+        *self
+            .first_seen
+            .lock()
+            .unwrap()
+            .entry(backend.uri.clone())
+            .or_insert_with(Instant::now)
+    }
+
+    fn retain_used(&self, backends: &[Backend]) {
+        self.backend_clients.retain_used(backends);
+        self.first_seen
+            .lock()
+            .unwrap()
+            .retain(|uri, _| backends.iter().any(|backend| &backend.uri == uri));
+    }
+}
+
+pub struct RoutingTable {
+    pub routes: matchit::Router<Route>,
+    /// The backends proxied to by the accepted HTTPRoutes, once per URI
+    pub backends: Vec<Backend>,
+    /// The outcome of adding each HTTPRoute, by name
+    pub statuses: HashMap<String, RouteStatus>,
+}
+
+/// A match of an accepted HTTPRoute, as listed at `/docs/routes.json`.
+/// Regular expression path matches are not routed, so not listed either.
+#[derive(Clone, Debug, Serialize)]
+pub struct ListedRoute {
+    /// The HTTPRoute, as `namespace/name`
+    pub route: String,
+    pub path: String,
+    /// `PathPrefix` or `Exact`
+    pub path_type: &'static str,
+    /// The matched method, or all methods when absent
+    pub method: Option<String>,
+    /// The header matches, all of which must match
+    pub headers: Vec<ListedValueMatch>,
+    /// The query parameter matches, all of which must match
+    pub query_params: Vec<ListedValueMatch>,
+    /// The URIs of the backends the match may be proxied to
+    pub backends: Vec<String>,
+}
+
+/// A header or query parameter match of a [ListedRoute]
+#[derive(Clone, Debug, Serialize)]
+pub struct ListedValueMatch {
+    pub name: String,
+    /// `Exact` or `RegularExpression`
+    pub r#type: &'static str,
+    pub value: String,
 }
 
 pub fn rebuild_routing_table(
     k8s_routes: &HashMap<String, HTTPRoute>,
+    backend_tls: &BackendTlsPolicies,
+    state: &RoutingState,
     client: reqwest::Client,
-) -> anyhow::Result<matchit::Router<Route>> {
-    let mut output = static_routes(client)?;
+    cfg: &ArxConfig,
+) -> anyhow::Result<RoutingTable> {
+    let mut output = static_routes(client, cfg.acme_challenge_dir.as_deref())?;
+    let mut backends = vec![];
+    let mut listing = vec![];
+    let mut statuses = HashMap::with_capacity(k8s_routes.len());
 
     for (name, http_route) in k8s_routes {
         let _entered = info_span!("route", name = name).entered();
 
-        if let Err(err) = try_add_http_route(&mut output, name, http_route) {
-            warn!(?err, "invalid HTTPRoute, ignoring");
+        // A route is added as a whole or not at all
+        let mut additions = RouteAdditions::default();
+
+        // a bug triggered by an unexpected route must not take the watcher down with it
+        let added = catch_unwind(AssertUnwindSafe(|| {
+            try_add_http_route(&mut additions, http_route, backend_tls, state, cfg)
+        }))
+        .unwrap_or_else(|_| Err(anyhow!("internal error while adding route")));
+
+        let status = match added {
+            Ok(mut warnings) => {
+                warnings.extend(additions.add_to(&mut output, &mut backends, &mut listing));
+                for warning in &warnings {
+                    warn!("{warning}");
+                }
+                RouteStatus::Accepted { warnings }
+            }
+            Err(err) => {
+                warn!(?err, "invalid HTTPRoute, ignoring");
+                RouteStatus::Rejected {
+                    reason: "UnsupportedValue",
+                    message: format!("{err:#}"),
+                }
+            }
+        };
+
+        statuses.insert(name.clone(), status);
+    }
+
+    if cfg.docs_route_listing {
+        listing.sort_by(|a, b| (&a.path, &a.route).cmp(&(&b.path, &b.route)));
+        let json = serde_json::to_vec(&listing)?;
+        try_insert_route(
+            &mut output,
+            "/docs/routes.json",
+            Route::Local(Arc::new(local::RouteListing { json: json.into() })),
+        );
+    }
+
+    state.retain_used(&backends);
+
+    Ok(RoutingTable {
+        routes: output,
+        backends,
+        statuses,
+    })
+}
+
+/// What an HTTPRoute adds to the routing table, collected before any of it is added
+#[derive(Default)]
+pub struct RouteAdditions {
+    /// Routes by path, in the order they are added
+    routes: Vec<(String, AddedRoute)>,
+    backends: Vec<Backend>,
+    listing: Vec<ListedRoute>,
+}
+
+enum AddedRoute {
+    Proxy(Proxy),
+    Redirect(Uri),
+}
+
+impl RouteAdditions {
+    fn proxy(&mut self, path: impl Into<String>, proxy: Proxy) {
+        self.routes.push((path.into(), AddedRoute::Proxy(proxy)));
+    }
+
+    fn redirect(&mut self, path: impl Into<String>, target: Uri) {
+        self.routes
+            .push((path.into(), AddedRoute::Redirect(target)));
+    }
+
+    /// Add everything to the routing table, returning warnings about redirects that were dropped
+    fn add_to(
+        self,
+        output: &mut matchit::Router<Route>,
+        backends: &mut Vec<Backend>,
+        listing: &mut Vec<ListedRoute>,
+    ) -> Vec<String> {
+        let mut warnings = vec![];
+        for (path, route) in self.routes {
+            match route {
+                AddedRoute::Proxy(proxy) => try_insert_proxy(output, &path, proxy),
+                AddedRoute::Redirect(target) => {
+                    try_insert_redirect(output, &path, target, &mut warnings)
+                }
+            }
         }
+        for backend in self.backends {
+            add_backend(backends, backend);
+        }
+        listing.extend(self.listing);
+        warnings
     }
+}
 
-    Ok(output)
+/// Add a backend, unless it's known already
+fn add_backend(backends: &mut Vec<Backend>, backend: Backend) {
+    if !backends.iter().any(|known| {
+        known.uri == backend.uri
+            && known.connect_host == backend.connect_host
+            && known.ca_certificates == backend.ca_certificates
+    }) {
+        backends.push(backend);
+    }
 }
 
+/// Collect what the HTTPRoute adds to the routing table in `additions`, returning warnings about ignored parts of it.
 pub fn try_add_http_route(
-    output: &mut matchit::Router<Route>,
-    name: &str,
+    additions: &mut RouteAdditions,
     http_route: &HTTPRoute,
-) -> anyhow::Result<()> {
+    backend_tls: &BackendTlsPolicies,
+    state: &RoutingState,
+    cfg: &ArxConfig,
+) -> anyhow::Result<Vec<String>> {
     let spec = &http_route.spec;
+    let namespace = http_route.namespace().unwrap_or_default();
+    let mut warnings = vec![];
 
     if let Some(_hostnames) = &spec.hostnames {
         // TODO: hostnames
     }
 
-    if let Some(rules) = &spec.rules {
-        for rule in rules {
-            let Some(backend_refs) = &rule.backend_refs else {
-                continue;
-            };
+    if spec.rules.as_ref().is_none_or(Vec::is_empty) {
+        if cfg.reject_ruleless_routes {
+            return Err(anyhow!("route has no rules"));
+        }
+        warnings.push("route has no rules, and matches no requests".to_string());
+    }
 
-            let backend_ref = match backend_refs.len() {
-                0 => continue,
-                1 => backend_refs.iter().next().unwrap(),
-                _ => {
-                    warn!("no support for multiple backend refs yet, using just the first one");
-                    backend_refs.iter().next().unwrap()
-                }
-            };
+    if let Some(rules) = &spec.rules {
+        for (rule_index, rule) in rules.iter().enumerate() {
+            let mut url_rewrite = None;
+            let mut auth_directive = AuthDirective::from(cfg.default_authn);
+            let mut policy = RoutePolicy::default();
 
-            let Some(backend_port) = backend_ref.port else {
-                continue;
-            };
-            let mut backend_class = BackendClass::Plain;
+            if let Some(filters) = &rule.filters {
+                let filter_types = filters
+                    .iter()
+                    .map(|filter| filter_type_name(&filter.r#type))
+                    .collect::<Vec<_>>();
+                validate_filter_types(&filter_types)?;
 
-            if let Some(filters) = &backend_ref.filters {
-                for filter in filters {
-                    // TODO: Support all core filters
-                    if let Some(ext) = &filter.extension_ref {
+                // filters are applied in declared order
+                for (filter, filter_type) in filters.iter().zip(&filter_types) {
+                    if let Some(rw) = &filter.url_rewrite {
+                        url_rewrite = Some(rw);
+                    } else if let Some(ext) = &filter.extension_ref {
                         if ext.group == "authly.id" {
                             match ext.name.as_str() {
-                                "mesh" => {
-                                    backend_class = BackendClass::AuthlyMesh;
+                                "authn" | "authn-mandatory" => {
+                                    auth_directive = AuthDirective::Mandatory;
+                                }
+                                "authn-opportunistic" => {
+                                    auth_directive = AuthDirective::Opportunistic;
+                                }
+                                "authn-disabled" => {
+                                    auth_directive = AuthDirective::Disabled;
                                 }
                                 _ => {
-                                    warn!(?ext.name, "invalid authly.id backend extension name");
+                                    warnings.push(format!(
+                                        "invalid authly.id HTTP route rule extension name: {}",
+                                        ext.name
+                                    ));
                                 }
                             }
+                        } else if ext.group == ARX_EXTENSION_GROUP && ext.kind == "RoutePolicy" {
+                            let Some(named_policy) = cfg.route_policies.get(&ext.name) else {
+                                return Err(anyhow!("route policy `{}` not found", ext.name));
+                            };
+                            policy.merge(named_policy);
+                        } else {
+                            unknown_extension_ref(
+                                &ext.group,
+                                &ext.kind,
+                                &ext.name,
+                                cfg,
+                                &mut warnings,
+                            )?;
                         }
+                    } else {
+                        warnings.push(format!("no support for {filter_type} filter"));
                     }
                 }
             }
 
-            if backend_port == 443 {
-                // Infer AuthlyMesh from the fact that the backend uses port 443.
-                backend_class = BackendClass::AuthlyMesh;
-            }
+            let Some(backend_refs) = &rule.backend_refs else {
+                continue;
+            };
+
+            let split_header = match &policy.split_by_header {
+                Some(header) => Some(
+                    HeaderName::from_str(header)
+                        .map_err(|_| anyhow!("invalid split header `{header}`"))?,
+                ),
+                None => None,
+            };
 
-            let backend_protocol = match (backend_port, backend_class) {
-                (443, _) | (_, BackendClass::AuthlyMesh) => "https",
-                _ => "http",
+            let backend_refs = match (backend_refs.len(), &split_header) {
+                (0, _) => continue,
+                (1, _) | (_, Some(_)) => backend_refs.as_slice(),
+                _ if policy.select_by_header.is_some() => backend_refs.as_slice(),
+                _ => {
+                    warnings.push(
+                        "no support for multiple backend refs yet, using just the first one"
+                            .to_string(),
+                    );
+                    &backend_refs[..1]
+                }
             };
 
-            let backend_uri = Uri::from_str(&format!(
-                "{protocol}://{name}:{port}",
-                protocol = backend_protocol,
-                name = backend_ref.name,
-                port = backend_port,
-            ))?;
+            let mut backends = Vec::with_capacity(backend_refs.len());
+            let mut backend_names = Vec::with_capacity(backend_refs.len());
+            for backend_ref in backend_refs {
+                let Some(backend) = backend_target(
+                    backend_ref,
+                    &namespace,
+                    backend_tls,
+                    &state.backend_clients,
+                    cfg,
+                    &mut warnings,
+                )?
+                else {
+                    continue;
+                };
+                backend_names.push(backend_ref.name.as_str());
+                backends.push(SplitBackend {
+                    // the Gateway API default weight is 1
+                    weight: backend_ref.weight.unwrap_or(1).max(0) as u32,
+                    backend,
+                });
+            }
 
-            let Some(matches) = &rule.matches else {
+            let Some(backend) = backends.first().map(|first| first.backend.clone()) else {
                 continue;
             };
-
-            for route_match in matches {
-                if let Some(_method) = &route_match.method {
-                    warn!(name, "no support for method match");
-                }
-                if let Some(_q) = &route_match.query_params {
-                    warn!(name, "no support for query_params match");
+            let header_selection = match &policy.select_by_header {
+                Some(selection) => Some(header_selection(selection, &backend_names, &backends)?),
+                None => None,
+            };
+            let traffic_split = match split_header {
+                Some(header) if backends.len() > 1 => {
+                    let traffic_split = TrafficSplit::new(header, backends);
+                    Some(match policy.slow_start {
+                        Some(window) => traffic_split
+                            .with_slow_start(window, |backend| state.backend_first_seen(backend)),
+                        None => traffic_split,
+                    })
                 }
+                _ => None,
+            };
 
-                let mut url_rewrite = None;
-                let mut auth_directive = AuthDirective::Disabled;
+            let json_field_removal = match policy.json_remove_fields.as_slice() {
+                [] => None,
+                paths => Some(JsonFieldRemoval::parse(paths)?),
+            };
+            let path_rewrite = match &policy.rewrite_path {
+                Some(rewrite) => Some(
+                    PathRewrite::new(&rewrite.pattern, &rewrite.replacement).map_err(|err| {
+                        anyhow!("invalid path rewrite pattern `{}`: {err}", rewrite.pattern)
+                    })?,
+                ),
+                None => None,
+            };
+            let link_rewrite =
+                (!policy.rewrite_links.is_empty()).then(|| LinkRewrite::new(&policy.rewrite_links));
+            let media_types = (!policy.request_content_types.is_empty()
+                || !policy.response_content_types.is_empty())
+            .then(|| MediaTypes {
+                request: policy.request_content_types.clone(),
+                response: policy.response_content_types.clone(),
+            });
 
-                if let Some(filters) = &rule.filters {
-                    for filter in filters {
-                        if let Some(rw) = &filter.url_rewrite {
-                            url_rewrite = Some(rw);
-                        }
+            let trailing_slash_redirect = policy
+                .trailing_slash_redirect
+                .unwrap_or(cfg.trailing_slash_redirect);
 
-                        if let Some(ext) = &filter.extension_ref {
-                            if ext.group == "authly.id" {
-                                match ext.name.as_str() {
-                                    "authn" | "authn-mandatory" => {
-                                        auth_directive = AuthDirective::Mandatory;
-                                    }
-                                    "authn-opportunistic" => {
-                                        auth_directive = AuthDirective::Opportunistic;
-                                    }
-                                    "authn-disabled" => {
-                                        auth_directive = AuthDirective::Disabled;
-                                    }
-                                    _ => {
-                                        warn!(?ext.name, "invalid authly.id HTTP route rule extension name");
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
+            let Some(matches) = &rule.matches else {
+                continue;
+            };
+
+            for route_match in matches {
+                let method = match &route_match.method {
+                    Some(method) => Some(to_http_method(method)),
+                    None => None,
+                };
+                let conditions = request_conditions(route_match)?;
 
                 if let Some(path) = &route_match.path {
                     let Some(value) = &path.value else {
                         continue;
                     };
+                    if !matches!(
+                        path.r#type,
+                        Some(HTTPRouteRulesMatchesPathType::RegularExpression)
+                    ) {
+                        validate_path(value)?;
+                    }
 
-                    let proxy = Proxy::from_backend_uri(backend_uri.clone())?
-                        .with_backend_class(backend_class);
+                    let proxy = Proxy::from_backend(backend.clone());
+                    let proxy = match method {
+                        Some(method) => proxy.with_method(method),
+                        None => proxy,
+                    };
+                    let proxy = match conditions {
+                        Some(conditions) => proxy.with_conditions(conditions),
+                        None => proxy,
+                    };
+                    let proxy = match &policy.ip_filter {
+                        Some(ip_filter) => proxy.with_ip_filter(ip_filter.clone()),
+                        None => proxy,
+                    };
+                    let proxy = match &policy.authly {
+                        Some(authly) if !cfg.authly_clusters.contains_key(authly) => {
+                            return Err(anyhow!("authly cluster `{authly}` not found"));
+                        }
+                        Some(authly) => proxy.with_authly_cluster(authly),
+                        None => proxy,
+                    };
+                    let proxy = match &traffic_split {
+                        Some(traffic_split) => proxy.with_traffic_split(traffic_split.clone()),
+                        None => proxy,
+                    };
+                    let proxy = match &header_selection {
+                        Some(header_selection) => {
+                            proxy.with_header_selection(header_selection.clone())
+                        }
+                        None => proxy,
+                    };
+                    let proxy = proxy.with_buffered(policy.buffered.unwrap_or(false));
+                    let proxy = proxy.with_body_digest(policy.require_body_digest.unwrap_or(false));
+                    let proxy = proxy.with_retries(policy.retries.unwrap_or(0));
+                    let proxy =
+                        proxy.with_grpc_status_to_http(policy.grpc_status_to_http.unwrap_or(false));
+                    let proxy =
+                        proxy.with_strip_set_cookie(policy.strip_set_cookie.unwrap_or(false));
+                    let proxy = proxy
+                        .with_forwarded_headers(!policy.omit_forwarded_headers.unwrap_or(false));
+                    let proxy = match &json_field_removal {
+                        Some(removal) => proxy.with_json_field_removal(removal.clone()),
+                        None => proxy,
+                    };
+                    let proxy = match &link_rewrite {
+                        Some(link_rewrite) => proxy.with_link_rewrite(link_rewrite.clone()),
+                        None => proxy,
+                    };
+                    let proxy = match &path_rewrite {
+                        Some(path_rewrite) => proxy.with_path_rewrite(path_rewrite.clone()),
+                        None => proxy,
+                    };
+                    let proxy = match &media_types {
+                        Some(media_types) => proxy.with_media_types(media_types.clone()),
+                        None => proxy,
+                    };
+                    let proxy = match policy.auth_timeout {
+                        Some(auth_timeout) => proxy.with_auth_timeout(auth_timeout),
+                        None => proxy,
+                    };
+                    let proxy = match policy.connect_timeout {
+                        Some(connect_timeout) => proxy.with_connect_timeout(connect_timeout),
+                        None => proxy,
+                    };
+                    let proxy = match policy.request_timeout {
+                        Some(request_timeout) => proxy.with_request_timeout(request_timeout),
+                        None => proxy,
+                    };
+                    let proxy = match policy.request_max_size {
+                        Some(request_max_size) => proxy.with_request_max_size(request_max_size),
+                        None => proxy,
+                    };
+                    let rule_key = format!(
+                        "{}/{}/{rule_index}",
+                        http_route.namespace().unwrap_or_default(),
+                        http_route.name().unwrap_or_default()
+                    );
+                    let proxy = match policy.max_in_flight {
+                        Some(max) => proxy.with_in_flight_limit(InFlightLimit {
+                            key: rule_key.clone(),
+                            max,
+                            wait: policy.max_in_flight_wait.unwrap_or_default(),
+                            queue: policy.max_in_flight_queue,
+                        }),
+                        None => proxy,
+                    };
+                    let proxy = match policy.max_websocket_tunnels {
+                        Some(max) => proxy.with_websocket_tunnel_limit(InFlightLimit {
+                            key: rule_key,
+                            max,
+                            wait: Duration::ZERO,
+                            queue: None,
+                        }),
+                        None => proxy,
+                    };
+                    let proxy = match (cfg.route_metrics, http_route.name()) {
+                        (true, Some(name)) => proxy.with_metrics(METRICS.route(&name)),
+                        _ => proxy,
+                    };
+                    for backend in proxy.backends() {
+                        add_backend(&mut additions.backends, backend.clone());
+                    }
                     let mut proxy = match auth_directive {
                         AuthDirective::Mandatory => {
                             proxy.with_auth_directive_fn(|_| AuthDirective::Mandatory)
@@ -249,32 +930,61 @@ pub fn try_add_http_route(
                         }
                     };
 
+                    let path_type = match path.r#type {
+                        None | Some(HTTPRouteRulesMatchesPathType::PathPrefix) => {
+                            Some("PathPrefix")
+                        }
+                        Some(HTTPRouteRulesMatchesPathType::Exact) => Some("Exact"),
+                        Some(HTTPRouteRulesMatchesPathType::RegularExpression) => None,
+                    };
+                    if let Some(path_type) = path_type {
+                        additions.listing.push(ListedRoute {
+                            route: format!("{namespace}/{}", http_route.name().unwrap_or_default()),
+                            path: value.clone(),
+                            path_type,
+                            method: proxy.method().map(ToString::to_string),
+                            headers: route_match
+                                .headers
+                                .iter()
+                                .flatten()
+                                .map(|header| ListedValueMatch {
+                                    name: header.name.clone(),
+                                    r#type: match_type_name(matches!(
+                                        header.r#type,
+                                        Some(HTTPRouteRulesMatchesHeadersType::RegularExpression)
+                                    )),
+                                    value: header.value.clone(),
+                                })
+                                .collect(),
+                            query_params: route_match
+                                .query_params
+                                .iter()
+                                .flatten()
+                                .map(|query_param| ListedValueMatch {
+                                    name: query_param.name.clone(),
+                                    r#type: match_type_name(matches!(
+                                        query_param.r#type,
+                                        Some(
+                                            HTTPRouteRulesMatchesQueryParamsType::RegularExpression
+                                        )
+                                    )),
+                                    value: query_param.value.clone(),
+                                })
+                                .collect(),
+                            backends: proxy
+                                .backends()
+                                .map(|backend| backend.uri.to_string())
+                                .collect(),
+                        });
+                    }
+
                     match path.r#type {
                         None | Some(HTTPRouteRulesMatchesPathType::PathPrefix) => {
-                            let prefix = if !value.ends_with('/') {
+                            let (prefix, unterminated) = if !value.ends_with('/') {
                                 // append a slash
-                                let terminated = format!("{value}/");
-                                try_insert_route(
-                                    output,
-                                    value,
-                                    Route::TemporaryRedirect(terminated.parse()?),
-                                );
-                                terminated
+                                (format!("{value}/"), value.as_str())
                             } else {
-                                // insert a redirect for missing slash
-                                let mut unterminated = value.as_str();
-                                while unterminated.ends_with('/') {
-                                    let mut chars = unterminated.chars();
-                                    chars.next_back();
-                                    unterminated = chars.as_str();
-                                }
-                                try_insert_route(
-                                    output,
-                                    unterminated,
-                                    Route::TemporaryRedirect(value.parse()?),
-                                );
-
-                                value.to_string()
+                                (value.to_string(), value.trim_end_matches('/'))
                             };
 
                             if let Some(url_rewrite) = url_rewrite {
@@ -290,18 +1000,22 @@ pub fn try_add_http_route(
                                 }
                             }
 
-                            try_insert_route(output, &prefix, Route::Proxy(proxy.clone()));
-                            try_insert_route(
-                                output,
-                                &format!("{prefix}{{*path}}"),
-                                Route::Proxy(proxy),
-                            );
+                            additions.proxy(&prefix, proxy.clone());
+                            additions.proxy(format!("{prefix}{{*path}}"), proxy.clone());
+
+                            if trailing_slash_redirect {
+                                // insert a redirect for missing slash
+                                additions.redirect(unterminated, prefix.parse()?);
+                            } else {
+                                additions.proxy(unterminated, proxy);
+                            }
                         }
                         Some(HTTPRouteRulesMatchesPathType::Exact) => {
-                            try_insert_route(output, value, Route::Proxy(proxy));
+                            additions.proxy(value, proxy);
                         }
                         Some(HTTPRouteRulesMatchesPathType::RegularExpression) => {
-                            warn!(name, "regular expression path match not supported");
+                            warnings
+                                .push("regular expression path match not supported".to_string());
                         }
                     }
                 }
@@ -309,41 +1023,410 @@ pub fn try_add_http_route(
         }
     }
 
-    Ok(())
+    Ok(warnings)
 }
 
-fn try_insert_route(output: &mut matchit::Router<Route>, path: &str, route: Route) {
-    if let Err(_e) = output.insert(path, route) {
-        info!(path, "not inserting route because already occupied");
+/// Map the header values of a `select_by_header` policy to the backends of a rule, by backendRef name
+fn header_selection(
+    selection: &HeaderBackendSelection,
+    backend_names: &[&str],
+    backends: &[SplitBackend],
+) -> anyhow::Result<HeaderSelection> {
+    let header = HeaderName::from_str(&selection.header)
+        .map_err(|_| anyhow!("invalid selection header `{}`", selection.header))?;
+
+    let mut selected = HashMap::with_capacity(selection.backends.len());
+    for (value, name) in &selection.backends {
+        let value = HeaderValue::from_str(value)
+            .map_err(|_| anyhow!("invalid selection header value `{value}`"))?;
+        let Some(index) = backend_names.iter().position(|backend| backend == name) else {
+            return Err(anyhow!("selected backendRef `{name}` not found"));
+        };
+        selected.insert(value, backends[index].backend.clone());
     }
+
+    Ok(HeaderSelection::new(header, selected))
 }
 
-#[cfg(test)]
-mod tests {
-    use indoc::indoc;
+/// The backend referenced by a rule, if it has a port
+fn backend_target(
+    backend_ref: &HTTPRouteRulesBackendRefs,
+    route_namespace: &str,
+    backend_tls_policies: &BackendTlsPolicies,
+    clients: &BackendClients,
+    cfg: &ArxConfig,
+    warnings: &mut Vec<String>,
+) -> anyhow::Result<Option<Backend>> {
+    let Some(backend_port) = backend_ref.port else {
+        return Ok(None);
+    };
+    let mut backend_class = BackendClass::Plain;
+    let mut backend_policy = BackendPolicy::default();
 
-    use super::*;
+    if let Some(filters) = &backend_ref.filters {
+        for filter in filters {
+            // TODO: Support all core filters
+            if let Some(ext) = &filter.extension_ref {
+                if ext.group == "authly.id" {
+                    match ext.name.as_str() {
+                        "mesh" => {
+                            backend_class = BackendClass::AuthlyMesh;
+                        }
+                        _ => {
+                            warnings.push(format!(
+                                "invalid authly.id backend extension name: {}",
+                                ext.name
+                            ));
+                        }
+                    }
+                } else if ext.group == ARX_EXTENSION_GROUP && ext.kind == "BackendPolicy" {
+                    let Some(named_policy) = cfg.backend_policies.get(&ext.name) else {
+                        return Err(anyhow!("backend policy `{}` not found", ext.name));
+                    };
+                    backend_policy = named_policy.clone();
+                } else {
+                    unknown_extension_ref(&ext.group, &ext.kind, &ext.name, cfg, warnings)?;
+                }
+            }
+        }
+    }
 
-    fn build_test_routing(yamls: Vec<&'static str>) -> matchit::Router<Route> {
-        let routes: Vec<HTTPRoute> = yamls
-            .iter()
-            .map(|yaml| serde_yaml::from_str(yaml).unwrap())
-            .collect();
+    // with BackendTLSPolicies, TLS is explicit
+    let infer_tls = !cfg.backend_tls_policies;
+    let backend_tls = if cfg.backend_tls_policies {
+        let namespace = backend_ref.namespace.as_deref().unwrap_or(route_namespace);
+        backend_tls_policies.get(namespace, &backend_ref.name)
+    } else {
+        None
+    };
 
-        let routes = routes
-            .into_iter()
-            .filter_map(filter_k8s_http_route)
-            .collect();
+    if backend_port == 443 && infer_tls {
+        // Infer AuthlyMesh from the fact that the backend uses port 443.
+        backend_class = BackendClass::AuthlyMesh;
+    }
+
+    let tls = backend_policy.tls || backend_policy.server_name.is_some() || backend_tls.is_some();
+    let backend_protocol = match (backend_port, backend_class) {
+        (443, _) if infer_tls => "https",
+        (_, BackendClass::AuthlyMesh) => "https",
+        _ if tls => "https",
+        _ => "http",
+    };
+
+    // the server name of a BackendPolicy, which is chosen per route, takes precedence
+    let server_name = backend_policy
+        .server_name
+        .or_else(|| backend_tls.map(|backend_tls| backend_tls.hostname.clone()));
 
-        rebuild_routing_table(&routes, reqwest::Client::new()).unwrap()
+    // the server name takes the place of the backend name in the URI, and the backend is connected to instead
+    let (host, connect_host) = match server_name {
+        Some(server_name) => (server_name, Some(backend_ref.name.clone())),
+        None => (backend_ref.name.clone(), None),
+    };
+
+    let backend_uri = Uri::from_str(&format!(
+        "{protocol}://{host}:{port}",
+        protocol = backend_protocol,
+        port = backend_port,
+    ))?;
+
+    if connect_host.is_some() && matches!(backend_class, BackendClass::AuthlyMesh) {
+        return Err(anyhow!(
+            "a server name can't be used with an Authly mesh backend"
+        ));
     }
 
-    #[test]
-    fn simple_route() {
-        let matchit_router = build_test_routing(vec![indoc! {
-            "
-            metadata:
-              name: test
+    let mut backend = Backend {
+        uri: backend_uri,
+        class: backend_class,
+        connect_host,
+        ca_certificates: backend_tls.and_then(|backend_tls| backend_tls.ca_certificates.clone()),
+        client: None,
+        http10: backend_policy.http10,
+    };
+    backend.client = clients
+        .get_or_create(&backend, cfg)
+        .map_err(|err| anyhow!("invalid backend client: {err}"))?;
+
+    Ok(Some(backend))
+}
+
+/// Report an extensionRef filter of an unrecognized group or kind,
+/// as a warning or, with `reject_unknown_extension_refs`, an error rejecting the route.
+fn unknown_extension_ref(
+    group: &str,
+    kind: &str,
+    name: &str,
+    cfg: &ArxConfig,
+    warnings: &mut Vec<String>,
+) -> anyhow::Result<()> {
+    let message = format!("unknown extensionRef {kind} `{name}` of group `{group}`");
+    if cfg.reject_unknown_extension_refs {
+        return Err(anyhow!(message));
+    }
+    warnings.push(message);
+    Ok(())
+}
+
+/// Check a path match value as the Gateway API CRD validation would, which the typed HTTPRoute doesn't enforce.
+///
+/// A valid path is an absolute URI path without braces, which would be taken for route parameters.
+fn validate_path(value: &str) -> anyhow::Result<()> {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || "-/._~!$&'()*+,;=:@%".contains(c);
+    if !value.starts_with('/') || !value.chars().all(valid_char) || value.parse::<Uri>().is_err() {
+        return Err(anyhow!("invalid path `{value}`"));
+    }
+    Ok(())
+}
+
+/// Validate the filter types of a rule according to the Gateway API:
+/// Filter types may not be repeated, except `RequestMirror` and `ExtensionRef`,
+/// and `URLRewrite` can not be combined with `RequestRedirect`.
+fn validate_filter_types(filter_types: &[&str]) -> anyhow::Result<()> {
+    for (index, filter_type) in filter_types.iter().enumerate() {
+        if matches!(*filter_type, "RequestMirror" | "ExtensionRef") {
+            continue;
+        }
+
+        if filter_types[..index].contains(filter_type) {
+            return Err(anyhow!("duplicate {filter_type} filter"));
+        }
+    }
+
+    let has_type = |wanted: &str| filter_types.contains(&wanted);
+    if has_type("URLRewrite") && has_type("RequestRedirect") {
+        return Err(anyhow!(
+            "URLRewrite and RequestRedirect filters can not be combined"
+        ));
+    }
+
+    Ok(())
+}
+
+fn try_insert_route(output: &mut matchit::Router<Route>, path: &str, route: Route) {
+    if let Err(_e) = output.insert(path, route) {
+        info!(path, "not inserting route because already occupied");
+    }
+}
+
+/// Insert a redirect, unless it would lead to a redirect loop.
+fn try_insert_redirect(
+    output: &mut matchit::Router<Route>,
+    path: &str,
+    target: Uri,
+    warnings: &mut Vec<String>,
+) {
+    if redirect_loops(output, path, &target) {
+        warnings.push(format!(
+            "dropping redirect from {path} to {target}, it would cause a redirect loop"
+        ));
+        return;
+    }
+
+    try_insert_route(output, path, Route::TemporaryRedirect(target));
+}
+
+/// Insert a proxy route, or add it as an alternative if the path is already proxied.
+fn try_insert_proxy(output: &mut matchit::Router<Route>, path: &str, proxy: Proxy) {
+    match output.remove(path) {
+        None => try_insert_route(output, path, Route::Proxy(proxy)),
+        Some(Route::Proxy(existing)) => {
+            try_insert_route(
+                output,
+                path,
+                Route::ProxyAlternatives(sorted_alternatives(vec![existing, proxy])),
+            );
+        }
+        Some(Route::ProxyAlternatives(mut alternatives)) => {
+            alternatives.push(proxy);
+            try_insert_route(
+                output,
+                path,
+                Route::ProxyAlternatives(sorted_alternatives(alternatives)),
+            );
+        }
+        Some(occupied) => {
+            try_insert_route(output, path, occupied);
+            info!(path, "not inserting route because already occupied");
+        }
+    }
+}
+
+/// Order proxies for the same path by the precedence of their matches, most specific first.
+/// Proxies with equally specific matches keep the order they were declared in.
+fn sorted_alternatives(mut alternatives: Vec<Proxy>) -> Vec<Proxy> {
+    alternatives.sort_by_key(|proxy| Reverse(proxy.match_specificity()));
+    alternatives
+}
+
+/// The header and query parameter conditions of a Gateway API match, if any
+fn request_conditions(
+    route_match: &HTTPRouteRulesMatches,
+) -> anyhow::Result<Option<RequestConditions>> {
+    let mut conditions = RequestConditions::default();
+
+    for header in route_match.headers.iter().flatten() {
+        let name = HeaderName::from_str(&header.name)
+            .map_err(|_| anyhow!("invalid header match name `{}`", header.name))?;
+        conditions = conditions.with_header(
+            name,
+            value_match(
+                matches!(
+                    header.r#type,
+                    Some(HTTPRouteRulesMatchesHeadersType::RegularExpression)
+                ),
+                &header.value,
+            )?,
+        );
+    }
+    for query_param in route_match.query_params.iter().flatten() {
+        conditions = conditions.with_query_param(
+            query_param.name.clone(),
+            value_match(
+                matches!(
+                    query_param.r#type,
+                    Some(HTTPRouteRulesMatchesQueryParamsType::RegularExpression)
+                ),
+                &query_param.value,
+            )?,
+        );
+    }
+
+    Ok((!conditions.is_empty()).then_some(conditions))
+}
+
+/// A header or query parameter value match, `Exact` unless a `RegularExpression`
+fn value_match(regular_expression: bool, value: &str) -> anyhow::Result<ValueMatch> {
+    if regular_expression {
+        ValueMatch::regular_expression(value)
+            .map_err(|err| anyhow!("invalid regular expression `{value}`: {err}"))
+    } else {
+        Ok(ValueMatch::Exact(value.to_string()))
+    }
+}
+
+fn match_type_name(regular_expression: bool) -> &'static str {
+    if regular_expression {
+        "RegularExpression"
+    } else {
+        "Exact"
+    }
+}
+
+fn to_http_method(method: &HTTPRouteRulesMatchesMethod) -> http::Method {
+    match method {
+        HTTPRouteRulesMatchesMethod::Get => http::Method::GET,
+        HTTPRouteRulesMatchesMethod::Head => http::Method::HEAD,
+        HTTPRouteRulesMatchesMethod::Post => http::Method::POST,
+        HTTPRouteRulesMatchesMethod::Put => http::Method::PUT,
+        HTTPRouteRulesMatchesMethod::Delete => http::Method::DELETE,
+        HTTPRouteRulesMatchesMethod::Connect => http::Method::CONNECT,
+        HTTPRouteRulesMatchesMethod::Options => http::Method::OPTIONS,
+        HTTPRouteRulesMatchesMethod::Trace => http::Method::TRACE,
+        HTTPRouteRulesMatchesMethod::Patch => http::Method::PATCH,
+    }
+}
+
+/// The Gateway API name of a filter type, e.g. `URLRewrite`.
+fn filter_type_name(filter_type: &HTTPRouteRulesFiltersType) -> &'static str {
+    match filter_type {
+        HTTPRouteRulesFiltersType::RequestHeaderModifier => "RequestHeaderModifier",
+        HTTPRouteRulesFiltersType::ResponseHeaderModifier => "ResponseHeaderModifier",
+        HTTPRouteRulesFiltersType::RequestMirror => "RequestMirror",
+        HTTPRouteRulesFiltersType::RequestRedirect => "RequestRedirect",
+        HTTPRouteRulesFiltersType::UrlRewrite => "URLRewrite",
+        HTTPRouteRulesFiltersType::ExtensionRef => "ExtensionRef",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::{formatdoc, indoc};
+
+    use std::{
+        convert::Infallible,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use bytes::Bytes;
+    use http_body_util::Full;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::{
+        config::{Authn, IpFilter},
+        route::MAX_REDIRECT_CHAIN,
+        static_routes::admin_routes,
+        testing::{
+            leak_config, spawn_admin, spawn_gateway, spawn_gateway_with_shared_routes, TestCa,
+        },
+    };
+
+    use super::*;
+
+    fn build_test_routing(yamls: Vec<&'static str>) -> matchit::Router<Route> {
+        build_test_routing_with_config(yamls, &ArxConfig::default())
+    }
+
+    fn build_test_routing_with_config(yamls: Vec<&str>, cfg: &ArxConfig) -> matchit::Router<Route> {
+        let routes: Vec<HTTPRoute> = yamls
+            .iter()
+            .map(|yaml| serde_yaml::from_str(yaml).unwrap())
+            .collect();
+
+        let routes = routes
+            .into_iter()
+            .filter_map(filter_k8s_http_route)
+            .collect();
+
+        rebuild_routing_table(
+            &routes,
+            &Default::default(),
+            &Default::default(),
+            reqwest::Client::new(),
+            cfg,
+        )
+        .unwrap()
+        .routes
+    }
+
+    fn build_test_statuses(yamls: Vec<&'static str>) -> HashMap<String, RouteStatus> {
+        let routes: Vec<HTTPRoute> = yamls
+            .iter()
+            .map(|yaml| serde_yaml::from_str(yaml).unwrap())
+            .collect();
+
+        let routes = routes
+            .into_iter()
+            .filter_map(filter_k8s_http_route)
+            .collect();
+
+        build_test_statuses_with_config(&routes, &ArxConfig::default())
+    }
+
+    fn build_test_statuses_with_config(
+        routes: &HashMap<String, HTTPRoute>,
+        cfg: &ArxConfig,
+    ) -> HashMap<String, RouteStatus> {
+        rebuild_routing_table(
+            routes,
+            &Default::default(),
+            &Default::default(),
+            reqwest::Client::new(),
+            cfg,
+        )
+        .unwrap()
+        .statuses
+    }
+
+    #[test]
+    fn simple_route() {
+        let matchit_router = build_test_routing(vec![indoc! {
+            "
+            metadata:
+              name: test
             spec:
               parentRefs:
                 - name: arx
@@ -436,4 +1519,1563 @@ mod tests {
 
         assert_eq!(Some("/api/auth/"), proxy.replace_prefix());
     }
+
+    #[tokio::test]
+    async fn method_not_allowed() {
+        let matchit_router = build_test_routing(vec![indoc! {
+            "
+            metadata:
+              name: test
+            spec:
+              parentRefs:
+                - name: arx
+              rules:
+                - matches:
+                    - path:
+                        value: /api
+                      method: GET
+                    - path:
+                        value: /api
+                      method: PUT
+                  backendRefs:
+                    - name: api
+                      port: 80
+            "
+        }]);
+
+        let Ok(matchit::Match {
+            value: Route::ProxyAlternatives(proxies),
+            ..
+        }) = matchit_router.at("/api/")
+        else {
+            panic!()
+        };
+        assert_eq!(2, proxies.len());
+
+        let cfg = leak_config(Default::default());
+        let addr = spawn_gateway(cfg, matchit_router).await;
+
+        let response = reqwest::Client::new()
+            .delete(format!("http://{addr}/api/"))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(405, response.status().as_u16());
+        assert_eq!(
+            response.headers().get(http::header::ALLOW).unwrap(),
+            "GET, HEAD, PUT"
+        );
+    }
+
+    fn matching_proxies<'r>(
+        routes: &'r matchit::Router<Route>,
+        req: &http::Request<()>,
+    ) -> Vec<&'r Proxy> {
+        let proxies = match routes.at(req.uri().path()).map(|matchit| matchit.value) {
+            Ok(Route::Proxy(proxy)) => std::slice::from_ref(proxy),
+            Ok(Route::ProxyAlternatives(proxies)) => proxies.as_slice(),
+            _ => &[],
+        };
+        proxies
+            .iter()
+            .filter(|proxy| proxy.matches_request(req))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn match_combines_path_header_and_method() {
+        let routes = build_test_routing(vec![indoc! {
+            "
+            metadata:
+              name: test
+            spec:
+              parentRefs:
+                - name: arx
+              rules:
+                - matches:
+                    - path:
+                        value: /api
+                      method: POST
+                      headers:
+                        - name: X-Version
+                          value: \"2\"
+                        - name: x-tenant
+                          type: RegularExpression
+                          value: \"[a-z]+\"
+                  backendRefs:
+                    - name: api
+                      port: 80
+            "
+        }]);
+
+        let request = |method: &str, path: &str, headers: &[(&str, &str)]| {
+            let mut request = http::Request::builder().method(method).uri(path);
+            for (name, value) in headers {
+                request = request.header(*name, *value);
+            }
+            request.body(()).unwrap()
+        };
+        let all = [("x-version", "2"), ("x-tenant", "acme")];
+
+        assert_eq!(
+            1,
+            matching_proxies(&routes, &request("POST", "/api/items", &all)).len()
+        );
+        // each condition must hold
+        assert!(matching_proxies(&routes, &request("GET", "/api/items", &all)).is_empty());
+        assert!(matching_proxies(&routes, &request("POST", "/other", &all)).is_empty());
+        assert!(matching_proxies(
+            &routes,
+            &request("POST", "/api/items", &[("x-tenant", "acme")])
+        )
+        .is_empty());
+        assert!(matching_proxies(
+            &routes,
+            &request(
+                "POST",
+                "/api/items",
+                &[("x-version", "3"), ("x-tenant", "acme")]
+            )
+        )
+        .is_empty());
+        // the regular expression matches the whole value
+        assert!(matching_proxies(
+            &routes,
+            &request(
+                "POST",
+                "/api/items",
+                &[("x-version", "2"), ("x-tenant", "acme-1")]
+            )
+        )
+        .is_empty());
+
+        // a request not meeting the header conditions matches no route, whatever its method
+        let addr = spawn_gateway(leak_config(Default::default()), routes).await;
+        let response = reqwest::Client::new()
+            .post(format!("http://{addr}/api/items"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(404, response.status().as_u16());
+        let response = reqwest::Client::new()
+            .get(format!("http://{addr}/api/items"))
+            .header("x-version", "2")
+            .header("x-tenant", "acme")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(405, response.status().as_u16());
+    }
+
+    #[test]
+    fn any_of_several_matches() {
+        let routes = build_test_routing(vec![indoc! {
+            "
+            metadata:
+              name: test
+            spec:
+              parentRefs:
+                - name: arx
+              rules:
+                - matches:
+                    - path:
+                        value: /app
+                      headers:
+                        - name: x-canary
+                          value: \"true\"
+                    - path:
+                        value: /app
+                      queryParams:
+                        - name: beta
+                          value: \"1\"
+                  backendRefs:
+                    - name: canary
+                      port: 80
+                - matches:
+                    - path:
+                        value: /app
+                  backendRefs:
+                    - name: stable
+                      port: 80
+            "
+        }]);
+
+        let backend_host = |req: http::Request<()>| {
+            matching_proxies(&routes, &req)
+                .first()
+                .map(|proxy| proxy.select_backend(&req).uri.host().unwrap().to_string())
+        };
+
+        let canary = Some("canary".to_string());
+        let stable = Some("stable".to_string());
+        assert_eq!(
+            canary,
+            backend_host(
+                http::Request::get("/app/")
+                    .header("x-canary", "true")
+                    .body(())
+                    .unwrap()
+            )
+        );
+        assert_eq!(
+            canary,
+            backend_host(http::Request::get("/app/?beta=1").body(()).unwrap())
+        );
+        assert_eq!(
+            stable,
+            backend_host(http::Request::get("/app/?beta=2").body(()).unwrap())
+        );
+        assert_eq!(
+            stable,
+            backend_host(http::Request::get("/app/").body(()).unwrap())
+        );
+    }
+
+    #[test]
+    fn duplicate_url_rewrite_rejected() {
+        let yaml = indoc! {
+            "
+            metadata:
+              name: test
+            spec:
+              parentRefs:
+                - name: arx
+              rules:
+                - matches:
+                    - path:
+                        value: /api
+                  filters:
+                    - type: URLRewrite
+                      urlRewrite:
+                        path:
+                          type: ReplacePrefixMatch
+                          replacePrefixMatch: /
+                    - type: URLRewrite
+                      urlRewrite:
+                        path:
+                          type: ReplacePrefixMatch
+                          replacePrefixMatch: /v2
+                  backendRefs:
+                    - name: api
+                      port: 80
+            "
+        };
+
+        let statuses = build_test_statuses(vec![yaml]);
+        let Some(RouteStatus::Rejected { reason, message }) = statuses.get("test") else {
+            panic!("{statuses:?}");
+        };
+        assert_eq!("UnsupportedValue", *reason);
+        assert_eq!("duplicate URLRewrite filter", message.as_str());
+
+        let matchit_router = build_test_routing(vec![yaml]);
+        assert!(matchit_router.at("/api/").is_err());
+    }
+
+    #[test]
+    fn backend_tls_policy() {
+        let yaml = indoc! {
+            "
+            metadata:
+              name: test
+            spec:
+              parentRefs:
+                - name: arx
+              rules:
+                - matches:
+                    - path:
+                        value: /tls
+                  backendRefs:
+                    - name: secure
+                      port: 8443
+                      filters:
+                        - type: ExtensionRef
+                          extensionRef:
+                            group: arx.protojour.com
+                            kind: BackendPolicy
+                            name: tls
+                - matches:
+                    - path:
+                        value: /sni
+                  backendRefs:
+                    - name: secure
+                      port: 8443
+                      filters:
+                        - type: ExtensionRef
+                          extensionRef:
+                            group: arx.protojour.com
+                            kind: BackendPolicy
+                            name: sni
+                - matches:
+                    - path:
+                        value: /plain
+                  backendRefs:
+                    - name: secure
+                      port: 8443
+            "
+        };
+
+        let cfg = ArxConfig {
+            backend_policies: [
+                (
+                    "tls".to_string(),
+                    BackendPolicy {
+                        tls: true,
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "sni".to_string(),
+                    BackendPolicy {
+                        server_name: Some("secure.example.com".to_string()),
+                        ..Default::default()
+                    },
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+        let routes = build_test_routing_with_config(vec![yaml], &cfg);
+        let req = http::Request::get("/").body(()).unwrap();
+
+        let backend = |path: &str| {
+            let Route::Proxy(proxy) = routes.at(path).unwrap().value else {
+                panic!("not a proxy");
+            };
+            proxy.select_backend(&req).clone()
+        };
+
+        let tls = backend("/tls/");
+        assert_eq!("https://secure:8443/", tls.uri.to_string());
+        assert!(matches!(tls.class, BackendClass::Plain));
+        assert_eq!(None, tls.connect_host);
+
+        let sni = backend("/sni/");
+        assert_eq!("https://secure.example.com:8443/", sni.uri.to_string());
+        assert_eq!(Some("secure"), sni.connect_host.as_deref());
+
+        assert_eq!("http://secure:8443/", backend("/plain/").uri.to_string());
+    }
+
+    const TEST_CA: &str = indoc! {"
+        -----BEGIN CERTIFICATE-----
+        MIIBezCCASGgAwIBAgIUH+XllF26AQ7hA7/zZD7KA40ornQwCgYIKoZIzj0EAwIw
+        EjEQMA4GA1UEAwwHVGVzdCBDQTAgFw0yNjEwMTYxOTQ2MjFaGA8yMTI2MDkyMjE5
+        NDYyMVowEjEQMA4GA1UEAwwHVGVzdCBDQTBZMBMGByqGSM49AgEGCCqGSM49AwEH
+        A0IABIJ9IHE0WruPEsHVbS8zYzgf5M2oanjTU+zeb3WJu0y4G3lmpL8PiTu16LM6
+        fWXjjMroKrxSLoACAFw19+rezE2jUzBRMB0GA1UdDgQWBBQFHboY3xtbw1DAt2+/
+        W+YsEpMQLTAfBgNVHSMEGDAWgBQFHboY3xtbw1DAt2+/W+YsEpMQLTAPBgNVHRMB
+        Af8EBTADAQH/MAoGCCqGSM49BAMCA0gAMEUCIQCZHOGfWeC0zEP2j2ZOXqYKnltQ
+        IlNK8W40o9vB1jBeAgIgNLPBivyzXq1YO2KhEUCwJ3k8TUk6Fddo7XAMHKwhI2w=
+        -----END CERTIFICATE-----
+    "};
+
+    #[test]
+    fn backend_tls_policy_applied() {
+        let policy: BackendTLSPolicy = serde_yaml::from_str(indoc! {
+            "
+            apiVersion: gateway.networking.k8s.io/v1alpha3
+            kind: BackendTLSPolicy
+            metadata:
+              name: secure-tls
+              namespace: default
+            spec:
+              targetRefs:
+                - group: ''
+                  kind: Service
+                  name: secure
+              validation:
+                caCertificateRefs:
+                  - group: ''
+                    kind: ConfigMap
+                    name: secure-ca
+                hostname: secure.example.com
+            "
+        })
+        .unwrap();
+        let mut backend_tls = BackendTlsPolicies::default();
+        backend_tls
+            .insert(&policy, vec![TEST_CA.to_string()])
+            .unwrap();
+
+        let http_route: HTTPRoute = serde_yaml::from_str(indoc! {
+            "
+            metadata:
+              name: test
+              namespace: default
+            spec:
+              parentRefs:
+                - name: arx
+              rules:
+                - matches:
+                    - path:
+                        value: /secure
+                  backendRefs:
+                    - name: secure
+                      port: 443
+                - matches:
+                    - path:
+                        value: /plain
+                  backendRefs:
+                    - name: plain
+                      port: 443
+            "
+        })
+        .unwrap();
+
+        let cfg = leak_config(ArxConfig {
+            backend_tls_policies: true,
+            ..Default::default()
+        });
+        let routes = rebuild_routing_table(
+            &[("test".to_string(), http_route)].into_iter().collect(),
+            &backend_tls,
+            &Default::default(),
+            reqwest::Client::new(),
+            cfg,
+        )
+        .unwrap()
+        .routes;
+        let req = http::Request::get("/").body(()).unwrap();
+
+        let backend = |path: &str| {
+            let Route::Proxy(proxy) = routes.at(path).unwrap().value else {
+                panic!("not a proxy");
+            };
+            proxy.select_backend(&req).clone()
+        };
+
+        let secure = backend("/secure/");
+        assert_eq!("https://secure.example.com:443/", secure.uri.to_string());
+        assert!(matches!(secure.class, BackendClass::Plain));
+        assert_eq!(Some("secure"), secure.connect_host.as_deref());
+        let ca_certificates = secure.ca_certificates.unwrap();
+        assert_eq!(TEST_CA, &*ca_certificates);
+        assert!(secure.client.is_some());
+
+        // port 443 no longer implies TLS
+        let plain = backend("/plain/");
+        assert_eq!("http://plain:443/", plain.uri.to_string());
+        assert_eq!(None, plain.ca_certificates);
+    }
+
+    #[test]
+    fn departed_backends_forgotten() {
+        let backend = |uri: &str| Backend {
+            uri: uri.parse().unwrap(),
+            class: BackendClass::Plain,
+            connect_host: None,
+            ca_certificates: None,
+            client: None,
+            http10: false,
+        };
+        let state = RoutingState::default();
+
+        let first_seen = state.backend_first_seen(&backend("http://app:8080"));
+        state.retain_used(&[backend("http://app:8080")]);
+        assert_eq!(
+            first_seen,
+            state.backend_first_seen(&backend("http://app:8080"))
+        );
+
+        // a backend coming back starts slow again
+        state.retain_used(&[backend("http://other:8080")]);
+        assert!(state.first_seen.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn backend_tls_policy_handshake() {
+        let ca = TestCa::new();
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = backend.local_addr().unwrap().port();
+        let server_names = ca.spawn_backend(backend, "secure.example.com", "secure");
+
+        let policy: BackendTLSPolicy = serde_yaml::from_str(indoc! {
+            "
+            apiVersion: gateway.networking.k8s.io/v1alpha3
+            kind: BackendTLSPolicy
+            metadata:
+              name: secure-tls
+              namespace: default
+            spec:
+              targetRefs:
+                - group: ''
+                  kind: Service
+                  name: localhost
+              validation:
+                caCertificateRefs:
+                  - group: ''
+                    kind: ConfigMap
+                    name: secure-ca
+                hostname: secure.example.com
+            "
+        })
+        .unwrap();
+        let mut backend_tls = BackendTlsPolicies::default();
+        backend_tls.insert(&policy, vec![ca.pem()]).unwrap();
+
+        let http_route: HTTPRoute = serde_yaml::from_str(&formatdoc! {
+            "
+            metadata:
+              name: test
+              namespace: default
+            spec:
+              parentRefs:
+                - name: arx
+              rules:
+                - matches:
+                    - path:
+                        value: /secure
+                  backendRefs:
+                    - name: localhost
+                      port: {port}
+            "
+        })
+        .unwrap();
+
+        let cfg = leak_config(ArxConfig {
+            backend_tls_policies: true,
+            ..Default::default()
+        });
+        let state = RoutingState::default();
+        let routes = rebuild_routing_table(
+            &[("test".to_string(), http_route)].into_iter().collect(),
+            &backend_tls,
+            &state,
+            reqwest::Client::new(),
+            cfg,
+        )
+        .unwrap()
+        .routes;
+        let addr = spawn_gateway(cfg, routes).await;
+
+        // the backend is connected to by its Service name, and verified by the policy's hostname and CA
+        let response = reqwest::get(format!("http://{addr}/secure")).await.unwrap();
+        assert_eq!(200, response.status().as_u16());
+        assert_eq!("secure", response.text().await.unwrap());
+        assert_eq!(
+            vec!["secure.example.com".to_string()],
+            *server_names.lock().unwrap()
+        );
+
+        // the client is dropped once no route uses the policy
+        rebuild_routing_table(
+            &Default::default(),
+            &backend_tls,
+            &state,
+            reqwest::Client::new(),
+            cfg,
+        )
+        .unwrap();
+        assert!(state.backend_clients.is_empty());
+    }
+
+    #[tokio::test]
+    async fn backends_sharing_server_name_have_own_connections() {
+        let ca = TestCa::new();
+        let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = first.local_addr().unwrap().port();
+        let second = TcpListener::bind(("127.0.0.2", port)).await.unwrap();
+        let first_server_names = ca.spawn_backend(first, "secure.example.com", "first");
+        let second_server_names = ca.spawn_backend(second, "secure.example.com", "second");
+
+        let policy: BackendTLSPolicy = serde_yaml::from_str(indoc! {
+            "
+            apiVersion: gateway.networking.k8s.io/v1alpha3
+            kind: BackendTLSPolicy
+            metadata:
+              name: secure-tls
+              namespace: default
+            spec:
+              targetRefs:
+                - group: ''
+                  kind: Service
+                  name: 127.0.0.1
+                - group: ''
+                  kind: Service
+                  name: 127.0.0.2
+              validation:
+                caCertificateRefs:
+                  - group: ''
+                    kind: ConfigMap
+                    name: secure-ca
+                hostname: secure.example.com
+            "
+        })
+        .unwrap();
+        let mut backend_tls = BackendTlsPolicies::default();
+        backend_tls.insert(&policy, vec![ca.pem()]).unwrap();
+
+        let http_route: HTTPRoute = serde_yaml::from_str(&formatdoc! {
+            "
+            metadata:
+              name: test
+              namespace: default
+            spec:
+              parentRefs:
+                - name: arx
+              rules:
+                - matches:
+                    - path:
+                        value: /first
+                  backendRefs:
+                    - name: 127.0.0.1
+                      port: {port}
+                - matches:
+                    - path:
+                        value: /second
+                  backendRefs:
+                    - name: 127.0.0.2
+                      port: {port}
+            "
+        })
+        .unwrap();
+
+        let cfg = leak_config(ArxConfig {
+            backend_tls_policies: true,
+            ..Default::default()
+        });
+        let routes = rebuild_routing_table(
+            &[("test".to_string(), http_route)].into_iter().collect(),
+            &backend_tls,
+            &Default::default(),
+            reqwest::Client::new(),
+            cfg,
+        )
+        .unwrap()
+        .routes;
+        let addr = spawn_gateway(cfg, routes).await;
+
+        // the backends share a URI, so a shared pool would reuse the first backend's connection
+        for _ in 0..2 {
+            for backend in ["first", "second"] {
+                let response = reqwest::get(format!("http://{addr}/{backend}"))
+                    .await
+                    .unwrap();
+                assert_eq!(200, response.status().as_u16());
+                assert_eq!(backend, response.text().await.unwrap());
+            }
+        }
+
+        for server_names in [first_server_names, second_server_names] {
+            let server_names = server_names.lock().unwrap();
+            assert!(!server_names.is_empty());
+            assert!(server_names.iter().all(|name| name == "secure.example.com"));
+        }
+    }
+
+    #[test]
+    fn trailing_slash_redirect_policy() {
+        let yaml = indoc! {
+            "
+            metadata:
+              name: test
+            spec:
+              parentRefs:
+                - name: arx
+              rules:
+                - matches:
+                    - path:
+                        value: /api
+                  filters:
+                    - type: ExtensionRef
+                      extensionRef:
+                        group: arx.protojour.com
+                        kind: RoutePolicy
+                        name: no-redirect
+                  backendRefs:
+                    - name: api
+                      port: 80
+                - matches:
+                    - path:
+                        value: /web
+                  backendRefs:
+                    - name: web
+                      port: 80
+            "
+        };
+
+        let cfg = ArxConfig {
+            route_policies: [(
+                "no-redirect".to_string(),
+                RoutePolicy {
+                    trailing_slash_redirect: Some(false),
+                    ..Default::default()
+                },
+            )]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+        let matchit_router = build_test_routing_with_config(vec![yaml], &cfg);
+
+        assert!(matches!(
+            matchit_router.at("/api").unwrap().value,
+            Route::Proxy(_)
+        ));
+        assert!(matches!(
+            matchit_router.at("/api/").unwrap().value,
+            Route::Proxy(_)
+        ));
+        assert!(matches!(
+            matchit_router.at("/web").unwrap().value,
+            Route::TemporaryRedirect(_)
+        ));
+
+        let statuses = build_test_statuses(vec![yaml]);
+        let Some(RouteStatus::Rejected { message, .. }) = statuses.get("test") else {
+            panic!("{statuses:?}");
+        };
+        assert_eq!("route policy `no-redirect` not found", message.as_str());
+    }
+
+    #[test]
+    fn check_flags_rejected_routes() {
+        let valid = indoc! {
+            "
+            metadata:
+              name: valid
+            spec:
+              parentRefs:
+                - name: arx
+              rules:
+                - matches:
+                    - path:
+                        type: PathPrefix
+                        value: /valid
+                  backendRefs:
+                    - name: valid
+                      port: 80
+            "
+        };
+        let invalid = indoc! {
+            "
+            metadata:
+              name: invalid
+            spec:
+              parentRefs:
+                - name: arx
+              rules:
+                - matches:
+                    - path:
+                        type: PathPrefix
+                        value: /invalid
+                  filters:
+                    - type: ExtensionRef
+                      extensionRef:
+                        group: arx.protojour.com
+                        kind: RoutePolicy
+                        name: missing
+                  backendRefs:
+                    - name: invalid
+                      port: 80
+            "
+        };
+        let routes = [valid, invalid]
+            .iter()
+            .map(|yaml| serde_yaml::from_str(yaml).unwrap())
+            .filter_map(filter_k8s_http_route)
+            .collect();
+
+        let problems =
+            check_routing_table(&routes, &Default::default(), &ArxConfig::default()).unwrap();
+
+        assert_eq!(
+            vec!["HTTPRoute `invalid` rejected: route policy `missing` not found".to_string()],
+            problems
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_path_rejected_by_watcher() {
+        let route = |name: &str, path: &str| -> HTTPRoute {
+            serde_yaml::from_str(&formatdoc! {
+                "
+                metadata:
+                  name: {name}
+                spec:
+                  parentRefs:
+                    - name: arx
+                  rules:
+                    - matches:
+                        - path:
+                            value: \"{path}\"
+                      backendRefs:
+                        - name: {name}
+                          port: 80
+                "
+            })
+            .unwrap()
+        };
+
+        let statuses = build_test_statuses_with_config(
+            &[
+                ("spaced".to_string(), route("spaced", "/a b")),
+                ("relative".to_string(), route("relative", "a")),
+                ("params".to_string(), route("params", "/{a}/{b}")),
+            ]
+            .into_iter()
+            .collect(),
+            &ArxConfig::default(),
+        );
+        for name in ["spaced", "relative", "params"] {
+            let Some(RouteStatus::Rejected { message, .. }) = statuses.get(name) else {
+                panic!("{name}: {:?}", statuses.get(name));
+            };
+            assert!(message.starts_with("invalid path"), "{message}");
+        }
+
+        // status reporting is not under test, the API server responds 404 to everything
+        let kube_client = kube::Client::new(
+            tower::service_fn(|_: http::Request<kube::client::Body>| async {
+                Ok::<_, Infallible>(
+                    http::Response::builder()
+                        .status(404)
+                        .body(Full::new(Bytes::new()))
+                        .unwrap(),
+                )
+            }),
+            "default",
+        );
+        let gateway_routes = Arc::new(ArcSwap::new(Arc::new(matchit::Router::new())));
+        let watcher = HttpRouteWatcher {
+            gateway_routes: gateway_routes.clone(),
+            k8s_routes: Mutex::new(Default::default()),
+            tls_policies: Mutex::new(Default::default()),
+            backend_tls: Mutex::new(Default::default()),
+            routing_state: Default::default(),
+            client: reqwest::Client::new(),
+            kube_client,
+            cfg: leak_config(Default::default()),
+        };
+
+        // the valid route is served next to the invalid one, and the watcher keeps going
+        watcher
+            .apply(vec![route("bad", "/a b"), route("good", "/good")])
+            .await
+            .unwrap();
+        assert!(gateway_routes.load().at("/good/").is_ok());
+        assert!(gateway_routes.load().at("/a b/").is_err());
+
+        watcher.apply(vec![route("later", "/later")]).await.unwrap();
+        assert!(gateway_routes.load().at("/later/").is_ok());
+    }
+
+    #[tokio::test]
+    async fn removed_route_metrics_pruned() {
+        let route = |name: &str| -> HTTPRoute {
+            serde_yaml::from_str(&formatdoc! {
+                "
+                metadata:
+                  name: {name}
+                spec:
+                  parentRefs:
+                    - name: arx
+                  rules:
+                    - matches:
+                        - path:
+                            value: /{name}
+                      backendRefs:
+                        - name: {name}
+                          port: 80
+                "
+            })
+            .unwrap()
+        };
+
+        // status reporting is not under test, the API server responds 404 to everything
+        let kube_client = kube::Client::new(
+            tower::service_fn(|_: http::Request<kube::client::Body>| async {
+                Ok::<_, Infallible>(
+                    http::Response::builder()
+                        .status(404)
+                        .body(Full::new(Bytes::new()))
+                        .unwrap(),
+                )
+            }),
+            "default",
+        );
+        let watcher = HttpRouteWatcher {
+            gateway_routes: Arc::new(ArcSwap::new(Arc::new(matchit::Router::new()))),
+            k8s_routes: Mutex::new(Default::default()),
+            tls_policies: Mutex::new(Default::default()),
+            backend_tls: Mutex::new(Default::default()),
+            routing_state: Default::default(),
+            client: reqwest::Client::new(),
+            kube_client,
+            cfg: leak_config(Default::default()),
+        };
+        let admin_addr = spawn_admin(admin_routes(RouteReloadTrigger::channel().0).unwrap()).await;
+        let scrape = || async {
+            reqwest::get(format!("http://{admin_addr}/admin/metrics"))
+                .await
+                .unwrap()
+                .text()
+                .await
+                .unwrap()
+        };
+
+        watcher
+            .apply(vec![route("metrics-kept"), route("metrics-removed")])
+            .await
+            .unwrap();
+        let metrics = scrape().await;
+        assert!(metrics.contains(r#"route="metrics-kept""#), "{metrics}");
+        assert!(metrics.contains(r#"route="metrics-removed""#), "{metrics}");
+
+        watcher
+            .delete(vec![route("metrics-removed")])
+            .await
+            .unwrap();
+        let metrics = scrape().await;
+        assert!(metrics.contains(r#"route="metrics-kept""#), "{metrics}");
+        assert!(!metrics.contains(r#"route="metrics-removed""#), "{metrics}");
+    }
+
+    #[tokio::test]
+    async fn admin_reload_routes() {
+        let cfg = leak_config(ArxConfig {
+            admin_api: true,
+            ..Default::default()
+        });
+        let http_route: HTTPRoute = serde_yaml::from_str(indoc! {
+            "
+            metadata:
+              name: test
+            spec:
+              parentRefs:
+                - name: arx
+              rules:
+                - matches:
+                    - path:
+                        value: /api
+                  backendRefs:
+                    - name: api
+                      port: 80
+            "
+        })
+        .unwrap();
+
+        let (reload_trigger, reload_requests) = RouteReloadTrigger::channel();
+        let gateway_routes = Arc::new(ArcSwap::new(Arc::new(
+            rebuild_routing_table(
+                &Default::default(),
+                &Default::default(),
+                &Default::default(),
+                reqwest::Client::new(),
+                cfg,
+            )
+            .unwrap()
+            .routes,
+        )));
+
+        // status reporting is not under test, the API server responds 404 to everything
+        let kube_client = kube::Client::new(
+            tower::service_fn(|_: http::Request<kube::client::Body>| async {
+                Ok::<_, Infallible>(
+                    http::Response::builder()
+                        .status(404)
+                        .body(Full::new(Bytes::new()))
+                        .unwrap(),
+                )
+            }),
+            "default",
+        );
+
+        // the watcher has seen a route, but the table has not been rebuilt
+        let watcher = Arc::new(HttpRouteWatcher {
+            gateway_routes: gateway_routes.clone(),
+            k8s_routes: Mutex::new([("test".to_string(), http_route)].into_iter().collect()),
+            tls_policies: Mutex::new(Default::default()),
+            backend_tls: Mutex::new(Default::default()),
+            routing_state: Default::default(),
+            client: reqwest::Client::new(),
+            kube_client,
+            cfg,
+        });
+        tokio::spawn(handle_reload_requests(
+            watcher,
+            reload_requests,
+            CancellationToken::new(),
+        ));
+
+        let addr = spawn_gateway_with_shared_routes(cfg, gateway_routes.clone()).await;
+        let admin_addr = spawn_admin(admin_routes(reload_trigger).unwrap()).await;
+        assert!(gateway_routes.load().at("/api/").is_err());
+
+        // the admin API is not part of the public routing table
+        for path in ["/admin/reload-routes", "/admin/metrics", "/admin/log-level"] {
+            let response = reqwest::Client::new()
+                .post(format!("http://{addr}{path}"))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(http::StatusCode::NOT_FOUND, response.status(), "{path}");
+        }
+
+        let response = reqwest::Client::new()
+            .post(format!("http://{admin_addr}/admin/reload-routes"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(http::StatusCode::NO_CONTENT, response.status());
+
+        let routes = gateway_routes.load();
+        assert!(matches!(routes.at("/api/").unwrap().value, Route::Proxy(_)));
+        assert!(routes.at("/admin/reload-routes").is_err());
+    }
+
+    #[tokio::test]
+    async fn backends_warmed_up_after_reload() {
+        // a backend counting the connections made to it
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = backend.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        tokio::spawn({
+            let connections = connections.clone();
+            async move {
+                loop {
+                    let (mut stream, _) = backend.accept().await.unwrap();
+                    connections.fetch_add(1, Ordering::SeqCst);
+                    tokio::spawn(async move {
+                        let mut buf = [0; 1024];
+                        while stream.read(&mut buf).await.is_ok_and(|read| read > 0) {
+                            let response = b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n";
+                            if stream.write_all(response).await.is_err() {
+                                return;
+                            }
+                        }
+                    });
+                }
+            }
+        });
+
+        // two rules with the same backend
+        let yaml = formatdoc! {
+            "
+            metadata:
+              name: test
+            spec:
+              parentRefs:
+                - name: arx
+              rules:
+                - matches:
+                    - path:
+                        value: /a
+                  backendRefs:
+                    - name: 127.0.0.1
+                      port: {port}
+                - matches:
+                    - path:
+                        value: /b
+                  backendRefs:
+                    - name: 127.0.0.1
+                      port: {port}
+            "
+        };
+        let k8s_routes = [("test".to_string(), serde_yaml::from_str(&yaml).unwrap())]
+            .into_iter()
+            .collect();
+
+        let cfg = leak_config(ArxConfig {
+            backend_warm_up: true,
+            ..Default::default()
+        });
+        update_routing_table(
+            &k8s_routes,
+            &Default::default(),
+            &Default::default(),
+            Arc::new(ArcSwap::new(Arc::new(matchit::Router::new()))),
+            reqwest::Client::new(),
+            cfg,
+        );
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while connections.load(Ordering::SeqCst) == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("backend was not warmed up");
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(1, connections.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn docs_route_listing() {
+        let route = |path: &str, method: &str| -> HTTPRoute {
+            serde_yaml::from_str(&formatdoc! {
+                "
+                metadata:
+                  name: test
+                  namespace: ns
+                spec:
+                  parentRefs:
+                    - name: arx
+                  rules:
+                    - matches:
+                        - path:
+                            type: Exact
+                            value: {path}
+                          method: {method}
+                      backendRefs:
+                        - name: svc
+                          port: 8080
+                "
+            })
+            .unwrap()
+        };
+        let listing = |addr: std::net::SocketAddr| async move {
+            let response = reqwest::Client::new()
+                .get(format!("http://{addr}/docs/routes.json"))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(http::StatusCode::OK, response.status());
+            response.json::<serde_json::Value>().await.unwrap()
+        };
+
+        let cfg = leak_config(ArxConfig {
+            docs_route_listing: true,
+            ..Default::default()
+        });
+        let gateway_routes = Arc::new(ArcSwap::new(Arc::new(matchit::Router::new())));
+        let addr = spawn_gateway_with_shared_routes(cfg, gateway_routes.clone()).await;
+
+        let k8s_routes = [("test".to_string(), route("/a", "GET"))]
+            .into_iter()
+            .collect();
+        update_routing_table(
+            &k8s_routes,
+            &Default::default(),
+            &Default::default(),
+            gateway_routes.clone(),
+            reqwest::Client::new(),
+            cfg,
+        );
+        assert_eq!(
+            serde_json::json!([{
+                "route": "ns/test",
+                "path": "/a",
+                "path_type": "Exact",
+                "method": "GET",
+                "headers": [],
+                "query_params": [],
+                "backends": ["http://svc:8080/"],
+            }]),
+            listing(addr).await
+        );
+
+        // the listing follows the routing table
+        let k8s_routes = [
+            ("test".to_string(), route("/b", "POST")),
+            ("other".to_string(), route("/c", "PUT")),
+        ]
+        .into_iter()
+        .collect();
+        update_routing_table(
+            &k8s_routes,
+            &Default::default(),
+            &Default::default(),
+            gateway_routes.clone(),
+            reqwest::Client::new(),
+            cfg,
+        );
+        let listed = listing(addr).await;
+        let paths: Vec<_> = listed
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|route| (route["path"].as_str(), route["method"].as_str()))
+            .collect();
+        assert_eq!(
+            vec![(Some("/b"), Some("POST")), (Some("/c"), Some("PUT"))],
+            paths
+        );
+
+        // header and query parameter matches are listed with their type
+        let matching: HTTPRoute = serde_yaml::from_str(indoc! {"
+            metadata:
+              name: matching
+              namespace: ns
+            spec:
+              parentRefs:
+                - name: arx
+              rules:
+                - matches:
+                    - path:
+                        type: Exact
+                        value: /d
+                      headers:
+                        - name: x-version
+                          value: v2
+                      queryParams:
+                        - type: RegularExpression
+                          name: page
+                          value: \"[0-9]+\"
+                  backendRefs:
+                    - name: svc
+                      port: 8080
+        "})
+        .unwrap();
+        let k8s_routes = [("matching".to_string(), matching)].into_iter().collect();
+        update_routing_table(
+            &k8s_routes,
+            &Default::default(),
+            &Default::default(),
+            gateway_routes.clone(),
+            reqwest::Client::new(),
+            cfg,
+        );
+        let listed = listing(addr).await;
+        assert_eq!(
+            serde_json::json!([{"name": "x-version", "type": "Exact", "value": "v2"}]),
+            listed[0]["headers"]
+        );
+        assert_eq!(
+            serde_json::json!([{"name": "page", "type": "RegularExpression", "value": "[0-9]+"}]),
+            listed[0]["query_params"]
+        );
+
+        // not served unless enabled
+        let routes = build_test_routing(vec![]);
+        assert_eq!(
+            Some("routes.json"),
+            routes.at("/docs/routes.json").unwrap().params.get("path")
+        );
+    }
+
+    #[tokio::test]
+    async fn route_ip_filter() {
+        let mock_server = wiremock::MockServer::start().await;
+        wiremock::Mock::given(wiremock::matchers::any())
+            .respond_with(wiremock::ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+
+        let yaml = formatdoc! {
+            "
+            metadata:
+              name: test
+            spec:
+              parentRefs:
+                - name: arx
+              rules:
+                - matches:
+                    - path:
+                        value: /internal
+                  filters:
+                    - type: ExtensionRef
+                      extensionRef:
+                        group: arx.protojour.com
+                        kind: RoutePolicy
+                        name: internal
+                  backendRefs:
+                    - name: 127.0.0.1
+                      port: {port}
+                - matches:
+                    - path:
+                        value: /public
+                  backendRefs:
+                    - name: 127.0.0.1
+                      port: {port}
+            ",
+            port = mock_server.address().port(),
+        };
+
+        let cfg = leak_config(ArxConfig {
+            ip_filter: IpFilter {
+                allow: vec!["127.0.0.0/8".parse().unwrap()],
+                deny: vec![],
+            },
+            route_policies: [(
+                "internal".to_string(),
+                RoutePolicy {
+                    ip_filter: Some(IpFilter {
+                        allow: vec!["10.0.0.0/8".parse().unwrap()],
+                        deny: vec![],
+                    }),
+                    ..Default::default()
+                },
+            )]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        });
+        let addr = spawn_gateway(cfg, build_test_routing_with_config(vec![&yaml], cfg)).await;
+        let client = reqwest::Client::new();
+
+        let response = client
+            .get(format!("http://{addr}/public/"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(http::StatusCode::OK, response.status());
+
+        let response = client
+            .get(format!("http://{addr}/internal/"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(http::StatusCode::FORBIDDEN, response.status());
+    }
+
+    #[tokio::test]
+    async fn mandatory_authn_by_default() {
+        let mock_server = wiremock::MockServer::start().await;
+        wiremock::Mock::given(wiremock::matchers::any())
+            .respond_with(wiremock::ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+
+        let yaml = formatdoc! {
+            "
+            metadata:
+              name: test
+            spec:
+              parentRefs:
+                - name: arx
+              rules:
+                - matches:
+                    - path:
+                        value: /private
+                  backendRefs:
+                    - name: 127.0.0.1
+                      port: {port}
+                - matches:
+                    - path:
+                        value: /public
+                  filters:
+                    - type: ExtensionRef
+                      extensionRef:
+                        group: authly.id
+                        kind: Service
+                        name: authn-disabled
+                  backendRefs:
+                    - name: 127.0.0.1
+                      port: {port}
+            ",
+            port = mock_server.address().port(),
+        };
+
+        let cfg = leak_config(ArxConfig {
+            default_authn: Authn::Mandatory,
+            ..Default::default()
+        });
+        let addr = spawn_gateway(cfg, build_test_routing_with_config(vec![&yaml], cfg)).await;
+        let client = reqwest::Client::new();
+
+        let response = client
+            .get(format!("http://{addr}/private/"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(http::StatusCode::UNAUTHORIZED, response.status());
+
+        let response = client
+            .get(format!("http://{addr}/public/"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(http::StatusCode::OK, response.status());
+    }
+
+    #[test]
+    fn redirect_loop_dropped() {
+        let http_route: HTTPRoute = serde_yaml::from_str(indoc! {
+            "
+            metadata:
+              name: test
+            spec:
+              parentRefs:
+                - name: arx
+              rules:
+                - matches:
+                    - path:
+                        value: /x
+                  backendRefs:
+                    - name: x
+                      port: 80
+            "
+        })
+        .unwrap();
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert("/x/", Route::TemporaryRedirect("/x".parse().unwrap()))
+            .unwrap();
+
+        let mut additions = RouteAdditions::default();
+        let mut warnings = try_add_http_route(
+            &mut additions,
+            &http_route,
+            &Default::default(),
+            &Default::default(),
+            &ArxConfig::default(),
+        )
+        .unwrap();
+        warnings.extend(additions.add_to(&mut routes, &mut vec![], &mut vec![]));
+        assert_eq!(
+            vec!["dropping redirect from /x to /x/, it would cause a redirect loop".to_string()],
+            warnings
+        );
+        assert!(routes.at("/x").is_err());
+        assert!(matches!(routes.at("/x/y").unwrap().value, Route::Proxy(_)));
+    }
+
+    #[test]
+    fn http_route_redirects_terminate() {
+        let route = |name: &str, path: &str, rewrite: &str| {
+            formatdoc! {
+                "
+                metadata:
+                  name: {name}
+                spec:
+                  parentRefs:
+                    - name: arx
+                  rules:
+                    - matches:
+                        - path:
+                            value: \"{path}\"
+                      filters:
+                        - type: URLRewrite
+                          urlRewrite:
+                            path:
+                              type: ReplacePrefixMatch
+                              replacePrefixMatch: \"{rewrite}\"
+                      backendRefs:
+                        - name: {name}
+                          port: 80
+                "
+            }
+        };
+        // prefixes with and without slashes, rewritten to each other, and next to the static routes
+        let yamls = [
+            route("bare", "/x", "/x/"),
+            route("slash", "/x/", "/x"),
+            route("double", "/x//", "/"),
+            route("onto", "/onto/", "/onto"),
+        ];
+        let routes = build_test_routing_with_config(
+            yamls.iter().map(String::as_str).collect(),
+            &Default::default(),
+        );
+
+        let follow = |path: &str| {
+            let mut chain = vec![path.to_string()];
+            loop {
+                let next = match routes.at(chain.last().unwrap()) {
+                    Ok(matchit::Match {
+                        value: Route::TemporaryRedirect(target),
+                        ..
+                    }) => target.path().to_string(),
+                    _ => return chain,
+                };
+                assert!(
+                    chain.len() <= MAX_REDIRECT_CHAIN,
+                    "redirect loop: {chain:?}"
+                );
+                chain.push(next);
+            }
+        };
+
+        assert_eq!(vec!["/x", "/x/"], follow("/x"));
+        assert_eq!(vec!["/", "/onto/"], follow("/"));
+        for path in ["/x/", "/x//", "/onto", "/favicon.ico", "/docs"] {
+            follow(path);
+        }
+    }
+
+    #[test]
+    fn ruleless_route_diagnosed() {
+        let http_route: HTTPRoute = serde_yaml::from_str(indoc! {
+            "
+            metadata:
+              name: test
+            spec:
+              parentRefs:
+                - name: arx
+            "
+        })
+        .unwrap();
+        let routes = [filter_k8s_http_route(http_route).unwrap()]
+            .into_iter()
+            .collect();
+
+        let statuses = build_test_statuses_with_config(&routes, &ArxConfig::default());
+        assert_eq!(
+            Some(&RouteStatus::Accepted {
+                warnings: vec!["route has no rules, and matches no requests".to_string()]
+            }),
+            statuses.get("test")
+        );
+
+        let cfg = ArxConfig {
+            reject_ruleless_routes: true,
+            ..Default::default()
+        };
+        let statuses = build_test_statuses_with_config(&routes, &cfg);
+        assert_eq!(
+            Some(&RouteStatus::Rejected {
+                reason: "UnsupportedValue",
+                message: "route has no rules".to_string()
+            }),
+            statuses.get("test")
+        );
+    }
+
+    #[test]
+    fn unknown_extension_ref_diagnosed() {
+        let http_route: HTTPRoute = serde_yaml::from_str(indoc! {
+            "
+            metadata:
+              name: test
+            spec:
+              parentRefs:
+                - name: arx
+              rules:
+                - matches:
+                    - path:
+                        value: /api
+                  filters:
+                    - type: ExtensionRef
+                      extensionRef:
+                        group: example.com
+                        kind: Auth
+                        name: strict
+                  backendRefs:
+                    - name: api
+                      port: 80
+            "
+        })
+        .unwrap();
+        let routes = [filter_k8s_http_route(http_route).unwrap()]
+            .into_iter()
+            .collect();
+
+        let message = "unknown extensionRef Auth `strict` of group `example.com`".to_string();
+        let statuses = build_test_statuses_with_config(&routes, &ArxConfig::default());
+        assert_eq!(
+            Some(&RouteStatus::Accepted {
+                warnings: vec![message.clone()]
+            }),
+            statuses.get("test")
+        );
+
+        let cfg = ArxConfig {
+            reject_unknown_extension_refs: true,
+            ..Default::default()
+        };
+        let statuses = build_test_statuses_with_config(&routes, &cfg);
+        assert_eq!(
+            Some(&RouteStatus::Rejected {
+                reason: "UnsupportedValue",
+                message
+            }),
+            statuses.get("test")
+        );
+    }
 }
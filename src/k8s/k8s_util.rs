@@ -1,4 +1,4 @@
-use std::{fmt::Debug, future::Future};
+use std::{fmt::Debug, future::Future, sync::Arc};
 
 use futures_util::StreamExt;
 use kube::{runtime::watcher::Event, Api, Resource};
@@ -11,6 +11,16 @@ pub trait ApiWatcherCallbacks<T>: Send + 'static {
     fn delete(&self, obj: Vec<T>) -> impl Future<Output = anyhow::Result<()>> + Send;
 }
 
+impl<T, C: ApiWatcherCallbacks<T> + Sync> ApiWatcherCallbacks<T> for Arc<C> {
+    fn apply(&self, obj: Vec<T>) -> impl Future<Output = anyhow::Result<()>> + Send {
+        self.as_ref().apply(obj)
+    }
+
+    fn delete(&self, obj: Vec<T>) -> impl Future<Output = anyhow::Result<()>> + Send {
+        self.as_ref().delete(obj)
+    }
+}
+
 pub async fn api_watcher<K, C>(api: Api<K>, callbacks: C, cancel: CancellationToken)
 where
     K: Clone + Debug + DeserializeOwned + Send + Sync + 'static + Resource,
@@ -1,3 +1,5 @@
+pub mod backend_tls;
 pub mod k8s_routing;
 
 mod k8s_util;
+mod route_status;
@@ -0,0 +1,165 @@
+//! Reporting the outcome of processing HTTPRoutes back to Kubernetes, as status conditions.
+//!
+//! Note: This requires permission to `patch` the `httproutes/status` subresource.
+
+use gateway_api::apis::standard::httproutes::HTTPRoute;
+use k8s_openapi::{apimachinery::pkg::apis::meta::v1::Time, chrono::Utc};
+use kube::{
+    api::{Patch, PatchParams},
+    runtime::reflector::Lookup,
+    Api,
+};
+use serde_json::{json, Value};
+use tracing::warn;
+
+/// The controller name Arx reports HTTPRoute status under
+const CONTROLLER_NAME: &str = "protojour.com/arx";
+
+/// The outcome of adding an HTTPRoute to the routing table
+#[derive(Clone, Debug, PartialEq)]
+pub enum RouteStatus {
+    /// The route was accepted, but parts of it may have been ignored
+    Accepted { warnings: Vec<String> },
+    /// The route was rejected and does not contribute to the routing table
+    Rejected {
+        reason: &'static str,
+        message: String,
+    },
+}
+
+impl RouteStatus {
+    /// The `status`, `reason` and `message` of the `Accepted` condition
+    fn accepted_condition(&self) -> (&'static str, &'static str, String) {
+        match self {
+            Self::Accepted { warnings } => ("True", "Accepted", warnings.join("; ")),
+            Self::Rejected { reason, message } => ("False", reason, message.clone()),
+        }
+    }
+}
+
+/// Write route statuses to the HTTPRoute resources, if they changed since last reported.
+pub async fn report_route_statuses(
+    kube_client: &kube::Client,
+    statuses: Vec<(HTTPRoute, RouteStatus)>,
+) {
+    for (http_route, status) in statuses {
+        let (Some(namespace), Some(name)) = (http_route.namespace(), http_route.name()) else {
+            continue;
+        };
+
+        let Some(patch) = status_patch(&http_route, &status) else {
+            continue;
+        };
+
+        if let Err(err) = Api::<HTTPRoute>::namespaced(kube_client.clone(), &namespace)
+            .patch_status(&name, &PatchParams::default(), &Patch::Merge(&patch))
+            .await
+        {
+            warn!(?err, %name, "could not report HTTPRoute status");
+        }
+    }
+}
+
+/// Make the status merge patch for a route, or `None` if the reported status is already up to date.
+///
+/// The status of other parents/controllers is preserved.
+fn status_patch(http_route: &HTTPRoute, status: &RouteStatus) -> Option<Value> {
+    let generation = http_route.metadata.generation;
+    let (condition_status, reason, message) = status.accepted_condition();
+
+    let existing_parents = serde_json::to_value(&http_route.status)
+        .ok()
+        .and_then(|status| status.get("parents").cloned())
+        .and_then(|parents| match parents {
+            Value::Array(parents) => Some(parents),
+            _ => None,
+        })
+        .unwrap_or_default();
+
+    let (own_parents, mut parents): (Vec<Value>, Vec<Value>) = existing_parents
+        .into_iter()
+        .partition(|parent| parent["controllerName"] == CONTROLLER_NAME);
+
+    let up_to_date = own_parents.iter().any(|parent| {
+        parent["conditions"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .any(|condition| {
+                condition["type"] == "Accepted"
+                    && condition["status"] == condition_status
+                    && condition["reason"] == reason
+                    && condition["message"] == message.as_str()
+                    && condition["observedGeneration"].as_i64() == generation
+            })
+    });
+    if up_to_date {
+        return None;
+    }
+
+    let parent_ref = http_route
+        .spec
+        .parent_refs
+        .iter()
+        .flatten()
+        .find(|parent_ref| parent_ref.name == "arx")
+        .and_then(|parent_ref| serde_json::to_value(parent_ref).ok())?;
+
+    parents.push(json!({
+        "parentRef": parent_ref,
+        "controllerName": CONTROLLER_NAME,
+        "conditions": [{
+            "type": "Accepted",
+            "status": condition_status,
+            "reason": reason,
+            "message": message,
+            "observedGeneration": generation,
+            "lastTransitionTime": Time(Utc::now()),
+        }],
+    }));
+
+    Some(json!({ "status": { "parents": parents } }))
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+
+    #[test]
+    fn status_patch_only_when_changed() {
+        let http_route: HTTPRoute = serde_yaml::from_str(indoc! {
+            "
+            metadata:
+              name: test
+              generation: 2
+            spec:
+              parentRefs:
+                - name: arx
+            "
+        })
+        .unwrap();
+
+        let status = RouteStatus::Rejected {
+            reason: "UnsupportedValue",
+            message: "duplicate URLRewrite filter".to_string(),
+        };
+
+        let patch = status_patch(&http_route, &status).unwrap();
+        let condition = &patch["status"]["parents"][0]["conditions"][0];
+        assert_eq!("False", condition["status"]);
+        assert_eq!("UnsupportedValue", condition["reason"]);
+        assert_eq!(2, condition["observedGeneration"]);
+
+        let reported: HTTPRoute = serde_json::from_value(json!({
+            "metadata": { "name": "test", "generation": 2 },
+            "spec": { "parentRefs": [{ "name": "arx" }] },
+            "status": patch["status"],
+        }))
+        .unwrap();
+
+        assert_eq!(None, status_patch(&reported, &status));
+        assert!(status_patch(&reported, &RouteStatus::Accepted { warnings: vec![] }).is_some());
+    }
+}
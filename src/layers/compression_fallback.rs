@@ -0,0 +1,419 @@
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_util::future::BoxFuture;
+use http::{header, Request, Response};
+use http_body::{Body, Frame, SizeHint};
+use http_body_util::BodyExt;
+use tower::{Layer, Service};
+use tower_http::BoxError;
+use tracing::warn;
+
+/// Wraps a compression layer, so that a response whose compression fails
+/// before any compressed data is produced is sent uncompressed instead.
+///
+/// The uncompressed frames are recorded until the first compressed frame is available,
+/// which is awaited before returning the response headers.
+/// Failures later in the stream can't be recovered, the client sees a broken body.
+#[derive(Clone)]
+pub struct CompressionFallbackLayer<L> {
+    compression: L,
+    enabled: bool,
+}
+
+impl<L> CompressionFallbackLayer<L> {
+    pub fn new(compression: L, enabled: bool) -> Self {
+        Self {
+            compression,
+            enabled,
+        }
+    }
+}
+
+impl<S, L: Clone> Layer<S> for CompressionFallbackLayer<L> {
+    type Service = CompressionFallback<S, L>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CompressionFallback {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CompressionFallback<S, L> {
+    inner: S,
+    layer: CompressionFallbackLayer<L>,
+}
+
+impl<ReqBody, ResBody, CompressedBody, S, L> Service<Request<ReqBody>> for CompressionFallback<S, L>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone,
+    S::Error: Send + 'static,
+    ResBody: Body<Data = Bytes> + Send + 'static,
+    ResBody::Error: Into<BoxError>,
+    L: Layer<Recording<S>>,
+    L::Service: Service<Request<ReqBody>, Response = Response<CompressedBody>, Error = S::Error>,
+    <L::Service as Service<Request<ReqBody>>>::Future: Send + 'static,
+    CompressedBody: Body<Data = Bytes> + Send + 'static,
+    CompressedBody::Error: Into<BoxError>,
+{
+    type Response = Response<FallbackBody<CompressedBody, ResBody>>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        // use the service that was driven to readiness
+        let clone = self.inner.clone();
+        let inner = std::mem::replace(&mut self.inner, clone);
+
+        let response_future = self
+            .layer
+            .compression
+            .layer(Recording {
+                inner,
+                enabled: self.layer.enabled,
+            })
+            .call(req);
+
+        Box::pin(async move {
+            let (mut parts, body) = response_future.await?.into_parts();
+            let mut body = Box::pin(body);
+
+            let Some(RecordingHandle(state)) =
+                parts.extensions.remove::<RecordingHandle<ResBody>>()
+            else {
+                return Ok(Response::from_parts(parts, FallbackBody::compressed(body)));
+            };
+
+            if !(state.lock().unwrap().recording
+                && parts.headers.contains_key(header::CONTENT_ENCODING))
+            {
+                state.lock().unwrap().stop_recording();
+                return Ok(Response::from_parts(parts, FallbackBody::compressed(body)));
+            }
+
+            match body.frame().await {
+                Some(Err(err)) if !state.lock().unwrap().failed => {
+                    let err: BoxError = err.into();
+                    warn!(?err, "compression failed, sending uncompressed response");
+
+                    let mut state = state.lock().unwrap();
+                    parts.headers.remove(header::CONTENT_ENCODING);
+
+                    Ok(Response::from_parts(
+                        parts,
+                        FallbackBody::Uncompressed {
+                            frames: std::mem::take(&mut state.frames).into(),
+                            rest: state.body.take(),
+                        },
+                    ))
+                }
+                first => {
+                    state.lock().unwrap().stop_recording();
+
+                    Ok(Response::from_parts(
+                        parts,
+                        FallbackBody::Compressed {
+                            first: Some(first.map(|result| result.map_err(Into::into))),
+                            body,
+                        },
+                    ))
+                }
+            }
+        })
+    }
+}
+
+/// Service recording the frames of uncompressed response bodies,
+/// so they can be replayed when compression fails.
+#[derive(Clone)]
+pub struct Recording<S> {
+    inner: S,
+    enabled: bool,
+}
+
+impl<ReqBody, ResBody, S> Service<Request<ReqBody>> for Recording<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+    ResBody: Body<Data = Bytes> + Send + 'static,
+{
+    type Response = Response<RecordingBody<ResBody>>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let enabled = self.enabled;
+        let response_future = self.inner.call(req);
+
+        Box::pin(async move {
+            let (mut parts, body) = response_future.await?.into_parts();
+
+            // a body that is already encoded is not compressed again
+            let state = Arc::new(Mutex::new(RecordingState {
+                body: Some(Box::pin(body)),
+                frames: vec![],
+                recording: enabled && !parts.headers.contains_key(header::CONTENT_ENCODING),
+                failed: false,
+            }));
+            parts.extensions.insert(RecordingHandle(state.clone()));
+
+            Ok(Response::from_parts(parts, RecordingBody { state }))
+        })
+    }
+}
+
+struct RecordingState<B> {
+    /// Taken when falling back to the uncompressed body
+    body: Option<Pin<Box<B>>>,
+    frames: Vec<Frame<Bytes>>,
+    recording: bool,
+    /// Whether the uncompressed body failed, in which case there is nothing to fall back to
+    failed: bool,
+}
+
+impl<B> RecordingState<B> {
+    fn stop_recording(&mut self) {
+        self.recording = false;
+        self.frames.clear();
+    }
+}
+
+/// Passes the recording state from [Recording] to [CompressionFallback] in the response extensions
+struct RecordingHandle<B>(Arc<Mutex<RecordingState<B>>>);
+
+impl<B> Clone for RecordingHandle<B> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+/// An uncompressed body, recording its frames while compression has not produced any output
+pub struct RecordingBody<B> {
+    state: Arc<Mutex<RecordingState<B>>>,
+}
+
+impl<B: Body<Data = Bytes>> Body for RecordingBody<B> {
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let Some(body) = state.body.as_mut() else {
+            return Poll::Ready(None);
+        };
+
+        let poll = body.as_mut().poll_frame(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(frame))) if state.recording => {
+                if let Some(data) = frame.data_ref() {
+                    state.frames.push(Frame::data(data.clone()));
+                } else if let Some(trailers) = frame.trailers_ref() {
+                    state.frames.push(Frame::trailers(trailers.clone()));
+                }
+            }
+            Poll::Ready(Some(Err(_))) => {
+                state.failed = true;
+            }
+            _ => {}
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.body.as_ref().is_none_or(|body| body.is_end_stream())
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let state = self.state.lock().unwrap();
+        state
+            .body
+            .as_ref()
+            .map(|body| body.size_hint())
+            .unwrap_or_default()
+    }
+}
+
+/// A compressed body, or the uncompressed one after compression failed
+pub enum FallbackBody<C, B> {
+    Compressed {
+        /// The first frame, already produced before the response headers were returned
+        first: Option<Option<Result<Frame<Bytes>, BoxError>>>,
+        body: Pin<Box<C>>,
+    },
+    Uncompressed {
+        /// Frames consumed by the failed compression
+        frames: VecDeque<Frame<Bytes>>,
+        rest: Option<Pin<Box<B>>>,
+    },
+}
+
+impl<C, B> FallbackBody<C, B> {
+    fn compressed(body: Pin<Box<C>>) -> Self {
+        Self::Compressed { first: None, body }
+    }
+}
+
+impl<C, B> Body for FallbackBody<C, B>
+where
+    C: Body<Data = Bytes>,
+    C::Error: Into<BoxError>,
+    B: Body<Data = Bytes>,
+    B::Error: Into<BoxError>,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        match self.get_mut() {
+            Self::Compressed { first, body } => match first.take() {
+                Some(first) => Poll::Ready(first),
+                None => body.as_mut().poll_frame(cx).map_err(Into::into),
+            },
+            Self::Uncompressed { frames, rest } => match (frames.pop_front(), rest) {
+                (Some(frame), _) => Poll::Ready(Some(Ok(frame))),
+                (None, Some(rest)) => rest.as_mut().poll_frame(cx).map_err(Into::into),
+                (None, None) => Poll::Ready(None),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use http_body_util::{BodyExt, Full, StreamBody};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{config::ArxConfig, layers::compression_layer};
+
+    /// A compression layer that consumes one frame of the body and then fails
+    #[derive(Clone)]
+    struct FailingCompressionLayer;
+
+    impl<S> Layer<S> for FailingCompressionLayer {
+        type Service = FailingCompression<S>;
+
+        fn layer(&self, inner: S) -> Self::Service {
+            FailingCompression(inner)
+        }
+    }
+
+    #[derive(Clone)]
+    struct FailingCompression<S>(S);
+
+    impl<S, B> Service<Request<()>> for FailingCompression<S>
+    where
+        S: Service<Request<()>, Response = Response<B>>,
+        S::Future: Send + 'static,
+        B: Send + 'static,
+    {
+        type Response = Response<FailingBody<B>>;
+        type Error = S::Error;
+        type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.0.poll_ready(cx)
+        }
+
+        fn call(&mut self, req: Request<()>) -> Self::Future {
+            let response_future = self.0.call(req);
+            Box::pin(async move {
+                let (mut parts, body) = response_future.await?.into_parts();
+                parts
+                    .headers
+                    .insert(header::CONTENT_ENCODING, "gzip".parse().unwrap());
+                Ok(Response::from_parts(parts, FailingBody(Some(body))))
+            })
+        }
+    }
+
+    struct FailingBody<B>(Option<B>);
+
+    impl<B: Body<Data = Bytes> + Unpin> Body for FailingBody<B> {
+        type Data = Bytes;
+        type Error = BoxError;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+            let Some(inner) = self.0.as_mut() else {
+                return Poll::Ready(None);
+            };
+            match Pin::new(inner).poll_frame(cx) {
+                Poll::Ready(_) => {
+                    self.0 = None;
+                    Poll::Ready(Some(Err("injected compression failure".into())))
+                }
+                Poll::Pending => Poll::Pending,
+            }
+        }
+    }
+
+    fn two_chunk_body() -> impl Body<Data = Bytes, Error = Infallible> + Send + 'static {
+        StreamBody::new(futures_util::stream::iter([
+            Ok(Frame::data(Bytes::from("hello "))),
+            Ok(Frame::data(Bytes::from("world"))),
+        ]))
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_uncompressed() {
+        let service =
+            CompressionFallbackLayer::new(FailingCompressionLayer, true).layer(tower::service_fn(
+                |_req: Request<()>| async { Ok::<_, Infallible>(Response::new(two_chunk_body())) },
+            ));
+
+        let response = service.oneshot(Request::new(())).await.unwrap();
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&b"hello world"[..], &body[..]);
+    }
+
+    #[tokio::test]
+    async fn compresses_without_failure() {
+        let cfg = Box::leak(Box::new(ArxConfig {
+            http_compression_fallback: true,
+            ..Default::default()
+        }));
+        let service = compression_layer(cfg).layer(tower::service_fn(|_req: Request<()>| async {
+            Ok::<_, Infallible>(Response::new(Full::new(Bytes::from("a".repeat(1000)))))
+        }));
+
+        let mut req = Request::new(());
+        req.headers_mut()
+            .insert(header::ACCEPT_ENCODING, "gzip".parse().unwrap());
+        let response = service.oneshot(req).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(!body.is_empty() && body.len() < 1000);
+    }
+}
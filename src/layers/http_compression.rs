@@ -1,8 +1,176 @@
-use http::header;
-use tower_http::compression::Predicate;
+use std::task::{Context, Poll};
+
+use http::{header, HeaderMap, Request, Response, StatusCode};
+use tower::{Layer, Service};
+use tower_http::compression::{
+    CompressionBody, CompressionLayer, CompressionLevel, Predicate, ResponseFuture,
+};
 
 use crate::config::ArxConfig;
 
+/// Content encodings, ordered from least to most preferred (like tower-http does)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Encoding {
+    Identity,
+    Deflate,
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+/// Negotiate the response encoding from `Accept-Encoding`, by q-value and then preference.
+pub fn negotiate_encoding(headers: &HeaderMap) -> Encoding {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let encoding = match parts.next()?.to_ascii_lowercase().as_str() {
+                "gzip" | "x-gzip" => Encoding::Gzip,
+                "deflate" => Encoding::Deflate,
+                "br" => Encoding::Brotli,
+                "zstd" => Encoding::Zstd,
+                "identity" => Encoding::Identity,
+                _ => return None,
+            };
+            let qvalue = match parts.find_map(|param| param.strip_prefix("q=")) {
+                Some(q) => (q.parse::<f32>().ok()? * 1000.0) as u16,
+                None => 1000,
+            };
+            Some((qvalue, encoding))
+        })
+        .filter(|(qvalue, _)| *qvalue > 0)
+        .max()
+        .map(|(_, encoding)| encoding)
+        .unwrap_or(Encoding::Identity)
+}
+
+/// The configured compression level for an encoding
+pub fn compression_level(cfg: &ArxConfig, encoding: Encoding) -> CompressionLevel {
+    let level = match encoding {
+        Encoding::Identity => None,
+        Encoding::Deflate => cfg.http_compression_level_deflate.as_ref(),
+        Encoding::Gzip => cfg.http_compression_level_gzip.as_ref(),
+        Encoding::Brotli => cfg.http_compression_level_br.as_ref(),
+        Encoding::Zstd => cfg.http_compression_level_zstd.as_ref(),
+    };
+    level.unwrap_or(&cfg.http_compression_level).0
+}
+
+/// Response compression with a compression level depending on the negotiated encoding.
+///
+/// tower-http compresses with one level for all algorithms,
+/// so there is one single-algorithm [CompressionLayer] per encoding.
+#[derive(Clone)]
+pub struct EncodingCompressionLayer<P> {
+    identity: CompressionLayer<P>,
+    deflate: CompressionLayer<P>,
+    gzip: CompressionLayer<P>,
+    br: CompressionLayer<P>,
+    zstd: CompressionLayer<P>,
+}
+
+impl<P: Predicate> EncodingCompressionLayer<P> {
+    pub fn new(cfg: &ArxConfig, predicate: P) -> Self {
+        let single = |encoding| {
+            let layer = CompressionLayer::new()
+                .gzip(encoding == Encoding::Gzip)
+                .deflate(encoding == Encoding::Deflate)
+                .br(encoding == Encoding::Brotli)
+                .zstd(encoding == Encoding::Zstd)
+                .quality(compression_level(cfg, encoding));
+            layer.compress_when(predicate.clone())
+        };
+
+        Self {
+            identity: single(Encoding::Identity),
+            deflate: single(Encoding::Deflate),
+            gzip: single(Encoding::Gzip),
+            br: single(Encoding::Brotli),
+            zstd: single(Encoding::Zstd),
+        }
+    }
+
+    fn for_encoding(&self, encoding: Encoding) -> &CompressionLayer<P> {
+        match encoding {
+            Encoding::Identity => &self.identity,
+            Encoding::Deflate => &self.deflate,
+            Encoding::Gzip => &self.gzip,
+            Encoding::Brotli => &self.br,
+            Encoding::Zstd => &self.zstd,
+        }
+    }
+}
+
+impl<S, P: Clone> Layer<S> for EncodingCompressionLayer<P> {
+    type Service = EncodingCompression<S, P>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        EncodingCompression {
+            inner,
+            layers: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EncodingCompression<S, P> {
+    inner: S,
+    layers: EncodingCompressionLayer<P>,
+}
+
+impl<ReqBody, ResBody, S, P> Service<Request<ReqBody>> for EncodingCompression<S, P>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone,
+    ResBody: http_body::Body,
+    P: Predicate,
+{
+    type Response = Response<CompressionBody<ResBody>>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future, P>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        // use the service that was driven to readiness
+        let clone = self.inner.clone();
+        let inner = std::mem::replace(&mut self.inner, clone);
+
+        self.layers
+            .for_encoding(negotiate_encoding(req.headers()))
+            .layer(inner)
+            .call(req)
+    }
+}
+
+/// Content types of formats that are compressed already, and don't benefit from another compression
+const COMPRESSED_CONTENT_TYPES: &[&str] = &[
+    "application/gzip",
+    "application/x-gzip",
+    "application/zip",
+    "application/zstd",
+    "application/x-7z-compressed",
+    "application/x-bzip2",
+    "application/x-xz",
+    "application/vnd.rar",
+    "audio/mp4",
+    "audio/mpeg",
+    "audio/ogg",
+    "font/woff",
+    "font/woff2",
+    "image/avif",
+    "image/gif",
+    "image/jpeg",
+    "image/png",
+    "image/webp",
+    "video/mp4",
+    "video/webm",
+];
+
 #[derive(Clone)]
 pub struct CompressionPredicate<'a> {
     pub cfg: &'a ArxConfig,
@@ -23,6 +191,46 @@ impl Predicate for CompressionPredicate<'_> {
                 .and_then(|val| val.parse().ok())
         });
 
+        // an empty body (e.g. the response to a HEAD request) has nothing to compress,
+        // and compressing would remove its `Content-Length`
+        if response.body().size_hint().exact() == Some(0) {
+            return false;
+        }
+
+        // compressing a range breaks its byte offsets, and a 304 refers to the uncompressed representation
+        if matches!(
+            response.status(),
+            StatusCode::PARTIAL_CONTENT | StatusCode::NOT_MODIFIED
+        ) || response.headers().contains_key(header::CONTENT_RANGE)
+        {
+            return false;
+        }
+
+        // never encode twice
+        if response.headers().contains_key(header::CONTENT_ENCODING) {
+            return false;
+        }
+
+        // already compressed formats
+        let media_type = response_content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim();
+        if COMPRESSED_CONTENT_TYPES
+            .iter()
+            .any(|compressed| compressed.eq_ignore_ascii_case(media_type))
+        {
+            return false;
+        }
+
+        // events must reach the client as they are sent, not when a compressed block fills up
+        if media_type.eq_ignore_ascii_case("text/event-stream")
+            && !self.cfg.http_compression_compress_event_streams
+        {
+            return false;
+        }
+
         // do not compress if content type is in the exempt list
         for content_type in &self.cfg.http_compression_exempt_content_types {
             if content_type == response_content_type {
@@ -38,6 +246,16 @@ impl Predicate for CompressionPredicate<'_> {
             return false;
         }
 
+        // some content types are compressed whatever their size
+        if self
+            .cfg
+            .http_compression_always_content_types
+            .iter()
+            .any(|always| always.eq_ignore_ascii_case(media_type))
+        {
+            return true;
+        }
+
         // only compress when the size of the response is above the minimum
         if let Some(response_content_size) = response_content_size {
             if response_content_size < self.cfg.http_compression_min_size.as_u64() {
@@ -52,15 +270,23 @@ impl Predicate for CompressionPredicate<'_> {
 
 #[cfg(test)]
 mod tests {
+    use std::convert::Infallible;
+
     use axum::http::HeaderValue;
+    use bytes::Bytes;
     use figment::providers::{Format, Serialized, Yaml};
     use figment::Figment;
-    use http::header::CONTENT_TYPE;
-    use tower_http::compression::Predicate;
+    use http::header::{
+        ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE,
+    };
+    use http::{HeaderMap, Request, Response, StatusCode};
+    use http_body_util::{BodyExt, Full};
+    use tower::{Layer, ServiceExt};
+    use tower_http::compression::{CompressionLevel, Predicate};
 
-    use crate::config::ArxConfig;
+    use crate::{config::ArxConfig, layers::compression_layer};
 
-    use super::CompressionPredicate;
+    use super::{compression_level, negotiate_encoding, CompressionPredicate, Encoding};
 
     fn config_from_yaml(yaml: &str) -> Result<ArxConfig, figment::Error> {
         Figment::from(Serialized::defaults(ArxConfig::default()))
@@ -107,6 +333,61 @@ mod tests {
         assert!(!compression_predicate.should_compress(&mock_response));
     }
 
+    #[test]
+    fn http_should_not_compress_empty_body() {
+        let cfg = config_from_yaml("http_compression_min_size: 0b").unwrap();
+        let compression_predicate = CompressionPredicate { cfg: &cfg };
+        let mut mock_response = axum::http::Response::new(String::new());
+        mock_response
+            .headers_mut()
+            .append(CONTENT_LENGTH, HeaderValue::from_static("1000"));
+        assert!(!compression_predicate.should_compress(&mock_response));
+    }
+
+    #[tokio::test]
+    async fn http_small_response_compressed_when_always_compressed() {
+        let cfg = Box::leak(Box::new(
+            config_from_yaml("http_compression_always_content_types: [\"application/json\"]")
+                .unwrap(),
+        ));
+        let json = Bytes::from(r#"{"id":123}"#);
+        assert_eq!(10, json.len());
+
+        let compression_predicate = CompressionPredicate { cfg };
+        let mut mock_response =
+            axum::http::Response::new(String::from_utf8(json.to_vec()).unwrap());
+        mock_response.headers_mut().append(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/json; charset=utf-8"),
+        );
+        assert!(compression_predicate.should_compress(&mock_response));
+        mock_response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        assert!(!compression_predicate.should_compress(&mock_response));
+
+        let service = compression_layer(cfg).layer(tower::service_fn({
+            let json = json.clone();
+            move |_req: Request<()>| {
+                let mut response = Response::new(Full::new(json.clone()));
+                response
+                    .headers_mut()
+                    .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                async move { Ok::<_, Infallible>(response) }
+            }
+        }));
+
+        let mut req = Request::new(());
+        req.headers_mut()
+            .insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        let response = service.oneshot(req).await.unwrap();
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        // the gzip magic number
+        assert_eq!(&[0x1f, 0x8b], &body[..2]);
+    }
+
     #[test]
     fn http_should_not_compress_exempt_content_type() {
         let cfg = config_from_yaml(
@@ -158,4 +439,179 @@ mod tests {
             .append(CONTENT_TYPE, HeaderValue::try_from("image/jpeg").unwrap());
         assert!(compression_predicate.should_compress(&mock_response));
     }
+
+    #[test]
+    fn http_should_not_compress_already_encoded() {
+        let cfg = default_config().unwrap();
+        let compression_predicate = CompressionPredicate { cfg: &cfg };
+        let mock_body: String = (0..64).map(|_| 'A').collect();
+        let mut mock_response = axum::http::Response::new(mock_body);
+        mock_response
+            .headers_mut()
+            .append(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        assert!(!compression_predicate.should_compress(&mock_response));
+    }
+
+    #[test]
+    fn http_should_not_compress_compressed_formats() {
+        let cfg = config_from_yaml("http_compression_compress_images: true").unwrap();
+        let compression_predicate = CompressionPredicate { cfg: &cfg };
+        for content_type in [
+            "application/zip",
+            "video/mp4",
+            "image/jpeg",
+            "application/gzip; foo=bar",
+        ] {
+            let mock_body: String = (0..64).map(|_| 'A').collect();
+            let mut mock_response = axum::http::Response::new(mock_body);
+            mock_response
+                .headers_mut()
+                .append(CONTENT_TYPE, HeaderValue::from_static(content_type));
+            assert!(
+                !compression_predicate.should_compress(&mock_response),
+                "{content_type}"
+            );
+        }
+    }
+
+    #[test]
+    fn http_should_not_compress_partial_or_not_modified() {
+        let cfg = default_config().unwrap();
+        let compression_predicate = CompressionPredicate { cfg: &cfg };
+        for status in [StatusCode::PARTIAL_CONTENT, StatusCode::NOT_MODIFIED] {
+            let mock_body: String = (0..64).map(|_| 'A').collect();
+            let mut mock_response = axum::http::Response::new(mock_body);
+            *mock_response.status_mut() = status;
+            assert!(
+                !compression_predicate.should_compress(&mock_response),
+                "{status}"
+            );
+        }
+
+        let mock_body: String = (0..64).map(|_| 'A').collect();
+        let mut mock_response = axum::http::Response::new(mock_body);
+        mock_response
+            .headers_mut()
+            .append(CONTENT_RANGE, HeaderValue::from_static("bytes 0-63/1000"));
+        assert!(!compression_predicate.should_compress(&mock_response));
+    }
+
+    #[tokio::test]
+    async fn http_range_response_not_compressed() {
+        let cfg = Box::leak(Box::new(default_config().unwrap()));
+        let range = Bytes::from("A".repeat(64));
+        let service = compression_layer(cfg).layer(tower::service_fn({
+            let range = range.clone();
+            move |_req: Request<()>| {
+                let mut response = Response::new(Full::new(range.clone()));
+                *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+                response
+                    .headers_mut()
+                    .insert(CONTENT_RANGE, HeaderValue::from_static("bytes 0-63/1000"));
+                async move { Ok::<_, Infallible>(response) }
+            }
+        }));
+
+        let mut req = Request::new(());
+        req.headers_mut()
+            .insert(ACCEPT_ENCODING, HeaderValue::from_static("br, gzip"));
+        let response = service.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::PARTIAL_CONTENT, response.status());
+        assert!(!response.headers().contains_key(CONTENT_ENCODING));
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&range[..], &body[..]);
+    }
+
+    #[tokio::test]
+    async fn http_gzip_response_not_compressed_again() {
+        let cfg = Box::leak(Box::new(default_config().unwrap()));
+        // a gzip header, followed by garbage
+        let gzipped = Bytes::from([0x1f, 0x8b, 0x08, 0x00].repeat(16));
+        let service = compression_layer(cfg).layer(tower::service_fn({
+            let gzipped = gzipped.clone();
+            move |_req: Request<()>| {
+                let mut response = Response::new(Full::new(gzipped.clone()));
+                response
+                    .headers_mut()
+                    .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+                async move { Ok::<_, Infallible>(response) }
+            }
+        }));
+
+        let mut req = Request::new(());
+        req.headers_mut()
+            .insert(ACCEPT_ENCODING, HeaderValue::from_static("br, gzip"));
+        let response = service.oneshot(req).await.unwrap();
+        assert_eq!(
+            1,
+            response.headers().get_all(CONTENT_ENCODING).iter().count()
+        );
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&gzipped[..], &body[..]);
+    }
+
+    #[tokio::test]
+    async fn http_event_stream_not_compressed() {
+        let cfg = Box::leak(Box::new(default_config().unwrap()));
+        let events = Bytes::from("data: event\n\n".repeat(16));
+        let service = compression_layer(cfg).layer(tower::service_fn({
+            let events = events.clone();
+            move |_req: Request<()>| {
+                let mut response = Response::new(Full::new(events.clone()));
+                response.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("text/event-stream; charset=utf-8"),
+                );
+                async move { Ok::<_, Infallible>(response) }
+            }
+        }));
+
+        let mut req = Request::new(());
+        req.headers_mut()
+            .insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        let response = service.oneshot(req).await.unwrap();
+        assert!(!response.headers().contains_key(CONTENT_ENCODING));
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&events[..], &body[..]);
+    }
+
+    #[test]
+    fn http_compression_level_per_encoding() {
+        let cfg = config_from_yaml(indoc::indoc! {"
+            http_compression_level: fastest
+            http_compression_level_br: 4
+            http_compression_level_gzip: 6
+            http_compression_level_deflate: best
+        "})
+        .unwrap();
+
+        let level_for = |accept_encoding: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(accept_encoding));
+            let encoding = negotiate_encoding(&headers);
+            (encoding, compression_level(&cfg, encoding))
+        };
+
+        assert_eq!(
+            (Encoding::Brotli, CompressionLevel::Precise(4)),
+            level_for("gzip, deflate, br")
+        );
+        assert_eq!(
+            (Encoding::Gzip, CompressionLevel::Precise(6)),
+            level_for("gzip, br;q=0.5")
+        );
+        assert_eq!(
+            (Encoding::Deflate, CompressionLevel::Best),
+            level_for("deflate")
+        );
+        assert_eq!(
+            (Encoding::Zstd, CompressionLevel::Fastest),
+            level_for("zstd, br")
+        );
+        assert_eq!(Encoding::Identity, level_for("identity, br;q=0").0);
+    }
 }
@@ -1,18 +1,20 @@
+use compression_fallback::CompressionFallbackLayer;
 use http::HeaderValue;
-use http_compression::CompressionPredicate;
-use tower_http::{
-    compression::CompressionLayer,
-    cors::{AllowHeaders, AllowMethods, Any, CorsLayer, ExposeHeaders},
-};
+use http_compression::{CompressionPredicate, EncodingCompressionLayer};
+use tower_http::cors::{AllowHeaders, AllowMethods, Any, CorsLayer, ExposeHeaders};
 
 use crate::config::{to_allow_methods, to_headernames, ArxConfig, OrAny};
 
+pub mod compression_fallback;
 pub mod http_compression;
 
-pub fn compression_layer(cfg: &ArxConfig) -> CompressionLayer<CompressionPredicate> {
-    CompressionLayer::new()
-        .quality(cfg.http_compression_level.0)
-        .compress_when(CompressionPredicate { cfg })
+pub fn compression_layer(
+    cfg: &ArxConfig,
+) -> CompressionFallbackLayer<EncodingCompressionLayer<CompressionPredicate>> {
+    CompressionFallbackLayer::new(
+        EncodingCompressionLayer::new(cfg, CompressionPredicate { cfg }),
+        cfg.http_compression_fallback,
+    )
 }
 
 pub fn cors_layer(cfg: &'static ArxConfig) -> CorsLayer {
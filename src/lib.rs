@@ -1,33 +1,59 @@
-use std::sync::Arc;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 
+use admission::RetryBudget;
 use anyhow::Context;
 use arc_swap::ArcSwap;
+use authentication::AccessTokenSource;
+use client_ip::PeerAddr;
 use config::ArxConfig;
-use gateway::{serve_gateway, Backends, Gateway, GatewayState};
+use gateway::{serve_admin, serve_gateway, Backends, Gateway, GatewayListener, GatewayState};
+use headers::parse_response_headers;
 use http_client::HttpClient;
-use k8s::k8s_routing::{self, spawn_k8s_watchers};
+use k8s::k8s_routing::{self, check_k8s_routes, spawn_k8s_watchers, RouteReloadTrigger};
+use static_routes::admin_routes;
 use thiserror::Error;
+use tokio::net::TcpListener;
 use tower_server::Scheme;
 
 pub mod config;
+pub mod log_level;
 
+mod access_log;
+mod admission;
 mod authentication;
+mod body;
+mod body_digest;
+mod client_ip;
 mod gateway;
+mod grpc_status;
 mod headers;
 mod http_client;
 mod hyper;
+mod json_transform;
 mod k8s;
 mod layers;
+mod link_rewrite;
 mod local;
+mod media_type;
+mod metrics;
+mod path_rewrite;
+mod proxy_protocol;
+mod request_id;
 mod reverse_proxy;
 mod route;
 mod static_routes;
 
+#[cfg(test)]
+mod testing;
+
 #[derive(Error, Debug)]
 enum ArxError {
     #[error("not authenticated")]
     NotAuthenticated,
 
+    #[error("authentication timed out")]
+    AuthTimeout,
+
     #[error("internal: {0}")]
     Internal(anyhow::Error),
 }
@@ -36,6 +62,17 @@ fn arx_anyhow(error: impl Into<anyhow::Error>) -> ArxError {
     ArxError::Internal(error.into())
 }
 
+/// Check the config and the HTTPRoutes currently in the cluster without starting the gateway,
+/// returning the problems found.
+pub async fn check(cfg: ArxConfig) -> anyhow::Result<Vec<String>> {
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    let mut problems = cfg.problems();
+    problems.extend(check_k8s_routes(&cfg).await?);
+
+    Ok(problems)
+}
+
 pub async fn run(cfg: ArxConfig) -> anyhow::Result<()> {
     let _ = rustls::crypto::ring::default_provider().install_default();
 
@@ -64,20 +101,58 @@ pub async fn run(cfg: ArxConfig) -> anyhow::Result<()> {
         (authly_client, authly_http_client)
     };
 
-    let http_server = tower_server::Builder::new("0.0.0.0:80".parse().unwrap())
-        .with_scheme(Scheme::Http)
-        .with_graceful_shutdown(cancel.clone())
-        .bind()
-        .await
-        .context("failed to bind http server")?;
-
-    let routes = Arc::new(ArcSwap::new(Arc::new(k8s_routing::rebuild_routing_table(
-        &Default::default(),
-        default_http_client
-            .current_instance()
-            .reqwest_client
-            .clone(),
-    )?)));
+    let mut authly_clusters: HashMap<String, Arc<dyn AccessTokenSource>> = HashMap::new();
+    for (name, url) in &cfg.authly_clusters {
+        let client = authly_client::Client::builder()
+            .with_url(url.clone())
+            .from_environment()
+            .await?
+            .connect()
+            .await
+            .with_context(|| format!("failed to connect to authly cluster `{name}`"))?;
+        authly_clusters.insert(name.clone(), Arc::new(client));
+    }
+
+    let listener =
+        if cfg.http2_cleartext || cfg.proxy_protocol || cfg.max_connections_per_ip.is_some() {
+            GatewayListener::Tcp(
+                TcpListener::bind("0.0.0.0:80")
+                    .await
+                    .context("failed to bind http server")?,
+                cancel.clone(),
+            )
+        } else {
+            GatewayListener::Server(
+                tower_server::Builder::new("0.0.0.0:80".parse().unwrap())
+                    .with_scheme(Scheme::Http)
+                    .with_graceful_shutdown(cancel.clone())
+                    .with_connection_middleware(
+                        |req: &mut http::Request<::hyper::body::Incoming>,
+                         remote_addr: SocketAddr| {
+                            req.extensions_mut().insert(PeerAddr(remote_addr));
+                        },
+                    )
+                    .bind()
+                    .await
+                    .context("failed to bind http server")?,
+            )
+        };
+
+    let (reload_trigger, reload_requests) = RouteReloadTrigger::channel();
+
+    let routes = Arc::new(ArcSwap::new(Arc::new(
+        k8s_routing::rebuild_routing_table(
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            default_http_client
+                .current_instance()
+                .reqwest_client
+                .clone(),
+            cfg,
+        )?
+        .routes,
+    )));
 
     let gateway = Gateway::new(GatewayState {
         routes: routes.clone(),
@@ -85,7 +160,12 @@ pub async fn run(cfg: ArxConfig) -> anyhow::Result<()> {
             default: default_http_client.clone(),
             authly: authly_http_client,
         },
-        authly_client: Some(authly_client),
+        authly_client: Some(Arc::new(authly_client)),
+        authly_clusters,
+        in_flight: Default::default(),
+        websocket_tunnels: Default::default(),
+        retry_budget: RetryBudget::from_config(cfg),
+        response_headers: parse_response_headers(cfg)?,
         cfg,
     });
 
@@ -95,11 +175,24 @@ pub async fn run(cfg: ArxConfig) -> anyhow::Result<()> {
             .current_instance()
             .reqwest_client
             .clone(),
+        reload_requests,
+        cfg,
         cancel.clone(),
     )
     .await?;
 
-    tokio::spawn(serve_gateway(gateway, http_server));
+    if cfg.admin_api {
+        let admin_server = tower_server::Builder::new(cfg.admin_bind_address)
+            .with_scheme(Scheme::Http)
+            .with_graceful_shutdown(cancel.clone())
+            .bind()
+            .await
+            .context("failed to bind admin server")?;
+
+        tokio::spawn(serve_admin(admin_routes(reload_trigger)?, admin_server));
+    }
+
+    tokio::spawn(serve_gateway(gateway, listener));
 
     cancel.cancelled().await;
 
@@ -0,0 +1,97 @@
+//! Rewriting of absolute backend URLs in response bodies
+
+use std::collections::HashMap;
+
+use aho_corasick::{AhoCorasick, MatchKind};
+
+/// Replacement of backend base URLs with gateway-facing base URLs in text.
+///
+/// Each occurrence is replaced once, so a replacement is never rewritten again,
+/// and where several base URLs match, the longest one is used.
+/// The text is scanned once, whatever the number of base URLs.
+#[derive(Clone, Debug)]
+pub struct LinkRewrite {
+    /// Matcher of the backend base URLs
+    matcher: AhoCorasick,
+    /// The gateway base URLs, in the order of the backend base URLs of the matcher
+    replacements: Vec<String>,
+}
+
+impl LinkRewrite {
+    pub fn new(replacements: &HashMap<String, String>) -> Self {
+        let (patterns, replacements): (Vec<_>, Vec<_>) = replacements
+            .iter()
+            .filter(|(from, _)| !from.is_empty())
+            .map(|(from, to)| (from.clone(), to.clone()))
+            .unzip();
+        let matcher = AhoCorasick::builder()
+            .match_kind(MatchKind::LeftmostLongest)
+            .build(patterns)
+            // only fails for patterns far larger than any URL
+            .expect("link rewrite patterns too large");
+
+        Self {
+            matcher,
+            replacements,
+        }
+    }
+
+    /// Rewrite the text, returning `None` when there is nothing to rewrite
+    pub fn apply(&self, text: &str) -> Option<String> {
+        if !self.matcher.is_match(text) {
+            return None;
+        }
+        Some(self.matcher.replace_all(text, &self.replacements))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrite_links() {
+        let rewrite = LinkRewrite::new(
+            &[
+                (
+                    "http://app:8080/".to_string(),
+                    "https://example.com/app/".to_string(),
+                ),
+                (
+                    "http://app:8080/static/".to_string(),
+                    "https://cdn.example.com/".to_string(),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        );
+
+        assert_eq!(
+            Some(
+                r#"<a href="https://example.com/app/page">, <img src="https://cdn.example.com/logo.png">"#
+                    .to_string()
+            ),
+            rewrite.apply(
+                r#"<a href="http://app:8080/page">, <img src="http://app:8080/static/logo.png">"#
+            )
+        );
+        assert_eq!(None, rewrite.apply("http://other:8080/page"));
+    }
+
+    #[test]
+    fn replacements_not_rewritten_again() {
+        let rewrite = LinkRewrite::new(
+            &[
+                ("http://a/".to_string(), "http://b/".to_string()),
+                ("http://b/".to_string(), "http://a/".to_string()),
+            ]
+            .into_iter()
+            .collect(),
+        );
+
+        assert_eq!(
+            Some("http://b/x http://a/y".to_string()),
+            rewrite.apply("http://a/x http://b/y")
+        );
+    }
+}
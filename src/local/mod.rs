@@ -1,17 +1,26 @@
 //! poor-man's low-level HTTP service system used within arx
 
+use std::{convert::Infallible, hash::Hasher, path::PathBuf};
+
 use async_trait::async_trait;
 use bytes::Bytes;
-use http::{header, HeaderName, HeaderValue};
+use http::{header, HeaderMap, HeaderName, HeaderValue};
 use http::{Method, StatusCode};
-use http_body_util::{BodyExt, Full};
+use http_body_util::{BodyExt, Full, Limited};
 use hyper::body::Incoming;
-use tower::ServiceExt;
+use siphasher::sip::SipHasher13;
+use tower::{Service, ServiceExt};
 use tower_http::services::{ServeDir, ServeFile};
+use tracing::{error, info, level_filters::LevelFilter};
 
 use health::health;
 
-use crate::hyper::{DynHttpError, HttpError, HyperResponse};
+use crate::{
+    hyper::{empty_body, DynHttpError, HttpError, HyperResponse},
+    k8s::k8s_routing::RouteReloadTrigger,
+    log_level::{log_level, set_log_level},
+    metrics::METRICS,
+};
 
 mod health;
 
@@ -20,11 +29,87 @@ type Res = Result<HyperResponse, HttpError>;
 fn match_get(req: &http::Request<Incoming>) -> Result<(), HttpError> {
     match req.method() {
         &Method::GET => Ok(()),
-        _ => Err(HttpError::Static(
-            StatusCode::METHOD_NOT_ALLOWED,
-            "method not allowed",
-        )),
+        _ => Err(HttpError::MethodNotAllowed(vec![Method::GET])),
+    }
+}
+
+fn match_post(req: &http::Request<Incoming>) -> Result<(), HttpError> {
+    match req.method() {
+        &Method::POST => Ok(()),
+        _ => Err(HttpError::MethodNotAllowed(vec![Method::POST])),
+    }
+}
+
+/// Serve files, adding `ETag` validation to the `Last-Modified` validation done by `service`.
+async fn serve_files<S, ReqBody, ResBody>(service: S, mut req: http::Request<ReqBody>) -> Res
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>, Error = Infallible>,
+    ResBody: http_body::Body<Data = Bytes> + Send + 'static,
+    ResBody::Error: std::error::Error + Send + Sync + 'static,
+{
+    let if_none_match = req.headers().get(header::IF_NONE_MATCH).cloned();
+    if if_none_match.is_some() {
+        // If-None-Match takes precedence
+        req.headers_mut().remove(header::IF_MODIFIED_SINCE);
+    }
+
+    let Ok(mut response) = service.oneshot(req).await;
+    if response.status() == StatusCode::OK {
+        if let Some(etag) = weak_etag(response.headers()) {
+            if if_none_match.is_some_and(|if_none_match| etag_matches(&if_none_match, &etag)) {
+                let mut not_modified = http::Response::new(empty_body());
+                *not_modified.status_mut() = StatusCode::NOT_MODIFIED;
+                *not_modified.headers_mut() = std::mem::take(response.headers_mut());
+                for content_header in [
+                    header::CONTENT_LENGTH,
+                    header::CONTENT_TYPE,
+                    header::ACCEPT_RANGES,
+                ] {
+                    not_modified.headers_mut().remove(content_header);
+                }
+                not_modified.headers_mut().insert(header::ETAG, etag);
+                return Ok(not_modified);
+            }
+
+            response.headers_mut().insert(header::ETAG, etag);
+        }
     }
+
+    Ok(response.map(|body| {
+        body.map_err(|err| -> DynHttpError { Box::new(err) })
+            .boxed_unsync()
+    }))
+}
+
+/// A weak ETag derived from the `Last-Modified` and `Content-Length` of a file response
+fn weak_etag(headers: &HeaderMap) -> Option<HeaderValue> {
+    let last_modified = headers.get(header::LAST_MODIFIED)?;
+    let content_length = headers.get(header::CONTENT_LENGTH)?;
+
+    // replicas behind a load balancer must agree on the tag, whatever toolchain built them
+    let mut hasher = SipHasher13::new_with_keys(0, 0);
+    hasher.write(last_modified.as_bytes());
+    // header values never contain NUL, so it separates them unambiguously
+    hasher.write(&[0]);
+    hasher.write(content_length.as_bytes());
+
+    HeaderValue::from_str(&format!("W/\"{:x}\"", hasher.finish())).ok()
+}
+
+/// Weak comparison of `If-None-Match` against an ETag
+fn etag_matches(if_none_match: &HeaderValue, etag: &HeaderValue) -> bool {
+    let Ok(if_none_match) = if_none_match.to_str() else {
+        return false;
+    };
+    let Ok(etag) = etag.to_str() else {
+        return false;
+    };
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+
+    if_none_match.trim() == "*"
+        || if_none_match
+            .split(',')
+            .any(|candidate| opaque(candidate) == opaque(etag))
 }
 
 /// HTTP services implemented by the gateway itself
@@ -49,8 +134,9 @@ impl LocalService for Onto {
 impl Onto {
     async fn handle_inner(&self, req: http::Request<Incoming>) -> Res {
         let service = ServeDir::new("onto").fallback(ServeFile::new("onto/index.html"));
-        let mut oneshot = service.oneshot(req).await.unwrap();
-        let headers = oneshot.headers_mut();
+        let mut response = serve_files(service, req).await?;
+        // injected into all responses, 304 included, as they would be part of the 200 response
+        let headers = response.headers_mut();
         headers.append(
             HeaderName::from_static("cross-origin-embedder-policy"),
             HeaderValue::from_static("credentialless"),
@@ -63,10 +149,7 @@ impl Onto {
             HeaderName::from_static("cross-origin-resource-policy"),
             HeaderValue::from_static("cross-origin"),
         );
-        Ok(oneshot.map(|body| {
-            body.map_err(|err| -> DynHttpError { Box::new(err) })
-                .boxed_unsync()
-        }))
+        Ok(response)
     }
 }
 
@@ -76,11 +159,27 @@ pub struct Docs;
 impl LocalService for Docs {
     async fn handle(&self, req: http::Request<Incoming>) -> Res {
         let service = ServeDir::new("docs").fallback(ServeFile::new("docs/index.html"));
+        serve_files(service, req).await
+    }
+}
+
+/// The proxied routes of the routing table, as JSON, served under `/docs/`
+pub struct RouteListing {
+    pub json: Bytes,
+}
 
-        Ok(service.oneshot(req).await.unwrap().map(|body| {
-            body.map_err(|err| -> DynHttpError { Box::new(err) })
-                .boxed_unsync()
-        }))
+#[async_trait]
+impl LocalService for RouteListing {
+    async fn handle(&self, req: http::Request<Incoming>) -> Res {
+        match_get(&req)?;
+        Ok(http::Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(
+                Full::new(self.json.clone())
+                    .map_err(|err| match err {})
+                    .boxed_unsync(),
+            )
+            .unwrap())
     }
 }
 
@@ -89,12 +188,7 @@ pub struct Static;
 #[async_trait]
 impl LocalService for Static {
     async fn handle(&self, req: http::Request<Incoming>) -> Res {
-        let service = ServeDir::new("static");
-
-        Ok(service.oneshot(req).await.unwrap().map(|body| {
-            body.map_err(|err| -> DynHttpError { Box::new(err) })
-                .boxed_unsync()
-        }))
+        serve_files(ServeDir::new("static"), req).await
     }
 }
 
@@ -133,3 +227,210 @@ impl LocalService for Services {
             .unwrap())
     }
 }
+
+/// Admin endpoint exposing metrics in the Prometheus text format
+pub struct Metrics;
+
+#[async_trait]
+impl LocalService for Metrics {
+    async fn handle(&self, req: http::Request<Incoming>) -> Res {
+        match_get(&req)?;
+        let text: Bytes = METRICS.render().into();
+
+        Ok(http::Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Full::new(text).map_err(|err| match err {}).boxed_unsync())
+            .unwrap())
+    }
+}
+
+/// ACME HTTP-01 challenge responses, as files named by their token
+pub struct AcmeChallenge {
+    pub dir: PathBuf,
+}
+
+#[async_trait]
+impl LocalService for AcmeChallenge {
+    async fn handle(&self, req: http::Request<Incoming>) -> Res {
+        match_get(&req)?;
+        let not_found = HttpError::Static(StatusCode::NOT_FOUND, "Not found");
+
+        let token = req.uri().path().rsplit('/').next().unwrap_or_default();
+        // tokens are base64url, which also keeps them within the directory
+        if token.is_empty()
+            || !token
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        {
+            return Err(not_found);
+        }
+
+        let key_authorization = tokio::fs::read(self.dir.join(token))
+            .await
+            .map_err(|_| not_found)?;
+
+        Ok(http::Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(
+                Full::new(Bytes::from(key_authorization))
+                    .map_err(|err| match err {})
+                    .boxed_unsync(),
+            )
+            .unwrap())
+    }
+
+    fn replace_prefix(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// Admin endpoint forcing a rebuild of the routing table
+pub struct ReloadRoutes {
+    pub trigger: RouteReloadTrigger,
+}
+
+#[async_trait]
+impl LocalService for ReloadRoutes {
+    async fn handle(&self, req: http::Request<Incoming>) -> Res {
+        match_post(&req)?;
+        if let Err(err) = self.trigger.reload().await {
+            error!(?err, "route reload failed");
+            return Err(HttpError::Static(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "route reload unavailable",
+            ));
+        }
+
+        Ok(http::Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(
+                Full::new(Bytes::new())
+                    .map_err(|err| match err {})
+                    .boxed_unsync(),
+            )
+            .unwrap())
+    }
+}
+
+/// Admin endpoint for reading (`GET`) and changing (`PUT`, e.g. `debug`) the log level
+pub struct LogLevel;
+
+#[async_trait]
+impl LocalService for LogLevel {
+    async fn handle(&self, req: http::Request<Incoming>) -> Res {
+        match req.method() {
+            &Method::GET => {
+                let level = log_level().ok_or(HttpError::Static(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "log level is not reloadable",
+                ))?;
+
+                Ok(http::Response::builder()
+                    .status(StatusCode::OK)
+                    .header(header::CONTENT_TYPE, "text/plain")
+                    .body(
+                        Full::new(Bytes::from(level.to_string()))
+                            .map_err(|err| match err {})
+                            .boxed_unsync(),
+                    )
+                    .unwrap())
+            }
+            &Method::PUT => {
+                let body = Limited::new(req.into_body(), 64)
+                    .collect()
+                    .await
+                    .map_err(|_| HttpError::bad_request("invalid log level"))?
+                    .to_bytes();
+                let level: LevelFilter = std::str::from_utf8(&body)
+                    .ok()
+                    .and_then(|level| level.trim().parse().ok())
+                    .ok_or(HttpError::bad_request("invalid log level"))?;
+
+                if let Err(err) = set_log_level(level) {
+                    error!(?err, "log level change failed");
+                    return Err(HttpError::Static(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "log level is not reloadable",
+                    ));
+                }
+                info!(%level, "log level changed");
+
+                Ok(http::Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(
+                        Full::new(Bytes::new())
+                            .map_err(|err| match err {})
+                            .boxed_unsync(),
+                    )
+                    .unwrap())
+            }
+            _ => Err(HttpError::MethodNotAllowed(vec![Method::GET, Method::PUT])),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::Empty;
+
+    use super::*;
+
+    #[test]
+    fn weak_etag_stable() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::LAST_MODIFIED,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("7"));
+
+        // the same for every build, so replicas agree
+        assert_eq!("W/\"6a603d18df3db01e\"", weak_etag(&headers).unwrap());
+    }
+
+    #[tokio::test]
+    async fn if_none_match_not_modified() {
+        let dir = std::env::temp_dir().join(format!("arx-serve-files-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("file.txt"), "content").unwrap();
+
+        let request = |if_none_match: Option<&HeaderValue>| {
+            let mut req = http::Request::get("/file.txt");
+            if let Some(if_none_match) = if_none_match {
+                req = req.header(header::IF_NONE_MATCH, if_none_match);
+            }
+            req.body(Empty::<Bytes>::new()).unwrap()
+        };
+
+        let response = serve_files(ServeDir::new(&dir), request(None))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let etag = response.headers().get(header::ETAG).unwrap().clone();
+
+        let response = serve_files(ServeDir::new(&dir), request(Some(&etag)))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_MODIFIED, response.status());
+        assert_eq!(response.headers().get(header::ETAG).unwrap(), &etag);
+        assert!(response
+            .into_body()
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes()
+            .is_empty());
+
+        let response = serve_files(
+            ServeDir::new(&dir),
+            request(Some(&HeaderValue::from_static("W/\"other\""))),
+        )
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
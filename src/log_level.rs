@@ -0,0 +1,95 @@
+//! Changing the log level at runtime
+
+use std::sync::OnceLock;
+
+use anyhow::anyhow;
+use tracing::{level_filters::LevelFilter, Subscriber};
+use tracing_subscriber::reload;
+
+/// Type-erased [reload::Handle], the subscriber type is only known to the binary
+trait LevelReloader: Send + Sync {
+    fn reload(&self, level: LevelFilter) -> anyhow::Result<()>;
+
+    fn current(&self) -> Option<LevelFilter>;
+}
+
+impl<S: 'static> LevelReloader for reload::Handle<LevelFilter, S> {
+    fn reload(&self, level: LevelFilter) -> anyhow::Result<()> {
+        Ok(reload::Handle::reload(self, level)?)
+    }
+
+    fn current(&self) -> Option<LevelFilter> {
+        self.clone_current()
+    }
+}
+
+static RELOADER: OnceLock<Box<dyn LevelReloader>> = OnceLock::new();
+
+/// A level filter that can be changed later with [set_log_level].
+///
+/// Only the first filter made is reloadable.
+pub fn reloadable_level_filter<S>(level: LevelFilter) -> reload::Layer<LevelFilter, S>
+where
+    S: Subscriber + 'static,
+{
+    let (layer, handle) = reload::Layer::new(level);
+    let _ = RELOADER.set(Box::new(handle));
+    layer
+}
+
+pub fn set_log_level(level: LevelFilter) -> anyhow::Result<()> {
+    RELOADER
+        .get()
+        .ok_or_else(|| anyhow!("log level is not reloadable"))?
+        .reload(level)
+}
+
+pub fn log_level() -> Option<LevelFilter> {
+    RELOADER.get()?.current()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use tracing::{debug, info, Event};
+    use tracing_subscriber::{layer::Context, layer::SubscriberExt, Layer, Registry};
+
+    use super::*;
+
+    struct EventCounter(Arc<AtomicUsize>);
+
+    impl<S: Subscriber> Layer<S> for EventCounter {
+        fn on_event(&self, _event: &Event<'_>, _ctx: Context<'_, S>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn change_log_level() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let subscriber = Registry::default()
+            .with(reloadable_level_filter(LevelFilter::INFO))
+            .with(EventCounter(count.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            debug!("filtered");
+            info!("logged");
+            assert_eq!(1, count.load(Ordering::SeqCst));
+
+            set_log_level(LevelFilter::DEBUG).unwrap();
+            assert_eq!(Some(LevelFilter::DEBUG), log_level());
+
+            debug!("logged");
+            assert_eq!(2, count.load(Ordering::SeqCst));
+
+            set_log_level(LevelFilter::WARN).unwrap();
+
+            info!("filtered");
+            assert_eq!(2, count.load(Ordering::SeqCst));
+        });
+    }
+}
@@ -1,6 +1,7 @@
 use std::env;
 
-use arx::config::ArxConfig;
+use anyhow::bail;
+use arx::{config::ArxConfig, log_level::reloadable_level_filter};
 use clap::Parser;
 use opentelemetry::trace::TracerProvider as _;
 use opentelemetry::KeyValue;
@@ -11,23 +12,40 @@ use opentelemetry_sdk::{
     trace::{RandomIdGenerator, Sampler, TracerProvider},
     Resource,
 };
-use tracing::{info, level_filters::LevelFilter, Level};
+use tracing::{error, info, level_filters::LevelFilter, Level};
 use tracing_opentelemetry::OpenTelemetryLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[derive(clap::Parser)]
-pub struct Cli {}
+pub struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Check the config and the current HTTPRoutes, then exit without serving.
+    /// Exits with an error if there are any problems.
+    Check,
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let _cli = Cli::parse();
+    let cli = Cli::parse();
     let cfg = ArxConfig::from_env();
 
+    // an unreadable config or log level is reported once logging is set up, with the default level
+    let log_level = cfg
+        .as_ref()
+        .ok()
+        .and_then(|cfg| cfg.log_level.parse::<Level>().ok())
+        .unwrap_or(Level::INFO);
+
     let tracing_layer = tracing_subscriber::registry()
         // coarse-grained filtering
-        .with(LevelFilter::from(cfg.log_level.parse::<Level>()?))
+        .with(reloadable_level_filter(LevelFilter::from(log_level)))
         .with(tracing_subscriber::fmt::layer().with_target(false));
 
     opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
@@ -72,7 +90,32 @@ async fn main() -> anyhow::Result<()> {
 
     info!("🏰 Arx v{VERSION}");
 
-    arx::run(cfg).await?;
+    let cfg = match cfg {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            for err in err {
+                error!("invalid config: {err}");
+            }
+            bail!("invalid config");
+        }
+    };
+
+    match cli.command {
+        None => {
+            cfg.log_level.parse::<Level>()?;
+            arx::run(cfg).await?
+        }
+        Some(Command::Check) => {
+            let problems = arx::check(cfg).await?;
+            for problem in &problems {
+                error!("{problem}");
+            }
+            if !problems.is_empty() {
+                bail!("found {} problem(s)", problems.len());
+            }
+            info!("no problems found");
+        }
+    }
 
     Ok(())
 }
@@ -0,0 +1,174 @@
+//! Enforcement of request and response media types
+
+use http::{header, HeaderMap, StatusCode};
+use http_body::Body;
+
+use crate::hyper::HttpError;
+
+/// The media types a route accepts in request bodies and returns in responses.
+///
+/// An empty list doesn't restrict anything.
+#[derive(Clone, Debug, Default)]
+pub struct MediaTypes {
+    /// Media types of request bodies, compared to `Content-Type`
+    pub request: Vec<String>,
+    /// Media types of responses, compared to `Accept`
+    pub response: Vec<String>,
+}
+
+impl MediaTypes {
+    /// Check a request before it's proxied.
+    ///
+    /// A request body of another media type is `415 Unsupported Media Type`,
+    /// and a request accepting none of the response media types is `406 Not Acceptable`.
+    pub fn check<B: Body>(&self, req: &http::Request<B>) -> Result<(), HttpError> {
+        let headers = req.headers();
+        if !self.request.is_empty() {
+            let supported = match headers.get(header::CONTENT_TYPE) {
+                Some(content_type) => {
+                    content_type
+                        .to_str()
+                        .ok()
+                        .map(media_type)
+                        .is_some_and(|media_type| {
+                            self.request
+                                .iter()
+                                .any(|supported| supported.eq_ignore_ascii_case(media_type))
+                        })
+                }
+                // no body, nothing to check
+                None => req.body().is_end_stream(),
+            };
+            if !supported {
+                return Err(HttpError::Static(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "unsupported media type",
+                ));
+            }
+        }
+
+        if !self.response.is_empty() && !self.acceptable(headers) {
+            return Err(HttpError::Static(
+                StatusCode::NOT_ACCEPTABLE,
+                "not acceptable",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Whether any response media type is acceptable to the client. Without `Accept`, anything is.
+    fn acceptable(&self, headers: &HeaderMap) -> bool {
+        let mut ranges = headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|accept| accept.to_str().ok())
+            .flat_map(|accept| accept.split(','))
+            .filter(|range| !range.trim().is_empty())
+            .peekable();
+        if ranges.peek().is_none() {
+            return true;
+        }
+
+        ranges.any(|range| {
+            let mut params = range.split(';');
+            let media_range = params.next().unwrap_or_default().trim();
+            let rejected = params.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .is_some_and(|q| q <= 0.0)
+            });
+
+            !rejected
+                && self
+                    .response
+                    .iter()
+                    .any(|media_type| matches_range(media_type, media_range))
+        })
+    }
+}
+
+/// The media type of a `Content-Type`, without parameters
+fn media_type(content_type: &str) -> &str {
+    content_type.split(';').next().unwrap_or_default().trim()
+}
+
+/// Whether a media type is in a media range, e.g. `application/json` in `application/*`
+fn matches_range(media_type: &str, range: &str) -> bool {
+    if range == "*/*" || media_type.eq_ignore_ascii_case(range) {
+        return true;
+    }
+    match (range.strip_suffix("/*"), media_type.split_once('/')) {
+        (Some(range_type), Some((type_, _))) => range_type.eq_ignore_ascii_case(type_),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use http_body_util::Full;
+
+    use super::*;
+
+    fn request(
+        pairs: &[(header::HeaderName, &'static str)],
+        body: &'static str,
+    ) -> http::Request<Full<Bytes>> {
+        let mut request = http::Request::new(Full::new(Bytes::from_static(body.as_bytes())));
+        for (name, value) in pairs {
+            request
+                .headers_mut()
+                .insert(name.clone(), http::HeaderValue::from_static(value));
+        }
+        request
+    }
+
+    #[test]
+    fn check_media_types() {
+        let json_only = MediaTypes {
+            request: vec!["application/json".to_string()],
+            response: vec!["application/json".to_string()],
+        };
+        let status = |request: http::Request<Full<Bytes>>| match json_only.check(&request) {
+            Ok(()) => 200,
+            Err(HttpError::Static(status, _)) => status.as_u16(),
+            Err(err) => panic!("unexpected error: {err:?}"),
+        };
+
+        assert_eq!(200, status(request(&[], "")));
+        assert_eq!(
+            200,
+            status(request(
+                &[
+                    (header::CONTENT_TYPE, "application/json; charset=utf-8"),
+                    (header::ACCEPT, "text/html, application/*;q=0.5"),
+                ],
+                "{}"
+            ))
+        );
+        assert_eq!(
+            415,
+            status(request(
+                &[(header::CONTENT_TYPE, "application/x-www-form-urlencoded")],
+                "a=1"
+            ))
+        );
+        // a body without a media type, whatever its framing
+        assert_eq!(415, status(request(&[], "hello")));
+        assert_eq!(
+            415,
+            status(request(&[(header::CONTENT_LENGTH, "05")], "hello"))
+        );
+        assert_eq!(406, status(request(&[(header::ACCEPT, "text/html")], "")));
+        assert_eq!(
+            406,
+            status(request(
+                &[(header::ACCEPT, "text/html, application/json;q=0")],
+                ""
+            ))
+        );
+    }
+}
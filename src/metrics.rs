@@ -0,0 +1,254 @@
+//! Gateway metrics, exposed in the Prometheus text format
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock, Mutex,
+    },
+    time::Duration,
+};
+
+/// The metrics of the running gateway
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+tokio::task_local! {
+    /// Metrics of the backend currently being requested, used to time new connections
+    pub static CURRENT_BACKEND: Arc<BackendMetrics>;
+}
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+pub struct Metrics {
+    routes: Mutex<BTreeMap<String, Arc<RouteMetrics>>>,
+    backends: Mutex<BTreeMap<String, Arc<BackendMetrics>>>,
+}
+
+/// Counters for one HTTPRoute
+#[derive(Default)]
+pub struct RouteMetrics {
+    /// Bytes of request bodies proxied to the backend
+    pub request_bytes: AtomicU64,
+    /// Bytes of response bodies proxied from the backend
+    pub response_bytes: AtomicU64,
+}
+
+/// Latency histograms for one backend
+#[derive(Default)]
+pub struct BackendMetrics {
+    /// Time to establish a new connection
+    pub connect: Histogram,
+    /// Time until the response headers arrive
+    pub ttfb: Histogram,
+    /// Time until the response body is finished
+    pub total: Histogram,
+}
+
+/// A latency histogram with [LATENCY_BUCKETS]
+#[derive(Default)]
+pub struct Histogram {
+    /// Non-cumulative observation counts, the last bucket is `+Inf`
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|le| seconds <= *le)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .sum()
+    }
+}
+
+impl Metrics {
+    /// Get the metrics of a route by name.
+    /// The counters are kept when the routing table is rebuilt, as long as the route is in it.
+    pub fn route(&self, name: &str) -> Arc<RouteMetrics> {
+        self.routes
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Forget the metrics of routes no longer in the routing table, nor proxying requests.
+    pub fn prune_routes(&self) {
+        self.routes
+            .lock()
+            .unwrap()
+            .retain(|_, route| Arc::strong_count(route) > 1);
+    }
+
+    /// Get the metrics of a backend by its authority (`host:port`).
+    pub fn backend(&self, authority: &str) -> Arc<BackendMetrics> {
+        self.backends
+            .lock()
+            .unwrap()
+            .entry(authority.to_string())
+            .or_default()
+            .clone()
+    }
+
+    pub fn render(&self) -> String {
+        let routes = self.routes.lock().unwrap();
+        let backends = self.backends.lock().unwrap();
+        let mut output = String::new();
+
+        for (metric, help, counter) in [
+            (
+                "arx_route_request_bytes_total",
+                "Bytes of request bodies proxied to the backend",
+                (|route| &route.request_bytes) as fn(&RouteMetrics) -> &AtomicU64,
+            ),
+            (
+                "arx_route_response_bytes_total",
+                "Bytes of response bodies proxied from the backend",
+                |route| &route.response_bytes,
+            ),
+        ] {
+            let _ = writeln!(output, "# HELP {metric} {help}");
+            let _ = writeln!(output, "# TYPE {metric} counter");
+            for (name, route) in routes.iter() {
+                let _ = writeln!(
+                    output,
+                    "{metric}{{route=\"{}\"}} {}",
+                    escape_label(name),
+                    counter(route).load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        for (metric, help, histogram) in [
+            (
+                "arx_backend_connect_seconds",
+                "Time to establish a new connection to the backend",
+                (|backend| &backend.connect) as fn(&BackendMetrics) -> &Histogram,
+            ),
+            (
+                "arx_backend_ttfb_seconds",
+                "Time until the response headers of the backend arrive",
+                |backend| &backend.ttfb,
+            ),
+            (
+                "arx_backend_total_seconds",
+                "Time until the response body of the backend is finished",
+                |backend| &backend.total,
+            ),
+        ] {
+            let _ = writeln!(output, "# HELP {metric} {help}");
+            let _ = writeln!(output, "# TYPE {metric} histogram");
+            for (authority, backend) in backends.iter() {
+                let backend_label = escape_label(authority);
+                let histogram = histogram(backend);
+                let mut cumulative = 0;
+                for (bucket, count) in histogram.buckets.iter().enumerate() {
+                    cumulative += count.load(Ordering::Relaxed);
+                    let le = match LATENCY_BUCKETS.get(bucket) {
+                        Some(le) => le.to_string(),
+                        None => "+Inf".to_string(),
+                    };
+                    let _ = writeln!(
+                        output,
+                        "{metric}_bucket{{backend=\"{backend_label}\",le=\"{le}\"}} {cumulative}"
+                    );
+                }
+                let _ = writeln!(
+                    output,
+                    "{metric}_sum{{backend=\"{backend_label}\"}} {}",
+                    histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+                );
+                let _ = writeln!(
+                    output,
+                    "{metric}_count{{backend=\"{backend_label}\"}} {cumulative}"
+                );
+            }
+        }
+
+        output
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::default();
+        metrics
+            .route("api")
+            .request_bytes
+            .fetch_add(3, Ordering::Relaxed);
+        metrics
+            .route("api")
+            .response_bytes
+            .fetch_add(5, Ordering::Relaxed);
+
+        assert_eq!(
+            metrics.render(),
+            indoc::indoc! {r#"
+                # HELP arx_route_request_bytes_total Bytes of request bodies proxied to the backend
+                # TYPE arx_route_request_bytes_total counter
+                arx_route_request_bytes_total{route="api"} 3
+                # HELP arx_route_response_bytes_total Bytes of response bodies proxied from the backend
+                # TYPE arx_route_response_bytes_total counter
+                arx_route_response_bytes_total{route="api"} 5
+                # HELP arx_backend_connect_seconds Time to establish a new connection to the backend
+                # TYPE arx_backend_connect_seconds histogram
+                # HELP arx_backend_ttfb_seconds Time until the response headers of the backend arrive
+                # TYPE arx_backend_ttfb_seconds histogram
+                # HELP arx_backend_total_seconds Time until the response body of the backend is finished
+                # TYPE arx_backend_total_seconds histogram
+            "#}
+        );
+    }
+
+    #[test]
+    fn render_histogram() {
+        let metrics = Metrics::default();
+        let backend = metrics.backend("api:80");
+        backend.ttfb.observe(Duration::from_millis(20));
+        backend.ttfb.observe(Duration::from_millis(300));
+        backend.ttfb.observe(Duration::from_secs(20));
+        assert_eq!(3, backend.ttfb.count());
+
+        let output = metrics.render();
+        for line in [
+            r#"arx_backend_ttfb_seconds_bucket{backend="api:80",le="0.01"} 0"#,
+            r#"arx_backend_ttfb_seconds_bucket{backend="api:80",le="0.025"} 1"#,
+            r#"arx_backend_ttfb_seconds_bucket{backend="api:80",le="0.5"} 2"#,
+            r#"arx_backend_ttfb_seconds_bucket{backend="api:80",le="10"} 2"#,
+            r#"arx_backend_ttfb_seconds_bucket{backend="api:80",le="+Inf"} 3"#,
+            r#"arx_backend_ttfb_seconds_sum{backend="api:80"} 20.32"#,
+            r#"arx_backend_ttfb_seconds_count{backend="api:80"} 3"#,
+            r#"arx_backend_connect_seconds_count{backend="api:80"} 0"#,
+        ] {
+            assert!(
+                output.lines().any(|l| l == line),
+                "{line} missing in:\n{output}"
+            );
+        }
+    }
+}
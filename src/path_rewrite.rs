@@ -0,0 +1,52 @@
+//! Rewriting of request paths with regular expressions
+
+use regex::Regex;
+
+/// Replacement of the request path matching a regular expression,
+/// with `$1` or `${name}` in the replacement substituted by the captures.
+#[derive(Clone, Debug)]
+pub struct PathRewrite {
+    regex: Regex,
+    replacement: String,
+}
+
+impl PathRewrite {
+    pub fn new(pattern: &str, replacement: &str) -> Result<Self, regex::Error> {
+        Ok(Self {
+            regex: Regex::new(pattern)?,
+            replacement: replacement.to_string(),
+        })
+    }
+
+    /// Rewrite the first match in the path, returning `None` when the path doesn't match
+    pub fn apply(&self, path: &str) -> Option<String> {
+        let captures = self.regex.captures(path)?;
+        let matched = captures.get(0)?;
+
+        let mut rewritten = String::with_capacity(path.len() + self.replacement.len());
+        rewritten.push_str(&path[..matched.start()]);
+        captures.expand(&self.replacement, &mut rewritten);
+        rewritten.push_str(&path[matched.end()..]);
+        Some(rewritten)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrite_paths() {
+        let rewrite = PathRewrite::new("^/v1/(.*)", "/api/$1").unwrap();
+        assert_eq!(Some("/api/users".to_string()), rewrite.apply("/v1/users"));
+        assert_eq!(None, rewrite.apply("/v2/users"));
+
+        let rewrite = PathRewrite::new("/users/(?<id>[0-9]+)$", "/people/${id}/profile").unwrap();
+        assert_eq!(
+            Some("/api/people/42/profile".to_string()),
+            rewrite.apply("/api/users/42")
+        );
+
+        assert!(PathRewrite::new("/v1/(", "/").is_err());
+    }
+}
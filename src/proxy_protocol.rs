@@ -0,0 +1,169 @@
+//! The PROXY protocol, used by L4 load balancers to pass on the original client address
+//!
+//! See <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Read a PROXY protocol v1 or v2 header from the start of a connection,
+/// leaving the stream positioned at the first byte after it.
+///
+/// Returns the original client address, or `None` when the connection
+/// isn't proxied on behalf of a client (`UNKNOWN` in v1, `LOCAL` or unsupported address families in v2).
+pub async fn read_proxy_header<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> io::Result<Option<SocketAddr>> {
+    let mut prefix = [0; 5];
+    stream.read_exact(&mut prefix).await?;
+
+    if prefix == V1_PREFIX {
+        read_v1(stream).await
+    } else if prefix == V2_SIGNATURE[..5] {
+        read_v2(stream).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    // the header is short, and nothing after it may be consumed
+    let mut line = V1_PREFIX.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY protocol v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY protocol v1 header is not ASCII"))?;
+    let mut fields = line.split(' ').skip(1);
+
+    match fields.next() {
+        Some("TCP4" | "TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid("invalid PROXY protocol v1 protocol")),
+    }
+
+    let (Some(src_ip), Some(_dst_ip), Some(src_port), Some(_dst_port), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        return Err(invalid("invalid PROXY protocol v1 header"));
+    };
+
+    let ip: IpAddr = src_ip
+        .parse()
+        .map_err(|_| invalid("invalid PROXY protocol v1 source address"))?;
+    let port: u16 = src_port
+        .parse()
+        .map_err(|_| invalid("invalid PROXY protocol v1 source port"))?;
+
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut rest = [0; 16 - 5];
+    stream.read_exact(&mut rest).await?;
+    if rest[..7] != V2_SIGNATURE[5..] {
+        return Err(invalid("invalid PROXY protocol v2 signature"));
+    }
+
+    let [version_command, family, len_hi, len_lo] = [rest[7], rest[8], rest[9], rest[10]];
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+
+    let mut addresses = vec![0; u16::from_be_bytes([len_hi, len_lo]) as usize];
+    stream.read_exact(&mut addresses).await?;
+
+    match version_command & 0x0f {
+        // LOCAL: e.g. health checks from the load balancer itself
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("unsupported PROXY protocol v2 command")),
+    }
+
+    // the address family is in the high nibble, the transport protocol in the low one
+    match family >> 4 {
+        0x1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        0x2 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        0x1 | 0x2 => Err(invalid("truncated PROXY protocol v2 addresses")),
+        _ => Ok(None),
+    }
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(header: &[u8]) -> (io::Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut stream = header;
+        let result = read_proxy_header(&mut stream).await;
+        (result, stream.to_vec())
+    }
+
+    #[tokio::test]
+    async fn v1() {
+        let (result, rest) = read(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 80\r\nGET /").await;
+        assert_eq!(Some("203.0.113.7:51234".parse().unwrap()), result.unwrap());
+        assert_eq!(b"GET /", rest.as_slice());
+
+        let (result, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 51234 80\r\n").await;
+        assert_eq!(
+            Some("[2001:db8::1]:51234".parse().unwrap()),
+            result.unwrap()
+        );
+
+        let (result, _) = read(b"PROXY UNKNOWN\r\n").await;
+        assert_eq!(None, result.unwrap());
+    }
+
+    #[tokio::test]
+    async fn v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        header.extend_from_slice(&[203, 0, 113, 7, 10, 0, 0, 1]);
+        header.extend_from_slice(&51234u16.to_be_bytes());
+        header.extend_from_slice(&80u16.to_be_bytes());
+        header.extend_from_slice(b"GET /");
+
+        let (result, rest) = read(&header).await;
+        assert_eq!(Some("203.0.113.7:51234".parse().unwrap()), result.unwrap());
+        assert_eq!(b"GET /", rest.as_slice());
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        let (result, _) = read(&local).await;
+        assert_eq!(None, result.unwrap());
+    }
+
+    #[tokio::test]
+    async fn missing_header() {
+        let (result, _) = read(b"GET / HTTP/1.1\r\n\r\n").await;
+        assert_eq!(io::ErrorKind::InvalidData, result.unwrap_err().kind());
+    }
+}
@@ -0,0 +1,247 @@
+//! Request IDs, identifying requests in the access log, error pages and requests to backends
+
+use std::str::FromStr;
+
+use http::{HeaderMap, HeaderName, HeaderValue};
+use opentelemetry::{
+    trace::{SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState},
+    Context,
+};
+use opentelemetry_sdk::trace::{IdGenerator, RandomIdGenerator};
+use tracing::{info_span, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::config::{ArxConfig, RequestIdFormat};
+
+/// The W3C trace context header
+const TRACEPARENT: HeaderName = HeaderName::from_static("traceparent");
+
+/// The ID of a request, in its extensions once assigned
+#[derive(Clone, Debug)]
+pub struct RequestId(pub HeaderValue);
+
+/// Identify a request as configured by `request_id`, setting the headers forwarded to backends.
+///
+/// Without a format, the ID is the `request_id_header` sent by the client, if any.
+pub fn assign_request_id<B>(req: &mut http::Request<B>, cfg: &ArxConfig) -> Option<HeaderValue> {
+    let header = HeaderName::from_str(&cfg.request_id_header).ok();
+    let headers = req.headers_mut();
+
+    let request_id = match cfg.request_id {
+        RequestIdFormat::None => headers.get(header?).cloned()?,
+        RequestIdFormat::Header => {
+            let header = header?;
+            match headers.get(&header) {
+                Some(request_id) => request_id.clone(),
+                None => {
+                    let request_id = hex_value(RandomIdGenerator::default().new_trace_id());
+                    headers.insert(header, request_id.clone());
+                    request_id
+                }
+            }
+        }
+        RequestIdFormat::Traceparent | RequestIdFormat::Both => {
+            let span_context = match parse_traceparent(headers) {
+                Some(span_context) => span_context,
+                None => {
+                    let span_context = new_trace();
+                    headers.insert(TRACEPARENT, traceparent(&span_context));
+                    span_context
+                }
+            };
+            let request_id = hex_value(span_context.trace_id());
+            if let (RequestIdFormat::Both, Some(header)) = (cfg.request_id, header) {
+                headers.insert(header, request_id.clone());
+            }
+            request_id
+        }
+    };
+
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+    Some(request_id)
+}
+
+/// A span for the logs of a request with an assigned ID.
+///
+/// With traceparent-derived IDs, the span continues the trace of the request,
+/// so the `traceparent` forwarded to backends keeps its trace ID.
+pub fn request_span<B>(req: &http::Request<B>, cfg: &ArxConfig) -> Span {
+    if cfg.request_id == RequestIdFormat::None {
+        return Span::none();
+    }
+    let Some(RequestId(request_id)) = req.extensions().get::<RequestId>() else {
+        return Span::none();
+    };
+
+    let span = info_span!("request_id", id = request_id.to_str().unwrap_or_default());
+    if cfg.request_id != RequestIdFormat::Header {
+        if let Some(span_context) = parse_traceparent(req.headers()) {
+            span.set_parent(Context::new().with_remote_span_context(span_context));
+        }
+    }
+    span
+}
+
+/// The trace context of a valid `traceparent` header (`{version}-{trace-id}-{parent-id}-{flags}`)
+fn parse_traceparent(headers: &HeaderMap) -> Option<SpanContext> {
+    let value = headers.get(TRACEPARENT)?.to_str().ok()?;
+    let mut fields = value.split('-');
+    let version = fields.next().filter(|field| is_lower_hex(field, 2))?;
+    let trace_id = fields.next().filter(|field| is_lower_hex(field, 32))?;
+    let parent_id = fields.next().filter(|field| is_lower_hex(field, 16))?;
+    let flags = fields.next().filter(|field| is_lower_hex(field, 2))?;
+
+    // later versions may append fields
+    if version == "ff" || (version == "00" && fields.next().is_some()) {
+        return None;
+    }
+
+    let trace_id = TraceId::from_hex(trace_id).ok()?;
+    let parent_id = SpanId::from_hex(parent_id).ok()?;
+    if trace_id == TraceId::INVALID || parent_id == SpanId::INVALID {
+        return None;
+    }
+
+    Some(SpanContext::new(
+        trace_id,
+        parent_id,
+        TraceFlags::new(u8::from_str_radix(flags, 16).ok()?),
+        true,
+        TraceState::default(),
+    ))
+}
+
+/// A sampled trace started by the gateway
+fn new_trace() -> SpanContext {
+    let generator = RandomIdGenerator::default();
+    SpanContext::new(
+        generator.new_trace_id(),
+        generator.new_span_id(),
+        TraceFlags::SAMPLED,
+        false,
+        TraceState::default(),
+    )
+}
+
+fn traceparent(span_context: &SpanContext) -> HeaderValue {
+    HeaderValue::from_str(&format!(
+        "00-{}-{}-{:02x}",
+        span_context.trace_id(),
+        span_context.span_id(),
+        span_context.trace_flags().to_u8()
+    ))
+    .expect("hex is a valid header value")
+}
+
+fn hex_value(trace_id: TraceId) -> HeaderValue {
+    HeaderValue::from_str(&trace_id.to_string()).expect("hex is a valid header value")
+}
+
+fn is_lower_hex(field: &str, len: usize) -> bool {
+    field.len() == len
+        && field
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assign(cfg: &ArxConfig, headers: &[(&str, &str)]) -> (Option<HeaderValue>, HeaderMap) {
+        let mut req = http::Request::new(());
+        for (name, value) in headers {
+            req.headers_mut().insert(
+                HeaderName::from_str(name).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        let request_id = assign_request_id(&mut req, cfg);
+        (request_id, req.headers().clone())
+    }
+
+    #[test]
+    fn traceparent_parsed() {
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(TRACEPARENT, HeaderValue::from_str(value).unwrap());
+            headers
+        };
+
+        let span_context = parse_traceparent(&headers(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ))
+        .unwrap();
+        assert_eq!(
+            "4bf92f3577b34da6a3ce929d0e0e4736",
+            span_context.trace_id().to_string()
+        );
+        assert!(span_context.is_sampled());
+
+        for invalid in [
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        ] {
+            assert!(parse_traceparent(&headers(invalid)).is_none(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn request_id_formats() {
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+
+        // by default, only a request ID from the client is used
+        let cfg = ArxConfig::default();
+        let (request_id, _) = assign(&cfg, &[]);
+        assert!(request_id.is_none());
+        let (request_id, _) = assign(&cfg, &[("x-request-id", "abc")]);
+        assert_eq!("abc", request_id.unwrap());
+
+        let cfg = ArxConfig {
+            request_id: RequestIdFormat::Header,
+            request_id_header: "x-correlation-id".to_string(),
+            ..Default::default()
+        };
+        let (request_id, headers) = assign(&cfg, &[("x-correlation-id", "abc")]);
+        assert_eq!("abc", request_id.unwrap());
+        assert_eq!("abc", headers["x-correlation-id"]);
+        let (request_id, headers) = assign(&cfg, &[]);
+        let request_id = request_id.unwrap();
+        assert_eq!(32, request_id.len());
+        assert_eq!(request_id, headers["x-correlation-id"]);
+        assert!(!headers.contains_key(TRACEPARENT));
+
+        let cfg = ArxConfig {
+            request_id: RequestIdFormat::Traceparent,
+            ..Default::default()
+        };
+        let (request_id, headers) = assign(&cfg, &[(TRACEPARENT.as_str(), traceparent)]);
+        assert_eq!(trace_id, request_id.unwrap());
+        assert_eq!(traceparent, headers[TRACEPARENT]);
+        assert!(!headers.contains_key("x-request-id"));
+        let (request_id, headers) = assign(&cfg, &[]);
+        let generated = parse_traceparent(&headers).unwrap();
+        assert_eq!(hex_value(generated.trace_id()), request_id.unwrap());
+
+        let cfg = ArxConfig {
+            request_id: RequestIdFormat::Both,
+            ..Default::default()
+        };
+        let (request_id, headers) = assign(
+            &cfg,
+            &[(TRACEPARENT.as_str(), traceparent), ("x-request-id", "abc")],
+        );
+        assert_eq!(trace_id, request_id.unwrap());
+        assert_eq!(trace_id, headers["x-request-id"]);
+        let (request_id, headers) = assign(&cfg, &[]);
+        let generated = parse_traceparent(&headers).unwrap();
+        let request_id = request_id.unwrap();
+        assert_eq!(hex_value(generated.trace_id()), request_id);
+        assert_eq!(request_id, headers["x-request-id"]);
+    }
+}
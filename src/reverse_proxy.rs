@@ -1,29 +1,91 @@
-use std::fmt::Debug;
+use std::{sync::Arc, time::Duration};
 
 use bytes::Bytes;
+use bytesize::ByteSize;
 use futures_util::{SinkExt, StreamExt};
 use http::{header, HeaderValue, StatusCode};
 use http_body::Body;
-use http_body_util::BodyExt;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
 use hyper_util::rt::TokioIo;
 use reqwest_websocket::RequestBuilderExt;
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::Instant,
+};
 use tokio_tungstenite::tungstenite::protocol::{self, WebSocketConfig};
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 use crate::{
-    http_client::HttpClientInstance,
-    hyper::{empty_body, HttpError, HyperResponse},
+    admission::{MessageAdmission, MessageRate, TunnelSlots},
+    body::{
+        caused_by, closed_early, BodyIdleTimeout, CountingBody, IdleTimeoutBody, TotalLatencyBody,
+        WithoutTrailersBody,
+    },
+    config::{ArxConfig, ExcessMessageHandling, InvalidHeaderHandling},
+    grpc_status::{is_grpc, GrpcStatus},
+    headers::{accepts_trailers, header_size, invalid_response_headers},
+    http_client::{HttpClientInstance, REQUEST_TIMEOUT},
+    hyper::{empty_body, DynHttpError, HttpError, HyperResponse},
+    json_transform::JsonFieldRemoval,
+    link_rewrite::LinkRewrite,
+    metrics::{RouteMetrics, CURRENT_BACKEND, METRICS},
+    route::RouteWatch,
 };
 
+/// Collect a request body in full, up to `max_size`.
+///
+/// Fails with `408 Request Timeout` when the body (an [IdleTimeoutBody]) times out,
+/// and with `413 Payload Too Large` when it exceeds the limit.
+pub async fn collect_request_body<B>(body: B, max_size: ByteSize) -> Result<Bytes, HttpError>
+where
+    B: Body<Data = Bytes>,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    Ok(Limited::new(body, max_size.as_u64() as usize)
+        .collect()
+        .await
+        .map_err(|err| {
+            if caused_by::<BodyIdleTimeout>(&*err) {
+                HttpError::Static(StatusCode::REQUEST_TIMEOUT, "request body timeout")
+            } else if err.is::<LengthLimitError>() {
+                HttpError::Static(StatusCode::PAYLOAD_TOO_LARGE, "request body too large")
+            } else {
+                HttpError::bad_request("invalid request body")
+            }
+        })?
+        .to_bytes())
+}
+
 /// Reverse-proxy a request.
 /// The URI is already rewritten to point to the backend server.
+///
+/// The request body does not need to be `Sync`: reqwest polls the body stream
+/// itself when sending, so there is no need to pump it through a channel,
+/// and backpressure is preserved.
+///
+/// Request bodies are limited to the `max_size` of the `request_body` handling, also while streaming,
+/// as a body without a `Content-Length` may turn out larger than declared. A request exceeding it
+/// is cut off, and the client gets `413 Payload Too Large` (unless the backend responded already).
+///
+/// When `buffered`, request and response bodies are instead collected in full
+/// (up to `request_max_size` and `buffered_response_max_size`) and forwarded with an accurate `Content-Length`, for backends that don't handle chunked streaming.
+/// `buffer_request` buffers only the request body, for HTTP/1.0 backends.
+///
+/// The `transforms` are applied to responses up to `json_transform_max_size`.
+/// Routes transforming bodies request an uncompressed response from the backend.
+///
+/// Response trailers are relayed only to clients sending `TE: trailers`, which is then passed on to the backend.
+/// It's also sent when translating gRPC statuses, which may be found in the trailers.
 pub async fn reverse_proxy<B>(
     mut req: http::Request<B>,
     client: &HttpClientInstance,
+    metrics: Option<Arc<RouteMetrics>>,
+    request_body: RequestBodyHandling,
+    transforms: ResponseTransforms<'_>,
+    cfg: &ArxConfig,
 ) -> Result<HyperResponse, HttpError>
 where
-    B: Body<Data = bytes::Bytes> + Send + Sync + 'static,
+    B: Body<Data = bytes::Bytes> + Send + 'static,
     B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
 {
     match req.headers().get(header::UPGRADE).map(|h| h.as_bytes()) {
@@ -32,150 +94,458 @@ where
             // FIXME: Currently tracing is disabled for websockets,
             // figure out a way to do (otel) tracing without reqwest-middleware.
             // reqwest-middleware and reqwest-websocket cannot currently be used simultaneously.
-            return proxy_websocket(req, &client.reqwest_client).await;
+            return proxy_websocket(req, &client.reqwest_client, cfg).await;
         }
         Some(_) => return Err(HttpError::bad_request("unrecognized `Upgrade` header")),
     }
 
     let method = req.method().clone();
     let uri = req.uri().clone();
-    let headers = std::mem::take(req.headers_mut());
-    let req_body = http_body_util::BodyDataStream::new(req.into_body());
+    let mut headers = std::mem::take(req.headers_mut());
+    let grpc_client = is_grpc(&headers);
+    // `TE` is hop-by-hop, only the client's acceptance of trailers is passed on to the backend
+    let trailers_accepted = accepts_trailers(&headers);
+    headers.remove(header::TE);
+    // the gRPC status of a call may only be found in the trailers
+    if trailers_accepted || (transforms.grpc_status_to_http && !grpc_client) {
+        headers.insert(header::TE, HeaderValue::from_static("trailers"));
+    }
+    // the framing of the forwarded body is decided by the HTTP client, never by the original request
+    headers.remove(header::TRANSFER_ENCODING);
+    // body transforms need the plain representation, the gateway compresses the response again for the client
+    if transforms.transforms_body() {
+        headers.insert(
+            header::ACCEPT_ENCODING,
+            HeaderValue::from_static("identity"),
+        );
+    }
+    let req_body = match &metrics {
+        Some(metrics) => CountingBody::new(req.into_body(), metrics.clone(), |metrics| {
+            &metrics.request_bytes
+        })
+        .boxed_unsync(),
+        None => req.into_body().boxed_unsync(),
+    };
+    let req_body = IdleTimeoutBody::new(req_body, cfg.request_body_idle_timeout);
+    let req_body = if request_body.buffered || request_body.buffer_request {
+        let bytes = collect_request_body(req_body, request_body.max_size).await?;
+        reqwest::Body::from(bytes)
+    } else {
+        let req_body = Limited::new(req_body, request_body.max_size.as_u64() as usize);
+        reqwest::Body::wrap_stream(http_body_util::BodyDataStream::new(req_body))
+    };
+
+    let is_head = method == http::Method::HEAD;
+
+    let backend_metrics = match uri.authority() {
+        Some(authority) if cfg.backend_metrics => Some(METRICS.backend(authority.as_str())),
+        _ => None,
+    };
 
-    let response_result = client
+    let start = std::time::Instant::now();
+    let request = client
         .middleware_client
         .request(method, uri.to_string())
         .headers(headers)
-        .body(reqwest::Body::wrap_stream(req_body))
-        .send()
-        .await;
+        .body(req_body);
+    // the route's request timeout overrides the client's
+    let request = match REQUEST_TIMEOUT.try_with(|timeout| *timeout) {
+        Ok(timeout) => request.timeout(timeout),
+        Err(_) => request,
+    };
+    let send = request.send();
+    let response_result = match &backend_metrics {
+        Some(backend_metrics) => CURRENT_BACKEND.scope(backend_metrics.clone(), send).await,
+        None => send.await,
+    };
 
-    reqwest_middleware_to_hyper_response(response_result)
-}
+    let mut response = reqwest_middleware_to_hyper_response(response_result, cfg)?;
 
-/// Reverse-proxy a request, where the request body is !Sync.
-/// The URI is already rewritten to point to the backend server.
-#[expect(unused)]
-pub async fn reverse_proxy_unsync<B>(
-    mut req: http::Request<B>,
-    client: &reqwest::Client,
-) -> Result<HyperResponse, HttpError>
-where
-    B: Body<Data = bytes::Bytes> + Send + Unpin + 'static,
-    B::Error: Into<Box<dyn std::error::Error + Send + Sync>> + Send + Debug,
-{
-    match req.headers().get(header::UPGRADE).map(|h| h.as_bytes()) {
-        None => {}
-        Some(b"websocket") => return proxy_websocket(req, client).await,
-        Some(_) => return Err(HttpError::bad_request("unrecognized `Upgrade` header")),
+    let response_header_size = header_size(response.headers());
+    if response_header_size as u64 > cfg.response_max_header_size.as_u64() {
+        warn!(
+            size = response_header_size,
+            "response headers from backend too large"
+        );
+        return Err(HttpError::bad_gateway(
+            "response headers from backend too large",
+        ));
     }
 
-    let method = req.method().clone();
-    let uri = req.uri().clone();
-    let headers = std::mem::take(req.headers_mut());
-    let mut req_body = req.into_body();
-
-    enum ForwardBodyError<B: Body> {
-        Input(B::Error),
-        TrailingHeaders,
-    }
-
-    // Because the request body is !Sync, it must be proxied through a channel first
-    // FIXME(backpressure): should not start streaming the body before the proxy request(below) has been sent.
-    // instead it should start polling as soon as reqwest starts polling _its_ body
-    let (request_body_future, req_body_rx) = {
-        let (tx, rx) = tokio::sync::mpsc::channel::<Result<Bytes, B::Error>>(1);
-        (
-            tokio::spawn(async move {
-                while let Some(frame_result) = req_body.frame().await {
-                    match frame_result {
-                        Ok(frame) => match frame.into_data() {
-                            Ok(data) => {
-                                let _ = tx.send(Ok(data)).await;
-                            }
-                            Err(_err) => return Err(ForwardBodyError::<B>::TrailingHeaders),
-                        },
-                        Err(err) => return Err(ForwardBodyError::Input(err)),
-                    };
-                }
+    // the framing of the response is decided by the server, e.g. delimited by closing the connection for HTTP/1.0 clients
+    response.headers_mut().remove(header::TRANSFER_ENCODING);
 
-                Ok(())
-            }),
-            rx,
+    if let Some(backend_metrics) = backend_metrics {
+        // the response headers have arrived, the body is still pending
+        backend_metrics.ttfb.observe(start.elapsed());
+        response =
+            response.map(|body| TotalLatencyBody::new(body, backend_metrics, start).boxed_unsync());
+    }
+
+    if transforms.strip_set_cookie {
+        response.headers_mut().remove(header::SET_COOKIE);
+    }
+
+    // A HEAD response never has a body, but keeps the headers (e.g. `Content-Length`) of the corresponding GET.
+    // Neither does a 304, whose validators and other headers refer to the representation cached by the client,
+    // so body transforms must not touch them. The empty body also makes sure the response is not compressed.
+    if is_head || response.status() == StatusCode::NOT_MODIFIED {
+        let (parts, _body) = response.into_parts();
+        return Ok(http::Response::from_parts(parts, empty_body()));
+    }
+
+    if transforms.grpc_status_to_http && !grpc_client {
+        response = grpc_status_to_http(response).await?;
+    }
+
+    if let Some(json_field_removal) = transforms.json_field_removal {
+        response = remove_json_fields(
+            response,
+            json_field_removal,
+            cfg.json_transform_max_size.as_u64(),
         )
+        .await?;
+    }
+
+    if let Some(link_rewrite) = transforms.link_rewrite {
+        response =
+            rewrite_links(response, link_rewrite, cfg.json_transform_max_size.as_u64()).await?;
+    }
+
+    if request_body.buffered {
+        response =
+            buffer_response(response, cfg.buffered_response_max_size.as_u64() as usize).await?;
+    }
+
+    if !trailers_accepted {
+        response.headers_mut().remove(header::TRAILER);
+        response = response.map(|body| WithoutTrailersBody::new(body).boxed_unsync());
+    }
+
+    Ok(match metrics {
+        Some(metrics) => response.map(|body| {
+            CountingBody::new(body, metrics, |metrics| &metrics.response_bytes).boxed_unsync()
+        }),
+        None => response,
+    })
+}
+
+/// How request bodies are forwarded, configured per route
+#[derive(Clone, Copy)]
+pub struct RequestBodyHandling {
+    /// Collect request and response bodies in full
+    pub buffered: bool,
+    /// Collect only the request body in full
+    pub buffer_request: bool,
+    /// The maximum size of request bodies
+    pub max_size: ByteSize,
+}
+
+/// Transformations of responses, configured per route
+#[derive(Clone, Copy, Default)]
+pub struct ResponseTransforms<'a> {
+    pub json_field_removal: Option<&'a JsonFieldRemoval>,
+    pub link_rewrite: Option<&'a LinkRewrite>,
+    pub grpc_status_to_http: bool,
+    pub strip_set_cookie: bool,
+}
+
+impl ResponseTransforms<'_> {
+    /// Whether response bodies may be rewritten, not just their headers
+    fn transforms_body(&self) -> bool {
+        self.json_field_removal.is_some() || self.link_rewrite.is_some()
+    }
+}
+
+/// Remove fields from a JSON response, leaving other responses untouched.
+async fn remove_json_fields(
+    response: HyperResponse,
+    json_field_removal: &JsonFieldRemoval,
+    max_size: u64,
+) -> Result<HyperResponse, HttpError> {
+    transform_body(
+        response,
+        max_size,
+        is_json,
+        |body| match serde_json::from_slice(&body) {
+            Ok(mut value) => {
+                json_field_removal.apply(&mut value);
+                Bytes::from(serde_json::to_vec(&value).unwrap())
+            }
+            Err(err) => {
+                debug!(?err, "invalid JSON response, not transformed");
+                body
+            }
+        },
+    )
+    .await
+}
+
+/// Rewrite backend URLs in an HTML or JSON response, leaving other responses untouched.
+async fn rewrite_links(
+    response: HyperResponse,
+    link_rewrite: &LinkRewrite,
+    max_size: u64,
+) -> Result<HyperResponse, HttpError> {
+    let is_html_or_json =
+        |media_type: &str| media_type.eq_ignore_ascii_case("text/html") || is_json(media_type);
+
+    transform_body(
+        response,
+        max_size,
+        is_html_or_json,
+        |body| match std::str::from_utf8(&body)
+            .ok()
+            .and_then(|text| link_rewrite.apply(text))
+        {
+            Some(rewritten) => Bytes::from(rewritten),
+            None => body,
+        },
+    )
+    .await
+}
+
+/// Translate the status of a failed gRPC call into an HTTP response, leaving other responses untouched.
+///
+/// The status is found in the headers of a trailers-only response,
+/// or in the trailers of a gRPC response without any message before them.
+/// Once a message arrives, the response is streamed as it is, with the status in its trailers.
+async fn grpc_status_to_http(response: HyperResponse) -> Result<HyperResponse, HttpError> {
+    if let Some(status) = GrpcStatus::from_headers(response.headers()) {
+        return Ok(if status.is_ok() {
+            response
+        } else {
+            status.into_http_response()
+        });
+    }
+    if !is_grpc(response.headers()) {
+        return Ok(response);
+    }
+
+    // the response status can only be chosen before the first message is relayed
+    let (parts, mut body) = response.into_parts();
+    let first = loop {
+        match body.frame().await.transpose().map_err(collect_error)? {
+            Some(frame) if frame.data_ref().is_some_and(|data| data.is_empty()) => continue,
+            first => break first,
+        }
     };
 
-    let req_body = tokio_stream::wrappers::ReceiverStream::new(req_body_rx);
+    if let Some(status) = first
+        .as_ref()
+        .and_then(|frame| frame.trailers_ref())
+        .and_then(GrpcStatus::from_headers)
+        .filter(|status| !status.is_ok())
+    {
+        return Ok(status.into_http_response());
+    }
 
-    let response_future = client
-        .request(method, uri.to_string())
-        .headers(headers)
-        .body(reqwest::Body::wrap_stream(req_body))
-        .send();
+    let frames =
+        futures_util::stream::iter(first.map(Ok)).chain(http_body_util::BodyStream::new(body));
+    Ok(http::Response::from_parts(
+        parts,
+        http_body_util::StreamBody::new(frames).boxed_unsync(),
+    ))
+}
+
+fn is_json(media_type: &str) -> bool {
+    media_type.eq_ignore_ascii_case("application/json") || media_type.ends_with("+json")
+}
 
-    let (request_body_join_result, response_result) =
-        tokio::join!(request_body_future, response_future);
+/// Transform the body of a response with a matching media type, collected up to `max_size`.
+/// Other responses are left untouched.
+///
+/// A matching response that can't be transformed, being content-encoded or too large,
+/// fails with `502 Bad Gateway` rather than being relayed untransformed.
+async fn transform_body(
+    response: HyperResponse,
+    max_size: u64,
+    matches_media_type: impl Fn(&str) -> bool,
+    transform: impl FnOnce(Bytes) -> Bytes,
+) -> Result<HyperResponse, HttpError> {
+    let media_type_matches = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .is_some_and(|media_type| matches_media_type(media_type.trim()));
+    if !media_type_matches {
+        return Ok(response);
+    }
+
+    if response
+        .headers()
+        .get_all(header::CONTENT_ENCODING)
+        .iter()
+        .any(|encoding| encoding.as_bytes() != b"identity")
+    {
+        warn!("content-encoded response from backend can't be transformed");
+        return Err(HttpError::bad_gateway(
+            "encoded response from backend can't be transformed",
+        ));
+    }
 
-    match request_body_join_result {
-        Ok(Ok(())) => reqwest_to_hyper_response(response_result),
-        Ok(Err(ForwardBodyError::Input(error))) => {
-            info!("input body error: {error:?}");
-            Err(HttpError::bad_request(""))
+    let (mut parts, body) = response.into_parts();
+    let original = collect_response_body(body, max_size as usize).await?;
+    let body = transform(original.clone());
+
+    // the representation is no longer byte-for-byte the backend's, only equivalent to it
+    if body != original {
+        weaken_etag(&mut parts.headers);
+    }
+
+    parts.headers.remove(header::TRANSFER_ENCODING);
+    parts.headers.remove(header::CONTENT_ENCODING);
+    parts
+        .headers
+        .insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+
+    Ok(http::Response::from_parts(
+        parts,
+        Full::new(body)
+            .map_err(|never| match never {})
+            .boxed_unsync(),
+    ))
+}
+
+/// Turn a strong `ETag` into a weak one, so it is not used for byte ranges of a transformed body.
+fn weaken_etag(headers: &mut http::HeaderMap) {
+    let Some(etag) = headers.get(header::ETAG) else {
+        return;
+    };
+    if etag.as_bytes().starts_with(b"W/") {
+        return;
+    }
+
+    let mut weak = b"W/".to_vec();
+    weak.extend_from_slice(etag.as_bytes());
+    match HeaderValue::from_bytes(&weak) {
+        Ok(weak) => {
+            headers.insert(header::ETAG, weak);
+        }
+        Err(_) => {
+            headers.remove(header::ETAG);
         }
-        Ok(Err(ForwardBodyError::TrailingHeaders)) => Err(HttpError::Static(
-            StatusCode::NOT_ACCEPTABLE,
-            "trailing headers not supported",
-        )),
-        Err(_join_error) => Err(HttpError::Static(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "headers not sent",
-        )),
     }
 }
 
-fn reqwest_to_hyper_response(
-    response_result: Result<reqwest::Response, reqwest::Error>,
+/// Collect a response body, so it's returned with an accurate `Content-Length` instead of being streamed.
+async fn buffer_response(
+    response: HyperResponse,
+    max_size: usize,
 ) -> Result<HyperResponse, HttpError> {
-    let response: http::Response<_> = response_result
+    let (mut parts, body) = response.into_parts();
+    let body = collect_response_body(body, max_size).await?;
+
+    parts.headers.remove(header::TRANSFER_ENCODING);
+    parts
+        .headers
+        .insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+
+    Ok(http::Response::from_parts(
+        parts,
+        Full::new(body)
+            .map_err(|never| match never {})
+            .boxed_unsync(),
+    ))
+}
+
+/// Collect a backend response body in full, failing with `502 Bad Gateway` when it exceeds `max_size`
+async fn collect_response_body<B>(body: B, max_size: usize) -> Result<Bytes, HttpError>
+where
+    B: Body<Data = Bytes>,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    Ok(Limited::new(body, max_size)
+        .collect()
+        .await
         .map_err(|err| {
-            if let Some(status) = err.status() {
-                HttpError::Dynamic(status, err.to_string())
+            if err.is::<LengthLimitError>() {
+                HttpError::bad_gateway("response body too large")
             } else {
-                HttpError::Dynamic(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+                collect_error(err)
             }
         })?
-        .into();
+        .to_bytes())
+}
 
-    let (parts, body) = response.into_parts();
-    Ok(http::Response::from_parts(
-        parts,
-        body.map_err(|err| err.into()).boxed_unsync(),
-    ))
+/// The error response for a backend response body that could not be collected
+fn collect_error(err: DynHttpError) -> HttpError {
+    if closed_early(&*err) {
+        warn!("backend closed the connection before completing the response body");
+        HttpError::bad_gateway("backend closed the connection before completing the response")
+    } else {
+        HttpError::Dynamic(StatusCode::BAD_GATEWAY, err.to_string())
+    }
 }
 
 fn reqwest_middleware_to_hyper_response(
     response_result: Result<reqwest::Response, reqwest_middleware::Error>,
+    cfg: &ArxConfig,
 ) -> Result<HyperResponse, HttpError> {
     let response: http::Response<_> = response_result
         .map_err(|err| {
-            if let Some(status) = err.status() {
+            if caused_by::<BodyIdleTimeout>(&err) {
+                HttpError::Static(StatusCode::REQUEST_TIMEOUT, "request body timeout")
+            } else if caused_by::<LengthLimitError>(&err) {
+                HttpError::Static(StatusCode::PAYLOAD_TOO_LARGE, "request body too large")
+            } else if let Some(status) = err.status() {
                 HttpError::Dynamic(status, err.to_string())
+            } else if err.is_connect() {
+                HttpError::Unreachable(err.to_string())
+            } else if err.is_timeout() {
+                HttpError::Dynamic(StatusCode::GATEWAY_TIMEOUT, err.to_string())
             } else {
                 HttpError::Dynamic(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
             }
         })?
         .into();
 
-    let (parts, body) = response.into_parts();
+    let (mut parts, body) = response.into_parts();
+    for name in invalid_response_headers(&parts.headers) {
+        match cfg.invalid_response_headers {
+            InvalidHeaderHandling::Drop => {
+                warn!(header = %name, value = ?parts.headers.get(&name), "dropping invalid response header");
+                parts.headers.remove(name);
+            }
+            InvalidHeaderHandling::Reject => {
+                return Err(HttpError::bad_gateway(
+                    "invalid response header from backend",
+                ));
+            }
+        }
+    }
+
+    if let Some(status) = cfg
+        .backend_status_map
+        .get(&parts.status.as_u16())
+        .and_then(|status| StatusCode::from_u16(*status).ok())
+    {
+        debug!(backend_status = %parts.status, %status, "replacing backend response");
+        // the backend's headers and body could tell the original status apart
+        return Ok(
+            HttpError::Static(status, status.canonical_reason().unwrap_or(""))
+                .into_hyper_response(),
+        );
+    }
+
     Ok(http::Response::from_parts(
         parts,
-        body.map_err(|err| err.into()).boxed_unsync(),
+        body.map_err(|err| {
+            // the response is already underway, so the client gets a truncated response
+            if closed_early(&err) {
+                warn!("backend closed the connection before completing the response body");
+            }
+            err.into()
+        })
+        .boxed_unsync(),
     ))
 }
 
 async fn proxy_websocket<B>(
     mut req: http::Request<B>,
     client: &reqwest::Client,
+    cfg: &ArxConfig,
 ) -> Result<HyperResponse, HttpError>
 where
     B: Body<Data = bytes::Bytes> + Send + 'static,
@@ -193,7 +563,38 @@ where
         .ok_or(HttpError::bad_request("`Sec-Websocket-Key` header missing"))?;
 
     let sec_websocket_protocol = req.headers().get(header::SEC_WEBSOCKET_PROTOCOL).cloned();
-    let headers = std::mem::take(req.headers_mut());
+    let route_watch = req.extensions_mut().remove::<RouteWatch>();
+    let tunnel_slots = req.extensions_mut().remove::<TunnelSlots>();
+    let mut headers = std::mem::take(req.headers_mut());
+
+    // The handshake headers belong to the front connection,
+    // the upstream connection performs its own handshake.
+    for name in [
+        header::CONNECTION,
+        header::UPGRADE,
+        header::SEC_WEBSOCKET_KEY,
+        header::SEC_WEBSOCKET_VERSION,
+        header::SEC_WEBSOCKET_EXTENSIONS,
+    ] {
+        headers.remove(name);
+    }
+
+    let denied_headers: Vec<_> = headers
+        .keys()
+        .filter(|name| !cfg.websocket_header_filter.permits(name))
+        .cloned()
+        .collect();
+    for name in denied_headers {
+        headers.remove(name);
+    }
+
+    // Refuse large handshakes here, rather than having the backend fail them
+    if header_size(&headers) as u64 > cfg.websocket_max_header_size.as_u64() {
+        return Err(HttpError::Static(
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            "request headers too large",
+        ));
+    }
 
     // establish proxy connection
     let upgrade_response = client
@@ -234,7 +635,14 @@ where
             }
         })?;
 
+    let idle_timeout = cfg.websocket_idle_timeout;
+    let ping_interval = cfg.websocket_ping_interval;
+    let message_rate = cfg.websocket_max_messages_per_second.map(MessageRate::new);
+    let excess_messages = cfg.websocket_message_rate_exceeded;
+
     // post-upgrade:
+    // The upgraded connection is detached from the HTTP connection's keep-alive handling,
+    // the tunnel is only closed when idle for `idle_timeout`, or when the route is removed given a `RouteWatch`.
     tokio::task::spawn(async move {
         let upgraded = match hyper::upgrade::on(&mut req).await {
             Ok(upgraded) => upgraded,
@@ -251,7 +659,19 @@ where
         )
         .await;
 
-        ws_tunnel(front_socket, back_socket).await;
+        ws_tunnel(
+            front_socket,
+            back_socket,
+            idle_timeout,
+            ping_interval,
+            route_watch,
+            message_rate,
+            excess_messages,
+        )
+        .await;
+
+        // make room for another tunnel
+        drop(tunnel_slots);
     });
 
     // pre-upgrade:
@@ -276,13 +696,67 @@ where
 async fn ws_tunnel<S>(
     mut front_socket: tokio_tungstenite::WebSocketStream<S>,
     mut back_socket: reqwest_websocket::WebSocket,
+    idle_timeout: Duration,
+    ping_interval: Option<Duration>,
+    route_watch: Option<RouteWatch>,
+    mut message_rate: Option<MessageRate>,
+    excess_messages: ExcessMessageHandling,
 ) where
     S: AsyncRead + AsyncWrite + Unpin,
 {
+    let idle = tokio::time::sleep(idle_timeout);
+    tokio::pin!(idle);
+
+    // the next keep-alive ping, postponed by every message sent to the client
+    let ping = tokio::time::sleep(ping_interval.unwrap_or(idle_timeout));
+    tokio::pin!(ping);
+
+    let unrouted = async {
+        match &route_watch {
+            Some(route_watch) => route_watch.unrouted().await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(unrouted);
+
     let (back_close_code, back_close_message): (reqwest_websocket::CloseCode, Option<String>) = loop {
         tokio::select! {
             msg = front_socket.next() => {
+                // the answer to a keep-alive ping is no sign of activity
+                if !matches!(msg, Some(Ok(tungstenite::protocol::Message::Pong(_)))) {
+                    idle.as_mut().reset(Instant::now() + idle_timeout);
+                }
+
                 // from client, to back server
+                let is_data = matches!(
+                    msg,
+                    Some(Ok(tungstenite::protocol::Message::Text(_) | tungstenite::protocol::Message::Binary(_)))
+                );
+                let admission = match message_rate.as_mut() {
+                    Some(rate) if is_data => rate.admit(),
+                    _ => MessageAdmission::Admitted,
+                };
+                match (admission, excess_messages) {
+                    (MessageAdmission::Admitted, _) => {}
+                    (MessageAdmission::SustainedExcess, ExcessMessageHandling::Close) => {
+                        debug!("websocket message rate exceeded, closing tunnel");
+                        let _ = front_socket
+                            .close(Some(protocol::CloseFrame {
+                                code: protocol::frame::coding::CloseCode::Policy,
+                                reason: "message rate exceeded".into(),
+                            }))
+                            .await;
+                        break (
+                            protocol::frame::coding::CloseCode::Policy.into(),
+                            Some("message rate exceeded".to_string()),
+                        );
+                    }
+                    (MessageAdmission::Excess | MessageAdmission::SustainedExcess, _) => {
+                        debug!("websocket message rate exceeded, dropping message");
+                        continue;
+                    }
+                }
+
                 match msg {
                     None => {
                         // client hung up
@@ -307,6 +781,11 @@ async fn ws_tunnel<S>(
                 }
             }
             msg = back_socket.next() => {
+                idle.as_mut().reset(Instant::now() + idle_timeout);
+                if let Some(ping_interval) = ping_interval {
+                    ping.as_mut().reset(Instant::now() + ping_interval);
+                }
+
                 // from back server, to client
                 match msg {
                     None => {
@@ -328,6 +807,29 @@ async fn ws_tunnel<S>(
                     }
                 }
             }
+            _ = &mut ping, if ping_interval.is_some() => {
+                let _ = front_socket.send(tungstenite::protocol::Message::Ping(vec![])).await;
+                if let Some(ping_interval) = ping_interval {
+                    ping.as_mut().reset(Instant::now() + ping_interval);
+                }
+            }
+            _ = &mut idle => {
+                debug!("websocket tunnel idle timeout");
+                break (reqwest_websocket::CloseCode::Normal, Some("idle timeout".to_string()));
+            }
+            _ = &mut unrouted => {
+                debug!("websocket route removed, closing tunnel");
+                let _ = front_socket
+                    .close(Some(protocol::CloseFrame {
+                        code: protocol::frame::coding::CloseCode::Away,
+                        reason: "route removed".into(),
+                    }))
+                    .await;
+                break (
+                    protocol::frame::coding::CloseCode::Away.into(),
+                    Some("route removed".to_string()),
+                );
+            }
         }
     };
 
@@ -336,3 +838,1482 @@ async fn ws_tunnel<S>(
         .close(back_close_code, back_close_message.as_deref())
         .await;
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{atomic::Ordering, Arc},
+        time::{Duration, Instant},
+    };
+
+    use arc_swap::ArcSwap;
+    use bytes::Bytes;
+    use bytesize::ByteSize;
+    use futures_util::{SinkExt, StreamExt};
+    use http_body::Body;
+    use http_body_util::{BodyExt, Full};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_util::sync::CancellationToken;
+    use tungstenite::{client::IntoClientRequest, protocol::frame::coding::CloseCode, Message};
+    use wiremock::{
+        matchers::{body_bytes, header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{
+        config::{ArxConfig, ExcessMessageHandling, HeaderCase, HeaderFilter},
+        http_client::HttpClient,
+        json_transform::JsonFieldRemoval,
+        link_rewrite::LinkRewrite,
+        metrics::{RouteMetrics, METRICS},
+        route::{Backend, BackendClass, Proxy},
+        testing::{leak_config, spawn_gateway, spawn_gateway_with_shared_routes},
+    };
+
+    use super::{reverse_proxy, RequestBodyHandling, ResponseTransforms};
+
+    fn streamed(cfg: &ArxConfig) -> RequestBodyHandling {
+        RequestBodyHandling {
+            buffered: false,
+            buffer_request: false,
+            max_size: cfg.request_max_size,
+        }
+    }
+
+    async fn spawn_ws_echo_backend() -> SocketAddr {
+        let app = axum::Router::new().route(
+            "/ws",
+            axum::routing::any(|ws: axum::extract::ws::WebSocketUpgrade| async {
+                ws.on_upgrade(|mut socket| async move {
+                    while let Some(Ok(msg)) = socket.recv().await {
+                        if socket.send(msg).await.is_err() {
+                            break;
+                        }
+                    }
+                })
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    fn ws_routes(backend: SocketAddr) -> matchit::Router<crate::route::Route> {
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/ws",
+                Proxy::from_backend_uri(format!("http://{backend}").parse().unwrap())
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+        routes
+    }
+
+    #[tokio::test]
+    async fn unsync_request_body() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/upload"))
+            .and(body_bytes(b"unsync body".to_vec()))
+            .respond_with(ResponseTemplate::new(200).set_body_string("ok"))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let cfg = leak_config(ArxConfig::default());
+        let client = HttpClient::create_default(cfg, CancellationToken::new())
+            .await
+            .unwrap();
+
+        // UnsyncBoxBody is Send but !Sync
+        let body = Full::new(Bytes::from_static(b"unsync body"))
+            .map_err(|never| match never {})
+            .boxed_unsync();
+
+        let req = http::Request::post(format!("{}/upload", mock_server.uri()))
+            .body(body)
+            .unwrap();
+
+        let response = reverse_proxy(
+            req,
+            &client.current_instance(),
+            None,
+            streamed(cfg),
+            Default::default(),
+            cfg,
+        )
+        .await
+        .unwrap();
+        assert_eq!(200, response.status().as_u16());
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&b"ok"[..], &body[..]);
+    }
+
+    #[tokio::test]
+    async fn route_metrics_count_body_bytes() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/upload"))
+            .and(body_bytes(b"0123456789".to_vec()))
+            .respond_with(ResponseTemplate::new(200).set_body_string("accepted"))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let cfg = leak_config(ArxConfig::default());
+        let client = HttpClient::create_default(cfg, CancellationToken::new())
+            .await
+            .unwrap();
+        let metrics = Arc::new(RouteMetrics::default());
+
+        let req = http::Request::post(format!("{}/upload", mock_server.uri()))
+            .body(Full::new(Bytes::from_static(b"0123456789")))
+            .unwrap();
+
+        let response = reverse_proxy(
+            req,
+            &client.current_instance(),
+            Some(metrics.clone()),
+            streamed(cfg),
+            Default::default(),
+            cfg,
+        )
+        .await
+        .unwrap();
+        assert_eq!(200, response.status().as_u16());
+        assert_eq!(10, metrics.request_bytes.load(Ordering::Relaxed));
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&b"accepted"[..], &body[..]);
+        assert_eq!(8, metrics.response_bytes.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn buffered_route_has_content_length() {
+        let app = axum::Router::new().route(
+            "/stream",
+            axum::routing::get(|| async {
+                axum::body::Body::from_stream(futures_util::stream::iter([
+                    Ok::<_, std::convert::Infallible>("chunk 1, "),
+                    Ok("chunk 2"),
+                ]))
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut routes = matchit::Router::new();
+        for (prefix, buffered) in [("/streaming", false), ("/buffered", true)] {
+            routes
+                .insert(
+                    format!("{prefix}/{{*path}}"),
+                    Proxy::from_backend_uri(format!("http://{backend}").parse().unwrap())
+                        .unwrap()
+                        .with_replace_prefix("/")
+                        .with_buffered(buffered)
+                        .into(),
+                )
+                .unwrap();
+        }
+
+        let cfg = leak_config(ArxConfig::default());
+        let addr = spawn_gateway(cfg, routes).await;
+        // no compression, which would change the framing
+        let client = reqwest::Client::builder()
+            .no_gzip()
+            .no_brotli()
+            .no_deflate()
+            .build()
+            .unwrap();
+
+        let response = client
+            .get(format!("http://{addr}/streaming/stream"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(200, response.status().as_u16());
+        assert_eq!("chunked", response.headers()["transfer-encoding"]);
+        assert!(!response.headers().contains_key("content-length"));
+
+        let response = client
+            .get(format!("http://{addr}/buffered/stream"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(200, response.status().as_u16());
+        assert_eq!("16", response.headers()["content-length"]);
+        assert!(!response.headers().contains_key("transfer-encoding"));
+        assert_eq!("chunk 1, chunk 2", response.text().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn oversized_buffered_response_refused() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("chunk 1, chunk 2"))
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/{*path}",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .with_buffered(true)
+                    .into(),
+            )
+            .unwrap();
+
+        let cfg = leak_config(ArxConfig {
+            buffered_response_max_size: ByteSize::b(10),
+            ..Default::default()
+        });
+        let addr = spawn_gateway(cfg, routes).await;
+
+        let response = reqwest::get(format!("http://{addr}/large")).await.unwrap();
+        assert_eq!(502, response.status().as_u16());
+        assert_eq!("response body too large", response.text().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn json_fields_removed() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/json"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "id": 1,
+                "debug": { "query": "select *" },
+            })))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/text"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(r#"{"debug":true}"#))
+            .mount(&mock_server)
+            .await;
+
+        let cfg = leak_config(ArxConfig::default());
+        let client = HttpClient::create_default(cfg, CancellationToken::new())
+            .await
+            .unwrap();
+        let removal = JsonFieldRemoval::parse(&["$.debug".to_string()]).unwrap();
+
+        let get = |path: &str| {
+            http::Request::get(format!("{}{path}", mock_server.uri()))
+                .body(http_body_util::Empty::<Bytes>::new())
+                .unwrap()
+        };
+
+        let response = reverse_proxy(
+            get("/json"),
+            &client.current_instance(),
+            None,
+            streamed(cfg),
+            ResponseTransforms {
+                json_field_removal: Some(&removal),
+                ..Default::default()
+            },
+            cfg,
+        )
+        .await
+        .unwrap();
+        assert_eq!("8", response.headers()["content-length"]);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&br#"{"id":1}"#[..], &body[..]);
+
+        // not JSON
+        let response = reverse_proxy(
+            get("/text"),
+            &client.current_instance(),
+            None,
+            streamed(cfg),
+            ResponseTransforms {
+                json_field_removal: Some(&removal),
+                ..Default::default()
+            },
+            cfg,
+        )
+        .await
+        .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&br#"{"debug":true}"#[..], &body[..]);
+    }
+
+    #[tokio::test]
+    async fn json_fields_removed_from_chunked_response() {
+        // streamed JSON, gzipped when accepted by the client, and by `/gzip` regardless
+        fn json_response(gzip: bool) -> http::Response<axum::body::Body> {
+            let chunks: Vec<&'static [u8]> = if gzip {
+                vec![&[0x1f, 0x8b, 0x08, 0x00], &[0x1f, 0x8b, 0x08, 0x00]]
+            } else {
+                vec![br#"{"id":1,"#, br#""debug":true}"#]
+            };
+            let mut response = http::Response::builder().header("content-type", "application/json");
+            if gzip {
+                response = response.header("content-encoding", "gzip");
+            }
+            response
+                .body(axum::body::Body::from_stream(futures_util::stream::iter(
+                    chunks
+                        .into_iter()
+                        .map(|chunk| Ok::<_, std::convert::Infallible>(Bytes::from_static(chunk))),
+                )))
+                .unwrap()
+        }
+        let app = axum::Router::new()
+            .route(
+                "/negotiated",
+                axum::routing::get(|headers: http::HeaderMap| async move {
+                    json_response(
+                        headers
+                            .get("accept-encoding")
+                            .is_some_and(|encoding| encoding.as_bytes() != b"identity"),
+                    )
+                }),
+            )
+            .route(
+                "/gzip",
+                axum::routing::get(|| async { json_response(true) }),
+            );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/{*path}",
+                Proxy::from_backend_uri(format!("http://{backend}").parse().unwrap())
+                    .unwrap()
+                    .with_json_field_removal(
+                        JsonFieldRemoval::parse(&["$.debug".to_string()]).unwrap(),
+                    )
+                    .into(),
+            )
+            .unwrap();
+        let addr = spawn_gateway(leak_config(ArxConfig::default()), routes).await;
+        let client = reqwest::Client::builder()
+            .no_gzip()
+            .no_brotli()
+            .no_deflate()
+            .build()
+            .unwrap();
+
+        // the backend is asked for an uncompressed response, collected although chunked
+        let response = client
+            .get(format!("http://{addr}/negotiated"))
+            .header("accept-encoding", "gzip")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(200, response.status().as_u16());
+        assert!(!response.headers().contains_key("content-encoding"));
+        assert_eq!(r#"{"id":1}"#, response.text().await.unwrap());
+
+        // a compressed response can't be transformed, and is not relayed with the fields in it
+        let response = client
+            .get(format!("http://{addr}/gzip"))
+            .header("accept-encoding", "gzip")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(502, response.status().as_u16());
+    }
+
+    #[tokio::test]
+    async fn not_modified_relayed() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/json"))
+            .and(header("if-none-match", "\"v1\""))
+            .and(header("if-modified-since", "Wed, 21 Oct 2015 07:28:00 GMT"))
+            .respond_with(
+                ResponseTemplate::new(304)
+                    .insert_header("etag", "\"v1\"")
+                    .insert_header("last-modified", "Wed, 21 Oct 2015 07:28:00 GMT")
+                    .insert_header("content-type", "application/json"),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/{*path}",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .with_json_field_removal(
+                        JsonFieldRemoval::parse(&["$.debug".to_string()]).unwrap(),
+                    )
+                    .into(),
+            )
+            .unwrap();
+        let cfg = leak_config(ArxConfig {
+            http_compression_min_size: ByteSize::b(0),
+            ..Default::default()
+        });
+        let addr = spawn_gateway(cfg, routes).await;
+
+        // compression is negotiated by hand, so the response is seen as sent
+        let response = reqwest::Client::builder()
+            .no_gzip()
+            .no_brotli()
+            .no_deflate()
+            .build()
+            .unwrap()
+            .get(format!("http://{addr}/json"))
+            .header("accept-encoding", "gzip, br")
+            .header("if-none-match", "\"v1\"")
+            .header("if-modified-since", "Wed, 21 Oct 2015 07:28:00 GMT")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(304, response.status().as_u16());
+        assert_eq!("\"v1\"", response.headers()["etag"]);
+        assert_eq!(
+            "Wed, 21 Oct 2015 07:28:00 GMT",
+            response.headers()["last-modified"]
+        );
+        assert!(!response.headers().contains_key("content-encoding"));
+        assert!(response.bytes().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn backend_links_rewritten() {
+        let mock_server = MockServer::start().await;
+        let backend_url = mock_server.uri();
+        Mock::given(method("GET"))
+            .and(path("/page"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw(
+                        format!(r#"<a href="{backend_url}/other">other</a>"#),
+                        "text/html; charset=utf-8",
+                    )
+                    .insert_header("etag", "\"v1\""),
+            )
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/script.js"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_raw(format!(r#"fetch("{backend_url}/api")"#), "text/javascript")
+                    .insert_header("etag", "\"v1\""),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/app/{*path}",
+                Proxy::from_backend_uri(backend_url.parse().unwrap())
+                    .unwrap()
+                    .with_replace_prefix("/")
+                    .with_link_rewrite(LinkRewrite::new(
+                        &[(backend_url.clone(), "https://example.com/app".to_string())]
+                            .into_iter()
+                            .collect(),
+                    ))
+                    .into(),
+            )
+            .unwrap();
+        let addr = spawn_gateway(leak_config(ArxConfig::default()), routes).await;
+
+        let response = reqwest::get(format!("http://{addr}/app/page"))
+            .await
+            .unwrap();
+        // the body changed, the validator no longer identifies it byte for byte
+        assert_eq!("W/\"v1\"", response.headers()["etag"]);
+        assert_eq!(
+            r#"<a href="https://example.com/app/other">other</a>"#,
+            response.text().await.unwrap()
+        );
+
+        // not HTML or JSON
+        let response = reqwest::get(format!("http://{addr}/app/script.js"))
+            .await
+            .unwrap();
+        assert_eq!("\"v1\"", response.headers()["etag"]);
+        assert_eq!(
+            format!(r#"fetch("{backend_url}/api")"#),
+            response.text().await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn grpc_status_translated_for_http_clients() {
+        let mock_server = MockServer::start().await;
+        // a trailers-only response, as sent for calls failing without a message
+        Mock::given(method("POST"))
+            .and(path("/items.Items/Get"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("content-type", "application/grpc")
+                    .insert_header("grpc-status", "5")
+                    .insert_header("grpc-message", "no%20such%20item"),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/items.Items/{method}",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .with_grpc_status_to_http(true)
+                    .into(),
+            )
+            .unwrap();
+        let addr = spawn_gateway(leak_config(ArxConfig::default()), routes).await;
+        let client = reqwest::Client::new();
+
+        let response = client
+            .post(format!("http://{addr}/items.Items/Get"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(404, response.status().as_u16());
+        assert_eq!(
+            serde_json::json!({
+                "code": 5,
+                "status": "NOT_FOUND",
+                "message": "no such item",
+            }),
+            response.json::<serde_json::Value>().await.unwrap()
+        );
+
+        // gRPC clients get the status as is
+        let response = client
+            .post(format!("http://{addr}/items.Items/Get"))
+            .header("content-type", "application/grpc")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(200, response.status().as_u16());
+        assert_eq!("5", response.headers()["grpc-status"]);
+    }
+
+    #[tokio::test]
+    async fn grpc_status_in_trailers_translated() {
+        fn grpc_response(
+            frames: Vec<http_body::Frame<Bytes>>,
+        ) -> ([(http::HeaderName, &'static str); 1], axum::body::Body) {
+            let frames = futures_util::stream::iter(
+                frames.into_iter().map(Ok::<_, std::convert::Infallible>),
+            );
+            (
+                [(http::header::CONTENT_TYPE, "application/grpc")],
+                axum::body::Body::new(http_body_util::StreamBody::new(frames)),
+            )
+        }
+        fn status(code: &'static str) -> http::HeaderMap {
+            http::HeaderMap::from_iter([(
+                http::HeaderName::from_static("grpc-status"),
+                http::HeaderValue::from_static(code),
+            )])
+        }
+
+        let app = axum::Router::new()
+            .route(
+                "/items.Items/Get",
+                axum::routing::post(|| async {
+                    grpc_response(vec![http_body::Frame::trailers(status("5"))])
+                }),
+            )
+            .route(
+                "/items.Items/List",
+                axum::routing::post(|| async {
+                    grpc_response(vec![
+                        http_body::Frame::data(Bytes::from_static(b"\0\0\0\0\x02{}")),
+                        http_body::Frame::trailers(status("0")),
+                    ])
+                }),
+            );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/items.Items/{method}",
+                Proxy::from_backend_uri(format!("http://{backend}").parse().unwrap())
+                    .unwrap()
+                    .with_grpc_status_to_http(true)
+                    .into(),
+            )
+            .unwrap();
+        let addr = spawn_gateway(leak_config(ArxConfig::default()), routes).await;
+        let client = reqwest::Client::new();
+
+        let response = client
+            .post(format!("http://{addr}/items.Items/Get"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(404, response.status().as_u16());
+        assert_eq!(
+            "NOT_FOUND",
+            response.json::<serde_json::Value>().await.unwrap()["status"]
+        );
+
+        // messages are streamed as they are
+        let response = client
+            .post(format!("http://{addr}/items.Items/List"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(200, response.status().as_u16());
+        assert_eq!(&b"\0\0\0\0\x02{}"[..], &response.bytes().await.unwrap()[..]);
+    }
+
+    #[tokio::test]
+    async fn trailers_forwarded_when_accepted() {
+        let app = axum::Router::new().route(
+            "/trailers",
+            axum::routing::get(|| async {
+                let trailers = http::HeaderMap::from_iter([(
+                    http::HeaderName::from_static("x-checksum"),
+                    http::HeaderValue::from_static("abc"),
+                )]);
+                let frames = futures_util::stream::iter([
+                    Ok::<_, std::convert::Infallible>(http_body::Frame::data(Bytes::from_static(
+                        b"body",
+                    ))),
+                    Ok(http_body::Frame::trailers(trailers)),
+                ]);
+                (
+                    [(http::header::TRAILER, "x-checksum")],
+                    axum::body::Body::new(http_body_util::StreamBody::new(frames)),
+                )
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/trailers",
+                Proxy::from_backend_uri(format!("http://{backend}").parse().unwrap())
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+        let addr = spawn_gateway(leak_config(ArxConfig::default()), routes).await;
+
+        let get = |te: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let request = format!(
+                "GET /trailers HTTP/1.1\r\nhost: localhost\r\n{te}connection: close\r\n\r\n"
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response.to_lowercase()
+        };
+
+        let response = get("te: trailers\r\n").await;
+        assert!(response.starts_with("http/1.1 200"), "{response}");
+        assert!(response.contains("\r\nx-checksum: abc\r\n"), "{response}");
+
+        let response = get("").await;
+        assert!(response.starts_with("http/1.1 200"), "{response}");
+        assert!(response.contains("body"), "{response}");
+        assert!(!response.contains("x-checksum"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn forwarded_header_case() {
+        for (header_case, expected) in [
+            (HeaderCase::Lowercase, "\r\nx-custom-header: value\r\n"),
+            (HeaderCase::Titlecase, "\r\nX-Custom-Header: value\r\n"),
+        ] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend = listener.local_addr().unwrap();
+            let received = tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                    .await
+                    .unwrap();
+                String::from_utf8(request).unwrap()
+            });
+
+            let mut routes = matchit::Router::new();
+            routes
+                .insert(
+                    "/headers",
+                    Proxy::from_backend_uri(format!("http://{backend}").parse().unwrap())
+                        .unwrap()
+                        .into(),
+                )
+                .unwrap();
+            let cfg = leak_config(ArxConfig {
+                backend_header_case: header_case,
+                ..Default::default()
+            });
+            let addr = spawn_gateway(cfg, routes).await;
+
+            let response = reqwest::Client::new()
+                .get(format!("http://{addr}/headers"))
+                .header("x-custom-header", "value")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(200, response.status().as_u16());
+
+            let request = received.await.unwrap();
+            assert!(request.contains(expected), "{request}");
+        }
+    }
+
+    #[tokio::test]
+    async fn http10_client_gets_close_delimited_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut request = vec![];
+                    let mut buf = [0; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let n = stream.read(&mut buf).await.unwrap();
+                        if n == 0 {
+                            return;
+                        }
+                        request.extend_from_slice(&buf[..n]);
+                    }
+                    let response: &[u8] = if request.starts_with(b"GET /close ") {
+                        b"HTTP/1.0 200 OK\r\ncontent-type: text/plain\r\n\r\nuntil close"
+                    } else {
+                        b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n7\r\nchunked\r\n0\r\n\r\n"
+                    };
+                    stream.write_all(response).await.unwrap();
+                    stream.shutdown().await.unwrap();
+                });
+            }
+        });
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/{*path}",
+                Proxy::from_backend_uri(format!("http://{backend}").parse().unwrap())
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+        let addr = spawn_gateway(leak_config(ArxConfig::default()), routes).await;
+
+        for (path, body) in [("/close", "until close"), ("/chunked", "chunked")] {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let request = format!("GET {path} HTTP/1.0\r\nhost: localhost\r\n\r\n");
+            stream.write_all(request.as_bytes()).await.unwrap();
+
+            let mut response = String::new();
+            tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut response))
+                .await
+                .expect("response not delimited")
+                .unwrap();
+            let response = response.to_lowercase();
+            assert!(response.starts_with("http/1.0 200 ok\r\n"), "{response}");
+            assert!(!response.contains("transfer-encoding"), "{response}");
+            assert!(response.ends_with(&format!("\r\n\r\n{body}")), "{response}");
+        }
+    }
+
+    #[tokio::test]
+    async fn encoded_response_passed_through() {
+        // a gzip header, followed by garbage, which can't be decompressed
+        let gzipped = [0x1f, 0x8b, 0x08, 0x00].repeat(16);
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/file"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("content-encoding", "gzip")
+                    .set_body_raw(gzipped.clone(), "text/plain"),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/file",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+        let addr = spawn_gateway(leak_config(ArxConfig::default()), routes).await;
+        // no decompression by the client either
+        let client = reqwest::Client::builder()
+            .no_gzip()
+            .no_brotli()
+            .no_deflate()
+            .build()
+            .unwrap();
+
+        let response = client
+            .get(format!("http://{addr}/file"))
+            .header("accept-encoding", "gzip")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(200, response.status().as_u16());
+        assert_eq!(
+            vec!["gzip"],
+            response
+                .headers()
+                .get_all("content-encoding")
+                .iter()
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(&gzipped[..], &response.bytes().await.unwrap()[..]);
+    }
+
+    #[tokio::test]
+    async fn large_response_headers_rejected() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/small"))
+            .respond_with(ResponseTemplate::new(200).insert_header("set-cookie", "session=small"))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/large"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("set-cookie", format!("session={}", "x".repeat(4096))),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/{*path}",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+        let cfg = leak_config(ArxConfig {
+            response_max_header_size: ByteSize::kib(1),
+            ..Default::default()
+        });
+        let addr = spawn_gateway(cfg, routes).await;
+
+        let response = reqwest::get(format!("http://{addr}/small")).await.unwrap();
+        assert_eq!(200, response.status().as_u16());
+
+        let response = reqwest::get(format!("http://{addr}/large")).await.unwrap();
+        assert_eq!(502, response.status().as_u16());
+        assert!(!response.headers().contains_key("set-cookie"));
+    }
+
+    #[tokio::test]
+    async fn set_cookie_stripped() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).insert_header("set-cookie", "session=s3ss10n"))
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        for (prefix, strip_set_cookie) in [("/public", true), ("/app", false)] {
+            routes
+                .insert(
+                    prefix,
+                    Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                        .unwrap()
+                        .with_strip_set_cookie(strip_set_cookie)
+                        .into(),
+                )
+                .unwrap();
+        }
+        let addr = spawn_gateway(leak_config(ArxConfig::default()), routes).await;
+
+        let response = reqwest::get(format!("http://{addr}/public")).await.unwrap();
+        assert_eq!(200, response.status().as_u16());
+        assert!(!response.headers().contains_key("set-cookie"));
+
+        let response = reqwest::get(format!("http://{addr}/app")).await.unwrap();
+        assert_eq!("session=s3ss10n", response.headers()["set-cookie"]);
+    }
+
+    #[tokio::test]
+    async fn backend_latency_metrics() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/latency"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("ok"))
+            .mount(&mock_server)
+            .await;
+
+        let cfg = leak_config(ArxConfig::default());
+        let client = HttpClient::create_default(cfg, CancellationToken::new())
+            .await
+            .unwrap();
+
+        let req = http::Request::get(format!("{}/latency", mock_server.uri()))
+            .body(http_body_util::Empty::<Bytes>::new())
+            .unwrap();
+
+        let response = reverse_proxy(
+            req,
+            &client.current_instance(),
+            None,
+            streamed(cfg),
+            Default::default(),
+            cfg,
+        )
+        .await
+        .unwrap();
+
+        let backend_metrics = METRICS.backend(&mock_server.address().to_string());
+        assert_eq!(1, backend_metrics.connect.count());
+        assert_eq!(1, backend_metrics.ttfb.count());
+        assert_eq!(0, backend_metrics.total.count());
+
+        response.into_body().collect().await.unwrap();
+        assert_eq!(1, backend_metrics.total.count());
+    }
+
+    #[tokio::test]
+    async fn websocket_outlives_keep_alive_timeout() {
+        let backend = spawn_ws_echo_backend().await;
+        let cfg = leak_config(ArxConfig {
+            keep_alive_timeout: Duration::from_millis(50),
+            websocket_ping_interval: Some(Duration::from_millis(100)),
+            ..Default::default()
+        });
+        let addr = spawn_gateway(cfg, ws_routes(backend)).await;
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws"))
+            .await
+            .unwrap();
+
+        // the quiet tunnel is kept alive past the keep-alive timeout
+        let start = Instant::now();
+        let ping = tokio::time::timeout(Duration::from_secs(1), socket.next())
+            .await
+            .expect("no ping within the interval")
+            .unwrap()
+            .unwrap();
+        assert!(matches!(ping, Message::Ping(_)), "{ping:?}");
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        socket.send(Message::Text("hello".into())).await.unwrap();
+        let echo = loop {
+            match socket.next().await.unwrap().unwrap() {
+                Message::Ping(_) => continue,
+                msg => break msg,
+            }
+        };
+        assert_eq!(Message::Text("hello".into()), echo);
+    }
+
+    #[tokio::test]
+    async fn websocket_idle_timeout() {
+        let backend = spawn_ws_echo_backend().await;
+        let cfg = leak_config(ArxConfig {
+            websocket_idle_timeout: Duration::from_millis(100),
+            ..Default::default()
+        });
+        let addr = spawn_gateway(cfg, ws_routes(backend)).await;
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws"))
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        match socket.next().await {
+            None | Some(Ok(Message::Close(_))) | Some(Err(_)) => {}
+            Some(Ok(msg)) => panic!("unexpected message: {msg:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn websocket_drained_on_route_removal() {
+        let backend = spawn_ws_echo_backend().await;
+        let cfg = leak_config(ArxConfig {
+            websocket_drain_on_route_change: true,
+            ..Default::default()
+        });
+        let routes = Arc::new(ArcSwap::new(Arc::new(ws_routes(backend))));
+        let addr = spawn_gateway_with_shared_routes(cfg, routes.clone()).await;
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws"))
+            .await
+            .unwrap();
+        socket.send(Message::Text("hello".into())).await.unwrap();
+        assert_eq!(
+            Message::Text("hello".into()),
+            socket.next().await.unwrap().unwrap()
+        );
+
+        // an unrelated routing change leaves the tunnel open
+        routes.store(Arc::new(ws_routes(backend)));
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        socket
+            .send(Message::Text("still there".into()))
+            .await
+            .unwrap();
+        assert_eq!(
+            Message::Text("still there".into()),
+            socket.next().await.unwrap().unwrap()
+        );
+
+        routes.store(Arc::new(matchit::Router::new()));
+
+        let message = tokio::time::timeout(Duration::from_secs(3), socket.next())
+            .await
+            .unwrap();
+        match message {
+            Some(Ok(Message::Close(Some(frame)))) => {
+                assert_eq!(CloseCode::Away, frame.code);
+            }
+            other => panic!("expected a close frame: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn websocket_tunnels_limited() {
+        let backend = spawn_ws_echo_backend().await;
+        let cfg = leak_config(ArxConfig {
+            websocket_max_tunnels: Some(1),
+            ..Default::default()
+        });
+        let addr = spawn_gateway(cfg, ws_routes(backend)).await;
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws"))
+            .await
+            .unwrap();
+
+        match tokio_tungstenite::connect_async(format!("ws://{addr}/ws")).await {
+            Err(tungstenite::Error::Http(response)) => {
+                assert_eq!(503, response.status().as_u16());
+            }
+            Err(err) => panic!("unexpected error: {err}"),
+            Ok(_) => panic!("unexpectedly connected"),
+        }
+
+        socket.close(None).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws"))
+            .await
+            .unwrap();
+        socket.send(Message::Text("hello".into())).await.unwrap();
+        assert_eq!(
+            Message::Text("hello".into()),
+            socket.next().await.unwrap().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn websocket_message_rate_limited() {
+        let backend = spawn_ws_echo_backend().await;
+
+        // excess messages are dropped
+        let cfg = leak_config(ArxConfig {
+            websocket_max_messages_per_second: Some(5),
+            ..Default::default()
+        });
+        let addr = spawn_gateway(cfg, ws_routes(backend)).await;
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws"))
+            .await
+            .unwrap();
+        for index in 0..10 {
+            socket.send(Message::Text(index.to_string())).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        socket.send(Message::Text("later".into())).await.unwrap();
+
+        let mut echoes = vec![];
+        while let Ok(Some(Ok(Message::Text(text)))) =
+            tokio::time::timeout(Duration::from_millis(200), socket.next()).await
+        {
+            echoes.push(text);
+        }
+        assert_eq!(vec!["0", "1", "2", "3", "4", "later"], echoes);
+
+        // or the tunnel is closed, once the rate is exceeded for several seconds in a row
+        let cfg = leak_config(ArxConfig {
+            websocket_max_messages_per_second: Some(5),
+            websocket_message_rate_exceeded: ExcessMessageHandling::Close,
+            ..Default::default()
+        });
+        let addr = spawn_gateway(cfg, ws_routes(backend)).await;
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws"))
+            .await
+            .unwrap();
+
+        // a single burst is only trimmed
+        for index in 0..10 {
+            socket.send(Message::Text(index.to_string())).await.unwrap();
+        }
+        let mut echoes = vec![];
+        while let Ok(Some(Ok(Message::Text(text)))) =
+            tokio::time::timeout(Duration::from_millis(200), socket.next()).await
+        {
+            echoes.push(text);
+        }
+        assert_eq!(vec!["0", "1", "2", "3", "4"], echoes);
+
+        // a sustained excess is not
+        let start = Instant::now();
+        let (mut sender, mut receiver) = socket.split();
+        tokio::spawn(async move {
+            while sender.send(Message::Text("spam".into())).await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        });
+        let close_frame = loop {
+            match tokio::time::timeout(Duration::from_secs(5), receiver.next())
+                .await
+                .unwrap()
+            {
+                Some(Ok(Message::Text(_))) => continue,
+                Some(Ok(Message::Close(close_frame))) => break close_frame,
+                other => panic!("unexpected message: {other:?}"),
+            }
+        };
+        assert_eq!(CloseCode::Policy, close_frame.unwrap().code);
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn websocket_large_headers_rejected() {
+        let backend = spawn_ws_echo_backend().await;
+        let cfg = leak_config(ArxConfig {
+            websocket_max_header_size: ByteSize::kib(1),
+            ..Default::default()
+        });
+        let addr = spawn_gateway(cfg, ws_routes(backend)).await;
+
+        let mut request = format!("ws://{addr}/ws").into_client_request().unwrap();
+        request.headers_mut().insert(
+            http::header::COOKIE,
+            format!("session={}", "x".repeat(4096)).parse().unwrap(),
+        );
+
+        match tokio_tungstenite::connect_async(request).await {
+            Err(tungstenite::Error::Http(response)) => {
+                assert_eq!(431, response.status().as_u16());
+            }
+            Err(err) => panic!("unexpected error: {err}"),
+            Ok(_) => panic!("unexpectedly connected"),
+        }
+    }
+
+    #[tokio::test]
+    async fn websocket_denied_headers_not_forwarded() {
+        let backend = spawn_ws_echo_backend().await;
+        let cfg = leak_config(ArxConfig {
+            websocket_header_filter: HeaderFilter {
+                allow: vec![],
+                deny: vec!["Cookie".into()],
+            },
+            websocket_max_header_size: ByteSize::kib(1),
+            ..Default::default()
+        });
+        let addr = spawn_gateway(cfg, ws_routes(backend)).await;
+
+        let mut request = format!("ws://{addr}/ws").into_client_request().unwrap();
+        request.headers_mut().insert(
+            http::header::COOKIE,
+            format!("session={}", "x".repeat(4096)).parse().unwrap(),
+        );
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        socket.send(Message::Text("hello".into())).await.unwrap();
+        let echo = socket.next().await.unwrap().unwrap();
+        assert_eq!(Message::Text("hello".into()), echo);
+    }
+
+    #[tokio::test]
+    async fn stalled_request_body_times_out() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/upload",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+
+        let cfg = leak_config(ArxConfig {
+            request_body_idle_timeout: Duration::from_millis(100),
+            ..Default::default()
+        });
+        let addr = spawn_gateway(cfg, routes).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"POST /upload HTTP/1.1\r\nhost: localhost\r\ncontent-length: 100\r\n\r\nonly ten b",
+            )
+            .await
+            .unwrap();
+
+        // the client stalls, and should be disconnected
+        let mut response = vec![];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+            .await
+            .expect("client was not disconnected")
+            .unwrap();
+
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 408"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn head_request() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("HEAD"))
+            .and(path("/file"))
+            .respond_with(ResponseTemplate::new(200).insert_header("content-length", "1000"))
+            .mount(&mock_server)
+            .await;
+
+        let cfg = leak_config(ArxConfig::default());
+        let client = HttpClient::create_default(cfg, CancellationToken::new())
+            .await
+            .unwrap();
+
+        let req = http::Request::head(format!("{}/file", mock_server.uri()))
+            .body(http_body_util::Empty::<Bytes>::new())
+            .unwrap();
+
+        let response = reverse_proxy(
+            req,
+            &client.current_instance(),
+            None,
+            streamed(cfg),
+            Default::default(),
+            cfg,
+        )
+        .await
+        .unwrap();
+        assert_eq!(200, response.status().as_u16());
+        assert_eq!(
+            response
+                .headers()
+                .get(http::header::CONTENT_LENGTH)
+                .unwrap(),
+            "1000"
+        );
+        assert_eq!(Some(0), response.body().size_hint().exact());
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn backend_status_remapped() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(403)
+                    .insert_header("x-denied-by", "policy")
+                    .set_body_string("access denied"),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/secret",
+                Proxy::from_backend_uri(mock_server.uri().parse().unwrap())
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+        let cfg = leak_config(ArxConfig {
+            backend_status_map: [(403, 404)].into_iter().collect(),
+            ..Default::default()
+        });
+        let addr = spawn_gateway(cfg, routes).await;
+
+        let response = reqwest::get(format!("http://{addr}/secret")).await.unwrap();
+        assert_eq!(404, response.status().as_u16());
+        assert!(response.headers().get("x-denied-by").is_none());
+        assert_eq!("Not Found", response.text().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn invalid_response_header_dropped() {
+        // a backend sending a `Content-Length` the HTTP client accepts, but hyper can't serve
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await.unwrap();
+                stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\ncontent-length: 5, 5\r\nx-valid: yes\r\n\r\nhello",
+                    )
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/invalid",
+                Proxy::from_backend_uri(format!("http://{backend_addr}").parse().unwrap())
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+        let addr = spawn_gateway(leak_config(ArxConfig::default()), routes).await;
+
+        let response = reqwest::get(format!("http://{addr}/invalid"))
+            .await
+            .unwrap();
+        assert_eq!(200, response.status().as_u16());
+        assert_eq!("yes", response.headers()["x-valid"]);
+        assert_eq!("hello", response.text().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn backend_closing_early() {
+        // the first two responses are cut short, the third is complete
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            for complete in [false, false, true] {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await.unwrap();
+                let response: &[u8] = if complete {
+                    b"HTTP/1.1 200 OK\r\ncontent-length: 10\r\nconnection: close\r\n\r\ncomplete!!"
+                } else {
+                    b"HTTP/1.1 200 OK\r\ncontent-length: 10\r\nconnection: close\r\n\r\ncut"
+                };
+                stream.write_all(response).await.unwrap();
+            }
+        });
+
+        let proxy = || {
+            Proxy::from_backend_uri(format!("http://{backend_addr}").parse().unwrap())
+                .unwrap()
+                .with_buffered(true)
+        };
+        let mut routes = matchit::Router::new();
+        routes.insert("/once", proxy().into()).unwrap();
+        routes
+            .insert("/retried", proxy().with_retries(1).into())
+            .unwrap();
+        let addr = spawn_gateway(leak_config(ArxConfig::default()), routes).await;
+
+        let response = reqwest::get(format!("http://{addr}/once")).await.unwrap();
+        assert_eq!(502, response.status().as_u16());
+        assert_eq!(
+            "backend closed the connection before completing the response",
+            response.text().await.unwrap()
+        );
+
+        let response = reqwest::get(format!("http://{addr}/retried"))
+            .await
+            .unwrap();
+        assert_eq!(200, response.status().as_u16());
+        assert_eq!("complete!!", response.text().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn http10_backend_gets_length_delimited_body() {
+        // an HTTP/1.0 backend, echoing the framing of the request body it receives
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            while !(request.ends_with(b"chunk 2") || request.ends_with(b"0\r\n\r\n")) {
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(n > 0, "incomplete request");
+                request.extend_from_slice(&buf[..n]);
+            }
+            let request = String::from_utf8(request).unwrap().to_lowercase();
+            let framing = if request.contains("transfer-encoding") {
+                "chunked"
+            } else if request.contains("content-length: 16") {
+                "length"
+            } else {
+                "unknown"
+            };
+            stream
+                .write_all(format!("HTTP/1.0 200 OK\r\n\r\n{framing}").as_bytes())
+                .await
+                .unwrap();
+        });
+
+        let mut routes = matchit::Router::new();
+        routes
+            .insert(
+                "/upload",
+                Proxy::from_backend(Backend {
+                    uri: format!("http://{backend_addr}").parse().unwrap(),
+                    class: BackendClass::Plain,
+                    connect_host: None,
+                    ca_certificates: None,
+                    client: None,
+                    http10: true,
+                })
+                .into(),
+            )
+            .unwrap();
+        let addr = spawn_gateway(leak_config(ArxConfig::default()), routes).await;
+
+        let body = reqwest::Body::wrap_stream(futures_util::stream::iter([
+            Ok::<_, std::convert::Infallible>("chunk 1, "),
+            Ok("chunk 2"),
+        ]));
+        let response = reqwest::Client::new()
+            .post(format!("http://{addr}/upload"))
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(200, response.status().as_u16());
+        assert_eq!("length", response.text().await.unwrap());
+    }
+}
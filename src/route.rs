@@ -1,15 +1,37 @@
-use std::{fmt::Debug, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    hash::Hasher,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use http::Uri;
+use arc_swap::ArcSwap;
+use bytesize::ByteSize;
+use http::{HeaderName, HeaderValue, Method, Uri};
 use hyper::body::Incoming;
+use regex::Regex;
+use siphasher::sip::SipHasher13;
 
-use crate::local::LocalService;
+use crate::{
+    config::{Authn, IpFilter},
+    http_client::HttpClient,
+    json_transform::JsonFieldRemoval,
+    link_rewrite::LinkRewrite,
+    local::LocalService,
+    media_type::MediaTypes,
+    metrics::RouteMetrics,
+    path_rewrite::PathRewrite,
+};
 
 /// A route that can be handled by the gateway
 #[derive(Clone)]
 pub enum Route {
     /// Proxy to another networked service
     Proxy(Proxy),
+    /// Proxy to one of several networked services sharing the same path.
+    /// The first proxy matching the request is selected.
+    ProxyAlternatives(Vec<Proxy>),
     /// A locally-implemented service/endpoint
     Local(Arc<dyn LocalService + Send + Sync>),
     /// Redirect to another URI
@@ -21,7 +43,14 @@ impl Debug for Route {
         match self {
             Route::Local(_) => write!(f, "Service"),
             Route::TemporaryRedirect(_) => write!(f, "Temporary redirect"),
-            Route::Proxy(proxy) => write!(f, "Proxy to `{}`", proxy.backend_uri),
+            Route::Proxy(proxy) => write!(f, "Proxy to `{}`", proxy.backend.uri),
+            Route::ProxyAlternatives(proxies) => {
+                write!(f, "Proxy alternatives:")?;
+                for proxy in proxies {
+                    write!(f, " `{}`", proxy.backend.uri)?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -36,18 +65,76 @@ pub enum AuthDirective {
     Disabled,
 }
 
+impl From<Authn> for AuthDirective {
+    fn from(authn: Authn) -> Self {
+        match authn {
+            Authn::Mandatory => Self::Mandatory,
+            Authn::Opportunistic => Self::Opportunistic,
+            Authn::Disabled => Self::Disabled,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum BackendClass {
     Plain,
     AuthlyMesh,
 }
 
+/// A backend service reached over HTTP
+#[derive(Clone, Debug)]
+pub struct Backend {
+    pub uri: Uri,
+    pub class: BackendClass,
+    /// The host to connect to, when the host of `uri` is only the TLS server name (SNI) of the backend
+    pub connect_host: Option<String>,
+    /// PEM-encoded CA certificates to verify the backend with, instead of the gateway's root certificates
+    pub ca_certificates: Option<Arc<str>>,
+    /// The client of the backend alone, verifying it with its `ca_certificates`
+    /// or connecting to its `connect_host`, instead of the default client
+    pub client: Option<HttpClient>,
+    /// The backend only speaks HTTP/1.0, so request bodies must be length-delimited
+    pub http10: bool,
+}
+
+impl Backend {
+    /// The TLS server name of the backend, and the host to connect to instead
+    pub fn server_name_host(&self) -> Option<(String, String)> {
+        match (self.uri.host(), &self.connect_host) {
+            (Some(server_name), Some(host)) => Some((server_name.to_string(), host.clone())),
+            _ => None,
+        }
+    }
+}
+
 /// A network service the gateway might proxy to
 #[derive(Clone)]
 pub struct Proxy {
-    backend_uri: Uri,
-    backend_class: BackendClass,
+    backend: Backend,
     replace_prefix: Option<String>,
+    path_rewrite: Option<Arc<PathRewrite>>,
+    method: Option<Method>,
+    conditions: Option<Arc<RequestConditions>>,
+    ip_filter: Option<Arc<IpFilter>>,
+    metrics: Option<Arc<RouteMetrics>>,
+    authly_cluster: Option<String>,
+    auth_timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    request_max_size: Option<ByteSize>,
+    traffic_split: Option<Arc<TrafficSplit>>,
+    header_selection: Option<Arc<HeaderSelection>>,
+    buffered: bool,
+    body_digest: bool,
+    json_field_removal: Option<Arc<JsonFieldRemoval>>,
+    link_rewrite: Option<Arc<LinkRewrite>>,
+    grpc_status_to_http: bool,
+    strip_set_cookie: bool,
+    forwarded_headers: bool,
+    media_types: Option<Arc<MediaTypes>>,
+    retries: u32,
+    in_flight_limit: Option<InFlightLimit>,
+    websocket_tunnel_limit: Option<InFlightLimit>,
     auth_directive_fn: fn(&http::Request<Incoming>) -> AuthDirective,
 }
 
@@ -56,16 +143,50 @@ impl Proxy {
     ///
     /// By default, the proxy service is `must_authenticate`.
     pub fn from_backend_uri(uri: Uri) -> anyhow::Result<Self> {
-        Ok(Self {
-            backend_uri: uri,
-            backend_class: BackendClass::Plain,
+        Ok(Self::from_backend(Backend {
+            uri,
+            class: BackendClass::Plain,
+            connect_host: None,
+            ca_certificates: None,
+            client: None,
+            http10: false,
+        }))
+    }
+
+    /// Make a proxy to a backend.
+    pub fn from_backend(backend: Backend) -> Self {
+        Self {
+            backend,
             replace_prefix: None,
+            path_rewrite: None,
+            method: None,
+            conditions: None,
+            ip_filter: None,
+            metrics: None,
+            authly_cluster: None,
+            auth_timeout: None,
+            connect_timeout: None,
+            request_timeout: None,
+            request_max_size: None,
+            traffic_split: None,
+            header_selection: None,
+            buffered: false,
+            body_digest: false,
+            json_field_removal: None,
+            link_rewrite: None,
+            grpc_status_to_http: false,
+            strip_set_cookie: false,
+            forwarded_headers: true,
+            media_types: None,
+            retries: 0,
+            in_flight_limit: None,
+            websocket_tunnel_limit: None,
             auth_directive_fn: |_| AuthDirective::Disabled,
-        })
+        }
     }
 
     pub fn with_backend_class(mut self, class: BackendClass) -> Self {
-        self.backend_class = class;
+        self.backend.class = class;
         self
     }
 
@@ -86,25 +207,817 @@ impl Proxy {
         }
     }
 
-    pub fn backend_uri(&self) -> &Uri {
-        &self.backend_uri
+    /// Only match requests with the given method
+    pub fn with_method(self, method: Method) -> Self {
+        Self {
+            method: Some(method),
+            ..self
+        }
+    }
+
+    /// Only match requests meeting all the given header and query parameter conditions
+    pub fn with_conditions(self, conditions: RequestConditions) -> Self {
+        Self {
+            conditions: Some(Arc::new(conditions)),
+            ..self
+        }
+    }
+
+    /// Use a route-specific client IP filter instead of the global one
+    pub fn with_ip_filter(self, ip_filter: IpFilter) -> Self {
+        Self {
+            ip_filter: Some(Arc::new(ip_filter)),
+            ..self
+        }
+    }
+
+    /// Count proxied body bytes in the given route metrics
+    pub fn with_metrics(self, metrics: Arc<RouteMetrics>) -> Self {
+        Self {
+            metrics: Some(metrics),
+            ..self
+        }
+    }
+
+    /// Authenticate with the named Authly cluster instead of the default one
+    pub fn with_authly_cluster(self, name: impl Into<String>) -> Self {
+        Self {
+            authly_cluster: Some(name.into()),
+            ..self
+        }
+    }
+
+    /// Use a route-specific timeout for the Authly exchange instead of the global one
+    pub fn with_auth_timeout(self, auth_timeout: Duration) -> Self {
+        Self {
+            auth_timeout: Some(auth_timeout),
+            ..self
+        }
     }
 
-    pub fn backend_class(&self) -> BackendClass {
-        self.backend_class
+    /// Use a route-specific connect timeout instead of the global one
+    pub fn with_connect_timeout(self, connect_timeout: Duration) -> Self {
+        Self {
+            connect_timeout: Some(connect_timeout),
+            ..self
+        }
+    }
+
+    /// Use a route-specific request timeout instead of the global one
+    pub fn with_request_timeout(self, request_timeout: Duration) -> Self {
+        Self {
+            request_timeout: Some(request_timeout),
+            ..self
+        }
+    }
+
+    /// Use a route-specific maximum request size instead of the global one
+    pub fn with_request_max_size(self, request_max_size: ByteSize) -> Self {
+        Self {
+            request_max_size: Some(request_max_size),
+            ..self
+        }
+    }
+
+    /// Split requests between several backends, replacing the backend given at construction
+    pub fn with_traffic_split(self, traffic_split: TrafficSplit) -> Self {
+        Self {
+            traffic_split: Some(Arc::new(traffic_split)),
+            ..self
+        }
+    }
+
+    /// Select the backend by the value of a request header, before any traffic split
+    pub fn with_header_selection(self, header_selection: HeaderSelection) -> Self {
+        Self {
+            header_selection: Some(Arc::new(header_selection)),
+            ..self
+        }
+    }
+
+    /// Buffer request and response bodies instead of streaming them
+    pub fn with_buffered(self, buffered: bool) -> Self {
+        Self { buffered, ..self }
+    }
+
+    /// Validate request bodies against their `Digest` header before forwarding them
+    pub fn with_body_digest(self, body_digest: bool) -> Self {
+        Self {
+            body_digest,
+            ..self
+        }
+    }
+
+    /// Remove fields from JSON responses
+    pub fn with_json_field_removal(self, json_field_removal: JsonFieldRemoval) -> Self {
+        Self {
+            json_field_removal: Some(Arc::new(json_field_removal)),
+            ..self
+        }
+    }
+
+    /// Rewrite the request path with a regular expression, after any prefix replacement
+    pub fn with_path_rewrite(self, path_rewrite: PathRewrite) -> Self {
+        Self {
+            path_rewrite: Some(Arc::new(path_rewrite)),
+            ..self
+        }
+    }
+
+    /// Rewrite backend URLs in HTML and JSON responses
+    pub fn with_link_rewrite(self, link_rewrite: LinkRewrite) -> Self {
+        Self {
+            link_rewrite: Some(Arc::new(link_rewrite)),
+            ..self
+        }
+    }
+
+    /// Translate the gRPC status of failed calls into HTTP for non-gRPC clients
+    pub fn with_grpc_status_to_http(self, grpc_status_to_http: bool) -> Self {
+        Self {
+            grpc_status_to_http,
+            ..self
+        }
+    }
+
+    /// Remove `Set-Cookie` from responses
+    pub fn with_strip_set_cookie(self, strip_set_cookie: bool) -> Self {
+        Self {
+            strip_set_cookie,
+            ..self
+        }
+    }
+
+    /// Set `X-Forwarded-*` headers on proxied requests, or else remove them
+    pub fn with_forwarded_headers(self, forwarded_headers: bool) -> Self {
+        Self {
+            forwarded_headers,
+            ..self
+        }
+    }
+
+    /// Reject requests with other media types than those of the route
+    pub fn with_media_types(self, media_types: MediaTypes) -> Self {
+        Self {
+            media_types: Some(Arc::new(media_types)),
+            ..self
+        }
+    }
+
+    /// Retry failed requests against the route's backends
+    pub fn with_retries(self, retries: u32) -> Self {
+        Self { retries, ..self }
+    }
+
+    /// Limit the requests in flight to the backends
+    pub fn with_in_flight_limit(self, in_flight_limit: InFlightLimit) -> Self {
+        Self {
+            in_flight_limit: Some(in_flight_limit),
+            ..self
+        }
+    }
+
+    /// Limit the websocket tunnels open to the backends, counting from upgrade until the tunnel closes
+    pub fn with_websocket_tunnel_limit(self, websocket_tunnel_limit: InFlightLimit) -> Self {
+        Self {
+            websocket_tunnel_limit: Some(websocket_tunnel_limit),
+            ..self
+        }
     }
 
     pub fn replace_prefix(&self) -> Option<&str> {
         self.replace_prefix.as_deref()
     }
 
+    pub fn method(&self) -> Option<&Method> {
+        self.method.as_ref()
+    }
+
+    pub fn ip_filter(&self) -> Option<&Arc<IpFilter>> {
+        self.ip_filter.as_ref()
+    }
+
+    pub fn metrics(&self) -> Option<&Arc<RouteMetrics>> {
+        self.metrics.as_ref()
+    }
+
+    pub fn authly_cluster(&self) -> Option<&str> {
+        self.authly_cluster.as_deref()
+    }
+
+    pub fn auth_timeout(&self) -> Option<Duration> {
+        self.auth_timeout
+    }
+
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        self.connect_timeout
+    }
+
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout
+    }
+
+    pub fn request_max_size(&self) -> Option<ByteSize> {
+        self.request_max_size
+    }
+
+    pub fn buffered(&self) -> bool {
+        self.buffered
+    }
+
+    pub fn body_digest(&self) -> bool {
+        self.body_digest
+    }
+
+    pub fn json_field_removal(&self) -> Option<&Arc<JsonFieldRemoval>> {
+        self.json_field_removal.as_ref()
+    }
+
+    pub fn path_rewrite(&self) -> Option<&Arc<PathRewrite>> {
+        self.path_rewrite.as_ref()
+    }
+
+    pub fn link_rewrite(&self) -> Option<&Arc<LinkRewrite>> {
+        self.link_rewrite.as_ref()
+    }
+
+    pub fn grpc_status_to_http(&self) -> bool {
+        self.grpc_status_to_http
+    }
+
+    pub fn strip_set_cookie(&self) -> bool {
+        self.strip_set_cookie
+    }
+
+    pub fn forwarded_headers(&self) -> bool {
+        self.forwarded_headers
+    }
+
+    pub fn media_types(&self) -> Option<&MediaTypes> {
+        self.media_types.as_deref()
+    }
+
+    pub fn in_flight_limit(&self) -> Option<&InFlightLimit> {
+        self.in_flight_limit.as_ref()
+    }
+
+    pub fn websocket_tunnel_limit(&self) -> Option<&InFlightLimit> {
+        self.websocket_tunnel_limit.as_ref()
+    }
+
+    /// The backend to proxy the request to
+    pub fn select_backend<B>(&self, req: &http::Request<B>) -> &Backend {
+        if let Some(backend) = self
+            .header_selection
+            .as_ref()
+            .and_then(|header_selection| header_selection.select(req))
+        {
+            return backend;
+        }
+
+        match &self.traffic_split {
+            Some(traffic_split) => &traffic_split.select(req).backend,
+            None => &self.backend,
+        }
+    }
+
+    /// All the backends the proxy may select
+    pub fn backends(&self) -> impl Iterator<Item = &Backend> {
+        let split_backends = self
+            .traffic_split
+            .iter()
+            .flat_map(|traffic_split| traffic_split.backends.iter().map(|split| &split.backend));
+        let selected_backends = self
+            .header_selection
+            .iter()
+            .flat_map(|header_selection| header_selection.backends.values());
+
+        std::iter::once(&self.backend)
+            .chain(split_backends)
+            .chain(selected_backends)
+    }
+
+    /// The backends to retry a request against if the selected one fails, one per retry.
+    ///
+    /// With a traffic split, retries go to the next backends (with a non-zero weight) in turn,
+    /// otherwise to the same backend.
+    pub fn retry_backends<B>(&self, req: &http::Request<B>) -> Vec<&Backend> {
+        let selected = self.select_backend(req);
+        let candidates: Vec<&Backend> = match &self.traffic_split {
+            Some(traffic_split) => traffic_split
+                .backends
+                .iter()
+                .filter(|split| split.weight > 0 || std::ptr::eq(&split.backend, selected))
+                .map(|split| &split.backend)
+                .collect(),
+            None => vec![selected],
+        };
+        let position = candidates
+            .iter()
+            .position(|candidate| std::ptr::eq(*candidate, selected))
+            .unwrap_or(0);
+
+        (1..=self.retries as usize)
+            .map(|retry| candidates[(position + retry) % candidates.len()])
+            .collect()
+    }
+
+    /// Whether the proxy matches the request, given that the path already matched
+    pub fn matches_request<B>(&self, req: &http::Request<B>) -> bool {
+        let method_matches = match &self.method {
+            Some(method) => req.method() == method,
+            None => true,
+        };
+        method_matches && self.matches_conditions(req)
+    }
+
+    /// Whether the request meets the header and query parameter conditions of the proxy, regardless of method
+    pub fn matches_conditions<B>(&self, req: &http::Request<B>) -> bool {
+        match &self.conditions {
+            Some(conditions) => conditions.matches(req),
+            None => true,
+        }
+    }
+
+    /// How specific the match of the proxy is, beyond the path.
+    /// Among proxies for the same path, the more specific match takes precedence:
+    /// one with a method, then the one with the most header conditions, then query parameter conditions.
+    pub fn match_specificity(&self) -> (bool, usize, usize) {
+        let (headers, query_params) = match &self.conditions {
+            Some(conditions) => (conditions.headers.len(), conditions.query_params.len()),
+            None => (0, 0),
+        };
+        (self.method.is_some(), headers, query_params)
+    }
+
     pub fn get_auth_directive(&self, req: &http::Request<Incoming>) -> AuthDirective {
         (self.auth_directive_fn)(req)
     }
 }
 
+/// How often a [RouteWatch] checks whether the routing table was replaced
+const ROUTE_WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Watching whether a path is still routed to a backend, for closing long-lived connections after routing changes
+#[derive(Clone)]
+pub struct RouteWatch {
+    routes: Arc<ArcSwap<matchit::Router<Route>>>,
+    path: String,
+    backend: Uri,
+}
+
+impl RouteWatch {
+    pub fn new(routes: Arc<ArcSwap<matchit::Router<Route>>>, path: String, backend: Uri) -> Self {
+        Self {
+            routes,
+            path,
+            backend,
+        }
+    }
+
+    /// Wait until the path is no longer routed to the backend
+    pub async fn unrouted(&self) {
+        let mut current = self.routes.load_full();
+        loop {
+            tokio::time::sleep(ROUTE_WATCH_INTERVAL).await;
+
+            let routes = self.routes.load_full();
+            if Arc::ptr_eq(&routes, &current) {
+                continue;
+            }
+            if !self.is_routed(&routes) {
+                return;
+            }
+            current = routes;
+        }
+    }
+
+    fn is_routed(&self, routes: &matchit::Router<Route>) -> bool {
+        let proxies = match routes.at(&self.path).map(|matchit| matchit.value) {
+            Ok(Route::Proxy(proxy)) => std::slice::from_ref(proxy),
+            Ok(Route::ProxyAlternatives(proxies)) => proxies.as_slice(),
+            _ => return false,
+        };
+        proxies
+            .iter()
+            .flat_map(Proxy::backends)
+            .any(|backend| backend.uri == self.backend)
+    }
+}
+
+/// A limit of requests in flight, or websocket tunnels open, to the backends of a route
+#[derive(Clone, Debug)]
+pub struct InFlightLimit {
+    /// Identifies the route, the limit is shared by proxies with the same key
+    pub key: String,
+    pub max: u32,
+    /// How long excess requests wait for others to complete before being rejected
+    pub wait: Duration,
+    /// How many excess requests may wait at once, any number by default
+    pub queue: Option<u32>,
+}
+
+/// Conditions on the headers and query parameters of a request, which must all hold for a proxy to match it.
+#[derive(Clone, Debug, Default)]
+pub struct RequestConditions {
+    headers: Vec<(HeaderName, ValueMatch)>,
+    query_params: Vec<(String, ValueMatch)>,
+}
+
+/// How a header or query parameter value is matched
+#[derive(Clone, Debug)]
+pub enum ValueMatch {
+    Exact(String),
+    /// A regular expression matching the whole value
+    RegularExpression(Regex),
+}
+
+impl ValueMatch {
+    pub fn regular_expression(pattern: &str) -> Result<Self, regex::Error> {
+        Ok(Self::RegularExpression(Regex::new(&format!(
+            "^(?:{pattern})$"
+        ))?))
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            Self::Exact(exact) => value == exact,
+            Self::RegularExpression(regex) => regex.is_match(value),
+        }
+    }
+}
+
+impl RequestConditions {
+    /// Require a header value. Only the first condition on a header name is kept.
+    pub fn with_header(mut self, name: HeaderName, value: ValueMatch) -> Self {
+        if !self.headers.iter().any(|(existing, _)| *existing == name) {
+            self.headers.push((name, value));
+        }
+        self
+    }
+
+    /// Require a query parameter value. Only the first condition on a parameter name is kept.
+    pub fn with_query_param(mut self, name: String, value: ValueMatch) -> Self {
+        if !self
+            .query_params
+            .iter()
+            .any(|(existing, _)| *existing == name)
+        {
+            self.query_params.push((name, value));
+        }
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty() && self.query_params.is_empty()
+    }
+
+    /// Whether the request meets all the conditions.
+    /// A repeated header matches if any of its values does, a repeated query parameter by its first value.
+    pub fn matches<B>(&self, req: &http::Request<B>) -> bool {
+        let headers_match = self.headers.iter().all(|(name, value_match)| {
+            req.headers()
+                .get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .any(|value| value_match.matches(value))
+        });
+        if !headers_match {
+            return false;
+        }
+
+        let query = req.uri().query().unwrap_or_default();
+        self.query_params.iter().all(|(name, value_match)| {
+            url::form_urlencoded::parse(query.as_bytes())
+                .find(|(param, _)| param == name)
+                .is_some_and(|(_, value)| value_match.matches(&value))
+        })
+    }
+}
+
+/// Selection of a backend by the exact value of a request header, e.g. `x-region: eu`.
+///
+/// Requests with another value, or without the header, are left to the default selection.
+#[derive(Clone, Debug)]
+pub struct HeaderSelection {
+    header: HeaderName,
+    backends: HashMap<HeaderValue, Backend>,
+}
+
+impl HeaderSelection {
+    pub fn new(header: HeaderName, backends: HashMap<HeaderValue, Backend>) -> Self {
+        Self { header, backends }
+    }
+
+    pub fn select<B>(&self, req: &http::Request<B>) -> Option<&Backend> {
+        self.backends.get(req.headers().get(&self.header)?)
+    }
+}
+
+/// A backend receiving a share of split traffic
+#[derive(Clone, Debug)]
+pub struct SplitBackend {
+    pub weight: u32,
+    pub backend: Backend,
+}
+
+/// Stateless splitting of traffic between backends, by hashing the value of a request header into weighted buckets.
+///
+/// The same header value always selects the same backend, as long as the backends and weights are unchanged.
+/// Requests without the header are sent to the first backend.
+#[derive(Clone, Debug)]
+pub struct TrafficSplit {
+    header: HeaderName,
+    backends: Vec<SplitBackend>,
+    slow_start: Option<SlowStart>,
+}
+
+/// Ramping up the weights of backends from when they started receiving traffic
+#[derive(Clone, Debug)]
+struct SlowStart {
+    window: Duration,
+    /// When each backend started receiving traffic
+    started: Vec<Instant>,
+}
+
+/// The resolution of slow start weights, per unit of configured weight
+const SLOW_START_STEPS: u64 = 1000;
+
+impl TrafficSplit {
+    /// Make a traffic split, `backends` must not be empty
+    pub fn new(header: HeaderName, backends: Vec<SplitBackend>) -> Self {
+        assert!(!backends.is_empty(), "no backends to split traffic between");
+        Self {
+            header,
+            backends,
+            slow_start: None,
+        }
+    }
+
+    /// Ramp up the weight of each backend linearly over `window`, starting when `started` says it started receiving traffic.
+    ///
+    /// While ramping up, the same header value may select different backends over time.
+    pub fn with_slow_start(self, window: Duration, started: impl Fn(&Backend) -> Instant) -> Self {
+        let started = self
+            .backends
+            .iter()
+            .map(|split| started(&split.backend))
+            .collect();
+        Self {
+            slow_start: Some(SlowStart { window, started }),
+            ..self
+        }
+    }
+
+    pub fn select<B>(&self, req: &http::Request<B>) -> &SplitBackend {
+        let first = &self.backends[0];
+        let Some(value) = req.headers().get(&self.header) else {
+            return first;
+        };
+
+        let weights: Vec<u64> = match &self.slow_start {
+            Some(slow_start) => slow_start.weights(&self.backends, Instant::now()),
+            None => self
+                .backends
+                .iter()
+                .map(|backend| backend.weight as u64)
+                .collect(),
+        };
+        let total_weight: u64 = weights.iter().sum();
+        if total_weight == 0 {
+            return first;
+        }
+
+        // SipHash-1-3 with fixed keys over the raw bytes, so all replicas and versions of the gateway agree,
+        // unlike `DefaultHasher`, whose algorithm may change between Rust releases
+        let mut hasher = SipHasher13::new_with_keys(0, 0);
+        hasher.write(value.as_bytes());
+        let mut bucket = hasher.finish() % total_weight;
+
+        for (backend, weight) in self.backends.iter().zip(weights) {
+            if bucket < weight {
+                return backend;
+            }
+            bucket -= weight;
+        }
+
+        first
+    }
+}
+
+impl SlowStart {
+    fn weights(&self, backends: &[SplitBackend], now: Instant) -> Vec<u64> {
+        let window = self.window.as_millis().max(1) as u64;
+
+        backends
+            .iter()
+            .zip(&self.started)
+            .map(|(backend, started)| {
+                let full = backend.weight as u64 * SLOW_START_STEPS;
+                let elapsed = now.saturating_duration_since(*started).as_millis() as u64;
+                if elapsed >= window || full == 0 {
+                    full
+                } else {
+                    // a starting backend always gets some traffic
+                    (full * elapsed / window).max(1)
+                }
+            })
+            .collect()
+    }
+}
+
+/// The maximum number of consecutive gateway redirects a client may be sent through
+pub const MAX_REDIRECT_CHAIN: usize = 8;
+
+/// Whether redirecting from `path` to `target` starts a chain of gateway redirects
+/// that loops or exceeds [MAX_REDIRECT_CHAIN].
+pub fn redirect_loops(routes: &matchit::Router<Route>, path: &str, target: &Uri) -> bool {
+    let mut visited = vec![path.to_string()];
+    let mut next = target.clone();
+
+    for _ in 0..MAX_REDIRECT_CHAIN {
+        if next.authority().is_some() {
+            // redirects away from the gateway are not followed
+            return false;
+        }
+        if visited.iter().any(|visited| visited == next.path()) {
+            return true;
+        }
+
+        let Ok(matchit) = routes.at(next.path()) else {
+            return false;
+        };
+        let Route::TemporaryRedirect(uri) = matchit.value else {
+            return false;
+        };
+
+        visited.push(next.path().to_string());
+        next = uri.clone();
+    }
+
+    true
+}
+
 impl From<Proxy> for Route {
     fn from(value: Proxy) -> Self {
         Route::Proxy(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_backend(uri: &str) -> Backend {
+        Backend {
+            uri: uri.parse().unwrap(),
+            class: BackendClass::Plain,
+            connect_host: None,
+            ca_certificates: None,
+            client: None,
+            http10: false,
+        }
+    }
+
+    fn split_request(user_id: &str) -> http::Request<()> {
+        http::Request::get("/")
+            .header("x-user-id", user_id)
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn traffic_split_by_header() {
+        let split = TrafficSplit::new(
+            HeaderName::from_static("x-user-id"),
+            vec![
+                SplitBackend {
+                    weight: 1,
+                    backend: test_backend("http://a"),
+                },
+                SplitBackend {
+                    weight: 3,
+                    backend: test_backend("http://b"),
+                },
+            ],
+        );
+
+        let mut b_count = 0;
+        for user_id in 0..4000 {
+            let req = split_request(&user_id.to_string());
+            let backend = &split.select(&req).backend;
+            // deterministic
+            assert_eq!(backend.uri, split.select(&req).backend.uri);
+            if backend.uri.host() == Some("b") {
+                b_count += 1;
+            }
+        }
+        assert!((2800..3200).contains(&b_count), "{b_count}");
+
+        // the same buckets on every build
+        let hosts: Vec<_> = (0..6)
+            .map(|user_id| {
+                let req = split_request(&user_id.to_string());
+                split.select(&req).backend.uri.host().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(vec!["b", "b", "b", "b", "a", "a"], hosts);
+
+        let without_header = http::Request::get("/").body(()).unwrap();
+        assert_eq!(Some("a"), split.select(&without_header).backend.uri.host());
+    }
+
+    #[test]
+    fn slow_start_reduces_new_backend_share() {
+        let now = Instant::now();
+        let window = Duration::from_secs(60);
+        let split = TrafficSplit::new(
+            HeaderName::from_static("x-user-id"),
+            vec![
+                SplitBackend {
+                    weight: 1,
+                    backend: test_backend("http://established"),
+                },
+                SplitBackend {
+                    weight: 1,
+                    backend: test_backend("http://new"),
+                },
+            ],
+        )
+        .with_slow_start(window, |backend| match backend.uri.host() {
+            Some("new") => now,
+            _ => now.checked_sub(window * 2).unwrap(),
+        });
+
+        let new_count = (0..4000)
+            .filter(|user_id| {
+                let req = split_request(&user_id.to_string());
+                split.select(&req).backend.uri.host() == Some("new")
+            })
+            .count();
+        // an even split would send it about 2000
+        assert!(new_count < 400, "{new_count}");
+
+        let Some(slow_start) = &split.slow_start else {
+            panic!("no slow start");
+        };
+        assert_eq!(
+            vec![1000, 500],
+            slow_start.weights(&split.backends, now + window / 2)
+        );
+    }
+
+    #[test]
+    fn retries_go_to_other_backends() {
+        let split = TrafficSplit::new(
+            HeaderName::from_static("x-user-id"),
+            ["a", "b", "c"]
+                .into_iter()
+                .map(|host| SplitBackend {
+                    weight: if host == "c" { 0 } else { 1 },
+                    backend: test_backend(&format!("http://{host}")),
+                })
+                .collect(),
+        );
+        let proxy = Proxy::from_backend(test_backend("http://a"))
+            .with_traffic_split(split)
+            .with_retries(3);
+
+        let without_header = http::Request::get("/").body(()).unwrap();
+        let hosts: Vec<_> = proxy
+            .retry_backends(&without_header)
+            .into_iter()
+            .map(|backend| backend.uri.host().unwrap())
+            .collect();
+        // `c` has no weight
+        assert_eq!(vec!["b", "a", "b"], hosts);
+
+        let unsplit = Proxy::from_backend(test_backend("http://a")).with_retries(2);
+        assert_eq!(2, unsplit.retry_backends(&without_header).len());
+    }
+
+    #[test]
+    fn backend_selected_by_header() {
+        let proxy = Proxy::from_backend(test_backend("http://default")).with_header_selection(
+            HeaderSelection::new(
+                HeaderName::from_static("x-region"),
+                [(HeaderValue::from_static("eu"), test_backend("http://eu"))]
+                    .into_iter()
+                    .collect(),
+            ),
+        );
+
+        let request = |region: Option<&str>| {
+            let mut req = http::Request::get("/");
+            if let Some(region) = region {
+                req = req.header("x-region", region);
+            }
+            req.body(()).unwrap()
+        };
+        let host = |req| proxy.select_backend(&req).uri.host().unwrap().to_string();
+
+        assert_eq!("eu", host(request(Some("eu"))));
+        assert_eq!("default", host(request(Some("us"))));
+        assert_eq!("default", host(request(None)));
+    }
+}
@@ -1,11 +1,26 @@
-use std::sync::Arc;
+use std::{path::Path, sync::Arc};
 
-use crate::{local, route::Route};
+use crate::{k8s::k8s_routing::RouteReloadTrigger, local, route::Route};
 
-/// Static/local routes that are always present
-pub fn static_routes(client: reqwest::Client) -> anyhow::Result<matchit::Router<Route>> {
+/// Static/local routes that are always present.
+///
+/// ACME challenges are served when given an `acme_challenge_dir`.
+pub fn static_routes(
+    client: reqwest::Client,
+    acme_challenge_dir: Option<&Path>,
+) -> anyhow::Result<matchit::Router<Route>> {
     let mut routes = matchit::Router::new();
     routes.insert("/health", Route::Local(Arc::new(local::Health { client })))?;
+
+    if let Some(dir) = acme_challenge_dir {
+        routes.insert(
+            "/.well-known/acme-challenge/{token}",
+            Route::Local(Arc::new(local::AcmeChallenge {
+                dir: dir.to_path_buf(),
+            })),
+        )?;
+    }
+
     routes.insert(
         "/favicon.ico",
         // deliberate redirect to .png
@@ -41,6 +56,23 @@ pub fn static_routes(client: reqwest::Client) -> anyhow::Result<matchit::Router<
     Ok(routes)
 }
 
+/// The admin API: reloading routes, metrics and the log level.
+///
+/// Served on the admin listener only, see `ArxConfig::admin_bind_address`.
+pub fn admin_routes(reload_trigger: RouteReloadTrigger) -> anyhow::Result<matchit::Router<Route>> {
+    let mut routes = matchit::Router::new();
+    routes.insert(
+        "/admin/reload-routes",
+        Route::Local(Arc::new(local::ReloadRoutes {
+            trigger: reload_trigger,
+        })),
+    )?;
+    routes.insert("/admin/metrics", Route::Local(Arc::new(local::Metrics)))?;
+    routes.insert("/admin/log-level", Route::Local(Arc::new(local::LogLevel)))?;
+
+    Ok(routes)
+}
+
 #[cfg(test)]
 mod tests {
     use http::Uri;
@@ -51,7 +83,7 @@ mod tests {
 
     #[tokio::test]
     async fn routes_smoke_test() {
-        let mut routes = static_routes(reqwest::Client::new()).unwrap();
+        let mut routes = static_routes(reqwest::Client::new(), None).unwrap();
 
         routes
             .insert(
@@ -89,7 +121,7 @@ mod tests {
             };
 
             let rewritten =
-                rewrite_proxied_uri(docs_uri.clone(), None, &matchit, Some("/")).unwrap();
+                rewrite_proxied_uri(docs_uri.clone(), None, &matchit, Some("/"), None).unwrap();
             assert_eq!("/", rewritten.path(), "prefix should be stripped");
         }
 
@@ -103,7 +135,7 @@ mod tests {
             };
 
             let rewritten =
-                rewrite_proxied_uri(authly_uri.clone(), None, &matchit, Some("/")).unwrap();
+                rewrite_proxied_uri(authly_uri.clone(), None, &matchit, Some("/"), None).unwrap();
             assert_eq!("/some/path", rewritten.path(), "prefix should be stripped");
         }
 
@@ -116,7 +148,8 @@ mod tests {
                 panic!("{:?}", matchit.value);
             };
 
-            let rewritten = rewrite_proxied_uri(storage_uri.clone(), None, &matchit, None).unwrap();
+            let rewritten =
+                rewrite_proxied_uri(storage_uri.clone(), None, &matchit, None, None).unwrap();
             assert_eq!(
                 "/unstripped/some/path",
                 rewritten.path(),
@@ -0,0 +1,226 @@
+//! Utilities for testing the gateway end-to-end
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    admission::RetryBudget,
+    authentication::AccessTokenSource,
+    client_ip::PeerAddr,
+    config::ArxConfig,
+    gateway::{serve_admin_request, Backends, Gateway, GatewayState},
+    headers::parse_response_headers,
+    http_client::HttpClient,
+    route::Route,
+};
+
+/// Leak a config, like `arx::run` does.
+pub fn leak_config(cfg: ArxConfig) -> &'static ArxConfig {
+    Box::leak(Box::new(cfg))
+}
+
+/// Make a gateway using default HTTP clients, and no default Authly.
+pub async fn test_gateway(
+    cfg: &'static ArxConfig,
+    routes: Arc<ArcSwap<matchit::Router<Route>>>,
+    authly_clusters: HashMap<String, Arc<dyn AccessTokenSource>>,
+) -> Gateway {
+    let http_client = HttpClient::create_default(cfg, CancellationToken::new())
+        .await
+        .unwrap();
+
+    Gateway::new(GatewayState {
+        routes,
+        backends: Backends {
+            default: http_client.clone(),
+            authly: http_client,
+        },
+        authly_client: None,
+        authly_clusters,
+        in_flight: Default::default(),
+        websocket_tunnels: Default::default(),
+        retry_budget: RetryBudget::from_config(cfg),
+        response_headers: parse_response_headers(cfg).unwrap(),
+        cfg,
+    })
+}
+
+/// Serve a gateway on an ephemeral local port, returning its address.
+pub async fn spawn_gateway(cfg: &'static ArxConfig, routes: matchit::Router<Route>) -> SocketAddr {
+    spawn_gateway_with_shared_routes(cfg, Arc::new(ArcSwap::new(Arc::new(routes)))).await
+}
+
+/// Serve a gateway whose routing table can be swapped out while it's running.
+pub async fn spawn_gateway_with_shared_routes(
+    cfg: &'static ArxConfig,
+    routes: Arc<ArcSwap<matchit::Router<Route>>>,
+) -> SocketAddr {
+    spawn_gateway_with_authly_clusters(cfg, routes, HashMap::new()).await
+}
+
+/// Serve a gateway with named Authly clusters, and no default Authly.
+pub async fn spawn_gateway_with_authly_clusters(
+    cfg: &'static ArxConfig,
+    routes: Arc<ArcSwap<matchit::Router<Route>>>,
+    authly_clusters: HashMap<String, Arc<dyn AccessTokenSource>>,
+) -> SocketAddr {
+    let gateway = test_gateway(cfg, routes, authly_clusters).await;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, remote_addr)) = listener.accept().await else {
+                return;
+            };
+            let gateway = gateway.clone();
+
+            tokio::spawn(async move {
+                let _ = auto::Builder::new(TokioExecutor::new())
+                    .serve_connection_with_upgrades(
+                        TokioIo::new(stream),
+                        hyper::service::service_fn(move |mut req: http::Request<_>| {
+                            req.extensions_mut().insert(PeerAddr(remote_addr));
+                            let gateway = gateway.clone();
+                            async move { gateway.serve_request(req).await }
+                        }),
+                    )
+                    .await;
+            });
+        }
+    });
+
+    addr
+}
+
+/// Serve admin routes on an ephemeral local port, returning its address.
+pub async fn spawn_admin(routes: matchit::Router<Route>) -> SocketAddr {
+    let routes = Arc::new(routes);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else {
+                return;
+            };
+            let routes = routes.clone();
+
+            tokio::spawn(async move {
+                let _ =
+                    auto::Builder::new(TokioExecutor::new())
+                        .serve_connection(
+                            TokioIo::new(stream),
+                            hyper::service::service_fn(move |req| {
+                                let routes = routes.clone();
+                                async move {
+                                    Ok::<_, Infallible>(serve_admin_request(&routes, req).await)
+                                }
+                            }),
+                        )
+                        .await;
+            });
+        }
+    });
+
+    addr
+}
+
+/// A certificate authority issuing the certificates of TLS backends
+pub struct TestCa {
+    key: rcgen::KeyPair,
+    cert: rcgen::Certificate,
+}
+
+impl TestCa {
+    pub fn new() -> Self {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let cert = params.self_signed(&key).unwrap();
+
+        Self { key, cert }
+    }
+
+    /// The PEM-encoded CA certificate
+    pub fn pem(&self) -> String {
+        self.cert.pem()
+    }
+
+    /// Serve `body` over TLS, with a certificate for `server_name` issued by the CA.
+    ///
+    /// Returns the server names (SNI) sent by the clients, in order.
+    pub fn spawn_backend(
+        &self,
+        listener: TcpListener,
+        server_name: &str,
+        body: &'static str,
+    ) -> Arc<Mutex<Vec<String>>> {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = rcgen::CertificateParams::new(vec![server_name.to_string()])
+            .unwrap()
+            .signed_by(&key, &self.cert, &self.key)
+            .unwrap();
+        let tls_config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.der().clone()],
+                rustls::pki_types::PrivatePkcs8KeyDer::from(key.serialize_der()).into(),
+            )
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tls_config));
+
+        let server_names = Arc::new(Mutex::new(vec![]));
+        tokio::spawn({
+            let server_names = server_names.clone();
+            async move {
+                loop {
+                    let Ok((stream, _)) = listener.accept().await else {
+                        return;
+                    };
+                    let acceptor = acceptor.clone();
+                    let server_names = server_names.clone();
+
+                    tokio::spawn(async move {
+                        let Ok(stream) = acceptor.accept(stream).await else {
+                            return;
+                        };
+                        if let Some(server_name) = stream.get_ref().1.server_name() {
+                            server_names.lock().unwrap().push(server_name.to_string());
+                        }
+
+                        let _ = auto::Builder::new(TokioExecutor::new())
+                            .serve_connection(
+                                TokioIo::new(stream),
+                                hyper::service::service_fn(move |_req| async move {
+                                    Ok::<_, Infallible>(http::Response::new(Full::new(
+                                        Bytes::from_static(body.as_bytes()),
+                                    )))
+                                }),
+                            )
+                            .await;
+                    });
+                }
+            }
+        });
+
+        server_names
+    }
+}